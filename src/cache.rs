@@ -0,0 +1,215 @@
+/*
+	cache
+	a job with several targets looks up the same things about its source datasets
+	for each of them: their children, snapshots, encryption and so on. the
+	SourceCache sits between the job and the executor, and answers a "zfs list" or
+	"zfs get" about one of the job's source datasets (or anything under them) on
+	the source host with what the host answered the last time it was asked. a zfs
+	command which changes a dataset (snapshot, destroy, receive and the like)
+	forgets everything kept about it and the datasets above and below it. holds,
+	releases and bookmarks don't change anything which is looked up, and nor does
+	destroying a bookmark, as a snapshot is always used in preference to its
+	bookmark. a cache only lasts for one run of one job.
+*/
+use async_trait::async_trait;
+use std::{collections::BTreeMap, sync::Mutex};
+use crate::executor::{CommandOutput, Stage, ZfsExecutor};
+
+// the zfs commands which change the datasets they're given. a send only reads its
+// source, but the receive at the other end of it is a change when it is into one of
+// the source datasets (or above or below one).
+const CHANGES:[&str; 11] = ["create", "destroy", "snapshot", "rollback", "clone", "promote", "rename", "set", "inherit", "receive", "recv"];
+
+pub struct SourceCache<'a>
+{
+	exec: &'a dyn ZfsExecutor,
+	host: String,
+	sources: Vec<String>,
+	// the output of each command, by its arguments.
+	outputs: Mutex<BTreeMap<Vec<String>, CommandOutput>>,
+}
+
+impl<'a> SourceCache<'a>
+{
+	pub fn new(exec:&'a dyn ZfsExecutor, host:&str, sources:&[&str]) -> SourceCache<'a>
+	{
+		SourceCache{exec, host:String::from(host), sources:sources.iter().map(|source| String::from(*source)).collect(), outputs:Mutex::new(BTreeMap::new())}
+	}
+
+	fn forget(&self, dataset:&str)
+	{
+		self.outputs.lock().unwrap().retain(|args, _| !related(dataset_of(args.last().map(|arg| arg.as_str()).unwrap_or("")), dataset));
+	}
+}
+
+// the dataset a snapshot or bookmark is of, or the dataset itself.
+fn dataset_of(name:&str) -> &str
+{
+	name.split(['@', '#']).next().unwrap_or(name)
+}
+
+fn is_under(dataset:&str, parent:&str) -> bool
+{
+	dataset == parent || dataset.strip_prefix(parent).is_some_and(|rest| rest.starts_with('/'))
+}
+
+// whether a change to one dataset can change what is listed about the other.
+fn related(a:&str, b:&str) -> bool
+{
+	is_under(a, b) || is_under(b, a)
+}
+
+#[async_trait]
+impl<'a> ZfsExecutor for SourceCache<'a>
+{
+	async fn run(&self, host:&str, program:&str, args:&[&str]) -> CommandOutput
+	{
+		if host != self.host || program != "zfs"
+		{
+			return self.exec.run(host, program, args).await;
+		}
+		match args.first()
+		{
+			Some(&"list")|Some(&"get")=>
+				{
+					let dataset = dataset_of(args.last().unwrap_or(&""));
+					if !self.sources.iter().any(|source| is_under(dataset, source.as_str()))
+					{
+						return self.exec.run(host, program, args).await;
+					}
+					let key:Vec<String> = args.iter().map(|arg| String::from(*arg)).collect();
+					if let Some(output) = self.outputs.lock().unwrap().get(&key)
+					{
+						return output.clone();
+					}
+					let output = self.exec.run(host, program, args).await;
+					if output.success
+					{
+						self.outputs.lock().unwrap().insert(key, output.clone());
+					}
+					output
+				},
+			Some(command) if CHANGES.contains(command)=>
+				{
+					let output = self.exec.run(host, program, args).await;
+					// a renamed dataset has two names, and a clone two datasets.
+					for arg in args.iter().skip(1).filter(|arg| !arg.starts_with('-') && (*command != "destroy" || !arg.contains('#')))
+					{
+						self.forget(dataset_of(arg));
+					}
+					output
+				},
+			_=>self.exec.run(host, program, args).await,
+		}
+	}
+
+	async fn pipeline(&self, stages:&[Stage]) -> Vec<CommandOutput>
+	{
+		let outputs = self.exec.pipeline(stages).await;
+		for stage in stages.iter().filter(|stage| stage.host == self.host && stage.program == "zfs")
+		{
+			if let (Some(command), Some(dataset)) = (stage.args.first(), stage.args.last())
+			{
+				if CHANGES.contains(&command.as_str())
+				{
+					self.forget(dataset_of(dataset.as_str()));
+				}
+			}
+		}
+		outputs
+	}
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+	use crate::fake::FakeExecutor;
+
+	const SNAPSHOTS:[&str; 9] = ["list", "-H", "-t", "snapshot", "-o", "name", "-s", "createtxg", "tank/data"];
+
+	fn fake() -> FakeExecutor
+	{
+		FakeExecutor::from_json(r#"{"hosts":{
+				"localhost":{"datasets":[{"name":"tank"}, {"name":"tank/data", "snapshots":[{"name":"one"}]}, {"name":"tank/data/child"}, {"name":"tank/other"}]}}}"#)
+	}
+
+	// how many times the fake was asked to run command on the local host.
+	fn runs(fake:&FakeExecutor, command:&[&str]) -> usize
+	{
+		let line = format!("zfs {}", command.join(" "));
+		fake.commands("localhost").iter().filter(|c| **c == line).count()
+	}
+
+	#[tokio::test]
+	async fn lookups_are_answered_from_the_cache()
+	{
+		let fake = fake();
+		let cache = SourceCache::new(&fake, "", &["tank/data"]);
+		let encryption = ["get", "-H", "-o", "value", "encryption", "tank/data/child"];
+		// as the first target and then the second would look them up.
+		for _ in 0..2
+		{
+			assert_eq!(cache.zfs("", &SNAPSHOTS).await.stdout, "tank/data@one\n");
+			assert!(cache.zfs("", &encryption).await.success);
+		}
+		assert_eq!(runs(&fake, &SNAPSHOTS), 1);
+		assert_eq!(runs(&fake, &encryption), 1);
+		// datasets which aren't the job's, and other hosts, are always looked up.
+		let other = ["list", "-H", "-o", "name", "tank/other"];
+		cache.zfs("", &other).await;
+		cache.zfs("", &other).await;
+		assert_eq!(runs(&fake, &other), 2);
+	}
+
+	#[tokio::test]
+	async fn changes_to_a_source_are_seen_by_the_next_lookup()
+	{
+		let fake = fake();
+		let cache = SourceCache::new(&fake, "", &["tank/data"]);
+		cache.zfs("", &SNAPSHOTS).await;
+		assert!(cache.zfs("", &["snapshot", "tank/data@two"]).await.success);
+		assert_eq!(cache.zfs("", &SNAPSHOTS).await.stdout, "tank/data@one\ntank/data@two\n");
+		assert!(cache.zfs("", &["destroy", "tank/data@one"]).await.success);
+		assert_eq!(cache.zfs("", &SNAPSHOTS).await.stdout, "tank/data@two\n");
+		// a recursive snapshot of the parent changes its children.
+		assert!(cache.zfs("", &["snapshot", "-r", "tank@three"]).await.success);
+		assert_eq!(cache.zfs("", &SNAPSHOTS).await.stdout, "tank/data@two\ntank/data@three\n");
+		assert_eq!(runs(&fake, &SNAPSHOTS), 4);
+	}
+
+	#[tokio::test]
+	async fn holds_and_bookmarks_dont_change_lookups()
+	{
+		let fake = fake();
+		let cache = SourceCache::new(&fake, "", &["tank/data"]);
+		cache.zfs("", &SNAPSHOTS).await;
+		assert!(cache.zfs("", &["hold", "piper:job:backup:backup", "tank/data@one"]).await.success);
+		assert!(cache.zfs("", &["bookmark", "tank/data@one", "tank/data#piper_backup"]).await.success);
+		assert!(cache.zfs("", &["destroy", "tank/data#piper_backup"]).await.success);
+		assert!(cache.zfs("", &["release", "piper:job:backup:backup", "tank/data@one"]).await.success);
+		// and nor does a change to a dataset which isn't above or below the source.
+		assert!(cache.zfs("", &["snapshot", "tank/other@two"]).await.success);
+		cache.zfs("", &SNAPSHOTS).await;
+		assert_eq!(runs(&fake, &SNAPSHOTS), 1);
+	}
+
+	#[tokio::test]
+	async fn a_send_received_into_a_source_is_seen_by_the_next_lookup()
+	{
+		let fake = fake();
+		let cache = SourceCache::new(&fake, "", &["tank/data"]);
+		let child = ["list", "-H", "-t", "snapshot", "-o", "name", "-s", "createtxg", "tank/data/child"];
+		assert_eq!(cache.zfs("", &child).await.stdout, "");
+		let stage = |name:&str, args:&[&str]| Stage{name:String::from(name), host:String::from(""), program:String::from("zfs"), args:args.iter().map(|a| String::from(*a)).collect()};
+		let outputs = cache.pipeline(&[stage("send", &["send", "tank/data@one"]), stage("recv", &["recv", "tank/data/child/copy"])]).await;
+		assert!(outputs.iter().all(|o| o.success));
+		cache.zfs("", &child).await;
+		assert_eq!(runs(&fake, &child), 2);
+		// a send on its own changes nothing.
+		cache.zfs("", &SNAPSHOTS).await;
+		cache.pipeline(&[stage("send", &["send", "-n", "tank/data@one"])]).await;
+		cache.zfs("", &SNAPSHOTS).await;
+		assert_eq!(runs(&fake, &SNAPSHOTS), 1);
+	}
+}
@@ -0,0 +1,174 @@
+/*
+	check
+	"piper check" is for nagios (or anything which runs nagios plugins). for each job
+	with a "max_lag" (and optionally a "warn_lag") it compares the creation time of
+	the newest snapshot on the source with that of the newest snapshot on the target,
+	and prints a line saying how far behind the target is. the first line is the
+	worst of them, and piper exits with the nagios code for it: 0 (ok), 1 (warning),
+	2 (critical) or 3 (unknown, when a job couldn't be checked at all).
+*/
+use std::time::Duration;
+use crate::duration::format_duration;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Status
+{
+	Ok,
+	Warning,
+	Critical,
+	Unknown,
+}
+
+impl Status
+{
+	pub fn name(&self) -> &'static str
+	{
+		match self
+		{
+			Status::Ok=>"OK",
+			Status::Warning=>"WARNING",
+			Status::Critical=>"CRITICAL",
+			Status::Unknown=>"UNKNOWN",
+		}
+	}
+
+	pub fn code(&self) -> i32
+	{
+		match self
+		{
+			Status::Ok=>0,
+			Status::Warning=>1,
+			Status::Critical=>2,
+			Status::Unknown=>3,
+		}
+	}
+
+	// how bad the status is. a job which can't be checked is worse than one which is
+	// only getting behind, but not as bad as one known to be too far behind.
+	fn severity(&self) -> u8
+	{
+		match self
+		{
+			Status::Ok=>0,
+			Status::Warning=>1,
+			Status::Unknown=>2,
+			Status::Critical=>3,
+		}
+	}
+}
+
+pub struct JobCheck
+{
+	pub name: String,
+	pub status: Status,
+	pub message: String,
+}
+
+// the status of a job whose target is lag behind its source.
+pub fn lag_status(name:&str, lag:Duration, warn_lag:Option<Duration>, max_lag:Option<Duration>) -> JobCheck
+{
+	let (status, limit) = match (warn_lag, max_lag)
+		{
+			(_, Some(max_lag)) if lag > max_lag=>(Status::Critical, format!(", more than the max_lag of {}", format_duration(max_lag))),
+			(Some(warn_lag), _) if lag > warn_lag=>(Status::Warning, format!(", more than the warn_lag of {}", format_duration(warn_lag))),
+			_=>(Status::Ok, String::from("")),
+		};
+	JobCheck{name:String::from(name), status, message:format!("{} behind the source{}", format_duration(lag), limit)}
+}
+
+// the result of the checks the way nagios expects it, one line for each, and the exit code.
+pub fn report(checks:&[JobCheck]) -> (Vec<String>, i32)
+{
+	let worst = match checks.iter().max_by_key(|check| check.status.severity())
+		{
+			None=>return (vec![String::from("PIPER UNKNOWN - no job has a max_lag or warn_lag to check")], Status::Unknown.code()),
+			Some(worst)=>worst,
+		};
+	let mut lines = vec![match worst.status
+		{
+			Status::Ok=>
+				{
+					let ess = if checks.len() == 1 {""} else {"s"};
+					format!("PIPER OK - {} job{} within their lag limits", checks.len(), ess)
+				},
+			_=>
+				{
+					let others = checks.iter().filter(|check| check.status != Status::Ok).count() - 1;
+					let more = if others == 0 {String::from("")} else {format!(" (and {} more)", others)};
+					format!("PIPER {} - job \"{}\": {}{}", worst.status.name(), worst.name, worst.message, more)
+				},
+		}];
+	for check in checks.iter()
+	{
+		lines.push(format!("{}: job \"{}\": {}", check.status.name(), check.name, check.message));
+	}
+	(lines, worst.status.code())
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+
+	const HOUR:Duration = Duration::from_secs(60*60);
+
+	fn status(lag:Duration) -> (&'static str, String)
+	{
+		let check = lag_status("root", lag, Some(HOUR), Some(2*HOUR));
+		(check.status.name(), check.message)
+	}
+
+	#[test]
+	fn ok_up_to_the_warn_lag()
+	{
+		assert_eq!(status(Duration::from_secs(0)), ("OK", String::from("0s behind the source")));
+		assert_eq!(status(HOUR), ("OK", String::from("1h behind the source")));
+	}
+
+	#[test]
+	fn warning_past_the_warn_lag()
+	{
+		assert_eq!(status(HOUR + Duration::from_secs(1)), ("WARNING", String::from("1h1s behind the source, more than the warn_lag of 1h")));
+		assert_eq!(status(2*HOUR).0, "WARNING");
+	}
+
+	#[test]
+	fn critical_past_the_max_lag()
+	{
+		assert_eq!(status(2*HOUR + Duration::from_secs(1)), ("CRITICAL", String::from("2h1s behind the source, more than the max_lag of 2h")));
+	}
+
+	#[test]
+	fn either_limit_on_its_own()
+	{
+		assert_eq!(lag_status("root", 3*HOUR, None, Some(2*HOUR)).status.name(), "CRITICAL");
+		assert_eq!(lag_status("root", HOUR, None, Some(2*HOUR)).status.name(), "OK");
+		assert_eq!(lag_status("root", 3*HOUR, Some(2*HOUR), None).status.name(), "WARNING");
+	}
+
+	#[test]
+	fn codes()
+	{
+		let codes:Vec<i32> = [Status::Ok, Status::Warning, Status::Critical, Status::Unknown].iter().map(|status| status.code()).collect();
+		assert_eq!(codes, vec![0, 1, 2, 3]);
+	}
+
+	#[test]
+	fn the_worst_check_is_reported()
+	{
+		let unknown = JobCheck{name:String::from("gone"), status:Status::Unknown, message:String::from("no dataset matches \"tank/gone\"")};
+		assert_eq!(report(&[lag_status("root", HOUR, Some(HOUR), None)]), (vec![String::from("PIPER OK - 1 job within their lag limits"), String::from("OK: job \"root\": 1h behind the source")], 0));
+		let (lines, code) = report(&[lag_status("root", 2*HOUR, Some(HOUR), None), unknown]);
+		assert_eq!(code, 3);
+		assert_eq!(lines, vec![
+				"PIPER UNKNOWN - job \"gone\": no dataset matches \"tank/gone\" (and 1 more)",
+				"WARNING: job \"root\": 2h behind the source, more than the warn_lag of 1h",
+				"UNKNOWN: job \"gone\": no dataset matches \"tank/gone\"",
+			]);
+		let unknown = JobCheck{name:String::from("gone"), status:Status::Unknown, message:String::from("")};
+		let (lines, code) = report(&[unknown, lag_status("root", 3*HOUR, None, Some(2*HOUR))]);
+		assert_eq!(code, 2);
+		assert_eq!(lines[0], "PIPER CRITICAL - job \"root\": 3h behind the source, more than the max_lag of 2h (and 1 more)");
+		assert_eq!(report(&[]), (vec![String::from("PIPER UNKNOWN - no job has a max_lag or warn_lag to check")], 3));
+	}
+}
@@ -0,0 +1,315 @@
+/*
+	daemon
+	with --daemon piper keeps running and runs each job whenever its "schedule"
+	says it is due, rather than relying on cron. each job is started as soon as it
+	comes due, alongside any others which are still running (within the usual
+	limits), and what it did is written out as soon as it finishes. SIGHUP reloads
+	the config file, hosts section and all, straight away: jobs which are running
+	carry on as they were, and the jobs started after it use the new config. SIGTERM
+	(or SIGINT) stops the daemon once any jobs which are running finish.
+*/
+use chrono::{DateTime, Local};
+use futures::{stream::FuturesUnordered, StreamExt};
+use log::*;
+use std::{collections::BTreeMap, collections::BTreeSet, future::Future, path::Path, pin::Pin, sync::Arc, time::Duration};
+use tokio::signal::unix::{signal, Signal, SignalKind};
+use crate::{duration::format_duration, events::Events, executor::{ProcessExecutor, ZfsExecutor}, fake::FakeExecutor, hosts::Hosts, report, schedule::Schedule, summary, summary::JobResult};
+use crate::{job_name, notify_results, plan::Mode, read_config, save_simulation, start_job, write_history, write_metrics, JobLimits, Piper, Run};
+
+struct ScheduledJob
+{
+	// the index of the job in piper.jobs.
+	index: usize,
+	name: String,
+	schedule: Schedule,
+	last_run: Option<DateTime<Local>>,
+	// none while the job is running, as well as when it never comes due.
+	next_run: Option<DateTime<Local>>,
+}
+
+// the jobs in the config which have a valid schedule. last_runs carries the
+// last run of each job over a reload, by job name, and the jobs which are still
+// running aren't due until they finish.
+fn scheduled_jobs(piper:&Piper, last_runs:&BTreeMap<String, DateTime<Local>>, running:&BTreeSet<String>, now:DateTime<Local>) -> Vec<ScheduledJob>
+{
+	let mut jobs = Vec::new();
+	for (index, j) in piper.jobs.iter().enumerate()
+	{
+		let name = job_name(j);
+		let text = match &j.schedule
+			{
+				None=>
+					{
+						warn!("Job \"{}\" has no schedule and will not be run by the daemon.", name);
+						continue;
+					},
+				Some(text)=>text,
+			};
+		let schedule = match Schedule::parse(text)
+			{
+				Err(e)=>
+					{
+						error!("Job \"{}\" has an invalid schedule \"{}\" and will not be run: {}", name, text, e);
+						continue;
+					},
+				Ok(schedule)=>schedule,
+			};
+		let last_run = last_runs.get(&name).copied();
+		let next_run = if running.contains(&name) {None} else {schedule.next_run(last_run, now)};
+		match (next_run, running.contains(&name))
+		{
+			(_, true)=>info!("Job \"{}\" ({}) is running.", name, text),
+			(None, false)=>warn!("Job \"{}\" schedule \"{}\" never comes due.", name, text),
+			(Some(next), false)=>info!("Job \"{}\" ({}) next runs at {}", name, text, next),
+		}
+		jobs.push(ScheduledJob{index, name, schedule, last_run, next_run});
+	}
+	jobs
+}
+
+// the jobs which are due at now, by their place in jobs.
+fn due_jobs(jobs:&[ScheduledJob], now:DateTime<Local>) -> Vec<usize>
+{
+	jobs.iter().enumerate().filter(|(_, job)| job.next_run.is_some_and(|next| next <= now)).map(|(i, _)| i).collect()
+}
+
+// how long to sleep at now until the next job is due. a day if none ever is, as
+// nothing but a signal or a job finishing can change that.
+fn time_to_next(jobs:&[ScheduledJob], now:DateTime<Local>) -> Duration
+{
+	match jobs.iter().filter_map(|job| job.next_run).min()
+	{
+		None=>Duration::from_secs(24*60*60),
+		Some(next)=>(next - now).to_std().unwrap_or(Duration::from_secs(0)),
+	}
+}
+
+fn listen(kind:SignalKind, name:&str) -> Option<Signal>
+{
+	match signal(kind)
+	{
+		Err(e)=>{error!("Can't listen for {}: {}", name, e);None},
+		Ok(s)=>Some(s),
+	}
+}
+
+// waits for the signal, or forever if it couldn't be listened for.
+async fn received(signal:&mut Option<Signal>)
+{
+	match signal
+	{
+		None=>std::future::pending::<()>().await,
+		Some(s)=>{s.recv().await;},
+	}
+}
+
+fn executor_for(piper:&Piper) -> ProcessExecutor
+{
+	ProcessExecutor::new(match &piper.hosts
+		{
+			None=>Hosts::new(),
+			Some(hosts)=>hosts.clone(),
+		})
+}
+
+// the config a job was started with, which it keeps to the end even if the config
+// is reloaded while it runs.
+#[derive(Clone)]
+struct Config
+{
+	piper: Arc<Piper>,
+	exec: Arc<ProcessExecutor>,
+	job_limits: Arc<JobLimits>,
+}
+
+impl Config
+{
+	fn new(piper:Piper) -> Config
+	{
+		let exec = Arc::new(executor_for(&piper));
+		let job_limits = Arc::new(JobLimits::new(&piper, &Mode::Run));
+		Config{piper:Arc::new(piper), exec, job_limits}
+	}
+}
+
+type RunningJob<'a> = Pin<Box<dyn Future<Output = (String, JobResult)> + 'a>>;
+
+// writes out what a job which has finished did, as a run of its own would.
+async fn finished(fake:Option<&FakeExecutor>, piper:&Piper, result:JobResult, report:Option<Option<&Path>>)
+{
+	let results = vec![result];
+	summary::summarize(&results);
+	if let Some(fake) = fake
+	{
+		save_simulation(fake);
+	}
+	write_metrics(piper, &results);
+	write_history(piper, &results, fake.is_some());
+	notify_results(piper, &results, fake.is_some()).await;
+	if let Some(path) = report
+	{
+		if let Err(e) = report::write(&results, path, true)
+		{
+			error!("{}", e);
+		}
+	}
+}
+
+// runs until told to stop. returns the exit code. the jobs are run by fake if there is
+// one, and otherwise on the hosts in the config. report is where the report of each
+// job is appended as it finishes (stdout if it has no path), if it is written at all.
+pub async fn run(fake:Option<&FakeExecutor>, json_file_path:&Path, piper:Piper, events:&Arc<Events>, send_no_op:bool, recv_no_op:bool, report:Option<Option<&Path>>) -> i32
+{
+	let mut sighup = listen(SignalKind::hangup(), "SIGHUP");
+	let mut sigterm = listen(SignalKind::terminate(), "SIGTERM");
+	let mut sigint = listen(SignalKind::interrupt(), "SIGINT");
+	let every_run = Run{mode:&Mode::Run, events, send_no_op, recv_no_op};
+	let every_run = &every_run;
+	let mut config = Config::new(piper);
+	let mut running_names:BTreeSet<String> = BTreeSet::new();
+	let mut jobs = scheduled_jobs(&config.piper, &BTreeMap::new(), &running_names, Local::now());
+	let mut running:FuturesUnordered<RunningJob> = FuturesUnordered::new();
+	let mut stopping = false;
+	info!("Piper daemon started with {} scheduled job{}.", jobs.len(), if jobs.len() == 1 {""} else {"s"});
+	loop
+	{
+		let now = Local::now();
+		if !stopping
+		{
+			for i in due_jobs(&jobs, now)
+			{
+				let job = &mut jobs[i];
+				info!("Starting job \"{}\"", job.name);
+				job.last_run = Some(now);
+				job.next_run = None;
+				running_names.insert(job.name.clone());
+				let (started_with, index, name) = (config.clone(), job.index, job.name.clone());
+				running.push(Box::pin(async move
+					{
+						let exec:&dyn ZfsExecutor = match fake
+							{
+								None=>started_with.exec.as_ref(),
+								Some(fake)=>fake,
+							};
+						let result = start_job(exec, &started_with.job_limits, &started_with.piper.jobs[index], every_run).await;
+						(name, result)
+					}));
+			}
+		}
+		else if running.is_empty()
+		{
+			break;
+		}
+		let sleep = time_to_next(&jobs, now);
+		if !stopping
+		{
+			debug!("Sleeping {} until the next job is due.", format_duration(sleep));
+		}
+		tokio::select!
+		{
+			Some((name, result)) = running.next(), if !running.is_empty() =>
+				{
+					running_names.remove(&name);
+					finished(fake, &config.piper, result, report).await;
+					// the job may have gone from the config while it ran.
+					if let Some(job) = jobs.iter_mut().find(|job| job.name == name)
+					{
+						job.next_run = job.schedule.next_run(job.last_run, Local::now());
+						if let Some(next) = job.next_run
+						{
+							info!("Job \"{}\" next runs at {}", job.name, next);
+						}
+					}
+				},
+			_ = tokio::time::sleep(sleep), if !stopping => {},
+			_ = received(&mut sighup) =>
+				{
+					info!("SIGHUP received. Reloading config file \"{}\"", json_file_path.display());
+					match read_config(json_file_path)
+					{
+						Err((_, message))=>error!("Keeping the current config. {}", message),
+						Ok(new_piper)=>
+							{
+								let last_runs:BTreeMap<String, DateTime<Local>> = jobs.iter().filter_map(|job| job.last_run.map(|last_run| (job.name.clone(), last_run))).collect();
+								config = Config::new(new_piper);
+								jobs = scheduled_jobs(&config.piper, &last_runs, &running_names, Local::now());
+							},
+					}
+				},
+			_ = received(&mut sigterm) => {info!("SIGTERM received. Stopping once the running jobs finish.");stopping = true;},
+			_ = received(&mut sigint) => {info!("SIGINT received. Stopping once the running jobs finish.");stopping = true;},
+		}
+	}
+	info!("Piper daemon stopped.");
+	summary::EXIT_OK
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+	use chrono::TimeZone;
+
+	fn piper() -> Piper
+	{
+		serde_json::from_str(r#"{"jobs":[
+				{"name":"hourly", "sourcedataset":"tank/data", "targetdataset":"backup:backup", "schedule":"every 1h"},
+				{"name":"unscheduled", "sourcedataset":"tank/data", "targetdataset":"backup:backup"},
+				{"name":"broken", "sourcedataset":"tank/data", "targetdataset":"backup:backup", "schedule":"every now and then"},
+				{"name":"daily", "sourcedataset":"tank/vms", "targetdataset":"offsite:pool", "schedule":"0 0 * * *"}]}"#).unwrap()
+	}
+
+	fn noon() -> DateTime<Local>
+	{
+		Local.with_ymd_and_hms(2024, 6, 1, 12, 30, 0).unwrap()
+	}
+
+	fn names(jobs:&[ScheduledJob], indexes:&[usize]) -> Vec<String>
+	{
+		indexes.iter().map(|i| jobs[*i].name.clone()).collect()
+	}
+
+	#[test]
+	fn only_jobs_with_a_valid_schedule_are_scheduled()
+	{
+		let jobs = scheduled_jobs(&piper(), &BTreeMap::new(), &BTreeSet::new(), noon());
+		assert_eq!(jobs.iter().map(|job| (job.name.as_str(), job.index)).collect::<Vec<_>>(), vec![("hourly", 0), ("daily", 3)]);
+	}
+
+	#[test]
+	fn the_next_job_due()
+	{
+		let mut jobs = scheduled_jobs(&piper(), &BTreeMap::new(), &BTreeSet::new(), noon());
+		// an interval job which hasn't run is due straight away, a cron job when cron says.
+		assert_eq!(names(&jobs, &due_jobs(&jobs, noon())), vec!["hourly"]);
+		assert_eq!(time_to_next(&jobs, noon()), Duration::from_secs(0));
+		assert_eq!(jobs[1].next_run, Some(Local.with_ymd_and_hms(2024, 6, 2, 0, 0, 0).unwrap()));
+		// once it has run it is next due an interval after it started.
+		jobs[0].last_run = Some(noon());
+		jobs[0].next_run = jobs[0].schedule.next_run(jobs[0].last_run, noon() + chrono::Duration::minutes(10));
+		let later = noon() + chrono::Duration::minutes(10);
+		assert!(due_jobs(&jobs, later).is_empty());
+		assert_eq!(time_to_next(&jobs, later), Duration::from_secs(50*60));
+		assert_eq!(names(&jobs, &due_jobs(&jobs, noon() + chrono::Duration::hours(12))), vec!["hourly", "daily"]);
+		// a job which is running (without a next run) is never due.
+		jobs[0].next_run = None;
+		assert!(due_jobs(&jobs, later).is_empty());
+		assert_eq!(time_to_next(&jobs, later), Duration::from_secs((11*60+20)*60));
+		assert_eq!(time_to_next(&[], later), Duration::from_secs(24*60*60));
+	}
+
+	#[test]
+	fn a_reload_keeps_the_last_runs()
+	{
+		let last_runs = BTreeMap::from([(String::from("hourly"), noon() - chrono::Duration::minutes(30)), (String::from("gone"), noon())]);
+		let jobs = scheduled_jobs(&piper(), &last_runs, &BTreeSet::new(), noon());
+		assert_eq!(jobs[0].last_run, Some(noon() - chrono::Duration::minutes(30)));
+		assert_eq!(jobs[0].next_run, Some(noon() + chrono::Duration::minutes(30)));
+		assert!(due_jobs(&jobs, noon()).is_empty());
+		// a job which is still running when the config is reloaded isn't due until it finishes.
+		let running = BTreeSet::from([String::from("daily")]);
+		let jobs = scheduled_jobs(&piper(), &last_runs, &running, noon() + chrono::Duration::hours(12));
+		assert_eq!(jobs[1].next_run, None);
+		assert_eq!(names(&jobs, &due_jobs(&jobs, noon() + chrono::Duration::hours(12))), vec!["hourly"]);
+	}
+}
@@ -0,0 +1,127 @@
+/*
+	duration
+	durations in the config file are written as a number followed by a unit, s
+	(seconds), m (minutes), h (hours), d (days) or w (weeks), and can be combined,
+	so "90m", "1h30m" and "5400s" are all the same. a number on its own is seconds.
+*/
+use std::time::Duration;
+
+pub fn parse_duration(text:&str) -> Result<Duration, String>
+{
+	let text = text.trim();
+	if text.is_empty()
+	{
+		return Err(String::from("empty duration"));
+	}
+	let mut seconds:u64 = 0;
+	let mut number = String::from("");
+	for c in text.chars()
+	{
+		if c.is_ascii_digit()
+		{
+			number.push(c);
+			continue;
+		}
+		let unit:u64 = match c
+			{
+				's'=>1,
+				'm'=>60,
+				'h'=>60*60,
+				'd'=>24*60*60,
+				'w'=>7*24*60*60,
+				_=>return Err(format!("unknown unit '{}' in duration \"{}\"", c, text)),
+			};
+		if number.is_empty()
+		{
+			return Err(format!("missing number before '{}' in duration \"{}\"", c, text));
+		}
+		let n:u64 = match number.parse()
+			{
+				Err(e)=>return Err(format!("bad number in duration \"{}\": {}", text, e)),
+				Ok(n)=>n,
+			};
+		seconds = match n.checked_mul(unit).and_then(|n| seconds.checked_add(n))
+			{
+				None=>return Err(format!("duration \"{}\" is too long", text)),
+				Some(seconds)=>seconds,
+			};
+		number = String::from("");
+	}
+	if !number.is_empty()
+	{
+		// a trailing number without a unit is seconds.
+		let n = match number.parse::<u64>()
+			{
+				Err(e)=>return Err(format!("bad number in duration \"{}\": {}", text, e)),
+				Ok(n)=>n,
+			};
+		seconds = match seconds.checked_add(n)
+			{
+				None=>return Err(format!("duration \"{}\" is too long", text)),
+				Some(seconds)=>seconds,
+			};
+	}
+	Ok(Duration::from_secs(seconds))
+}
+
+// the reverse of parse_duration, for logging.
+pub fn format_duration(duration:Duration) -> String
+{
+	let mut seconds = duration.as_secs();
+	if seconds == 0
+	{
+		return String::from("0s");
+	}
+	let mut text = String::from("");
+	for (unit, size) in [("d", 24*60*60), ("h", 60*60), ("m", 60), ("s", 1)]
+	{
+		if seconds >= size
+		{
+			text.push_str(format!("{}{}", seconds / size, unit).as_str());
+			seconds %= size;
+		}
+	}
+	text
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+
+	#[test]
+	fn units_combine()
+	{
+		assert_eq!(parse_duration("90m"), Ok(Duration::from_secs(5400)));
+		assert_eq!(parse_duration("1h30m"), Ok(Duration::from_secs(5400)));
+		assert_eq!(parse_duration(" 5400 "), Ok(Duration::from_secs(5400)));
+		assert_eq!(parse_duration("1w1d1s"), Ok(Duration::from_secs(8*24*60*60 + 1)));
+	}
+
+	#[test]
+	fn bad_durations_are_errors()
+	{
+		assert!(parse_duration("").is_err());
+		assert!(parse_duration("h").is_err());
+		assert!(parse_duration("5x").is_err());
+		assert!(parse_duration("99999999999999999999s").is_err());
+	}
+
+	#[test]
+	fn overflow_is_an_error()
+	{
+		assert!(parse_duration("18446744073709551615w").is_err());
+		assert!(parse_duration("300000000000000d").is_err());
+		assert!(parse_duration("18446744073709551615s1s").is_err());
+		assert!(parse_duration("18446744073709551615s1").is_err());
+		assert_eq!(parse_duration("18446744073709551615"), Ok(Duration::from_secs(u64::MAX)));
+	}
+
+	#[test]
+	fn format_is_the_reverse_of_parse()
+	{
+		assert_eq!(format_duration(Duration::from_secs(0)), "0s");
+		assert_eq!(format_duration(Duration::from_secs(5400)), "1h30m");
+		assert_eq!(format_duration(Duration::from_secs(90061)), "1d1h1m1s");
+	}
+}
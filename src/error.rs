@@ -0,0 +1,94 @@
+/*
+	error
+	everything that can stop a dataset (or a whole job) from being replicated.
+	each variant maps to an error class which is what gets reported in the per-job
+	summary at the end of a run.
+*/
+use std::fmt;
+
+#[derive(Debug, Clone)]
+pub enum PiperError
+{
+	// the job itself can't be run as configured.
+	Config(String),
+	// ssh to a remote host failed.
+	SshFailure{host:String, message:String},
+	// a dataset piper needed to look at doesn't exist.
+	DatasetMissing{host:String, dataset:String},
+	// there is nothing (matching the prefix) on the source to replicate.
+	NoSnapshots{host:String, dataset:String, prefix:String},
+	// the target can't be replicated into incrementally from the source.
+	DivergedTarget{dataset:String, reason:String},
+	// any other zfs command failed.
+	Command{host:String, command:String, message:String},
+	SendFailure(String),
+	RecvFailure(String),
+	// a stage of the pipeline between zfs send and zfs recv failed.
+	StageFailure{stage:String, message:String},
+	// a lock file couldn't be taken, normally because another piper holds it.
+	Locked{path:String, message:String},
+	// what apply would do to a dataset is no longer what the plan being applied says.
+	Drifted{dataset:String, reason:String},
+	// a pre_hook, post_hook or on_failure command failed or timed out.
+	HookFailure{hook:String, message:String},
+}
+
+fn host_or_localhost(host:&str) -> &str
+{
+	if host.is_empty() {"localhost"} else {host}
+}
+
+impl PiperError
+{
+	// a short, stable name for the kind of error, for summaries and monitoring.
+	pub fn class(&self) -> &'static str
+	{
+		match self
+		{
+			PiperError::Config(_)=>"config",
+			PiperError::SshFailure{..}=>"ssh_failure",
+			PiperError::DatasetMissing{..}=>"dataset_missing",
+			PiperError::NoSnapshots{..}=>"no_snapshots",
+			PiperError::DivergedTarget{..}=>"diverged_target",
+			PiperError::Command{..}=>"command_failure",
+			PiperError::SendFailure(_)=>"send_failure",
+			PiperError::RecvFailure(_)=>"recv_failure",
+			PiperError::StageFailure{..}=>"pipeline_failure",
+			PiperError::Locked{..}=>"locked",
+			PiperError::Drifted{..}=>"drifted",
+			PiperError::HookFailure{..}=>"hook_failure",
+		}
+	}
+}
+
+impl fmt::Display for PiperError
+{
+	fn fmt(&self, f:&mut fmt::Formatter) -> fmt::Result
+	{
+		match self
+		{
+			PiperError::Config(message)=>write!(f, "configuration error: {}", message),
+			PiperError::SshFailure{host, message}=>write!(f, "can't login to host {}: {}", host, message),
+			PiperError::DatasetMissing{host, dataset}=>write!(f, "dataset \"{}\" does not exist on {}", dataset, host_or_localhost(host)),
+			PiperError::NoSnapshots{host, dataset, prefix}=>
+				if prefix.is_empty()
+				{
+					write!(f, "no snapshots of \"{}\" on {}", dataset, host_or_localhost(host))
+				}
+				else
+				{
+					write!(f, "no snapshots of \"{}\" starting with \"{}\" on {}", dataset, prefix, host_or_localhost(host))
+				},
+			PiperError::DivergedTarget{dataset, reason}=>write!(f, "target \"{}\" has diverged from the source: {}", dataset, reason),
+			PiperError::Command{host, command, message}=>write!(f, "\"{}\" failed on {}: {}", command, host_or_localhost(host), message),
+			PiperError::SendFailure(message)=>write!(f, "zfs send failed: {}", message),
+			PiperError::RecvFailure(message)=>write!(f, "zfs receive failed: {}", message),
+			PiperError::StageFailure{stage, message}=>write!(f, "{} failed: {}", stage, message),
+			PiperError::Locked{path, message}=>write!(f, "can't take lock \"{}\": {}", path, message),
+			PiperError::Drifted{dataset, reason}=>write!(f, "\"{}\" has changed since the plan was made: {}", dataset, reason),
+			PiperError::HookFailure{hook, message}=>write!(f, "{} {}", hook, message),
+		}
+	}
+}
+
+impl std::error::Error for PiperError {}
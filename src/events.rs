@@ -0,0 +1,97 @@
+/*
+	events
+	with "--events <file>" piper writes a json object to the file (or to stdout, for
+	"-") for each thing that happens during a run, one per line, as it happens:
+	job_started, snapshot_created, send_started, recv_line, send_finished,
+	hold_placed, hold_released and job_finished. each has the time, the name of the
+	event and the name of the job, along with whatever else there is to say about
+	the event. the log is for people, the events are for programs.
+*/
+use chrono::Local;
+use serde_json::{Map, Value};
+use std::{fs::OpenOptions, io::Write, sync::Mutex};
+
+pub struct Events
+{
+	out: Option<Mutex<Box<dyn Write + Send>>>,
+}
+
+impl Events
+{
+	// events which go nowhere.
+	pub fn none() -> Events
+	{
+		Events{out:None}
+	}
+
+	// events appended to the file at path, or written to stdout if path is "-".
+	pub fn open(path:&str) -> Result<Events, String>
+	{
+		let out:Box<dyn Write + Send> = if path == "-"
+			{
+				Box::new(std::io::stdout())
+			}
+			else
+			{
+				Box::new(OpenOptions::new().create(true).append(true).open(path).map_err(|e| format!("Error opening events file \"{}\": {}", path, e))?)
+			};
+		Ok(Events{out:Some(Mutex::new(out))})
+	}
+
+	// writes an event. fields are (name, value) pairs added to the event.
+	pub fn emit(&self, event:&str, job:&str, fields:&[(&str, Value)])
+	{
+		let out = match &self.out
+			{
+				None=>return,
+				Some(out)=>out,
+			};
+		let mut object = Map::new();
+		object.insert(String::from("time"), Value::from(Local::now().to_rfc3339()));
+		object.insert(String::from("event"), Value::from(event));
+		object.insert(String::from("job"), Value::from(job));
+		for (name, value) in fields.iter()
+		{
+			object.insert(String::from(*name), value.clone());
+		}
+		if let Ok(mut out) = out.lock()
+		{
+			// an event which can't be written is dropped rather than stopping the run.
+			let _ = writeln!(out, "{}", Value::Object(object)).and_then(|_| out.flush());
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+
+	#[test]
+	fn events_are_appended_one_per_line_in_order()
+	{
+		let path = std::env::temp_dir().join(format!("piper-events-{}.json", std::process::id()));
+		let _ = std::fs::remove_file(&path);
+		let events = Events::open(path.to_str().unwrap()).unwrap();
+		events.emit("job_started", "root", &[("source", Value::from("tank/data")), ("target", Value::from("backup:backup"))]);
+		events.emit("hold_placed", "root", &[("host", Value::from("")), ("snapshot", Value::from("tank/data@two")), ("tag", Value::from("piper:root:backup:backup"))]);
+		events.emit("job_finished", "root", &[("failed", Value::from(false))]);
+		// and another run goes on the end.
+		Events::open(path.to_str().unwrap()).unwrap().emit("job_started", "vms", &[]);
+		let text = std::fs::read_to_string(&path).unwrap();
+		let lines:Vec<Value> = text.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+		let names:Vec<(&str, &str)> = lines.iter().map(|line| (line["event"].as_str().unwrap(), line["job"].as_str().unwrap())).collect();
+		assert_eq!(names, vec![("job_started", "root"), ("hold_placed", "root"), ("job_finished", "root"), ("job_started", "vms")]);
+		let keys:Vec<&String> = lines[1].as_object().unwrap().keys().collect();
+		assert_eq!(keys, vec!["event", "host", "job", "snapshot", "tag", "time"]);
+		assert_eq!(lines[1]["tag"], "piper:root:backup:backup");
+		assert!(chrono::DateTime::parse_from_rfc3339(lines[0]["time"].as_str().unwrap()).is_ok());
+		std::fs::remove_file(&path).unwrap();
+	}
+
+	#[test]
+	fn no_events_go_nowhere()
+	{
+		Events::none().emit("job_started", "root", &[]);
+	}
+}
@@ -0,0 +1,388 @@
+/*
+	executor
+	every command piper runs, whether zfs itself or anything else, on the local host
+	or on a remote host, goes through a ZfsExecutor. the ProcessExecutor runs real
+	commands, using a Transport to decide how a command reaches its host (directly
+	for the local host, over ssh for remote hosts). the FakeExecutor (see fake.rs)
+	simulates zfs in memory so the rest of piper can be exercised without any pools.
+*/
+use async_trait::async_trait;
+use log::*;
+use std::process::Stdio;
+use tokio::process::Command;
+use crate::hosts::Hosts;
+use futures::future::join_all;
+
+#[derive(Clone)]
+pub struct CommandOutput
+{
+	pub success: bool,
+	// the exit code, if there was one. ssh exits with 255 when it can't reach the host.
+	pub code: Option<i32>,
+	pub stdout: String,
+	pub stderr: String,
+}
+
+impl CommandOutput
+{
+	// the output of a command which could not be run at all.
+	pub fn failed(message:String) -> CommandOutput
+	{
+		CommandOutput{success:false, code:None, stdout:String::from(""), stderr:message}
+	}
+
+	// true if ssh couldn't reach host, as opposed to the command failing once there.
+	pub fn unreachable(&self, host:&str) -> bool
+	{
+		!host.is_empty() && self.code == Some(255)
+	}
+}
+
+// how a command gets to the host it runs on.
+pub trait Transport: Send + Sync
+{
+	fn command(&self, host:&str, program:&str, args:&[&str]) -> Command;
+}
+
+pub struct LocalTransport;
+
+impl Transport for LocalTransport
+{
+	fn command(&self, _host:&str, program:&str, args:&[&str]) -> Command
+	{
+		let mut command = Command::new(program);
+		command.args(args);
+		command
+	}
+}
+
+// uses the options in the hosts section of the config file for hosts which are in it.
+pub struct SshTransport
+{
+	hosts: Hosts,
+}
+
+impl Transport for SshTransport
+{
+	fn command(&self, host:&str, program:&str, args:&[&str]) -> Command
+	{
+		let mut command = Command::new("ssh");
+		match self.hosts.get(host)
+		{
+			None=>{command.arg(host);},
+			Some(config)=>
+				{
+					command.args(config.ssh_args());
+					command.arg(match &config.hostname
+						{
+							None=>host,
+							Some(hostname)=>hostname.as_str(),
+						});
+				},
+		}
+		command.arg(program);
+		command.args(args);
+		command
+	}
+}
+
+// one process of a pipeline, such as "zfs send" or a compressor. the stdout of each
+// stage is piped into the stdin of the next.
+pub struct Stage
+{
+	// what the stage is called when it is reported.
+	pub name: String,
+	pub host: String,
+	pub program: String,
+	pub args: Vec<String>,
+}
+
+// quotes word for a posix shell, if it needs it.
+pub fn shell_quote(word:&str) -> String
+{
+	if !word.is_empty() && word.chars().all(|c| c.is_ascii_alphanumeric() || "@%+=:,./_-".contains(c))
+	{
+		String::from(word)
+	}
+	else
+	{
+		format!("'{}'", word.replace('\'', "'\\''"))
+	}
+}
+
+#[async_trait]
+pub trait ZfsExecutor: Send + Sync
+{
+	// runs program with args on host ("" is the local host) and waits for it to finish.
+	async fn run(&self, host:&str, program:&str, args:&[&str]) -> CommandOutput;
+
+	// runs "zfs <args>" on host.
+	async fn zfs(&self, host:&str, args:&[&str]) -> CommandOutput
+	{
+		self.run(host, "zfs", args).await
+	}
+
+	// runs the stages, each on its own host, piping each into the next, and returns
+	// the output of each stage (in the same order) once they have all finished.
+	async fn pipeline(&self, stages:&[Stage]) -> Vec<CommandOutput>;
+}
+
+pub struct ProcessExecutor
+{
+	local: LocalTransport,
+	ssh: SshTransport,
+}
+
+impl ProcessExecutor
+{
+	pub fn new(hosts:Hosts) -> ProcessExecutor
+	{
+		ProcessExecutor{local:LocalTransport, ssh:SshTransport{hosts}}
+	}
+
+	fn transport(&self, host:&str) -> &dyn Transport
+	{
+		if host.is_empty() {&self.local} else {&self.ssh}
+	}
+}
+
+async fn collect(child:tokio::process::Child) -> CommandOutput
+{
+	match child.wait_with_output().await
+	{
+		Err(e)=>CommandOutput::failed(format!("{}", e)),
+		Ok(output)=>CommandOutput{
+				success: output.status.success(),
+				code: output.status.code(),
+				stdout: String::from_utf8_lossy(&output.stdout).into_owned(),
+				stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+			},
+	}
+}
+
+#[async_trait]
+impl ZfsExecutor for ProcessExecutor
+{
+	async fn run(&self, host:&str, program:&str, args:&[&str]) -> CommandOutput
+	{
+		let mut command = self.transport(host).command(host, program, args);
+		// a command which is given up on (a hook which times out, say) is killed.
+		match command.stdin(Stdio::null()).stdout(Stdio::piped()).stderr(Stdio::piped()).kill_on_drop(true).spawn()
+		{
+			Err(e)=>CommandOutput::failed(format!("Error running {}:{}", program, e)),
+			Ok(child)=>collect(child).await,
+		}
+	}
+
+	async fn pipeline(&self, stages:&[Stage]) -> Vec<CommandOutput>
+	{
+		// consecutive stages on the same remote host are run by a single ssh, as a shell
+		// pipeline, so that data only crosses the network where it has to. stages on
+		// the local host are run one process each.
+		let mut groups:Vec<Vec<&Stage>> = Vec::new();
+		for stage in stages.iter()
+		{
+			match groups.last_mut()
+			{
+				Some(group) if !stage.host.is_empty() && group[0].host == stage.host=>group.push(stage),
+				_=>groups.push(vec![stage]),
+			}
+		}
+		let mut children:Vec<tokio::process::Child> = Vec::new();
+		let mut stdin = Stdio::null();
+		for (g, group) in groups.iter().enumerate()
+		{
+			let host = group[0].host.as_str();
+			let mut command = if group.len() == 1
+				{
+					let args:Vec<&str> = group[0].args.iter().map(|a| a.as_str()).collect();
+					self.transport(host).command(host, group[0].program.as_str(), &args)
+				}
+				else
+				{
+					let script = group_script(group);
+					debug!("Running on {}: {}", host, script);
+					self.transport(host).command(host, "sh", &["-c", shell_quote(script.as_str()).as_str()])
+				};
+			let spawned = command.stdin(stdin).stdout(Stdio::piped()).stderr(Stdio::piped()).spawn();
+			let mut child = match spawned
+				{
+					Err(e)=>
+						{
+							for mut child in children
+							{
+								let _ = child.kill().await;
+							}
+							let names:Vec<&str> = group.iter().map(|stage| stage.name.as_str()).collect();
+							return stages.iter().map(|_| CommandOutput::failed(format!("Error spawning {}:{}", names.join(" | "), e))).collect();
+						},
+					Ok(child)=>child,
+				};
+			// the last group's stdout is kept so that it can be reported.
+			stdin = if g + 1 < groups.len()
+				{
+					match child.stdout.take().map(|o| o.try_into())
+					{
+						Some(Ok(o))=>o,
+						_=>Stdio::null(),
+					}
+				}
+				else
+				{
+					Stdio::null()
+				};
+			children.push(child);
+		}
+		let outputs = join_all(children.into_iter().map(collect)).await;
+		let mut results = Vec::new();
+		for (group, output) in groups.iter().zip(outputs)
+		{
+			if group.len() == 1
+			{
+				results.push(output);
+			}
+			else
+			{
+				results.append(&mut split_group_output(group.len(), output));
+			}
+		}
+		results
+	}
+}
+
+// marks the exit status of each stage of a group in its stderr.
+const STAGE_STATUS:&str = "piper-stage-status";
+// marks each line of stderr with the stage of a group which wrote it, as "piper-stage-<n> <line>".
+const STAGE_PREFIX:&str = "piper-stage-";
+
+// a shell pipeline for the stages of a group, in which each stage writes its exit
+// status to stderr after it finishes, as the exit status of the pipeline as a whole
+// is only that of the last stage. the stderr of each stage (its status included)
+// goes through a sed which marks every line with the stage, while its stdout goes
+// on to the next stage through fd 3. this is plain sh, as the remote shell may not be bash.
+fn group_script(group:&[&Stage]) -> String
+{
+	let mut parts = Vec::new();
+	for (i, stage) in group.iter().enumerate()
+	{
+		let mut words = vec![shell_quote(stage.program.as_str())];
+		for arg in stage.args.iter()
+		{
+			words.push(shell_quote(arg.as_str()));
+		}
+		parts.push(format!("{{ {{ {}; echo \"{} {} $?\" >&2; }} 2>&1 >&3 3>&- | sed 's/^/{}{} /' >&2 3>&-; }} 3>&1", words.join(" "), STAGE_STATUS, i, STAGE_PREFIX, i));
+	}
+	parts.join(" | ")
+}
+
+// splits the output of a group back into the output of each of its stages. anything
+// on stderr which isn't marked with a stage (from ssh itself, say) goes to all of them.
+fn split_group_output(count:usize, output:CommandOutput) -> Vec<CommandOutput>
+{
+	let mut codes:Vec<Option<i32>> = vec![None; count];
+	let mut stderrs:Vec<String> = vec![String::from(""); count];
+	for line in output.stderr.lines()
+	{
+		let (stage, text) = match line.strip_prefix(STAGE_PREFIX).and_then(|rest| rest.split_once(' ')).and_then(|(i, text)| i.parse::<usize>().ok().map(|i| (i, text)))
+			{
+				Some((i, text)) if i < count=>(Some(i), text),
+				_=>(None, line),
+			};
+		let fields:Vec<&str> = text.split(' ').collect();
+		if let (Some(i), 3, Some(&STAGE_STATUS)) = (stage, fields.len(), fields.first())
+		{
+			if let Ok(code) = fields[2].parse::<i32>()
+			{
+				codes[i] = Some(code);
+				continue;
+			}
+		}
+		for (j, stderr) in stderrs.iter_mut().enumerate()
+		{
+			if stage.is_none() || stage == Some(j)
+			{
+				stderr.push_str(text);
+				stderr.push('\n');
+			}
+		}
+	}
+	let mut results = Vec::new();
+	for (i, (code, stderr)) in codes.into_iter().zip(stderrs).enumerate()
+	{
+		// ssh exits with 255 when it couldn't reach the host, in which case nothing ran.
+		let code = if output.code == Some(255) {Some(255)} else {code};
+		results.push(CommandOutput{
+			success: code == Some(0),
+			code,
+			stdout: if i + 1 == count {output.stdout.clone()} else {String::from("")},
+			stderr,
+		});
+	}
+	results
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+
+	fn stage(name:&str, program:&str, args:&[&str]) -> Stage
+	{
+		Stage{name:String::from(name), host:String::from("remote"), program:String::from(program), args:args.iter().map(|a| String::from(*a)).collect()}
+	}
+
+	fn args(command:&Command) -> Vec<String>
+	{
+		let command = command.as_std();
+		std::iter::once(command.get_program()).chain(command.get_args()).map(|arg| arg.to_string_lossy().into_owned()).collect()
+	}
+
+	#[test]
+	fn ssh_connects_to_the_hostname_of_the_entry_or_the_host_itself()
+	{
+		let mut hosts = Hosts::new();
+		hosts.insert(String::from("backup"), crate::hosts::HostConfig{hostname:Some(String::from("backup.example.com")), port:Some(2222), ..Default::default()});
+		hosts.insert(String::from("offsite"), crate::hosts::HostConfig{user:Some(String::from("piper")), ..Default::default()});
+		let ssh = SshTransport{hosts};
+		assert_eq!(args(&ssh.command("backup", "zfs", &["list"])), vec!["ssh", "-p", "2222", "backup.example.com", "zfs", "list"]);
+		assert_eq!(args(&ssh.command("offsite", "zfs", &["list"])), vec!["ssh", "-l", "piper", "offsite", "zfs", "list"]);
+		assert_eq!(args(&ssh.command("elsewhere", "zfs", &["list"])), vec!["ssh", "elsewhere", "zfs", "list"]);
+	}
+
+	#[test]
+	fn group_output_is_split_by_stage()
+	{
+		let stderr = "piper-stage-0 cannot send: broken\npiper-stage-0 piper-stage-status 0 1\npiper-stage-1 mbuffer: warning\npiper-stage-1 piper-stage-status 1 0\nssh: warning\n";
+		let outputs = split_group_output(2, CommandOutput{success:true, code:Some(0), stdout:String::from("out\n"), stderr:String::from(stderr)});
+		assert_eq!(outputs.len(), 2);
+		assert!(!outputs[0].success);
+		assert_eq!(outputs[0].code, Some(1));
+		assert_eq!(outputs[0].stderr, "cannot send: broken\nssh: warning\n");
+		assert_eq!(outputs[0].stdout, "");
+		assert!(outputs[1].success);
+		assert_eq!(outputs[1].stderr, "mbuffer: warning\nssh: warning\n");
+		assert_eq!(outputs[1].stdout, "out\n");
+	}
+
+	#[test]
+	fn unreachable_group_fails_every_stage()
+	{
+		let outputs = split_group_output(2, CommandOutput{success:false, code:Some(255), stdout:String::from(""), stderr:String::from("ssh: connect to host remote port 22: Connection refused\n")});
+		assert!(outputs.iter().all(|output| output.code == Some(255) && output.stderr.contains("Connection refused")));
+	}
+
+	#[tokio::test]
+	async fn group_script_keeps_each_stages_stderr()
+	{
+		let first = stage("first", "sh", &["-c", "echo data; echo first failed >&2; exit 3"]);
+		let second = stage("second", "sh", &["-c", "cat; echo second said >&2"]);
+		let script = group_script(&[&first, &second]);
+		let child = Command::new("sh").args(["-c", script.as_str()]).stdout(Stdio::piped()).stderr(Stdio::piped()).spawn().unwrap();
+		let outputs = split_group_output(2, collect(child).await);
+		assert_eq!(outputs[0].code, Some(3));
+		assert_eq!(outputs[0].stderr, "first failed\n");
+		assert_eq!(outputs[1].code, Some(0));
+		assert_eq!(outputs[1].stderr, "second said\n");
+		assert_eq!(outputs[1].stdout, "data\n");
+	}
+}
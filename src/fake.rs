@@ -0,0 +1,1083 @@
+/*
+	fake
+	an in-memory stand in for zfs (and ssh) on any number of hosts. the pools are
+	loaded from a json file, every zfs command piper runs is simulated against them,
+	and the result is written back to the file so that consecutive runs build on each
+	other. this is what "piper --simulate <state.json>" runs against, so the planning
+	and replication logic can be exercised (in CI, say) without any real pools.
+
+	a state file looks like:
+	{
+		"hosts": {
+			"localhost": {
+				"datasets": [
+					{ "name": "tank/data", "snapshots": [ { "name": "one" }, { "name": "two" } ] }
+				]
+			},
+			"backup": {
+				"datasets": [ { "name": "backup" } ],
+				"interrupt_receives": 1
+			}
+		}
+	}
+	guids and createtxgs are filled in for anything which doesn't have them. the local
+	host is "localhost". "unreachable": true makes ssh to a host fail,
+	"interrupt_receives": n makes the next n receives on a host fail part way through,
+	and "fail_commands": ["zfs bookmark", ...] makes the commands run on a host which
+	start with any of them fail.
+*/
+use async_trait::async_trait;
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, fs::File, path::Path, path::PathBuf, sync::Mutex};
+use crate::executor::{CommandOutput, Stage, ZfsExecutor};
+
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct FakeSnapshot
+{
+	name: String,
+	#[serde(default)]
+	guid: u64,
+	#[serde(default)]
+	createtxg: u64,
+	#[serde(default)]
+	creation: i64,
+	#[serde(default)]
+	written: u64,
+	#[serde(default, skip_serializing_if = "Vec::is_empty")]
+	holds: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct FakeDataset
+{
+	name: String,
+	#[serde(default)]
+	createtxg: u64,
+	#[serde(default = "default_encryption")]
+	encryption: String,
+	#[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+	properties: BTreeMap<String, String>,
+	#[serde(default)]
+	snapshots: Vec<FakeSnapshot>,
+	#[serde(default, skip_serializing_if = "Vec::is_empty")]
+	bookmarks: Vec<FakeSnapshot>,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	receive_resume_token: Option<String>,
+}
+
+fn default_encryption() -> String
+{
+	String::from("off")
+}
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct FakeHost
+{
+	#[serde(default)]
+	datasets: Vec<FakeDataset>,
+	#[serde(default)]
+	unreachable: bool,
+	#[serde(default)]
+	interrupt_receives: u32,
+	// commands which fail on this host, by how they start, such as "zfs bookmark".
+	#[serde(default, skip_serializing_if = "Vec::is_empty")]
+	fail_commands: Vec<String>,
+	// every command run on this host, for checking what piper actually did.
+	#[serde(default, skip_serializing_if = "Vec::is_empty")]
+	commands: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct FakeState
+{
+	#[serde(default)]
+	hosts: BTreeMap<String, FakeHost>,
+	#[serde(default)]
+	txg: u64,
+}
+
+pub struct FakeExecutor
+{
+	path: PathBuf,
+	state: Mutex<FakeState>,
+}
+
+// what a "zfs send" would put in its stream.
+struct FakeStream
+{
+	dataset: String,
+	encryption: String,
+	raw: bool,
+	replicate: bool,
+	from_guid: u64,
+	snapshots: Vec<FakeSnapshot>,
+	// all snapshots of the source, for -R -F purging on the target.
+	source_guids: Vec<u64>,
+}
+
+fn host_name(host:&str) -> &str
+{
+	if host.is_empty() {"localhost"} else {host}
+}
+
+fn ok(stdout:String) -> CommandOutput
+{
+	CommandOutput{success:true, code:Some(0), stdout, stderr:String::from("")}
+}
+
+// what ssh does when it can't reach a host.
+fn unreachable(host:&str) -> CommandOutput
+{
+	CommandOutput{success:false, code:Some(255), stdout:String::from(""), stderr:format!("ssh: connect to host {} port 22: Connection refused", host)}
+}
+
+fn err(stderr:String) -> CommandOutput
+{
+	CommandOutput{success:false, code:Some(1), stdout:String::from(""), stderr}
+}
+
+// splits "pool/ds@snap" or "pool/ds#bookmark" into ("pool/ds", '@', "snap").
+fn split_name(name:&str) -> (&str, char, &str)
+{
+	if let Some((dataset, snapshot)) = name.split_once('@')
+	{
+		return (dataset, '@', snapshot);
+	}
+	if let Some((dataset, bookmark)) = name.split_once('#')
+	{
+		return (dataset, '#', bookmark);
+	}
+	(name, ' ', "")
+}
+
+impl FakeState
+{
+	fn next_txg(&mut self) -> u64
+	{
+		self.txg += 1;
+		self.txg
+	}
+
+	// fills in guids and createtxgs missing from a hand written state file.
+	fn normalize(&mut self)
+	{
+		let mut txg = self.txg;
+		for host in self.hosts.values()
+		{
+			for dataset in host.datasets.iter()
+			{
+				txg = txg.max(dataset.createtxg);
+				for snapshot in dataset.snapshots.iter().chain(dataset.bookmarks.iter())
+				{
+					txg = txg.max(snapshot.createtxg).max(snapshot.guid);
+				}
+			}
+		}
+		let hosts:Vec<String> = self.hosts.keys().cloned().collect();
+		for host in hosts
+		{
+			let count = self.hosts[&host].datasets.len();
+			for d in 0..count
+			{
+				if self.hosts[&host].datasets[d].createtxg == 0
+				{
+					txg += 1;
+					self.hosts.get_mut(&host).unwrap().datasets[d].createtxg = txg;
+				}
+				let snapshots = self.hosts[&host].datasets[d].snapshots.len();
+				for s in 0..snapshots
+				{
+					let snapshot = &mut self.hosts.get_mut(&host).unwrap().datasets[d].snapshots[s];
+					if snapshot.createtxg == 0
+					{
+						txg += 1;
+						snapshot.createtxg = txg;
+					}
+					if snapshot.guid == 0
+					{
+						txg += 1;
+						snapshot.guid = txg;
+					}
+					if snapshot.creation == 0
+					{
+						snapshot.creation = snapshot.createtxg as i64;
+					}
+				}
+			}
+			self.hosts.get_mut(&host).unwrap().datasets.sort_by(|a, b| a.name.cmp(&b.name));
+		}
+		self.txg = txg;
+	}
+
+	fn host(&mut self, host:&str) -> &mut FakeHost
+	{
+		self.hosts.entry(String::from(host_name(host))).or_default()
+	}
+
+	fn dataset(&mut self, host:&str, name:&str) -> Option<&mut FakeDataset>
+	{
+		self.host(host).datasets.iter_mut().find(|d| d.name == name)
+	}
+
+	fn no_such(name:&str) -> CommandOutput
+	{
+		err(format!("cannot open '{}': dataset does not exist", name))
+	}
+
+	fn list(&mut self, host:&str, args:&[&str]) -> CommandOutput
+	{
+		let mut types = String::from("filesystem");
+		let mut columns = String::from("name");
+		let mut recursive = false;
+		let mut depth:Option<usize> = None;
+		let mut sort:Option<(String, bool)> = None;
+		let mut names:Vec<&str> = Vec::new();
+		let mut i = 0;
+		while i < args.len()
+		{
+			match args[i]
+			{
+				"-H"|"-p" => {},
+				"-r" => recursive = true,
+				"-d" => {i += 1; depth = args.get(i).and_then(|d| d.parse().ok());},
+				"-t" => {i += 1; types = String::from(*args.get(i).unwrap_or(&""));},
+				"-o" => {i += 1; columns = String::from(*args.get(i).unwrap_or(&""));},
+				"-s" => {i += 1; sort = Some((String::from(*args.get(i).unwrap_or(&"")), true));},
+				"-S" => {i += 1; sort = Some((String::from(*args.get(i).unwrap_or(&"")), false));},
+				name => names.push(name),
+			}
+			i += 1;
+		}
+		let types:Vec<&str> = types.split(',').collect();
+		let want_datasets = types.iter().any(|t| *t == "filesystem" || *t == "volume" || *t == "all");
+		let want_snapshots = types.iter().any(|t| *t == "snapshot" || *t == "snap" || *t == "all");
+		let want_bookmarks = types.iter().any(|t| *t == "bookmark" || *t == "all");
+		// without -r or -d only the named dataset is listed, along with its own snapshots.
+		let (dataset_depth, snapshot_depth) = match (recursive, depth)
+			{
+				(true, _)=>(usize::MAX, usize::MAX),
+				(false, Some(depth))=>(depth, depth),
+				(false, None)=>(0, 1),
+			};
+		// rows of (name, values)
+		let mut rows:Vec<(String, Vec<String>)> = Vec::new();
+		let columns:Vec<&str> = columns.split(',').collect();
+		let datasets = self.host(host).datasets.clone();
+		for name in names.iter()
+		{
+			let (dataset_name, kind, _) = split_name(name);
+			let dataset = match datasets.iter().find(|d| d.name == dataset_name)
+				{
+					None=>return FakeState::no_such(name),
+					Some(dataset)=>dataset,
+				};
+			if kind != ' '
+			{
+				let found = if kind == '@' {dataset.snapshots.iter().find(|s| format!("{}@{}", dataset.name, s.name) == *name)}
+					else {dataset.bookmarks.iter().find(|s| format!("{}#{}", dataset.name, s.name) == *name)};
+				match found
+				{
+					None=>return FakeState::no_such(name),
+					Some(snapshot)=>
+						{
+							let values:Vec<String> = columns.iter().map(|c| snapshot_property(dataset, snapshot, kind, c)).collect();
+							rows.push((String::from(*name), values));
+						},
+				}
+				continue;
+			}
+			let prefix = format!("{}/", dataset.name);
+			for d in datasets.iter()
+			{
+				let level = if d.name == dataset.name {0}
+					else if d.name.starts_with(prefix.as_str()) {d.name[prefix.len()..].split('/').count()}
+					else {continue};
+				if want_datasets && level <= dataset_depth
+				{
+					let values:Vec<String> = columns.iter().map(|c| dataset_property(d, c)).collect();
+					rows.push((d.name.clone(), values));
+				}
+				if level + 1 > snapshot_depth
+				{
+					continue;
+				}
+				if want_snapshots
+				{
+					for s in d.snapshots.iter()
+					{
+						let values:Vec<String> = columns.iter().map(|c| snapshot_property(d, s, '@', c)).collect();
+						rows.push((format!("{}@{}", d.name, s.name), values));
+					}
+				}
+				if want_bookmarks
+				{
+					for s in d.bookmarks.iter()
+					{
+						let values:Vec<String> = columns.iter().map(|c| snapshot_property(d, s, '#', c)).collect();
+						rows.push((format!("{}#{}", d.name, s.name), values));
+					}
+				}
+			}
+		}
+		if let Some((column, ascending)) = sort
+		{
+			// sort on the property itself, even when it isn't one of the columns output.
+			let key = |name:&String| -> (u64, String)
+				{
+					let value = self_property(&datasets, name, column.as_str());
+					(value.parse().unwrap_or(0), value)
+				};
+			rows.sort_by(|a, b| { let (ka, kb) = (key(&a.0), key(&b.0)); if ascending {ka.cmp(&kb)} else {kb.cmp(&ka)} });
+		}
+		let mut stdout = String::from("");
+		for (_, values) in rows
+		{
+			stdout.push_str(values.join("\t").as_str());
+			stdout.push('\n');
+		}
+		ok(stdout)
+	}
+
+	fn get(&mut self, host:&str, args:&[&str]) -> CommandOutput
+	{
+		let mut columns = String::from("name,property,value,source");
+		let mut positional:Vec<&str> = Vec::new();
+		let mut i = 0;
+		while i < args.len()
+		{
+			match args[i]
+			{
+				"-H"|"-p" => {},
+				"-o" => {i += 1; columns = String::from(*args.get(i).unwrap_or(&""));},
+				arg => positional.push(arg),
+			}
+			i += 1;
+		}
+		if positional.len() < 2
+		{
+			return err(String::from("missing arguments"));
+		}
+		let datasets = self.host(host).datasets.clone();
+		let mut stdout = String::from("");
+		for property in positional[0].split(',')
+		{
+			for name in positional[1..].iter()
+			{
+				let (dataset_name, _, _) = split_name(name);
+				if !datasets.iter().any(|d| d.name == dataset_name) || (name.contains(['@','#']) && self_property(&datasets, name, "name") == "-")
+				{
+					return FakeState::no_such(name);
+				}
+				let value = self_property(&datasets, name, property);
+				let values:Vec<String> = columns.split(',').map(|c| match c
+					{
+						"name"=>String::from(*name),
+						"property"=>String::from(property),
+						"value"=>value.clone(),
+						"source"=>String::from("-"),
+						_=>String::from("-"),
+					}).collect();
+				stdout.push_str(values.join("\t").as_str());
+				stdout.push('\n');
+			}
+		}
+		ok(stdout)
+	}
+
+	fn snapshot_mut(&mut self, host:&str, name:&str) -> Option<&mut FakeSnapshot>
+	{
+		let (dataset_name, kind, snapshot_name) = split_name(name);
+		let dataset = self.dataset(host, dataset_name)?;
+		if kind == '@'
+		{
+			dataset.snapshots.iter_mut().find(|s| s.name == snapshot_name)
+		}
+		else if kind == '#'
+		{
+			dataset.bookmarks.iter_mut().find(|s| s.name == snapshot_name)
+		}
+		else
+		{
+			None
+		}
+	}
+
+	fn hold(&mut self, host:&str, args:&[&str], release:bool) -> CommandOutput
+	{
+		let args:Vec<&str> = args.iter().filter(|a| **a != "-r").cloned().collect();
+		if args.len() != 2
+		{
+			return err(String::from("usage: zfs hold|release tag snapshot"));
+		}
+		let (tag, name) = (args[0], args[1]);
+		let snapshot = match self.snapshot_mut(host, name)
+			{
+				None=>return err(format!("cannot {} snapshot '{}': dataset does not exist", if release {"release hold from"} else {"hold"}, name)),
+				Some(snapshot)=>snapshot,
+			};
+		let position = snapshot.holds.iter().position(|h| h == tag);
+		match (release, position)
+		{
+			(false, Some(_))=>err(format!("cannot hold snapshot '{}': tag already exists on this dataset", name)),
+			(false, None)=>{snapshot.holds.push(String::from(tag)); ok(String::from(""))},
+			(true, None)=>err(format!("cannot release hold from snapshot '{}': no such tag on this dataset", name)),
+			(true, Some(p))=>{snapshot.holds.remove(p); ok(String::from(""))},
+		}
+	}
+
+	fn holds(&mut self, host:&str, args:&[&str]) -> CommandOutput
+	{
+		let mut stdout = String::from("");
+		for name in args.iter().filter(|a| !a.starts_with('-'))
+		{
+			let snapshot = match self.snapshot_mut(host, name)
+				{
+					None=>return FakeState::no_such(name),
+					Some(snapshot)=>snapshot.clone(),
+				};
+			for tag in snapshot.holds.iter()
+			{
+				stdout.push_str(format!("{}\t{}\t{}\n", name, tag, snapshot.creation).as_str());
+			}
+		}
+		ok(stdout)
+	}
+
+	fn bookmark(&mut self, host:&str, args:&[&str]) -> CommandOutput
+	{
+		if args.len() != 2
+		{
+			return err(String::from("usage: zfs bookmark snapshot bookmark"));
+		}
+		let snapshot = match self.snapshot_mut(host, args[0])
+			{
+				None=>return FakeState::no_such(args[0]),
+				Some(snapshot)=>snapshot.clone(),
+			};
+		let (dataset_name, _, bookmark_name) = split_name(args[1]);
+		let dataset = match self.dataset(host, dataset_name)
+			{
+				None=>return FakeState::no_such(args[1]),
+				Some(dataset)=>dataset,
+			};
+		if dataset.bookmarks.iter().any(|b| b.name == bookmark_name)
+		{
+			return err(format!("cannot create bookmark '{}': bookmark exists", args[1]));
+		}
+		dataset.bookmarks.push(FakeSnapshot{name:String::from(bookmark_name), holds:Vec::new(), ..snapshot});
+		ok(String::from(""))
+	}
+
+	fn destroy(&mut self, host:&str, args:&[&str]) -> CommandOutput
+	{
+		let recursive = args.contains(&"-r");
+		let name = match args.iter().find(|a| !a.starts_with('-'))
+			{
+				None=>return err(String::from("usage: zfs destroy name")),
+				Some(name)=>*name,
+			};
+		let (dataset_name, kind, snapshot_name) = split_name(name);
+		let datasets = &mut self.host(host).datasets;
+		let prefix = format!("{}/", dataset_name);
+		if kind == ' '
+		{
+			if !datasets.iter().any(|d| d.name == dataset_name)
+			{
+				return FakeState::no_such(name);
+			}
+			if !recursive && datasets.iter().any(|d| d.name.starts_with(prefix.as_str()))
+			{
+				return err(format!("cannot destroy '{}': filesystem has children", name));
+			}
+			if datasets.iter().filter(|d| d.name == dataset_name || d.name.starts_with(prefix.as_str())).any(|d| d.snapshots.iter().any(|s| !s.holds.is_empty()))
+			{
+				return err(format!("cannot destroy '{}': dataset is busy", name));
+			}
+			datasets.retain(|d| d.name != dataset_name && !d.name.starts_with(prefix.as_str()));
+			return ok(String::from(""));
+		}
+		let mut found = false;
+		for dataset in datasets.iter_mut().filter(|d| d.name == dataset_name || (recursive && d.name.starts_with(prefix.as_str())))
+		{
+			let list = if kind == '@' {&mut dataset.snapshots} else {&mut dataset.bookmarks};
+			if let Some(position) = list.iter().position(|s| s.name == snapshot_name)
+			{
+				if !list[position].holds.is_empty()
+				{
+					return err(format!("cannot destroy snapshot {}@{}: dataset is busy", dataset.name, snapshot_name));
+				}
+				list.remove(position);
+				found = true;
+			}
+		}
+		if found {ok(String::from(""))} else {FakeState::no_such(name)}
+	}
+
+	fn snapshot(&mut self, host:&str, args:&[&str]) -> CommandOutput
+	{
+		let recursive = args.contains(&"-r");
+		let name = match args.iter().find(|a| !a.starts_with('-'))
+			{
+				None=>return err(String::from("usage: zfs snapshot name")),
+				Some(name)=>*name,
+			};
+		let (dataset_name, _, snapshot_name) = split_name(name);
+		if self.dataset(host, dataset_name).is_none()
+		{
+			return FakeState::no_such(name);
+		}
+		let prefix = format!("{}/", dataset_name);
+		let txg = self.next_txg();
+		let creation = chrono::Local::now().timestamp();
+		let mut guid = txg;
+		for dataset in self.host(host).datasets.iter_mut().filter(|d| d.name == dataset_name || (recursive && d.name.starts_with(prefix.as_str())))
+		{
+			if dataset.snapshots.iter().any(|s| s.name == snapshot_name)
+			{
+				return err(format!("cannot create snapshot '{}@{}': dataset already exists", dataset.name, snapshot_name));
+			}
+			guid += 1_000_000;
+			dataset.snapshots.push(FakeSnapshot{name:String::from(snapshot_name), guid, createtxg:txg, creation, written:1024, holds:Vec::new()});
+		}
+		ok(String::from(""))
+	}
+
+	fn create(&mut self, host:&str, args:&[&str]) -> CommandOutput
+	{
+		let parents = args.contains(&"-p");
+		let mut properties:BTreeMap<String, String> = BTreeMap::new();
+		let mut name = "";
+		let mut i = 0;
+		while i < args.len()
+		{
+			match args[i]
+			{
+				"-o" => {i += 1; if let Some((k, v)) = args.get(i).and_then(|a| a.split_once('=')) {properties.insert(String::from(k), String::from(v));}},
+				arg if arg.starts_with('-') => {},
+				arg => name = arg,
+			}
+			i += 1;
+		}
+		if self.dataset(host, name).is_some()
+		{
+			return if parents {ok(String::from(""))} else {err(format!("cannot create '{}': dataset already exists", name))};
+		}
+		let mut path = String::from("");
+		for (n, component) in name.split('/').enumerate()
+		{
+			path = if n == 0 {String::from(component)} else {format!("{}/{}", path, component)};
+			if self.dataset(host, path.as_str()).is_some()
+			{
+				continue;
+			}
+			if path != name && !parents
+			{
+				return err(format!("cannot create '{}': parent does not exist", name));
+			}
+			if n == 0
+			{
+				return err(format!("cannot create '{}': no such pool '{}'", name, path));
+			}
+			let encryption = self.inherited_encryption(host, path.as_str());
+			let createtxg = self.next_txg();
+			let dataset = FakeDataset{name:path.clone(), createtxg, encryption, properties:properties.clone(), ..Default::default()};
+			self.host(host).datasets.push(dataset);
+		}
+		self.host(host).datasets.sort_by(|a, b| a.name.cmp(&b.name));
+		ok(String::from(""))
+	}
+
+	fn inherited_encryption(&mut self, host:&str, name:&str) -> String
+	{
+		match name.rsplit_once('/')
+		{
+			None=>default_encryption(),
+			Some((parent, _))=>match self.dataset(host, parent)
+				{
+					None=>default_encryption(),
+					Some(parent)=>parent.encryption.clone(),
+				},
+		}
+	}
+
+	fn receive_abort(&mut self, host:&str, args:&[&str]) -> CommandOutput
+	{
+		let name = match args.iter().find(|a| !a.starts_with('-'))
+			{
+				None=>return err(String::from("usage: zfs recv -A name")),
+				Some(name)=>*name,
+			};
+		let (partial_full, exists) = match self.dataset(host, name)
+			{
+				None=>return FakeState::no_such(name),
+				Some(dataset)=>
+					{
+						if dataset.receive_resume_token.is_none()
+						{
+							return err(format!("'{}' does not have any resumable receive state to abort", name));
+						}
+						dataset.receive_resume_token = None;
+						(dataset.snapshots.is_empty(), true)
+					},
+			};
+		// an aborted full receive leaves nothing behind.
+		if partial_full && exists
+		{
+			self.host(host).datasets.retain(|d| d.name != name);
+		}
+		ok(String::from(""))
+	}
+
+	// works out what "zfs send <args>" on host would send.
+	fn stream(&mut self, host:&str, args:&[&str]) -> Result<FakeStream, String>
+	{
+		let mut raw = false;
+		let mut replicate = false;
+		let mut from = String::from("");
+		let mut intermediates = false;
+		let mut token = String::from("");
+		let mut name = "";
+		let mut i = 0;
+		while i < args.len()
+		{
+			match args[i]
+			{
+				"-w" => raw = true,
+				"-R" => replicate = true,
+				"-i" => {i += 1; from = String::from(*args.get(i).unwrap_or(&""));},
+				"-I" => {i += 1; from = String::from(*args.get(i).unwrap_or(&"")); intermediates = true;},
+				"-t" => {i += 1; token = String::from(*args.get(i).unwrap_or(&""));},
+				"-X" => {i += 1;},
+				arg if arg.starts_with('-') => {},
+				arg => name = arg,
+			}
+			i += 1;
+		}
+		let mut from_guid = 0;
+		if !token.is_empty()
+		{
+			// fake tokens are "fake:<from guid>:<snapshot being sent>"
+			let parts:Vec<&str> = token.splitn(3, ':').collect();
+			if parts.len() != 3 || parts[0] != "fake"
+			{
+				return Err(String::from("cannot resume send: resume token is corrupt"));
+			}
+			from_guid = parts[1].parse().unwrap_or(0);
+			let toname = String::from(parts[2]);
+			if self.snapshot_mut(host, toname.as_str()).is_none()
+			{
+				return Err(format!("cannot resume send: '{}' used in the initial send no longer exists", toname));
+			}
+			let (dataset_name, _, _) = split_name(toname.as_str());
+			let dataset = self.dataset(host, dataset_name).unwrap().clone();
+			let snapshot = self.snapshot_mut(host, toname.as_str()).unwrap().clone();
+			return Ok(FakeStream{dataset:dataset.name.clone(), encryption:dataset.encryption.clone(), raw: dataset.encryption != "off", replicate:false, from_guid,
+					source_guids:dataset.snapshots.iter().map(|s| s.guid).collect(), snapshots:vec![snapshot]});
+		}
+		let (dataset_name, _, _) = split_name(name);
+		let to = match self.snapshot_mut(host, name)
+			{
+				None=>return Err(format!("cannot open '{}': dataset does not exist", name)),
+				Some(to)=>to.clone(),
+			};
+		let dataset = self.dataset(host, dataset_name).unwrap().clone();
+		let mut from_createtxg = 0;
+		if !from.is_empty()
+		{
+			// "-i #bookmark" and "-i @snapshot" are relative to the dataset being sent.
+			let from_full = if from.starts_with('@') || from.starts_with('#') {format!("{}{}", dataset_name, from)} else {from.clone()};
+			let base = match self.snapshot_mut(host, from_full.as_str())
+				{
+					None=>return Err(format!("cannot open '{}': dataset does not exist", from_full)),
+					Some(base)=>base.clone(),
+				};
+			if base.createtxg >= to.createtxg
+			{
+				return Err(format!("cannot send '{}': not an earlier snapshot from the same fs", from_full));
+			}
+			from_guid = base.guid;
+			from_createtxg = base.createtxg;
+		}
+		let snapshots:Vec<FakeSnapshot> = if intermediates
+			{
+				dataset.snapshots.iter().filter(|s| s.createtxg > from_createtxg && s.createtxg <= to.createtxg).cloned().collect()
+			}
+			else
+			{
+				vec![to]
+			};
+		Ok(FakeStream{dataset:dataset.name.clone(), encryption:dataset.encryption.clone(), raw, replicate, from_guid,
+				source_guids:dataset.snapshots.iter().map(|s| s.guid).collect(), snapshots})
+	}
+
+	fn send(&mut self, host:&str, args:&[&str]) -> CommandOutput
+	{
+		let stream = match self.stream(host, args)
+			{
+				Err(e)=>return err(e),
+				Ok(stream)=>stream,
+			};
+		if !args.contains(&"-n")
+		{
+			// a stream with nowhere to go.
+			return err(String::from("Error: Stream can not be written to a terminal."));
+		}
+		let mut stdout = String::from("");
+		if let Some(position) = args.iter().position(|a| *a == "-t")
+		{
+			stdout.push_str("resume token contents:\nnvlist version: 0\n");
+			stdout.push_str(format!("\tfromguid = 0x{:x}\n", stream.from_guid).as_str());
+			stdout.push_str(format!("\ttoname = {}@{}\n", stream.dataset, stream.snapshots[0].name).as_str());
+			trace!("fake: checked token {}", args[position + 1]);
+		}
+		let size:u64 = stream.snapshots.iter().map(|s| s.written.max(1)).sum();
+		if args.contains(&"-P")
+		{
+			stdout.push_str(format!("{}\t{}\t{}\t{}\n", if stream.from_guid == 0 {"full"} else {"incremental"}, stream.dataset, stream.snapshots.last().map(|s| s.name.as_str()).unwrap_or(""), size).as_str());
+			stdout.push_str(format!("size\t{}\n", size).as_str());
+		}
+		ok(stdout)
+	}
+
+	fn receive(&mut self, host:&str, args:&[&str], stream:FakeStream, dry_run:bool) -> CommandOutput
+	{
+		let mut force = false;
+		let mut resumable = false;
+		let mut last_element = false;
+		let mut discard_pool = false;
+		let mut inherit_encryption = false;
+		let mut properties:BTreeMap<String, String> = BTreeMap::new();
+		let mut target = "";
+		let mut i = 0;
+		while i < args.len()
+		{
+			match args[i]
+			{
+				"-n" => {},
+				"-F" => force = true,
+				"-s" => resumable = true,
+				"-e" => last_element = true,
+				"-d" => discard_pool = true,
+				"-o" => {i += 1; if let Some((k, v)) = args.get(i).and_then(|a| a.split_once('=')) {properties.insert(String::from(k), String::from(v));}},
+				"-x" => {i += 1; if args.get(i) == Some(&"encryption") {inherit_encryption = true;}},
+				arg if arg.starts_with('-') => {},
+				arg => target = arg,
+			}
+			i += 1;
+		}
+		let target_name = if last_element
+			{
+				format!("{}/{}", target, stream.dataset.rsplit('/').next().unwrap_or(""))
+			}
+			else if discard_pool
+			{
+				format!("{}/{}", target, stream.dataset.split_once('/').map(|(_, rest)| rest).unwrap_or(""))
+			}
+			else
+			{
+				String::from(target)
+			};
+		let last = match stream.snapshots.last()
+			{
+				None=>return err(String::from("cannot receive: empty stream")),
+				Some(last)=>last.clone(),
+			};
+		let verbose = format!("receiving {} stream of {}@{} into {}@{}\n", if stream.from_guid == 0 {"full"} else {"incremental"}, stream.dataset, last.name, target_name, last.name);
+		let existing = self.dataset(host, target_name.as_str()).cloned();
+		if let Some(existing) = &existing
+		{
+			if let Some(token) = &existing.receive_resume_token
+			{
+				let resuming = token.splitn(3, ':').nth(2).map(|toname| toname.ends_with(format!("@{}", last.name).as_str())).unwrap_or(false);
+				if !resuming
+				{
+					return err(format!("cannot receive new stream: destination {} contains partially-complete state from \"zfs receive -s\".", target_name));
+				}
+			}
+		}
+		if stream.from_guid == 0
+		{
+			if let Some(existing) = &existing
+			{
+				if existing.receive_resume_token.is_none() && (!force || !existing.snapshots.is_empty())
+				{
+					return err(format!("cannot receive new filesystem stream: destination '{}' exists\nmust specify -F to overwrite it", target_name));
+				}
+			}
+			let parent = target_name.rsplit_once('/').map(|(parent, _)| String::from(parent)).unwrap_or_default();
+			if self.dataset(host, parent.as_str()).is_none()
+			{
+				return err(format!("cannot receive new filesystem stream: parent '{}' does not exist", parent));
+			}
+		}
+		else
+		{
+			let existing = match &existing
+				{
+					None=>return err(format!("cannot receive incremental stream: destination '{}' does not exist", target_name)),
+					Some(existing)=>existing,
+				};
+			let base = match existing.snapshots.iter().find(|s| s.guid == stream.from_guid)
+				{
+					None=>return err(String::from("cannot receive incremental stream: most recent snapshot of destination does not match incremental source")),
+					Some(base)=>base.clone(),
+				};
+			let newer:Vec<&FakeSnapshot> = existing.snapshots.iter().filter(|s| s.createtxg > base.createtxg).collect();
+			if !newer.is_empty() && !force
+			{
+				return err(format!("cannot receive incremental stream: destination {} has been modified\nsince most recent snapshot", target_name));
+			}
+			if newer.iter().any(|s| !s.holds.is_empty())
+			{
+				return err(format!("cannot receive incremental stream: destination {} has been modified\nsince most recent snapshot (and held snapshots can not be rolled back)", target_name));
+			}
+		}
+		if dry_run
+		{
+			return ok(format!("would {}", verbose));
+		}
+		if self.host(host).interrupt_receives > 0
+		{
+			self.host(host).interrupt_receives -= 1;
+			if resumable
+			{
+				let token = format!("fake:{}:{}@{}", stream.from_guid, stream.dataset, last.name);
+				match self.dataset(host, target_name.as_str())
+				{
+					Some(dataset)=>dataset.receive_resume_token = Some(token),
+					None=>
+						{
+							let encryption = if stream.raw {stream.encryption.clone()} else {default_encryption()};
+							let createtxg = self.next_txg();
+							self.host(host).datasets.push(FakeDataset{name:target_name.clone(), createtxg, encryption, receive_resume_token:Some(token), ..Default::default()});
+							self.host(host).datasets.sort_by(|a, b| a.name.cmp(&b.name));
+						},
+				}
+			}
+			return CommandOutput{success:false, code:Some(1), stdout:verbose, stderr:String::from("cannot receive: connection lost (simulated interruption)")};
+		}
+		let txg = self.next_txg();
+		if self.dataset(host, target_name.as_str()).is_none()
+		{
+			let encryption = if stream.raw {stream.encryption.clone()} else if inherit_encryption {self.inherited_encryption(host, target_name.as_str())} else {default_encryption()};
+			self.host(host).datasets.push(FakeDataset{name:target_name.clone(), createtxg:txg, encryption, ..Default::default()});
+			self.host(host).datasets.sort_by(|a, b| a.name.cmp(&b.name));
+		}
+		let dataset = self.dataset(host, target_name.as_str()).unwrap();
+		dataset.receive_resume_token = None;
+		for (k, v) in properties
+		{
+			dataset.properties.insert(k, v);
+		}
+		if stream.from_guid != 0
+		{
+			let base_createtxg = dataset.snapshots.iter().find(|s| s.guid == stream.from_guid).map(|s| s.createtxg).unwrap_or(0);
+			dataset.snapshots.retain(|s| s.createtxg <= base_createtxg);
+			if stream.replicate && force
+			{
+				// -F with a replication stream purges snapshots which no longer exist on the source.
+				dataset.snapshots.retain(|s| !s.holds.is_empty() || stream.source_guids.contains(&s.guid));
+			}
+		}
+		for (n, snapshot) in stream.snapshots.iter().enumerate()
+		{
+			dataset.snapshots.push(FakeSnapshot{name:snapshot.name.clone(), guid:snapshot.guid, createtxg:txg + n as u64, creation:snapshot.creation, written:snapshot.written, holds:Vec::new()});
+		}
+		self.txg = txg + stream.snapshots.len() as u64;
+		ok(verbose)
+	}
+
+	fn zfs(&mut self, host:&str, args:&[&str]) -> CommandOutput
+	{
+		let rest = if args.len() > 1 {&args[1..]} else {&[]};
+		match args.first().cloned().unwrap_or("")
+		{
+			"list" => self.list(host, rest),
+			"get" => self.get(host, rest),
+			"hold" => self.hold(host, rest, false),
+			"release" => self.hold(host, rest, true),
+			"holds" => self.holds(host, rest),
+			"bookmark" => self.bookmark(host, rest),
+			"destroy" => self.destroy(host, rest),
+			"snapshot" => self.snapshot(host, rest),
+			"create" => self.create(host, rest),
+			"send" => self.send(host, rest),
+			"recv"|"receive" if rest.contains(&"-A") => self.receive_abort(host, rest),
+			command => err(format!("fake zfs does not support \"zfs {}\"", command)),
+		}
+	}
+}
+
+fn dataset_property(dataset:&FakeDataset, property:&str) -> String
+{
+	match property
+	{
+		"name" => dataset.name.clone(),
+		"type" => String::from("filesystem"),
+		"createtxg" => format!("{}", dataset.createtxg),
+		"encryption" => dataset.encryption.clone(),
+		"guid" => format!("{}", dataset.createtxg),
+		"receive_resume_token" => dataset.receive_resume_token.clone().unwrap_or(String::from("-")),
+		property => dataset.properties.get(property).cloned().unwrap_or(String::from("-")),
+	}
+}
+
+fn snapshot_property(dataset:&FakeDataset, snapshot:&FakeSnapshot, kind:char, property:&str) -> String
+{
+	match property
+	{
+		"name" => format!("{}{}{}", dataset.name, kind, snapshot.name),
+		"type" => String::from(if kind == '@' {"snapshot"} else {"bookmark"}),
+		"guid" => format!("{}", snapshot.guid),
+		"createtxg" => format!("{}", snapshot.createtxg),
+		"creation" => format!("{}", snapshot.creation),
+		"written"|"used"|"referenced" => format!("{}", snapshot.written),
+		"userrefs" => format!("{}", snapshot.holds.len()),
+		property => dataset_property(dataset, property),
+	}
+}
+
+// the value of property for a dataset, snapshot or bookmark, "-" if there is no such thing.
+fn self_property(datasets:&[FakeDataset], name:&str, property:&str) -> String
+{
+	let (dataset_name, kind, snapshot_name) = split_name(name);
+	let dataset = match datasets.iter().find(|d| d.name == dataset_name)
+		{
+			None=>return String::from("-"),
+			Some(dataset)=>dataset,
+		};
+	let snapshot = match kind
+		{
+			'@'=>dataset.snapshots.iter().find(|s| s.name == snapshot_name),
+			'#'=>dataset.bookmarks.iter().find(|s| s.name == snapshot_name),
+			_=>return dataset_property(dataset, property),
+		};
+	match snapshot
+	{
+		None=>String::from("-"),
+		Some(snapshot)=>snapshot_property(dataset, snapshot, kind, property),
+	}
+}
+
+impl FakeExecutor
+{
+	pub fn load(path:&Path) -> Result<FakeExecutor, String>
+	{
+		let file = File::open(path).map_err(|e| format!("Could not open simulation file \"{}\": {}", path.display(), e))?;
+		let mut state:FakeState = serde_json::from_reader(file).map_err(|e| format!("Error reading simulation file \"{}\": {}", path.display(), e))?;
+		state.normalize();
+		Ok(FakeExecutor{path:path.to_path_buf(), state:Mutex::new(state)})
+	}
+
+	// simulated pools which aren't loaded from a file, for tests.
+	#[cfg(test)]
+	pub fn from_json(json:&str) -> FakeExecutor
+	{
+		let mut state:FakeState = serde_json::from_str(json).unwrap();
+		state.normalize();
+		FakeExecutor{path:PathBuf::new(), state:Mutex::new(state)}
+	}
+
+	// the commands run on host so far, oldest first, for tests.
+	#[cfg(test)]
+	pub fn commands(&self, host:&str) -> Vec<String>
+	{
+		self.state.lock().unwrap().host(host).commands.clone()
+	}
+
+	// writes the simulated pools back to the file they were loaded from.
+	pub fn save(&self) -> Result<(), String>
+	{
+		let state = self.state.lock().unwrap();
+		let file = File::create(&self.path).map_err(|e| format!("Could not write simulation file \"{}\": {}", self.path.display(), e))?;
+		serde_json::to_writer_pretty(file, &*state).map_err(|e| format!("Error writing simulation file \"{}\": {}", self.path.display(), e))
+	}
+
+	fn record(state:&mut FakeState, host:&str, program:&str, args:&[&str])
+	{
+		let line = format!("{} {}", program, args.join(" "));
+		debug!("fake {}: {}", host_name(host), line);
+		state.host(host).commands.push(line);
+	}
+}
+
+#[async_trait]
+impl ZfsExecutor for FakeExecutor
+{
+	async fn run(&self, host:&str, program:&str, args:&[&str]) -> CommandOutput
+	{
+		let mut state = self.state.lock().unwrap();
+		if !host.is_empty() && state.host(host).unreachable
+		{
+			return unreachable(host);
+		}
+		FakeExecutor::record(&mut state, host, program, args);
+		let line = format!("{} {}", program, args.join(" "));
+		if state.host(host).fail_commands.iter().any(|command| line.starts_with(command.as_str()))
+		{
+			return err(format!("fake failure of \"{}\"", line));
+		}
+		match program
+		{
+			"zfs" => state.zfs(host, args),
+			"hostname" => ok(format!("{}\n", if host.is_empty() {"localhost"} else {host})),
+			// anything else (the ssh login test, hooks and the like) simply succeeds.
+			_ => ok(String::from("")),
+		}
+	}
+
+	// only the zfs send and zfs recv stages are simulated. any stages between them
+	// (compression, buffering and the like) pass the stream through untouched.
+	async fn pipeline(&self, stages:&[Stage]) -> Vec<CommandOutput>
+	{
+		let mut state = self.state.lock().unwrap();
+		for stage in stages.iter()
+		{
+			if !stage.host.is_empty() && state.host(stage.host.as_str()).unreachable
+			{
+				return stages.iter().map(|_| unreachable(stage.host.as_str())).collect();
+			}
+		}
+		let mut results:Vec<CommandOutput> = Vec::new();
+		let mut stream:Option<Result<FakeStream, String>> = None;
+		let mut dry_run = false;
+		for stage in stages.iter()
+		{
+			let host = stage.host.as_str();
+			let args:Vec<&str> = stage.args.iter().map(|a| a.as_str()).collect();
+			FakeExecutor::record(&mut state, host, stage.program.as_str(), &args);
+			if stage.program == "zfs" && args.first() == Some(&"send")
+			{
+				dry_run = dry_run || args.contains(&"-n");
+				let sent = state.stream(host, &args[1..]);
+				results.push(match &sent
+					{
+						Err(e)=>err(e.clone()),
+						// with -P zfs send writes the size of the stream to stderr before sending it.
+						Ok(sent) if args.contains(&"-P")=>
+							{
+								let size:u64 = sent.snapshots.iter().map(|s| s.written.max(1)).sum();
+								CommandOutput{success:true, code:Some(0), stdout:String::from(""), stderr:format!("{}\t{}\t{}\t{}\nsize\t{}\n", if sent.from_guid == 0 {"full"} else {"incremental"}, sent.dataset, sent.snapshots.last().map(|s| s.name.as_str()).unwrap_or(""), size, size)}
+							},
+						Ok(_)=>ok(String::from("")),
+					});
+				stream = Some(sent);
+			}
+			else if stage.program == "zfs" && args.first() == Some(&"recv")
+			{
+				dry_run = dry_run || args.contains(&"-n");
+				results.push(match stream.take()
+					{
+						Some(Ok(sent))=>state.receive(host, &args[1..], sent, dry_run),
+						_=>err(String::from("cannot receive: failed to read from stream")),
+					});
+			}
+			else
+			{
+				results.push(ok(String::from("")));
+			}
+		}
+		results
+	}
+}
@@ -0,0 +1,228 @@
+/*
+	filter
+	which of the children of a recursive job are replicated. "exclude" and
+	"include" are lists of patterns matched against the whole name of each child
+	dataset. a pattern is a glob, in which "*" matches anything but "/", "**"
+	matches anything at all and "?" matches one character but "/", or, starting
+	with "re:", a regular expression, which matches if it is found anywhere in
+	the name (anchor it with ^ and $ otherwise). a child is left out if it matches
+	an exclude pattern, or if there are include patterns and neither it nor a
+	dataset above it (up to the job's sourcedataset) matches one. a child which is
+	left out is still excluded from its parent's send, and its own children are
+	left out with it, unless it is only left out for not matching an include
+	pattern which could match a dataset under it (as "tank/data/a/b/c" can match
+	something under "tank/data/a"). then it is passed through: it isn't
+	replicated, but the datasets under it are looked at in turn.
+*/
+use regex::Regex;
+
+pub struct Pattern
+{
+	regex: Regex,
+	// for a glob, a regex for each of its components, or None for one containing
+	// "**", which can match any number of them. a regular expression has none.
+	components: Option<Vec<Option<Regex>>>,
+}
+
+impl Pattern
+{
+	pub fn new(pattern:&str) -> Result<Pattern, String>
+	{
+		let bad = |e:regex::Error| format!("bad pattern \"{}\": {}", pattern, e);
+		match pattern.strip_prefix("re:")
+		{
+			Some(expression)=>Ok(Pattern{regex:Regex::new(expression).map_err(bad)?, components:None}),
+			None=>
+				{
+					let mut components = Vec::new();
+					for component in pattern.split('/')
+					{
+						components.push(if component.contains("**") {None} else {Some(Regex::new(glob_to_regex(component).as_str()).map_err(bad)?)});
+					}
+					Ok(Pattern{regex:Regex::new(glob_to_regex(pattern).as_str()).map_err(bad)?, components:Some(components)})
+				},
+		}
+	}
+
+	pub fn matches(&self, name:&str) -> bool
+	{
+		self.regex.is_match(name)
+	}
+
+	// whether the pattern could match a dataset under name. a regular expression might.
+	pub fn may_match_under(&self, name:&str) -> bool
+	{
+		let components = match &self.components
+			{
+				None=>return true,
+				Some(components)=>components,
+			};
+		let names:Vec<&str> = name.split('/').collect();
+		for (i, name) in names.iter().enumerate()
+		{
+			match components.get(i)
+			{
+				None=>return false,
+				Some(None)=>return true,
+				Some(Some(regex))=>if !regex.is_match(name)
+					{
+						return false;
+					},
+			}
+		}
+		components.len() > names.len()
+	}
+}
+
+fn glob_to_regex(glob:&str) -> String
+{
+	let mut expression = String::from("^");
+	let mut chars = glob.chars().peekable();
+	while let Some(c) = chars.next()
+	{
+		match c
+		{
+			'*' if chars.peek() == Some(&'*')=>
+				{
+					chars.next();
+					expression.push_str(".*");
+				},
+			'*'=>expression.push_str("[^/]*"),
+			'?'=>expression.push_str("[^/]"),
+			c=>expression.push_str(regex::escape(c.to_string().as_str()).as_str()),
+		}
+	}
+	expression.push('$');
+	expression
+}
+
+fn compile(patterns:&Option<Vec<String>>) -> Result<Vec<Pattern>, String>
+{
+	patterns.iter().flatten().map(|pattern| Pattern::new(pattern.as_str())).collect()
+}
+
+// what is done with a child dataset.
+#[derive(Debug, PartialEq)]
+pub enum Wanted
+{
+	// it is replicated.
+	Yes,
+	// it isn't replicated, but the datasets under it might be.
+	Through,
+	// neither it nor anything under it is replicated.
+	No,
+}
+
+pub struct ChildFilter
+{
+	include: Vec<Pattern>,
+	exclude: Vec<Pattern>,
+}
+
+impl ChildFilter
+{
+	pub fn new(include:&Option<Vec<String>>, exclude:&Option<Vec<String>>) -> Result<ChildFilter, String>
+	{
+		Ok(ChildFilter{include:compile(include)?, exclude:compile(exclude)?})
+	}
+
+	// whether child, under the job's source dataset source, is replicated, as far as the patterns go.
+	pub fn wants(&self, source:&str, child:&str) -> Wanted
+	{
+		if self.exclude.iter().any(|pattern| pattern.matches(child))
+		{
+			return Wanted::No;
+		}
+		if self.include.is_empty()
+		{
+			return Wanted::Yes;
+		}
+		let mut dataset = child;
+		while dataset.len() > source.len()
+		{
+			if self.include.iter().any(|pattern| pattern.matches(dataset))
+			{
+				return Wanted::Yes;
+			}
+			dataset = match dataset.rsplit_once('/')
+				{
+					None=>break,
+					Some((parent, _))=>parent,
+				};
+		}
+		if self.include.iter().any(|pattern| pattern.may_match_under(child))
+		{
+			return Wanted::Through;
+		}
+		Wanted::No
+	}
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+
+	fn filter(include:&[&str], exclude:&[&str]) -> ChildFilter
+	{
+		let list = |patterns:&[&str]| if patterns.is_empty() {None} else {Some(patterns.iter().map(|pattern| String::from(*pattern)).collect())};
+		ChildFilter::new(&list(include), &list(exclude)).unwrap()
+	}
+
+	#[test]
+	fn globs_match_whole_names()
+	{
+		let pattern = Pattern::new("tank/*/tmp").unwrap();
+		assert!(pattern.matches("tank/data/tmp"));
+		assert!(!pattern.matches("tank/data/a/tmp"));
+		assert!(!pattern.matches("tank/data/tmpfiles"));
+		assert!(Pattern::new("**/tmp").unwrap().matches("tank/data/a/tmp"));
+		assert!(Pattern::new("re:/(cache|tmp)$").unwrap().matches("tank/data/cache"));
+	}
+
+	#[test]
+	fn may_match_under_follows_the_components()
+	{
+		let pattern = Pattern::new("tank/data/a/b/*").unwrap();
+		assert!(pattern.may_match_under("tank/data/a"));
+		assert!(pattern.may_match_under("tank/data/a/b"));
+		assert!(!pattern.may_match_under("tank/data/c"));
+		assert!(!pattern.may_match_under("tank/data/a/b/c"));
+		assert!(Pattern::new("tank/**/tmp").unwrap().may_match_under("tank/data/a/b"));
+		assert!(Pattern::new("re:tmp").unwrap().may_match_under("tank/data"));
+	}
+
+	#[test]
+	fn no_patterns_want_everything()
+	{
+		assert_eq!(filter(&[], &[]).wants("tank/data", "tank/data/a"), Wanted::Yes);
+	}
+
+	#[test]
+	fn exclude_wins()
+	{
+		let filter = filter(&["tank/data/**"], &["**/tmp"]);
+		assert_eq!(filter.wants("tank/data", "tank/data/tmp"), Wanted::No);
+		assert_eq!(filter.wants("tank/data", "tank/data/a"), Wanted::Yes);
+	}
+
+	#[test]
+	fn include_matches_the_child_or_above_it()
+	{
+		let filter = filter(&["tank/data/a"], &[]);
+		assert_eq!(filter.wants("tank/data", "tank/data/a"), Wanted::Yes);
+		assert_eq!(filter.wants("tank/data", "tank/data/a/b"), Wanted::Yes);
+		assert_eq!(filter.wants("tank/data", "tank/data/b"), Wanted::No);
+	}
+
+	#[test]
+	fn deep_includes_pass_through_their_ancestors()
+	{
+		let filter = filter(&["tank/data/a/b/*"], &[]);
+		assert_eq!(filter.wants("tank/data", "tank/data/a"), Wanted::Through);
+		assert_eq!(filter.wants("tank/data", "tank/data/a/b"), Wanted::Through);
+		assert_eq!(filter.wants("tank/data", "tank/data/a/b/c"), Wanted::Yes);
+		assert_eq!(filter.wants("tank/data", "tank/data/a/c"), Wanted::No);
+		assert_eq!(filter.wants("tank/data", "tank/data/b"), Wanted::No);
+	}
+}
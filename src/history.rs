@@ -0,0 +1,341 @@
+/*
+	history
+	piper appends a json object to the history file ("/var/lib/piper/history.jsonl"
+	by default) for every send it makes, one per line: the job, source and target,
+	the snapshots sent from and to, the bytes sent, when it started and how long it
+	took, and whether it succeeded. nothing is ever rewritten, so the file is safe to
+	rotate or trim by hand. "piper history" lists the sends, and "piper stats" sums
+	them up for each source and target: when it last succeeded, how often it fails,
+	and whether the incremental sends are growing or shrinking.
+*/
+use chrono::{DateTime, Local, NaiveDate, TimeZone};
+use serde::{Deserialize, Serialize};
+use std::{fs::{self, File, OpenOptions}, io::{BufRead, BufReader, Write}, path::Path};
+use crate::duration::parse_duration;
+use crate::plan::{format_size, host_and_dataset};
+use crate::summary::JobResult;
+
+#[derive(Serialize, Deserialize)]
+pub struct Entry
+{
+	pub job: String,
+	pub source: String,
+	pub target: String,
+	// "" for a full send.
+	pub from: String,
+	pub to: String,
+	pub bytes: u64,
+	pub started: String,
+	pub seconds: f64,
+	// "ok" or "failed".
+	pub result: String,
+	pub error_class: Option<String>,
+}
+
+impl Entry
+{
+	fn started(&self) -> Option<DateTime<Local>>
+	{
+		DateTime::parse_from_rfc3339(self.started.as_str()).ok().map(|started| started.with_timezone(&Local))
+	}
+
+	fn ok(&self) -> bool
+	{
+		self.result == "ok"
+	}
+}
+
+// which entries to show.
+pub struct Filter
+{
+	pub job: Option<String>,
+	pub since: Option<DateTime<Local>>,
+}
+
+impl Filter
+{
+	fn matches(&self, entry:&Entry) -> bool
+	{
+		if let Some(job) = &self.job
+		{
+			if entry.job != *job
+			{
+				return false;
+			}
+		}
+		match (self.since, entry.started())
+		{
+			(Some(since), Some(started))=>started >= since,
+			(Some(_), None)=>false,
+			(None, _)=>true,
+		}
+	}
+}
+
+// the time given to --since, either a duration before now ("7d"), a date
+// ("2024-06-01", from midnight) or a time ("2024-06-01T12:00:00+02:00").
+pub fn parse_since(text:&str) -> Result<DateTime<Local>, String>
+{
+	if let Ok(duration) = parse_duration(text)
+	{
+		return chrono::Duration::from_std(duration).map(|duration| Local::now() - duration).map_err(|e| format!("bad --since \"{}\": {}", text, e));
+	}
+	if let Ok(time) = DateTime::parse_from_rfc3339(text)
+	{
+		return Ok(time.with_timezone(&Local));
+	}
+	match NaiveDate::parse_from_str(text, "%Y-%m-%d").ok().and_then(|date| date.and_hms_opt(0, 0, 0)).and_then(|midnight| Local.from_local_datetime(&midnight).earliest())
+	{
+		None=>Err(format!("bad --since \"{}\" (must be a duration such as \"7d\", a date such as \"2024-06-01\" or an rfc3339 time)", text)),
+		Some(since)=>Ok(since),
+	}
+}
+
+// appends every send made in a run to the history file, creating it (and the
+// directory it is in) if need be.
+pub fn append(path:&Path, jobs:&[JobResult]) -> Result<(), String>
+{
+	let mut lines = Vec::new();
+	for job in jobs.iter()
+	{
+		for dataset in job.datasets.iter()
+		{
+			for send in dataset.sends.iter()
+			{
+				let entry = Entry{
+					job: job.name.clone(),
+					source: host_and_dataset(dataset.sourcehost.as_str(), dataset.dataset.as_str()),
+					target: host_and_dataset(dataset.targethost.as_str(), dataset.target.as_str()),
+					from: send.from.clone(),
+					to: send.to.clone(),
+					bytes: send.bytes,
+					started: send.started.clone(),
+					seconds: send.seconds,
+					result: String::from(if send.error.is_none() {"ok"} else {"failed"}),
+					error_class: send.error.clone(),
+				};
+				if let Ok(line) = serde_json::to_string(&entry)
+				{
+					lines.push(line);
+				}
+			}
+		}
+	}
+	if lines.is_empty()
+	{
+		return Ok(());
+	}
+	if let Some(directory) = path.parent()
+	{
+		if !directory.as_os_str().is_empty()
+		{
+			fs::create_dir_all(directory).map_err(|e| format!("Error creating history directory \"{}\": {}", directory.display(), e))?;
+		}
+	}
+	let mut file = OpenOptions::new().create(true).append(true).open(path).map_err(|e| format!("Error opening history file \"{}\": {}", path.display(), e))?;
+	// written all at once, so a daemon run and a manual run don't interleave their lines.
+	file.write_all(format!("{}\n", lines.join("\n")).as_bytes()).map_err(|e| format!("Error writing history file \"{}\": {}", path.display(), e))
+}
+
+// the entries in the history file which match filter, oldest first. lines which can't
+// be parsed (a line cut short by a crash, say) are skipped.
+pub fn read(path:&Path, filter:&Filter) -> Result<Vec<Entry>, String>
+{
+	let file = match File::open(path)
+		{
+			Err(e) if e.kind() == std::io::ErrorKind::NotFound=>return Ok(Vec::new()),
+			Err(e)=>return Err(format!("Error opening history file \"{}\": {}", path.display(), e)),
+			Ok(file)=>file,
+		};
+	Ok(BufReader::new(file).lines().map_while(Result::ok).filter_map(|line| serde_json::from_str::<Entry>(line.as_str()).ok()).filter(|entry| filter.matches(entry)).collect())
+}
+
+fn format_time(entry:&Entry) -> String
+{
+	match entry.started()
+	{
+		None=>entry.started.clone(),
+		Some(started)=>started.format("%Y-%m-%d %H:%M:%S").to_string(),
+	}
+}
+
+pub fn print(entries:&[Entry])
+{
+	if entries.is_empty()
+	{
+		println!("No sends in the history.");
+		return;
+	}
+	for entry in entries.iter()
+	{
+		let sent = if entry.from.is_empty()
+			{
+				format!("full \"{}\"", entry.to)
+			}
+			else
+			{
+				format!("\"{}\" -> \"{}\"", entry.from, entry.to)
+			};
+		let result = match &entry.error_class
+			{
+				Some(class) if !entry.ok()=>format!("FAILED ({})", class),
+				_=>String::from(if entry.ok() {"ok"} else {"FAILED"}),
+			};
+		println!("{} [{}] \"{}\" -> \"{}\": {}, {} in {:.1}s: {}", format_time(entry), entry.job, entry.source, entry.target, sent, format_size(entry.bytes), entry.seconds, result);
+	}
+}
+
+// sums up the entries for each source and target.
+pub fn stats(entries:&[Entry])
+{
+	let mut pairs:Vec<(&str, &str, &str)> = Vec::new();
+	for entry in entries.iter()
+	{
+		let pair = (entry.job.as_str(), entry.source.as_str(), entry.target.as_str());
+		if !pairs.contains(&pair)
+		{
+			pairs.push(pair);
+		}
+	}
+	if pairs.is_empty()
+	{
+		println!("No sends in the history.");
+		return;
+	}
+	for (job, source, target) in pairs.iter()
+	{
+		let sends:Vec<&Entry> = entries.iter().filter(|entry| entry.job == *job && entry.source == *source && entry.target == *target).collect();
+		let failed = sends.iter().filter(|entry| !entry.ok()).count();
+		println!("[{}] \"{}\" -> \"{}\":", job, source, target);
+		println!("\t{} sends, {} failed, {} sent in all", sends.len(), failed, format_size(sends.iter().map(|entry| entry.bytes).sum()));
+		match sends.iter().rev().find(|entry| entry.ok())
+		{
+			None=>println!("\tnever succeeded"),
+			Some(entry)=>println!("\tlast succeeded {} (\"{}\")", format_time(entry), entry.to),
+		}
+		if let Some(entry) = sends.last().filter(|entry| !entry.ok())
+		{
+			println!("\tlast failed {} ({})", format_time(entry), entry.error_class.as_deref().unwrap_or("unknown"));
+		}
+		let deltas:Vec<u64> = sends.iter().filter(|entry| entry.ok() && !entry.from.is_empty()).map(|entry| entry.bytes).collect();
+		if let Some(newest) = deltas.last()
+		{
+			let average = deltas.iter().sum::<u64>() / deltas.len() as u64;
+			println!("\tincremental sends: average {}, newest {}{}", format_size(average), format_size(*newest), trend(&deltas));
+		}
+	}
+}
+
+// how the newer half of the incremental sends compares with the older half, if
+// there are enough of them to say.
+fn trend(deltas:&[u64]) -> String
+{
+	if deltas.len() < 4
+	{
+		return String::from("");
+	}
+	let half = deltas.len() / 2;
+	let older = deltas[..half].iter().sum::<u64>() as f64 / half as f64;
+	let newer = deltas[deltas.len() - half..].iter().sum::<u64>() as f64 / half as f64;
+	if older == 0.0
+	{
+		return String::from("");
+	}
+	format!(", trend {:+.0}%", (newer - older) * 100.0 / older)
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+	use std::time::Duration;
+	use crate::summary::{DatasetResult, Outcome, SendRecord};
+
+	fn send(from:&str, to:&str, bytes:u64, error:Option<&str>) -> SendRecord
+	{
+		SendRecord{from:String::from(from), to:String::from(to), bytes, started:String::from("2024-06-01T00:05:00+00:00"), seconds:1.5, error:error.map(String::from)}
+	}
+
+	fn job(sends:Vec<SendRecord>) -> JobResult
+	{
+		let dataset = DatasetResult{
+				dataset: String::from("tank/data"),
+				sourcehost: String::from(""),
+				targethost: String::from("backup"),
+				target: String::from("backup/data"),
+				started: Local::now(),
+				duration: Duration::from_secs(3),
+				sends,
+				result: Ok(Outcome::Incremental),
+				plan: None,
+				lag: None,
+			};
+		JobResult{name:String::from("root"), error:None, datasets:vec![dataset], matched:None}
+	}
+
+	fn everything() -> Filter
+	{
+		Filter{job:None, since:None}
+	}
+
+	#[test]
+	fn one_line_per_send()
+	{
+		let path = std::env::temp_dir().join(format!("piper-history-{}.jsonl", std::process::id()));
+		let _ = fs::remove_file(&path);
+		append(&path, &[job(vec![send("", "tank/data@a", 4096, None)])]).unwrap();
+		append(&path, &[job(vec![send("tank/data@a", "tank/data@b", 512, Some("recv_failure"))])]).unwrap();
+		// a run without any sends adds nothing.
+		append(&path, &[job(Vec::new())]).unwrap();
+		let text = fs::read_to_string(&path).unwrap();
+		assert_eq!(text.lines().next().unwrap(), r#"{"job":"root","source":"tank/data","target":"backup:backup/data","from":"","to":"tank/data@a","bytes":4096,"started":"2024-06-01T00:05:00+00:00","seconds":1.5,"result":"ok","error_class":null}"#);
+		// a line cut short is skipped.
+		fs::write(&path, format!("{}{{\"job\":\"ro", text)).unwrap();
+		let entries = read(&path, &everything()).unwrap();
+		fs::remove_file(&path).unwrap();
+		assert_eq!(entries.len(), 2);
+		assert!(entries[0].ok());
+		assert_eq!(entries[1].result, "failed");
+		assert_eq!(entries[1].error_class.as_deref(), Some("recv_failure"));
+	}
+
+	#[test]
+	fn a_missing_file_is_no_history()
+	{
+		assert!(read(Path::new("/nonexistent/piper/history.jsonl"), &everything()).unwrap().is_empty());
+	}
+
+	#[test]
+	fn filters()
+	{
+		let entry = |job:&str, started:&str| Entry{job:String::from(job), source:String::from(""), target:String::from(""), from:String::from(""), to:String::from(""),
+				bytes:0, started:String::from(started), seconds:0.0, result:String::from("ok"), error_class:None};
+		let since = parse_since("2024-06-01T00:00:00+00:00").unwrap();
+		let filter = Filter{job:Some(String::from("root")), since:Some(since)};
+		assert!(filter.matches(&entry("root", "2024-06-01T00:00:00+00:00")));
+		assert!(!filter.matches(&entry("root", "2024-05-31T23:59:59+00:00")));
+		assert!(!filter.matches(&entry("other", "2024-06-02T00:00:00+00:00")));
+		assert!(!filter.matches(&entry("root", "yesterday")));
+		assert!(everything().matches(&entry("root", "yesterday")));
+	}
+
+	#[test]
+	fn since()
+	{
+		let week_ago = parse_since("7d").unwrap();
+		let expected = Local::now() - chrono::Duration::days(7);
+		assert!((week_ago - expected).num_seconds().abs() < 5);
+		assert_eq!(parse_since("2024-06-01").unwrap(), Local.with_ymd_and_hms(2024, 6, 1, 0, 0, 0).unwrap());
+		assert!(parse_since("last week").is_err());
+	}
+
+	#[test]
+	fn trends()
+	{
+		// too few sends to say.
+		assert_eq!(trend(&[100, 200, 300]), "");
+		assert_eq!(trend(&[100, 100, 200, 200]), ", trend +100%");
+		assert_eq!(trend(&[200, 200, 999, 100, 100]), ", trend -50%");
+		assert_eq!(trend(&[0, 0, 100, 100]), "");
+	}
+}
@@ -0,0 +1,174 @@
+/*
+	hooks
+	a job can run a command of its own before it replicates anything ("pre_hook",
+	to quiesce a database, say), after it has replicated everything ("post_hook",
+	to check the backup on the target) and if it fails ("on_failure"). each hook
+	runs with sh -c on the local host, or on the source or target host over the
+	same ssh as zfs, with PIPER_* environment variables saying what the job
+	replicates and, once it has run, how it went. a hook which exits with anything
+	but 0, or runs for longer than its timeout, has failed: a failed pre_hook skips
+	the job, and a failed post_hook fails it. hooks are run under timeout(1) on the
+	host they run on, so that a hook which runs too long is stopped there, along with
+	everything it started, rather than just losing its ssh connection.
+*/
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use tokio::time::timeout;
+use crate::duration::{format_duration, parse_duration};
+use crate::error::PiperError;
+use crate::executor::{shell_quote, ZfsExecutor};
+
+// how long a hook which has run out of time has to stop before it is killed.
+const KILL_AFTER:Duration = Duration::from_secs(10);
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Hook
+{
+	pub command: String,
+	// "local" (the default), "source" or "target".
+	pub host: Option<String>,
+	// "1h" by default.
+	pub timeout: Option<String>,
+}
+
+impl Hook
+{
+	pub fn validate(&self, name:&str) -> Result<(), String>
+	{
+		match self.host.as_deref()
+		{
+			None|Some("local")|Some("source")|Some("target")=>{},
+			Some(host)=>return Err(format!("unknown {} host \"{}\" (must be \"local\", \"source\" or \"target\")", name, host)),
+		}
+		if let Some(t) = &self.timeout
+		{
+			parse_duration(t).map_err(|e| format!("bad {} timeout: {}", name, e))?;
+		}
+		Ok(())
+	}
+
+	fn timeout(&self) -> Duration
+	{
+		self.timeout.as_deref().and_then(|t| parse_duration(t).ok()).unwrap_or(Duration::from_secs(60*60))
+	}
+
+	// the host the hook runs on. "" is the local host.
+	fn host<'a>(&self, sourcehost:&'a str, targethost:&'a str) -> &'a str
+	{
+		match self.host.as_deref()
+		{
+			Some("source")=>sourcehost,
+			Some("target")=>targethost,
+			_=>"",
+		}
+	}
+}
+
+// runs the hook called name with the variables in env.
+pub async fn run(exec:&dyn ZfsExecutor, padding:&str, name:&str, hook:&Hook, sourcehost:&str, targethost:&str, env:&[(&str, String)]) -> Result<(), PiperError>
+{
+	let host = hook.host(sourcehost, targethost);
+	info!("{}Running {} \"{}\" on {}", padding, name, hook.command, if host.is_empty() {"localhost"} else {host});
+	// the variables are set by the shell rather than passed to it, as ssh doesn't pass them on.
+	let variables:Vec<String> = env.iter().map(|(variable, value)| format!("{}={}", variable, shell_quote(value.as_str()))).collect();
+	let script = format!("export {}\n{}", variables.join(" "), hook.command);
+	// a remote command line is run by the remote shell, so the script has to be quoted once more.
+	let script = if host.is_empty() {script} else {shell_quote(script.as_str())};
+	let limit = hook.timeout();
+	let seconds = format!("{}s", limit.as_secs().max(1));
+	let grace = format!("{}s", KILL_AFTER.as_secs());
+	let still_running = || PiperError::HookFailure{hook:String::from(name), message:format!("still running after {}", format_duration(limit))};
+	// timeout(1) should have stopped the hook by the time this one runs out, but a host
+	// which stops answering could keep it waiting.
+	let hook_out = match timeout(limit + KILL_AFTER + KILL_AFTER, exec.run(host, "timeout", &["-k", grace.as_str(), seconds.as_str(), "sh", "-c", script.as_str()])).await
+		{
+			Err(_)=>return Err(still_running()),
+			Ok(hook_out)=>hook_out,
+		};
+	// timeout(1) exits with 124 when it stops the hook, and 137 when it has to kill it.
+	if hook_out.code == Some(124) || hook_out.code == Some(137)
+	{
+		return Err(still_running());
+	}
+	for line in hook_out.stdout.lines()
+	{
+		info!("{}\t{}: {}", padding, name, line);
+	}
+	if hook_out.success
+	{
+		debug!("{}{} succeeded.", padding, name);
+		return Ok(());
+	}
+	let status = match hook_out.code
+		{
+			None=>String::from("failed"),
+			Some(code)=>format!("exited with {}", code),
+		};
+	Err(PiperError::HookFailure{hook:String::from(name), message:format!("{}: {}", status, hook_out.stderr.trim())})
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+	use std::{fs, path::PathBuf, time::Instant};
+	use crate::executor::ProcessExecutor;
+	use crate::hosts::Hosts;
+
+	fn hook(command:String, timeout:&str) -> Hook
+	{
+		Hook{command, host:None, timeout:Some(String::from(timeout))}
+	}
+
+	fn temp_path(test:&str) -> PathBuf
+	{
+		let path = std::env::temp_dir().join(format!("piper-hook-{}-{}", std::process::id(), test));
+		let _ = fs::remove_file(&path);
+		path
+	}
+
+	#[tokio::test]
+	async fn hooks_get_their_variables_as_they_are()
+	{
+		let path = temp_path("env");
+		let exec = ProcessExecutor::new(Hosts::new());
+		let env = [("PIPER_JOB", String::from("it's a \"job\"")), ("PIPER_SOURCE_DATASET", String::from("tank/a tank/b")), ("PIPER_ERROR", String::from("$HOME `id`"))];
+		let command = format!("printf '%s\\n' \"$PIPER_JOB\" \"$PIPER_SOURCE_DATASET\" \"$PIPER_ERROR\" > {}", path.display());
+		run(&exec, "", "post_hook", &hook(command, "1m"), "", "", &env).await.unwrap();
+		assert_eq!(fs::read_to_string(&path).unwrap(), "it's a \"job\"\ntank/a tank/b\n$HOME `id`\n");
+		fs::remove_file(&path).unwrap();
+	}
+
+	#[tokio::test]
+	async fn a_failed_hook_reports_its_exit_code_and_stderr()
+	{
+		let exec = ProcessExecutor::new(Hosts::new());
+		match run(&exec, "", "pre_hook", &hook(String::from("echo not ready >&2; exit 3"), "1m"), "", "", &[]).await
+		{
+			Err(PiperError::HookFailure{hook, message})=>
+				{
+					assert_eq!(hook, "pre_hook");
+					assert_eq!(message, "exited with 3: not ready");
+				},
+			_=>panic!("the hook didn't fail"),
+		}
+	}
+
+	#[tokio::test]
+	async fn a_hook_which_runs_too_long_is_stopped_with_what_it_started()
+	{
+		let path = temp_path("timeout");
+		let exec = ProcessExecutor::new(Hosts::new());
+		let started = Instant::now();
+		let command = format!("sleep 2 && touch {}", path.display());
+		match run(&exec, "", "pre_hook", &hook(command, "1s"), "", "", &[]).await
+		{
+			Err(PiperError::HookFailure{message, ..})=>assert_eq!(message, "still running after 1s"),
+			_=>panic!("the hook didn't time out"),
+		}
+		assert!(started.elapsed() < Duration::from_secs(2));
+		tokio::time::sleep(Duration::from_millis(1500)).await;
+		assert!(!path.exists());
+	}
+}
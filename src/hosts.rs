@@ -0,0 +1,153 @@
+/*
+	hosts
+	the optional "hosts" section of the config file says how to ssh to each remote
+	host, so that the user, port, key and so on don't have to be set up in the
+	~/.ssh/config of the user piper runs as. the name of each entry can be used as
+	the host in sourcedataset/targetdataset, as can its hostname.
+*/
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct HostConfig
+{
+	// the host to connect to, if not the name of the entry.
+	pub hostname: Option<String>,
+	pub user: Option<String>,
+	pub port: Option<u16>,
+	pub identity_file: Option<String>,
+	// passed to ssh as "-o <option>", such as "Ciphers=aes128-gcm@openssh.com".
+	pub ssh_options: Option<Vec<String>>,
+	// share one ssh connection between all of the commands run on the host.
+	pub multiplex: Option<bool>,
+	// how long a shared connection stays open once the last command using it finishes.
+	pub control_persist: Option<String>,
+}
+
+pub type Hosts = BTreeMap<String, HostConfig>;
+
+// the name of the entry in hosts for host, which is either the name of the entry
+// or its hostname. hosts which aren't in hosts are returned unchanged.
+pub fn resolve<'a>(hosts:&'a Hosts, host:&'a str) -> &'a str
+{
+	if host.is_empty() || hosts.contains_key(host)
+	{
+		return host;
+	}
+	for (name, config) in hosts.iter()
+	{
+		if config.hostname.as_deref() == Some(host)
+		{
+			return name.as_str();
+		}
+	}
+	host
+}
+
+impl HostConfig
+{
+	// the arguments to ssh which go before the destination.
+	pub fn ssh_args(&self) -> Vec<String>
+	{
+		let mut args = Vec::new();
+		if let Some(user) = &self.user
+		{
+			args.push(String::from("-l"));
+			args.push(user.clone());
+		}
+		if let Some(port) = &self.port
+		{
+			args.push(String::from("-p"));
+			args.push(port.to_string());
+		}
+		if let Some(identity_file) = &self.identity_file
+		{
+			args.push(String::from("-i"));
+			args.push(identity_file.clone());
+			// only offer the given key, not every key ssh-agent has as well.
+			args.push(String::from("-o"));
+			args.push(String::from("IdentitiesOnly=yes"));
+		}
+		if self.multiplex == Some(true)
+		{
+			args.push(String::from("-o"));
+			args.push(String::from("ControlMaster=auto"));
+			args.push(String::from("-o"));
+			// %C is a hash of the user, host and port, which keeps the socket path short.
+			args.push(String::from("ControlPath=/tmp/piper-ssh-%C"));
+			args.push(String::from("-o"));
+			args.push(format!("ControlPersist={}", match &self.control_persist
+				{
+					None=>"60s",
+					Some(s)=>s.as_str(),
+				}));
+		}
+		if let Some(options) = &self.ssh_options
+		{
+			for option in options.iter()
+			{
+				args.push(String::from("-o"));
+				args.push(option.clone());
+			}
+		}
+		args
+	}
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+
+	fn hosts() -> Hosts
+	{
+		let mut hosts = Hosts::new();
+		hosts.insert(String::from("backup"), HostConfig{hostname:Some(String::from("backup.example.com")), ..Default::default()});
+		hosts.insert(String::from("offsite"), HostConfig::default());
+		hosts
+	}
+
+	#[test]
+	fn hosts_resolve_to_their_entry()
+	{
+		let hosts = hosts();
+		assert_eq!(resolve(&hosts, "backup"), "backup");
+		assert_eq!(resolve(&hosts, "backup.example.com"), "backup");
+		assert_eq!(resolve(&hosts, "offsite"), "offsite");
+		// hosts which aren't in the hosts section are used as they are.
+		assert_eq!(resolve(&hosts, "elsewhere.example.com"), "elsewhere.example.com");
+		assert_eq!(resolve(&hosts, ""), "");
+		assert_eq!(resolve(&Hosts::new(), "backup.example.com"), "backup.example.com");
+	}
+
+	#[test]
+	fn an_empty_entry_has_no_ssh_args()
+	{
+		assert!(HostConfig::default().ssh_args().is_empty());
+		assert!(HostConfig{hostname:Some(String::from("backup.example.com")), control_persist:Some(String::from("5m")), multiplex:Some(false), ..Default::default()}.ssh_args().is_empty());
+	}
+
+	#[test]
+	fn ssh_args_of_each_option()
+	{
+		let config = HostConfig{
+				hostname: Some(String::from("backup.example.com")),
+				user: Some(String::from("root")),
+				port: Some(2222),
+				identity_file: Some(String::from("/root/.ssh/id_piper")),
+				ssh_options: Some(vec![String::from("Ciphers=aes128-gcm@openssh.com"), String::from("Compression=no")]),
+				multiplex: None,
+				control_persist: None,
+			};
+		assert_eq!(config.ssh_args(), vec!["-l", "root", "-p", "2222", "-i", "/root/.ssh/id_piper", "-o", "IdentitiesOnly=yes", "-o", "Ciphers=aes128-gcm@openssh.com", "-o", "Compression=no"]);
+	}
+
+	#[test]
+	fn ssh_args_of_a_shared_connection()
+	{
+		let config = HostConfig{multiplex:Some(true), ..Default::default()};
+		assert_eq!(config.ssh_args(), vec!["-o", "ControlMaster=auto", "-o", "ControlPath=/tmp/piper-ssh-%C", "-o", "ControlPersist=60s"]);
+		let config = HostConfig{multiplex:Some(true), control_persist:Some(String::from("10m")), ..Default::default()};
+		assert_eq!(config.ssh_args().last().map(|arg| arg.as_str()), Some("ControlPersist=10m"));
+	}
+}
@@ -0,0 +1,215 @@
+/*
+	lock
+	lock files stop two pipers from replicating into the same place at once (say
+	when a long full send is still running when cron starts the next run). a lock
+	file holds the pid of the piper which has it, and is locked with flock for as
+	long as that piper runs. the kernel drops the flock when the process exits, so a
+	file left behind by a piper which was killed is stale, and is taken over in the
+	same step as it is locked, which two pipers can't both do.
+*/
+use log::*;
+use std::{collections::BTreeMap, fs, fs::File, fs::OpenOptions, fs::TryLockError, io::Read, io::Seek, io::Write, os::unix::fs::MetadataExt, path::Path, path::PathBuf, process, sync::Arc, sync::Mutex};
+use tokio::sync::OwnedMutexGuard;
+use crate::error::PiperError;
+
+pub struct Lock
+{
+	path: PathBuf,
+	// the flock is held for as long as the file is open.
+	_file: File,
+}
+
+fn lock_error(path:&Path, message:String) -> PiperError
+{
+	PiperError::Locked{path:path.display().to_string(), message}
+}
+
+// whether file is still the one at path. the piper which held the lock removes the
+// file when it finishes, so a file opened just before then is locked after it has
+// gone, and another piper may already have created and locked a new one.
+fn is_current(file:&File, path:&Path) -> bool
+{
+	match (file.metadata(), fs::metadata(path))
+	{
+		(Ok(opened), Ok(current))=>opened.dev() == current.dev() && opened.ino() == current.ino(),
+		_=>false,
+	}
+}
+
+impl Lock
+{
+	pub fn acquire(path:&Path) -> Result<Lock, PiperError>
+	{
+		debug!("Taking lock \"{}\"", path.display());
+		// another attempt is only needed when the file is removed from under us.
+		for _ in 0..3
+		{
+			let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path).map_err(|e| lock_error(path, format!("{}", e)))?;
+			let mut pid = String::from("");
+			let _ = file.read_to_string(&mut pid);
+			let pid = pid.trim();
+			match file.try_lock()
+			{
+				Ok(())=>(),
+				Err(TryLockError::WouldBlock)=>
+					{
+						return Err(lock_error(path, if pid.is_empty() {String::from("held by another piper which is just starting")} else {format!("held by running process {}", pid)}));
+					},
+				Err(TryLockError::Error(e))=>return Err(lock_error(path, format!("{}", e))),
+			}
+			if !is_current(&file, path)
+			{
+				continue;
+			}
+			// whatever the file holds is left behind by a piper which is no longer running.
+			let mut stale = String::from("");
+			let _ = file.rewind().and_then(|_| file.read_to_string(&mut stale));
+			if !stale.trim().is_empty()
+			{
+				warn!("Taking over stale lock \"{}\" (process \"{}\" is not running).", path.display(), stale.trim());
+			}
+			if let Err(e) = file.set_len(0).and_then(|_| file.rewind()).and_then(|_| writeln!(file, "{}", process::id()))
+			{
+				let _ = fs::remove_file(path);
+				return Err(lock_error(path, format!("{}", e)));
+			}
+			return Ok(Lock{path:path.to_path_buf(), _file:file});
+		}
+		Err(lock_error(path, String::from("another piper took the lock first")))
+	}
+}
+
+impl Drop for Lock
+{
+	fn drop(&mut self)
+	{
+		// the file is removed while it is still locked, so nobody can have taken it over.
+		debug!("Releasing lock \"{}\"", self.path.display());
+		let _ = fs::remove_file(&self.path);
+	}
+}
+
+// the per-job locks, all in one directory. a job lock is named after the dataset the
+// job replicates into rather than the job so that jobs in different config files which
+// replicate into the same place still exclude each other. jobs in the same run which
+// replicate into the same place wait for each other rather than fail.
+pub struct JobLocks
+{
+	dir: PathBuf,
+	in_process: Mutex<BTreeMap<PathBuf, Arc<tokio::sync::Mutex<()>>>>,
+}
+
+pub struct JobLock
+{
+	// the file is released before the guard so that the next job in this run finds it gone.
+	_file: Lock,
+	_guard: OwnedMutexGuard<()>,
+}
+
+impl JobLocks
+{
+	pub fn new(dir:&Path) -> JobLocks
+	{
+		JobLocks{dir:dir.to_path_buf(), in_process:Mutex::new(BTreeMap::new())}
+	}
+
+	pub async fn acquire(&self, padding:&str, target:&str) -> Result<JobLock, PiperError>
+	{
+		let path = self.dir.join(format!("{}.lock", target.replace(['/',':','@','#'], "_")));
+		let mutex = match self.in_process.lock()
+			{
+				Err(_)=>Arc::new(tokio::sync::Mutex::new(())),
+				Ok(mut map)=>map.entry(path.clone()).or_insert_with(|| Arc::new(tokio::sync::Mutex::new(()))).clone(),
+			};
+		let guard = match mutex.clone().try_lock_owned()
+			{
+				Ok(guard)=>guard,
+				Err(_)=>
+					{
+						info!("{}Waiting for another job replicating into \"{}\"...", padding, target);
+						mutex.lock_owned().await
+					},
+			};
+		Ok(JobLock{_file:Lock::acquire(path.as_path())?, _guard:guard})
+	}
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+
+	// a lock file path of its own for each test, in a directory which is removed first.
+	fn lock_path(test:&str) -> PathBuf
+	{
+		let dir = std::env::temp_dir().join(format!("piper-lock-{}-{}", process::id(), test));
+		let _ = fs::remove_dir_all(&dir);
+		fs::create_dir_all(&dir).unwrap();
+		dir.join("piper.lock")
+	}
+
+	#[test]
+	fn a_lock_holds_the_pid_and_is_removed_when_dropped()
+	{
+		let path = lock_path("held");
+		let lock = Lock::acquire(&path).unwrap();
+		assert_eq!(fs::read_to_string(&path).unwrap().trim(), process::id().to_string());
+		drop(lock);
+		assert!(!path.exists());
+		// and it can be taken again.
+		assert!(Lock::acquire(&path).is_ok());
+	}
+
+	#[test]
+	fn a_live_lock_is_not_taken()
+	{
+		let path = lock_path("live");
+		let _lock = Lock::acquire(&path).unwrap();
+		match Lock::acquire(&path)
+		{
+			Err(PiperError::Locked{message, ..})=>assert_eq!(message, format!("held by running process {}", process::id())),
+			_=>panic!("a lock which is held was taken again"),
+		}
+		// the lock which failed leaves the file alone.
+		assert_eq!(fs::read_to_string(&path).unwrap().trim(), process::id().to_string());
+	}
+
+	#[test]
+	fn a_stale_lock_is_taken_over()
+	{
+		// a pid which is running, such as our own, doesn't make a lock live if it isn't locked.
+		for contents in ["4194305\n", "1\n", process::id().to_string().as_str()]
+		{
+			let path = lock_path("stale");
+			fs::write(&path, contents).unwrap();
+			let _lock = Lock::acquire(&path).unwrap();
+			assert_eq!(fs::read_to_string(&path).unwrap(), format!("{}\n", process::id()));
+		}
+	}
+
+	#[test]
+	fn an_unparsable_lock_is_taken_over()
+	{
+		for contents in ["", "not a pid\nat all\n"]
+		{
+			let path = lock_path("unparsable");
+			fs::write(&path, contents).unwrap();
+			let _lock = Lock::acquire(&path).unwrap();
+			assert_eq!(fs::read_to_string(&path).unwrap(), format!("{}\n", process::id()));
+		}
+	}
+
+	#[tokio::test]
+	async fn job_locks_in_one_run_wait_for_each_other()
+	{
+		let path = lock_path("jobs");
+		let locks = JobLocks::new(path.parent().unwrap());
+		let first = locks.acquire("", "backup:backup/data").await.unwrap();
+		assert!(path.parent().unwrap().join("backup_backup_data.lock").exists());
+		let second = locks.acquire("", "backup:backup/data");
+		tokio::pin!(second);
+		assert!(tokio::time::timeout(std::time::Duration::from_millis(50), &mut second).await.is_err());
+		drop(first);
+		assert!(second.await.is_ok());
+	}
+}
@@ -1,50 +1,296 @@
 extern crate printwrap;
-use tokio::{process::Command};
-use tokio::io::{BufReader,AsyncBufReadExt};
 use serde::{Deserialize, Serialize};
 use log::*;
-use std::{process,env,fs::File,path::Path, process::Stdio,str};
+use std::{process,env,fs::File,path::Path,collections::BTreeMap};
 use chrono::{Local};
 use async_recursion::async_recursion;
+use futures::future::join_all;
+
+mod cache;
+mod check;
+mod daemon;
+mod duration;
+mod error;
+mod events;
+mod executor;
+mod fake;
+mod filter;
+mod history;
+mod hooks;
+mod hosts;
+mod lock;
+mod mapping;
+mod metrics;
+mod notify;
+mod parallel;
+mod pipeline;
+mod plan;
+mod report;
+mod retention;
+mod schedule;
+mod summary;
+use cache::SourceCache;
+use check::{JobCheck, Status};
+use duration::parse_duration;
+use error::PiperError;
+use events::Events;
+use executor::{CommandOutput, ProcessExecutor, Stage, ZfsExecutor};
+use fake::FakeExecutor;
+use filter::{ChildFilter, Pattern, Wanted};
+use hooks::Hook;
+use hosts::Hosts;
+use lock::{JobLocks, Lock};
+use mapping::{TargetMap, TargetMapping};
+use notify::Notifier;
+use parallel::Limits;
+use pipeline::PipelineOptions;
+use plan::{Action, DatasetPlan, IncrementalStep, Mode, Plan};
+use retention::{RetentionRule, TargetSnapshot};
+use schedule::Schedule;
+use summary::{DatasetResult, JobResult, Outcome, SendRecord};
+use serde_json::Value;
+use std::sync::Arc;
 
 #[derive(Serialize, Deserialize)]
 struct Job 
 {
+	name: Option<String>,
 	sourcedataset: String,
+	// "<property>=<value>": replicate the datasets under sourcedataset with the property set to the value.
+	source_property: Option<String>,
 	prefix: Option<String>,
 	recursive: Option<bool>,
+	// patterns for the children of a recursive job to replicate, or not to.
+	include: Option<Vec<String>>,
+	exclude: Option<Vec<String>>,
+	parallel_children: Option<bool>,
 	canmount: Option<bool>,
 	inherit_encryption: Option<bool>,
+	resume_policy: Option<String>,
+	intermediates: Option<bool>,
+	schedule: Option<String>,
+	compression: Option<String>,
+	mbuffer: Option<String>,
+	rate_limit: Option<u64>,
+	target_retention: Option<Vec<RetentionRule>>,
+	// how far the target may fall behind the source before "piper check" warns, or
+	// reports it as critical.
+	warn_lag: Option<String>,
+	max_lag: Option<String>,
+	snapshot_before_send: Option<bool>,
+	pre_hook: Option<Hook>,
+	post_hook: Option<Hook>,
+	on_failure: Option<Hook>,
+	// how the datasets are named on the target. "<targetdataset>/<last part of sourcedataset>" if not given.
+	target_mapping: Option<TargetMapping>,
+	// either a targetdataset, or a list of targets to replicate to one after another.
+	targetdataset: Option<String>,
+	targets: Option<Vec<Target>>,
+}
+
+// one of the targets of a job, with the settings which can be different for each.
+// any not given are the job's.
+#[derive(Serialize, Deserialize, Clone)]
+struct Target
+{
 	targetdataset: String,
+	prefix: Option<String>,
+	intermediates: Option<bool>,
+	compression: Option<String>,
+	mbuffer: Option<String>,
+	rate_limit: Option<u64>,
 }
 #[derive(Serialize, Deserialize)]
 struct Piper
 {
+	lock_file: Option<String>,
+	job_lock_dir: Option<String>,
+	hosts: Option<Hosts>,
+	max_parallel: Option<usize>,
+	host_limits: Option<BTreeMap<String, usize>>,
+	metrics_file: Option<String>,
+	history_file: Option<String>,
+	notifiers: Option<Vec<Notifier>>,
+	notify_state_file: Option<String>,
 	jobs: Vec<Job>,
 }
 
-fn load_config(file_path: &Path) -> Piper
+// the settings of a job which are the same for every dataset replicated by it.
+struct JobOptions
+{
+	name: String,
+	recursive: bool,
+	children: ChildFilter,
+	parallel_children: bool,
+	canmount: bool,
+	inherit_encryption: bool,
+	prefix: String,
+	resume_policy: String,
+	intermediates: bool,
+	// receives don't use -F when the target has its own retention rules.
+	target_retention: Option<Vec<RetentionRule>>,
+	// the name on the target of each dataset replicated.
+	targets: TargetMap,
+	// the tag of the holds on the snapshots replicated to the target (see hold_tag).
+	hold_tag: String,
+	pipeline: PipelineOptions,
+	mode: Mode,
+	events: Arc<Events>,
+	send_no_op: bool,
+	recv_no_op: bool,
+}
+
+// how the jobs of a run are run, the same for all of them.
+struct Run<'a>
+{
+	mode: &'a Mode,
+	events: &'a Arc<Events>,
+	send_no_op: bool,
+	recv_no_op: bool,
+}
+
+// reads the config file. if it can't be read, the error is returned with the exit code for it.
+fn read_config(file_path: &Path) -> Result<Piper, (i32, String)>
 {
 	debug!("Json_file_path: \"{}\"", file_path.display());
 	let file = match File::open(file_path) 
 	{
-		Err(error) => {error!("Could not open file \"{}\"\n{}", file_path.display(),error);process::exit(1)},
+		Err(error) => return Err((1, format!("Could not open file \"{}\"\n{}", file_path.display(),error))),
 		Ok(file) => file,
 	};
-	let piper= match serde_json::from_reader(file)
+	let piper:Piper = match serde_json::from_reader(file)
 	{
-		Err(error) => {error!("Error reading file \"{}\"\n{}",file_path.display(), error);process::exit(3)},
+		Err(error) => return Err((3, format!("Error reading file \"{}\"\n{}",file_path.display(), error))),
 		Ok(piper) => piper,
 	};
-	return piper;
+	if let Some(notifiers) = &piper.notifiers
+	{
+		if let Err(e) = notify::validate(notifiers)
+		{
+			return Err((3, format!("Error in file \"{}\"\n{}", file_path.display(), e)));
+		}
+	}
+	for j in piper.jobs.iter()
+	{
+		if let Err(e) = job_targets(j)
+		{
+			return Err((3, format!("Error in file \"{}\"\n{}", file_path.display(), e)));
+		}
+	}
+	Ok(piper)
+}
+
+fn load_config(file_path: &Path) -> Piper
+{
+	match read_config(file_path)
+	{
+		Err((code, message)) => {error!("{}", message);process::exit(code)},
+		Ok(piper) => piper,
+	}
 }
 
 fn walk_json(piper: &Piper)
 {
+	match &piper.lock_file
+	{
+		None=> println!("Lock_File:\"/var/run/piper.lock (default)\""),
+		Some(s)=> println!("Lock_File:\"{}\"", s),
+	}
+	match &piper.job_lock_dir
+	{
+		None=> println!("Job_Lock_Dir:\"NONE (default)\""),
+		Some(s)=> println!("Job_Lock_Dir:\"{}\"", s),
+	}
+	if let Some(hosts) = &piper.hosts
+	{
+		for (name, host) in hosts.iter()
+		{
+			println!("Host:\"{}\"", name);
+			match &host.hostname
+			{
+				None=> println!("\tHostname:\"{} (default)\"", name),
+				Some(s)=> println!("\tHostname:\"{}\"", s),
+			}
+			match &host.user
+			{
+				None=> println!("\tUser:\"NONE (ssh default)\""),
+				Some(s)=> println!("\tUser:\"{}\"", s),
+			}
+			match &host.port
+			{
+				None=> println!("\tPort:\"NONE (ssh default)\""),
+				Some(s)=> println!("\tPort:\"{}\"", s),
+			}
+			match &host.identity_file
+			{
+				None=> println!("\tIdentity_File:\"NONE (ssh default)\""),
+				Some(s)=> println!("\tIdentity_File:\"{}\"", s),
+			}
+			match &host.multiplex
+			{
+				None=> println!("\tMultiplex:\"FALSE (default)\""),
+				Some(s)=> println!("\tMultiplex:\"{}\"", if *s {"TRUE"}else{"FALSE"}),
+			}
+			println!("\tSsh command:\"ssh {}\"", host.ssh_args().join(" "));
+		}
+	}
+	match &piper.max_parallel
+	{
+		None=> println!("Max_Parallel:\"1 (default)\""),
+		Some(n)=> println!("Max_Parallel:\"{}\"", n),
+	}
+	if let Some(host_limits) = &piper.host_limits
+	{
+		for (host, limit) in host_limits.iter()
+		{
+			println!("Host_Limit:\"{}\" \"{}\"", host, limit);
+		}
+	}
+	match &piper.metrics_file
+	{
+		None=> println!("Metrics_File:\"NONE (default)\""),
+		Some(s)=> println!("Metrics_File:\"{}\"", s),
+	}
+	match &piper.history_file
+	{
+		None=> println!("History_File:\"/var/lib/piper/history.jsonl (default)\""),
+		Some(s)=> println!("History_File:\"{}\"", s),
+	}
+	match &piper.notify_state_file
+	{
+		None=> println!("Notify_State_File:\"/var/lib/piper/notify.json (default)\""),
+		Some(s)=> println!("Notify_State_File:\"{}\"", s),
+	}
+	for notifier in piper.notifiers.iter().flatten()
+	{
+		println!("Notifier:");
+		match (&notifier.command, &notifier.webhook, &notifier.sendmail)
+		{
+			(Some(s), _, _)=> println!("\tCommand:\"{}\"", s),
+			(_, Some(s), _)=> println!("\tWebhook:\"{}\"", s),
+			(_, _, Some(s))=> println!("\tSendmail:\"{}\" with \"{}\"", s, notifier.sendmail_path.as_deref().unwrap_or("/usr/sbin/sendmail")),
+			_=> {},
+		}
+		match &notifier.severity
+		{
+			None=> println!("\tSeverity:\"failure, recovery (default)\""),
+			Some(s)=> println!("\tSeverity:\"{}\"", s.join(", ")),
+		}
+		match &notifier.jobs
+		{
+			None=> println!("\tJobs:\"ALL (default)\""),
+			Some(s)=> println!("\tJobs:\"{}\"", s.join("\", \"")),
+		}
+	}
 	for j in &piper.jobs
 	{
-		println!("Job:");
+		println!("Job: \"{}\"", job_name(j));
 		println!("\tSource Dataset:\"{}\"", j.sourcedataset);
+		match &j.source_property
+		{
+			None=> println!("\tSource_Property:\"NONE (default)\""),
+			Some(s)=> println!("\tSource_Property:\"{}\"", s),
+		}
 		match &j.prefix
 		{
 			None=>println!("\t\tPrefix: NO-PREFIX"),
@@ -55,6 +301,11 @@ fn walk_json(piper: &Piper)
 			None=> println!("\tRecursive:\"FALSE (default)\""),
 			Some(s)=> println!("\tRecursive:\"{}\"", *s),
 		}
+		match &j.parallel_children
+		{
+			None=> println!("\tParallel_Children:\"FALSE (default)\""),
+			Some(s)=> println!("\tParallel_Children:\"{}\"", if *s {"TRUE"}else{"FALSE"}),
+		}
 		match &j.canmount
 		{
 			None=> println!("\tCanmount:\"OFF (default)\""),
@@ -65,7 +316,172 @@ fn walk_json(piper: &Piper)
 			None=> println!("\tInherited_Encryption:\"TRUE (default)\""),
 			Some(s)=> println!("\tInherited_Encryption:\"{}\"", if *s {"TRUE"}else{"FALSE"}),
 		}
-		println!("\tTarget Dataset:\"{}\"", j.targetdataset);
+		match &j.resume_policy
+		{
+			None=> println!("\tResume_Policy:\"resume (default)\""),
+			Some(s)=> println!("\tResume_Policy:\"{}\"", s),
+		}
+		match &j.intermediates
+		{
+			None=> println!("\tIntermediates:\"FALSE (default)\""),
+			Some(s)=> println!("\tIntermediates:\"{}\"", if *s {"TRUE"}else{"FALSE"}),
+		}
+		match &j.compression
+		{
+			None=> println!("\tCompression:\"NONE (default)\""),
+			Some(s)=> println!("\tCompression:\"{}\"", s),
+		}
+		match &j.mbuffer
+		{
+			None=> println!("\tMbuffer:\"NONE (default)\""),
+			Some(s)=> println!("\tMbuffer:\"{}\"", s),
+		}
+		match &j.rate_limit
+		{
+			None=> println!("\tRate_Limit:\"NONE (default)\""),
+			Some(s)=> println!("\tRate_Limit:\"{} bytes/second\"", s),
+		}
+		match &j.target_retention
+		{
+			None=> println!("\tTarget_Retention:\"NONE (the receive uses -F) (default)\""),
+			Some(rules)=>
+				{
+					println!("\tTarget_Retention:");
+					for rule in rules.iter()
+					{
+						println!("\t\tkeep {}{}{}", rule.keep,
+							match &rule.period {None=>String::from(""), Some(s)=>format!(" {}", s)},
+							match &rule.prefix {None=>String::from(""), Some(s)=>format!(" with prefix \"{}\"", s)});
+					}
+				},
+		}
+		for (label, patterns) in [("Include", &j.include), ("Exclude", &j.exclude)]
+		{
+			match patterns
+			{
+				None=> println!("\t{}:\"NONE (default)\"", label),
+				Some(patterns)=> println!("\t{}:\"{}\"", label, patterns.join("\", \"")),
+			}
+		}
+		match &j.snapshot_before_send
+		{
+			None=> println!("\tSnapshot_Before_Send:\"FALSE (default)\""),
+			Some(s)=> println!("\tSnapshot_Before_Send:\"{}\"", if *s {"TRUE"}else{"FALSE"}),
+		}
+		match &j.schedule
+		{
+			None=> println!("\tSchedule:\"NONE (not run by --daemon)\""),
+			Some(s)=> match Schedule::parse(s)
+				{
+					Err(e)=>println!("\tSchedule:\"{}\" INVALID: {}", s, e),
+					Ok(_)=>println!("\tSchedule:\"{}\"", s),
+				},
+		}
+		for (label, lag) in [("Warn_Lag", &j.warn_lag), ("Max_Lag", &j.max_lag)]
+		{
+			match lag
+			{
+				None=> println!("\t{}:\"NONE (not checked)\"", label),
+				Some(s)=> match parse_duration(s)
+					{
+						Err(e)=>println!("\t{}:\"{}\" INVALID: {}", label, s, e),
+						Ok(_)=>println!("\t{}:\"{}\"", label, s),
+					},
+			}
+		}
+		for (label, hook) in [("Pre_Hook", &j.pre_hook), ("Post_Hook", &j.post_hook), ("On_Failure", &j.on_failure)]
+		{
+			match hook
+			{
+				None=> println!("\t{}:\"NONE (default)\"", label),
+				Some(hook)=> println!("\t{}:\"{}\" on \"{}\" with timeout \"{}\"", label, hook.command, hook.host.as_deref().unwrap_or("local"), hook.timeout.as_deref().unwrap_or("1h")),
+			}
+		}
+		if let Some(s) = &j.targetdataset
+		{
+			println!("\tTarget Dataset:\"{}\"", s);
+			println!("\tHold_Tag:\"{}\"", hold_tag(j, s.as_str()));
+		}
+		for t in j.targets.iter().flatten()
+		{
+			println!("\tTarget:\"{}\"", t.targetdataset);
+			println!("\t\tHold_Tag:\"{}\"", hold_tag(j, t.targetdataset.as_str()));
+			match &t.prefix
+			{
+				None=> println!("\t\tPrefix:\"the job's (default)\""),
+				Some(s)=> println!("\t\tPrefix:\"{}\"", s),
+			}
+			match &t.intermediates
+			{
+				None=> println!("\t\tIntermediates:\"the job's (default)\""),
+				Some(s)=> println!("\t\tIntermediates:\"{}\"", if *s {"TRUE"}else{"FALSE"}),
+			}
+			match &t.compression
+			{
+				None=> println!("\t\tCompression:\"the job's (default)\""),
+				Some(s)=> println!("\t\tCompression:\"{}\"", s),
+			}
+			match &t.mbuffer
+			{
+				None=> println!("\t\tMbuffer:\"the job's (default)\""),
+				Some(s)=> println!("\t\tMbuffer:\"{}\"", s),
+			}
+			match &t.rate_limit
+			{
+				None=> println!("\t\tRate_Limit:\"the job's (default)\""),
+				Some(s)=> println!("\t\tRate_Limit:\"{} bytes/second\"", s),
+			}
+		}
+		match &j.target_mapping
+		{
+			None=> println!("\tTarget_Mapping:\"<targetdataset>/<last part of sourcedataset> (default)\""),
+			Some(m)=> println!("\tTarget_Mapping: full_path:\"{}\" strip_prefix:\"{}\" rename:\"{}\" prepend_host:\"{}\"",
+				if m.full_path == Some(true) || m.strip_prefix.is_some() {"TRUE"} else {"FALSE"}, m.strip_prefix.as_deref().unwrap_or(""),
+				m.rename.as_deref().unwrap_or(""), if m.prepend_host == Some(true) {"TRUE"} else {"FALSE"}),
+		}
+	}
+}
+
+// the name a job is reported under. defaults to "<sourcedataset> -> <targetdataset>",
+// with the targets separated by commas if there are several.
+fn job_name(j:&Job) -> String
+{
+	match &j.name
+	{
+		None=>format!("{} -> {}", j.sourcedataset, target_names(j).join(", ")),
+		Some(s)=>s.clone(),
+	}
+}
+
+// the targetdataset of each of the job's targets, as given in the config file.
+fn target_names(j:&Job) -> Vec<&str>
+{
+	j.targetdataset.iter().map(|s| s.as_str()).chain(j.targets.iter().flatten().map(|t| t.targetdataset.as_str())).collect()
+}
+
+// the job's targets, with the job's settings filled in where they don't have their own.
+// a job with a targetdataset has just the one.
+fn job_targets(j:&Job) -> Result<Vec<Target>, String>
+{
+	match (&j.targetdataset, &j.targets)
+	{
+		(Some(targetdataset), None)=>Ok(vec![Target{
+				targetdataset: targetdataset.clone(),
+				prefix: j.prefix.clone(),
+				intermediates: j.intermediates,
+				compression: j.compression.clone(),
+				mbuffer: j.mbuffer.clone(),
+				rate_limit: j.rate_limit,
+			}]),
+		(None, Some(targets)) if !targets.is_empty()=>Ok(targets.iter().map(|t| Target{
+				targetdataset: t.targetdataset.clone(),
+				prefix: t.prefix.clone().or(j.prefix.clone()),
+				intermediates: t.intermediates.or(j.intermediates),
+				compression: t.compression.clone().or(j.compression.clone()),
+				mbuffer: t.mbuffer.clone().or(j.mbuffer.clone()),
+				rate_limit: t.rate_limit.or(j.rate_limit),
+			}).collect()),
+		_=>Err(format!("job \"{}\" must have either a \"targetdataset\" or a list of \"targets\"", job_name(j))),
 	}
 }
 
@@ -73,13 +489,31 @@ fn usage()
 {
 	printwrap::print_wrap(5,0,"Usage:");
 	printwrap::print_wrap(5,0,"    piper [options]");
+	printwrap::print_wrap(5,0,"    piper [options] plan [--json] [-o <plan file>]");
+	printwrap::print_wrap(5,0,"    piper [options] apply <plan file>");
+	printwrap::print_wrap(5,0,"    piper [options] check");
+	printwrap::print_wrap(5,0,"    piper [options] history|stats [--job <name>] [--since <time>]");
 	printwrap::print_wrap(5,0,"Options:");
 	printwrap::print_wrap(5,24,"    -f <config file>    Load the specified JSON config file.");
 	printwrap::print_wrap(5,24,"                        Default: /usr/local/etc/znappr/piper.json");
 	//printwrap::print_wrap(0,24,"    -s | --stdout       Log messages to stdout rather than syslog.");
 	printwrap::print_wrap(5,24,"    -c | --configtest   Validate the config json file then exit.");
+	printwrap::print_wrap(5,24,"    --daemon            Keep running, and run each job whenever its \"schedule\" says it is due instead of running every job once. SIGHUP reloads the config file (for the jobs started after it) and SIGTERM stops piper once any running jobs finish. With --report, the report of each job is written when it finishes (and appended to a --report-file).");
+	printwrap::print_wrap(5,24,"    --events <file>     Append a json object to the specified file (\"-\" for stdout) for each event of the run as it happens: job_started, snapshot_created, send_started, recv_line, send_finished, hold_placed, hold_released and job_finished.");
 	printwrap::print_wrap(5,24,"    -h | --help         Print this usage information and exit.");
+	printwrap::print_wrap(5,24,"    plan                Work out what would be done to each dataset (full, incremental, resume, up to date, blocked or diverged, with the estimated size from \"zfs send -nP\") without doing any of it, and print the plan.");
+	printwrap::print_wrap(5,24,"    --json              Print the plan as json.");
+	printwrap::print_wrap(5,24,"    -o <plan file>      Save the plan as json to the specified file as well.");
+	printwrap::print_wrap(5,24,"    apply <plan file>   Carry out a saved plan. If anything has changed since the plan was made, nothing is done and piper exits with code 7.");
+	printwrap::print_wrap(5,24,"    check               For each job with a \"max_lag\" (and optionally a \"warn_lag\"), such as '\"max_lag\":\"26h\",', compare the creation time of the newest snapshot on the source with that of the newest on the target, print the result in the form nagios expects and exit with the nagios code of the worst job: 0 (OK), 1 (WARNING, more than warn_lag behind), 2 (CRITICAL, more than max_lag behind or never replicated) or 3 (UNKNOWN, a job couldn't be checked).");
+	printwrap::print_wrap(5,24,"    history             List every send recorded in the history file (\"/var/lib/piper/history.jsonl\" by default, or set '\"history_file\":\"<path>\",' in the config file), oldest first, then exit.");
+	printwrap::print_wrap(5,24,"    stats               Sum up the history for each source and target: sends, failures, bytes sent, the last success and the average, newest and trend of the incremental sends, then exit.");
+	printwrap::print_wrap(5,24,"    --job <name>        Only show the history of the named job.");
+	printwrap::print_wrap(5,24,"    --since <time>      Only show the history since the time, a duration before now (\"7d\"), a date (\"2024-06-01\") or an rfc3339 time.");
 	printwrap::print_wrap(5,24,"    -n | -nn            Do a No-Operation dry-run. Performs all actions, except no actual replication will occur. If the \"-n\" option is specified, the zfs send action will include the \"-n\" option and no data will be sent. If the \"-nn\" option is specified, data *will* be sent but the zfs receive action will include the \"-n\" option and no data will be written.");
+	printwrap::print_wrap(5,24,"    --simulate <file>   Run against simulated pools loaded from the specified JSON file instead of running zfs and ssh. The simulated pools are written back to the file afterwards, so consecutive runs build on each other. Intended for testing configurations and piper itself without any real pools. Notifications are logged rather than sent.");
+	printwrap::print_wrap(5,24,"    --report json       At the end of the run, write a json object for each dataset to stdout, one per line, with the source, target, snapshots sent from and to, mode (full, incremental, resumed or up to date), bytes sent, duration, result and error class.");
+	printwrap::print_wrap(5,24,"    --report-file <file> Write the report to the specified file rather than stdout.");
 	printwrap::print_wrap(5,24,"    -p                  Print a generic configuration file. This file will not be tailored to this computer, but will serve as a starting point to customizing your own configuration file.");
 	printwrap::print_wrap(5,24,"    -v | -vv            Increase the level of messaging by one or two levels (the maximum).");
 	printwrap::print_wrap(5,0,"");
@@ -91,415 +525,900 @@ fn usage()
 	printwrap::print_wrap(5,0,"");
 	printwrap::print_wrap(5,0,"For all actions on remote hosts, the only transport supported is ssh. Keys must have already been created on the local system for the user piper will run as, and copied to the target hosts or the ssh connections will fail and no replication will take place. Remote ssh connections will only be as the same user piper is running as locally. Ssh is used for \"zfs receive\" as well as \"zfs list\" for querying the status of target datasets and past replciation.");
 	printwrap::print_wrap(5,0,"");
+	printwrap::print_wrap(5,0,"How to ssh to a remote host can be set in the optional \"hosts\" section of the config file rather than in ~/.ssh/config. Each entry is named after the host (the name used before the \":\" in sourcedataset/targetdataset) and can have a \"hostname\" (the host to actually connect to, if not the name of the entry), \"user\", \"port\", \"identity_file\" (only this key will be offered), \"ssh_options\" (a list of options passed to ssh with \"-o\", such as \"Ciphers=aes128-gcm@openssh.com\"), \"multiplex\" (true to share one ssh connection between all the commands run on the host, using ssh's ControlMaster) and \"control_persist\" (how long a shared connection stays open after its last command, \"60s\" by default). A host given by its hostname rather than the name of its entry uses the entry too. When running with --daemon, changes to the hosts section take effect on SIGHUP, like the rest of the config file.");
+	printwrap::print_wrap(5,0,"");
+	printwrap::print_wrap(5,0,"By default a job's sourcedataset is received into its targetdataset under the last part of its name, so \"tank/home\" replicated to \"backup\" is \"backup/home\", and its children (with \"recursive\") are under that. A \"target_mapping\" in the job, such as '\"target_mapping\": { \"full_path\": true, \"prepend_host\": true },', names the target otherwise (\"backup/server1/tank/home\" for \"server1:tank/home\"). \"full_path\" keeps the whole path of the sourcedataset, \"strip_prefix\" keeps the path below the prefix given (and implies \"full_path\"), \"rename\" gives the sourcedataset another name in place of the last part of its own, and \"prepend_host\" puts the name of the source host first (the name used before the \":\" in sourcedataset, or the local host's short hostname). Children always keep their path below the sourcedataset. Datasets above the target which don't exist yet are created, with canmount=off, before the first full replication.");
+	printwrap::print_wrap(5,0,"");
+	printwrap::print_wrap(5,0,"A job can replicate every dataset which matches its sourcedataset rather than just the one it names, so that a new dataset is picked up without editing the configuration. A sourcedataset with a wildcard, such as \"tank/vms/*\", is a glob (\"*\" matches anything but \"/\", \"**\" anything at all and \"?\" one character but \"/\") matched against the datasets on the source host when the job is run. Adding '\"source_property\":\"piper:replicate=offsite\",' replicates the datasets at or under the sourcedataset (which can be a glob too) whose user property piper:replicate is \"offsite\". For a recursive job, the datasets under one which matches are replicated as its children, not on their own. Each dataset matched is replicated with the job's settings into the job's targetdataset, named as its target_mapping says (two of them can't be replicated into the same place), and the summary lists the datasets each job matched. The datasets are replicated one after another, or all at once with \"parallel_children\". Hooks are run once for the job, with all of the datasets matched in PIPER_SOURCE_DATASET (and their targets in PIPER_TARGET_DATASET), separated by spaces, and \"piper check\" checks each of them on its own.");
+	printwrap::print_wrap(5,0,"");
+	printwrap::print_wrap(5,0,"A job can replicate to several targets, such as a backup server on the LAN and an offsite host, with a list of \"targets\" in place of its targetdataset, such as '\"targets\": [ { \"targetdataset\": \"backup:tank/backups\" }, { \"targetdataset\": \"offsite:pool/backups\", \"prefix\": \"DAILY\", \"compression\": \"zstd\" } ],'. Each target can have its own \"prefix\", \"intermediates\", \"compression\", \"mbuffer\" and \"rate_limit\", and otherwise uses the job's. The targets are replicated to one after another, and the source is only looked at once for all of them. Each target keeps its own incremental base, bookmark and holds, so a target which falls behind keeps its base however far ahead the others get. \"snapshot_before_send\" makes one snapshot for all of the targets which share a prefix, named with that prefix, so a job whose targets use two prefixes makes two. A hook on the \"target\" host is run on each target in turn, with just that target in PIPER_TARGET_HOST and PIPER_TARGET_DATASET. Other hooks are run once, with PIPER_TARGET_HOST empty and each target as \"<host>:<dataset>\" in PIPER_TARGET_DATASET. \"piper check\" checks each target on its own.");
+	printwrap::print_wrap(5,0,"");
 	printwrap::print_wrap(5,0,"Some assumptions, defaults, and considerations when using piper:");
 	printwrap::print_wrap(5,8,"  - Though the configuration file and this documentation refers to datasets, piper will replicate zvols as well if you specify them directly in the sourcedataset/targetdataset configuration fields, or if they exist as children included in a recursive replication.");
 	printwrap::print_wrap(5,8,"  - Replication will always include the \"-R\" and \"-s\" zfs send options. This will include all properties of the dataset. Acutal recursive replication will be handled separately within piper.");
+	printwrap::print_wrap(5,8,"  - A recursive job replicates every child of the sourcedataset on its own. Adding '\"exclude\":[\"**/tmp\", \"tank/docker/*\"],' to a job leaves out the children whose names match any of the patterns, and adding an \"include\" list replicates only the children which match one of its patterns (or are under one which does). The children above a dataset an include pattern names (such as \"tank/data/a\" for \"tank/data/a/b/*\") aren't replicated unless they match one themselves, but piper still looks through them for the datasets which do. A pattern is a glob, in which \"*\" matches anything but \"/\", \"**\" matches anything and \"?\" matches one character but \"/\", or, if it starts with \"re:\", a regular expression, such as \"re:/(cache|tmp)$\", which matches if it is found anywhere in the name. A child can also be left out on the source by setting the user property piper:skip to on (\"zfs set piper:skip=on tank/data/cache\"), which as it is inherited leaves out everything under it too. This works on the sourcedataset itself, and on the datasets a wildcard sourcedataset or a source_property matches, as well. Children which are left out, and everything under them, are still excluded (with \"-X\") from their parent's send, so they aren't replicated at all.");
 	printwrap::print_wrap(5,8,"  - Piper handles encrypted datasets in one of three ways:");
 	printwrap::print_wrap(5,10,"        * If the source dataset is encrypted, the \"-w\" (raw) option will be used and the destination will retain the original encryption type and key settings.");
 	printwrap::print_wrap(5,10,"        * Unencrypted source datasets will inherit the encryption options of the containing dataset on the target, and thus become encrypted if the target is encrypted unless...");
 	printwrap::print_wrap(5,10,"        * If the optional configuration setting \"inherit_encryption\":false is set, encryption settings will not be inherited (as by default they would have), and the replicated dataset will remain unencrypted as it was on the source.");
 	printwrap::print_wrap(5,8,"  - By default, canmount will be set to off (\"-o canmount=off\") on zfs recv for all replications. This can be overridden by adding '\"canmount\":true,' to the job in the config file. This will set \"-o canmount=on\". Piper does not provide an option to set \"canmount=noauto\".");
 	printwrap::print_wrap(5,8,"  - The zfs receive will include \"-F\" (force rollback/purge).");
-	printwrap::print_wrap(5,8,"  - Piper does not create snapshots, but at least one snapshot must exist in order to replicate a dataset. At least a second must exist in the source dataset and the first in both the source and destination datasets to perform an incremental replication. Piper will inspect the source and destination datasets to determine which snapshots to be used by using zfs list and sorting by the createtxg property. Either or both the sourcedataset and targetdataset can be remote. This is indicated by prepending the \"<hostname>:\" to the sourcedataset or targetdataset in the configuration.");
-	printwrap::print_wrap(5,8,"  - Piper does not care where these snapshots came from, but if the last snapshot used for replication is destroyed, further replication attempts will fail as incremential replication is always between a current snapshot the previous snapshot used. If that snapshot doesn't exist, it can't be used as a base for further replication.");
-	printwrap::print_wrap(5,8,"  - Piper does not destroy snapshots on the source, either, but the \"-F\" option on zfs receive does have the side effect/benefit of purging snapshots on the destination that no-longer exist on the source.");
+	printwrap::print_wrap(5,8,"  - The zfs receive will include \"-s\" so that an interrupted replication can be resumed. Before planning each dataset, piper checks the target for a \"receive_resume_token\". If one is found, piper resumes the interrupted transfer with \"zfs send -t <token>\" and then continues as normal. If the token is stale (the snapshot it refers to no longer exists on the source), the partial state is discarded with \"zfs recv -A\" and replication is planned from scratch. Adding '\"resume_policy\":\"abort\",' to the job in the config file will always discard partial state rather than resume it.");
+	printwrap::print_wrap(5,8,"  - By default each incremental replication sends only the newest snapshot, so snapshots taken on the source between runs are not kept on the target. Adding '\"intermediates\":true,' to the job in the config file sends them all with \"zfs send -I\". With a \"prefix\" set, only the snapshots in between which match the prefix are sent, one after another with \"zfs send -i\".");
+	printwrap::print_wrap(5,8,"  - By default the receive uses \"-F\", so snapshots destroyed on the source are destroyed on the target as well. Adding a \"target_retention\" list of rules to the job in the config file, such as '\"target_retention\":[{\"period\":\"hourly\",\"keep\":48},{\"period\":\"daily\",\"keep\":30},{\"period\":\"monthly\",\"keep\":12}],', receives without \"-F\" and instead destroys the snapshots on the target which none of the rules keep after each replication. Each rule keeps the newest \"keep\" snapshots whose names start with its \"prefix\" (all snapshots if it has none), or with a \"period\" (hourly, daily, weekly, monthly or yearly) the newest snapshot of each of the newest \"keep\" periods. Snapshots which no rule matches, held snapshots and the newest snapshot (the base for the next incremental replication) are never destroyed.");
+	printwrap::print_wrap(5,8,"  - Piper does not create snapshots by default, but at least one snapshot must exist in order to replicate a dataset. At least a second must exist in the source dataset and the first in both the source and destination datasets to perform an incremental replication. Piper will inspect the source and destination datasets to determine which snapshots to be used by using zfs list and sorting by the createtxg property. The base of an incremental replication is the newest snapshot which exists on both the source and the target. Snapshots are matched by their guid rather than their name, so a snapshot renamed on either side is still recognised. Snapshots which only exist on the target (newer than the common snapshot) will be rolled back by the receive. If there is no common snapshot, piper reports why and does not replicate the dataset. Either or both the sourcedataset and targetdataset can be remote. This is indicated by prepending the \"<hostname>:\" to the sourcedataset or targetdataset in the configuration. Adding '\"snapshot_before_send\":true,' to a job makes piper create a snapshot of the source dataset (recursively if the job is recursive) named \"<prefix><timestamp>\", such as \"HOURLY__2024-06-01_00.05.00\", just before it is replicated (after any pre_hook), so that the replication always sends a point-in-time snapshot which matches the prefix. A plan being applied is carried out as it was made, without a new snapshot.");
+	printwrap::print_wrap(5,8,"  - Piper does not care where these snapshots came from, but if the last snapshot used for replication is destroyed, further replication attempts will fail as incremential replication is always between a current snapshot the previous snapshot used. If that snapshot doesn't exist, it can't be used as a base for further replication. To stop this, piper will place a hold on the most recently used snapshots on both the source and destination. This will cause \"zfs destroy\" to fail when attempting to delete the snapshot. When the snapshot is no longer the most recently used, the hold will be released, but only once the new snapshot is held on both the source and the destination (a replication which fails doesn't change any holds). Each job holds its snapshots with its own tag for each target, \"piper:<job name>:<targetdataset>\" (or \"piper:<sourcedataset>:<targetdataset>\" for a job without a name, as \"piper -c\" shows), so jobs replicating the same source to different targets, or replicating a target on to somewhere else, don't release each other's holds. Only the job's own tag is released from the previous snapshot. Older versions of piper held every snapshot with the tag \"piper\". Such a hold on the previous snapshot is released along with the job's own, once the new snapshot is held with the job's tag, so that the job's tag takes over from it. A job whose name or targetdataset is changed gets a new tag, and the holds with the old one have to be released by hand (\"zfs release <tag> <snapshot>\"). After each successful replication piper also creates a bookmark of the replicated snapshot on the source, named \"<dataset>#piper_<target>\", where <target> is the parent of the targetdataset, after its host and a \":\" if it has one, with every character in them but letters, digits, \".\" and \"-\" written as \"_\", its code in hex and \"_\" again (so \"backup:tank/backups\" becomes \"backup:tank_2f_backups\"), so that no two targets share a bookmark. A replication whose snapshot can't be bookmarked fails with the error class \"command_failure\", although the snapshot was replicated, as the target would have nothing to fall back on. Bookmarks made by older versions of piper, with \"_\" in place of those characters, are still used as a base but are no longer moved, and can be destroyed by hand. If the snapshot is destroyed regardless, the bookmark is used as the base of the next incremental replication (\"zfs send -i <dataset>#piper_<target>\"), so snapshot pruning tools on the source can not break the replication chain. Incremental replications from a bookmark send the dataset with \"-p\" rather than \"-R\".");
+	printwrap::print_wrap(5,8,"  - Piper does not destroy snapshots on the source, either, but the \"-F\" option on zfs receive does have the side effect/benefit of purging snapshots on the destination that no-longer exist on the source (unless the job has a \"target_retention\", see above).");
 	printwrap::print_wrap(5,8,"  - Piper by default will replicate the first snapshot found for a given dataset. Sometimes this may not be desired. If one makes snapshots every 5 minutes *and* every hour, but purge the 5-minute snapshots after 2 hours, an initial replication at midnight may replicate the most recent 5-minute snapshot. However, an incremental replication the following night will attempt to perform an incremental between the current most recent 5-minute snapshot and the 5-minute snapshot from the previous night ... which would have been purged. This replication will fail. To avoid this, an optional field labeled \"prefix\" can be included in the configuration file. Piper will *only* replicate snapshots with this string at the beginning of the snapshot tag. For example, a configuration file with the line:");
 	printwrap::print_wrap(5,8,"              \"prefix\" : \"HOURLY__\",");
 	printwrap::print_wrap(5,8,"        for the replication job will only replicate snapshots which begin with \"HOURLY__\", and ignore all others. If no other snapshots exist, replication will not happen.");
 	printwrap::print_wrap(5,8,"");
-	printwrap::print_wrap(5,0,"All piper logging is to stdout.");
+	printwrap::print_wrap(5,8,"  - By default zfs send is piped straight into zfs recv (over ssh for remote hosts). A job can add stages between them: '\"compression\":\"zstd\",' (or \"lz4\") compresses the stream on the sending host and decompresses it on the receiving host, when they are different hosts; '\"rate_limit\":n,' limits the stream to n bytes per second on the sending host (using pv); and '\"mbuffer\":\"1G\",' buffers the stream in an mbuffer of that size on the receiving host. The programs used must be installed on the hosts they run on. Consecutive stages on the same remote host are run by a single ssh. The exit status and errors of each stage are checked and reported separately, and a failed stage other than zfs send or zfs recv is reported with the error class \"pipeline_failure\".");
+	printwrap::print_wrap(5,8,"  - By default jobs are run one at a time. Adding '\"max_parallel\":n,' to the top of the config file lets up to n datasets be replicated at once, from any of the jobs. The number of replications to or from any one host can be limited further with '\"host_limits\":{\"<hostname>\":n},' (the local host is \"localhost\"), so that a slow link to one host doesn't hold back replications to the others. Child datasets of a recursive job are replicated one after another unless '\"parallel_children\":true,' is added to the job, in which case they are replicated concurrently (within the same limits) once their parent has been replicated.");
+	printwrap::print_wrap(5,8,"  - Only one piper runs at a time. Piper takes a lock file (\"/var/run/piper.lock\" by default, or set '\"lock_file\":\"<path>\",' in the config file, or '\"lock_file\":\"\",' for none) for the whole run, and exits with code 6 if another piper holds it. The lock file holds the pid of the piper which has it, and is locked (with flock) for as long as that piper runs, so a lock file left behind by a piper which was killed is noticed and taken over. Adding '\"job_lock_dir\":\"<directory>\",' to the config file also takes a lock file in that directory for each job, named after the dataset the job replicates into, so that pipers run with different config files (and lock files) can run at the same time without replicating into the same dataset at once (jobs in the same run wait for each other instead). A job which can't take its lock fails with the error class \"locked\".");
+	printwrap::print_wrap(5,8,"  - A job can have a \"pre_hook\" (run before it replicates anything, once its lock is taken and its hosts can be reached), a \"post_hook\" (run once it has replicated everything successfully) and an \"on_failure\" hook, each such as '{\"command\":\"<command>\",\"host\":\"source\",\"timeout\":\"5m\"},'. The command is run with sh -c on the \"local\" (the default), \"source\" or \"target\" host, under timeout (from coreutils, which has to be installed there), which stops it, and anything it started, if it runs for longer than the timeout (1h by default). A failed pre_hook skips the job, and a failed post_hook fails it, with the error class \"hook_failure\". Hooks get the environment variables PIPER_JOB, PIPER_HOOK, PIPER_SOURCE_HOST, PIPER_SOURCE_DATASET, PIPER_TARGET_HOST and PIPER_TARGET_DATASET, and the post_hook and on_failure hooks also PIPER_RESULT, PIPER_ERROR_CLASS, PIPER_ERROR, PIPER_FROM_SNAPSHOT, PIPER_SNAPSHOT, PIPER_BYTES, PIPER_DATASETS and PIPER_FAILED_DATASETS. Hooks aren't run by \"piper plan\".");
+	printwrap::print_wrap(5,8,"  - A \"notifiers\" list at the top of the config file is told when a job fails, and when a job which failed the last time it ran succeeds again (but not when a job keeps succeeding, or keeps failing). Each notifier is one of '{\"command\":\"<command>\"}' (run with sh -c, with the notification as json on its stdin), '{\"webhook\":\"http://<host>[:<port>]/<path>\"}' (the json is POSTed to it, plain http only) or '{\"sendmail\":\"<address>\"}' (an email is piped to /usr/sbin/sendmail -t, or the \"sendmail_path\" given), and can be limited with '\"severity\":[\"failure\"]' or '[\"recovery\"]' and '\"jobs\":[\"<name>\"]'. Whether each job failed last time is kept in \"/var/lib/piper/notify.json\" (or set '\"notify_state_file\":\"<path>\",').");
+	printwrap::print_wrap(5,8,"  - Adding '\"metrics_file\":\"<path>.prom\",' to the top of the config file writes the file in the prometheus text format after each run, for node_exporter's textfile collector. For each source and target it holds piper_last_success_timestamp_seconds, piper_lag_seconds (how much older the newest snapshot on the target is than the newest on the source), piper_last_result (1 or 0), piper_last_bytes_sent, piper_last_duration_seconds and the counters piper_sent_bytes_total and piper_failures_total (by error class), along with piper_last_run_timestamp_seconds. Datasets a run didn't look at are carried forward from the file as it was, and the file is written to \"<path>.tmp\" and renamed into place.");
+	printwrap::print_wrap(5,8,"  - Each job can be given a \"name\" in the config file, which is used when reporting on the job. If no name is given the job is named \"<sourcedataset> -> <targetdataset>\" (with its targets separated by commas if it has several).");
+	printwrap::print_wrap(5,8,"");
+	printwrap::print_wrap(5,0,"All piper logging is to stdout. At the end of each run piper logs a summary with the result of each job, and the class of error (ssh_failure, dataset_missing, no_snapshots, diverged_target, send_failure, recv_failure, pipeline_failure, command_failure, hook_failure, locked, drifted or config) for each dataset which failed. Each line logged for a job starts with the name of the job in square brackets, so that the output of jobs running at the same time can be told apart.");
 	printwrap::print_wrap(5,0,"");
-	printwrap::print_wrap(5,0,"Piper is intended to be run via cron. When running from cron, the frequency piper is run should correspond to the most frequent snapshots for each dataset to be replicated. Run piper a few minutes after the snapshots are scheduled. If you make daily snapshots there is no need to run piper more frequently. It won't hurt, but isn't necessary. A typical cron line for daily replication might look like this:");
+	printwrap::print_wrap(5,0,"Exit codes:");
+	printwrap::print_wrap(5,24,"    0                   All jobs succeeded (or there was nothing to replicate).");
+	printwrap::print_wrap(5,24,"    1                   Usage information was printed, or the config file could not be opened.");
+	printwrap::print_wrap(5,24,"    3                   The config file (or simulation file) could not be parsed.");
+	printwrap::print_wrap(5,24,"    4                   Some, but not all, jobs failed.");
+	printwrap::print_wrap(5,24,"    5                   All jobs failed.");
+	printwrap::print_wrap(5,24,"    6                   Another piper is running (the lock file is held), so no jobs were run.");
+	printwrap::print_wrap(5,24,"    7                   The plan given to apply is out of date, so no jobs were run.");
+	printwrap::print_wrap(5,24,"    10                  Logging could not be started.");
+	printwrap::print_wrap(5,0,"");
+	printwrap::print_wrap(5,0,"Piper can be run via cron. When running from cron, the frequency piper is run should correspond to the most frequent snapshots for each dataset to be replicated. Run piper a few minutes after the snapshots are scheduled. If you make daily snapshots there is no need to run piper more frequently. It won't hurt, but isn't necessary. A typical cron line for daily replication might look like this:");
 	printwrap::print_wrap(5,0,"    5  0  *  *  *    /usr/local/bin/piper  &>> /var/log/piper.log");
 	printwrap::print_wrap(5,8,"or, for hourly replication:");
 	printwrap::print_wrap(5,0,"    5  *  *  *  *    /usr/local/bin/piper  &>> /var/log/piper.log");
 	printwrap::print_wrap(5,8,"");
+	printwrap::print_wrap(5,0,"Alternatively piper can be run as a daemon with \"--daemon\", in which case each job is run according to its own \"schedule\" in the config file, so hourly and daily jobs can live in one config file. A schedule is either an interval, such as '\"schedule\":\"every 1h\",' (s, m, h, d and w units can be combined, as in \"1h30m\"), measured from the start of the job's last run, or a cron expression with five fields (minute hour day-of-month month day-of-week), such as '\"schedule\":\"5 0 * * *\",', or one of @hourly, @daily, @weekly or @monthly. Jobs without a schedule are not run by the daemon. Each job is started as soon as it comes due, alongside any which are still running (within max_parallel and host_limits). Sending piper SIGHUP reloads the config file straight away (jobs which are running carry on as they were), and SIGTERM or SIGINT stops it once any running jobs finish.");
+	printwrap::print_wrap(5,8,"");
 
 	process::exit(1);
 }
 
 fn print_config()
 {
-	println!("{{\n\t\"comment\":\"piper configuration.\",\n\t\"jobs\": [\n\t\t\t{{\n\t\t\t\t\"sourcedataset\" : \"zroot/ROOT/root\",\n\t\t\t\t\"recursive\" : true,\n\t\t\t\t\"targetdataset\": \"remoteserver:zroot/backups/computer\"\n\t\t\t}},\n\t\t\t{{\n\t\t\t\t\"sourcedataset\" : \"zroot/data/database\",\n\t\t\t\t\"recursive\" : true,\n\t\t\t\t\"inherit_encryption\" : true,\n\t\t\t\t\"canmount\" : true,\n\t\t\t\t\"targetdataset\": \"remoteserver:zroot/data\"\n\t\t\t}}\n\t]\n}}");
+	println!("{{\n\t\"comment\":\"piper configuration.\",\n\t\"hosts\": {{\n\t\t\"remoteserver\": {{\n\t\t\t\"hostname\": \"backup.example.com\",\n\t\t\t\"user\": \"root\",\n\t\t\t\"port\": 2222,\n\t\t\t\"identity_file\": \"/root/.ssh/id_piper\",\n\t\t\t\"multiplex\": true\n\t\t}}\n\t}},\n\t\"max_parallel\": 2,\n\t\"host_limits\": {{ \"remoteserver\": 1 }},\n\t\"jobs\": [\n\t\t\t{{\n\t\t\t\t\"name\" : \"root\",\n\t\t\t\t\"sourcedataset\" : \"zroot/ROOT/root\",\n\t\t\t\t\"recursive\" : true,\n\t\t\t\t\"targetdataset\": \"remoteserver:zroot/backups/computer\"\n\t\t\t}},\n\t\t\t{{\n\t\t\t\t\"sourcedataset\" : \"zroot/data/database\",\n\t\t\t\t\"recursive\" : true,\n\t\t\t\t\"inherit_encryption\" : true,\n\t\t\t\t\"canmount\" : true,\n\t\t\t\t\"targetdataset\": \"remoteserver:zroot/data\"\n\t\t\t}}\n\t]\n}}");
 	process::exit(1);
 }
 
-fn can_login_to_host(host:&str) -> bool
+async fn can_login_to_host(exec:&dyn ZfsExecutor, host:&str) -> Result<(), PiperError>
 {
-	let mut can_login_status=false;
 	debug!("can log into host \"{}\"", host);
 	debug!("testing \"ssh {} exit\"",host);
-	let mut can_login = std::process::Command::new("ssh");
-			can_login.arg(host);
-			can_login.arg("exit");
-	let can_login_out= match can_login.stderr(Stdio::piped())
-			.output()
-			{
-				Err(e)=>{error!("Error getting error output from can_login:{}",e);return can_login_status},
-				Ok(can_login_out)=>can_login_out
-			};
-	let stderr = match String::from_utf8(can_login_out.stderr)
-			{
-				Err(e)=>{error!("Error converting can_login_out to utf8:{}",e);return can_login_status},
-				Ok(stderr)=>stderr
-			};
-
-	match can_login.status()
-	{
-		Err(_e)=> can_login_status=false,
-		Ok(o)=> can_login_status=o.success()
-	}
-	if !can_login_status
+	let can_login_out = exec.run(host, "exit", &[]).await;
+	debug!("\t\t{}", can_login_out.success);
+	if !can_login_out.success
 	{
-		let lines = stderr.lines();
+		let lines = can_login_out.stderr.lines();
 		for line in lines
 		{
 			error!("{}",line);
 		}
+		return Err(PiperError::SshFailure{host:String::from(host), message:String::from(can_login_out.stderr.trim())});
 	}
-	debug!("\t\t{}", can_login_status);
-	return can_login_status;
+	Ok(())
 }
 
-//is_dataset_encrypted needs to return a Result<T> as bool isn't sufficient (i.e. results need to be true,false,error)
-fn is_dataset_encrypted(padding:&str,host: &str, dataset:&str) -> bool
+// turns a failed zfs command into the appropriate PiperError.
+fn zfs_error(host:&str, name:&str, command:&str, output:&CommandOutput) -> PiperError
+{
+	if output.unreachable(host)
+	{
+		PiperError::SshFailure{host:String::from(host), message:String::from(output.stderr.trim())}
+	}
+	else if output.stderr.contains("does not exist")
+	{
+		PiperError::DatasetMissing{host:String::from(host), dataset:String::from(name)}
+	}
+	else
+	{
+		PiperError::Command{host:String::from(host), command:format!("zfs {} {}", command, name), message:String::from(output.stderr.trim())}
+	}
+}
+
+async fn is_dataset_encrypted(exec:&dyn ZfsExecutor, padding:&str,host: &str, dataset:&str) -> Result<bool, PiperError>
 {
 	debug!("{}is dataset encrypted {}",padding , dataset);
 	let full_command = format!("zfs list -H -t filesystem,volume -o encryption  {}",dataset);
 	debug!("{}{}", padding, full_command);
-	//let fs_list = match std::process::Command::new("zfs")
-	let mut fs_list = if host=="" {std::process::Command::new("zfs")}else{std::process::Command::new("ssh")};
-			if host != ""
-			{
-				fs_list.arg(host);
-				fs_list.arg("zfs");
-			}
-			fs_list.arg("list");
-			fs_list.arg("-H");
-			fs_list.arg("-t");
-			fs_list.arg("filesystem,volume");
-			fs_list.arg("-o");
-			fs_list.arg("encryption");
-			fs_list.arg(dataset);
-			fs_list.stdout(Stdio::piped());
-			let fs_list_output= match fs_list.stdout(Stdio::piped())
-			.output()
-			{
-				Err(e)=>{error!("{}Error getting is dataset encrypted output:{}", padding,e);return false },
-				Ok(fs_list_output)=>fs_list_output,
-			};			
-	let stdout = match String::from_utf8(fs_list_output.stdout)
-				{
-					Err(e)=> {error!("{}error converting fs_list output to utf8: {}", padding,e);return false},
-					Ok(stdout)=>stdout,
-				};
-	let mut lines = stdout.lines();
-	let line = String::from(match lines.next()
-								{
-									Some(text)=> text,
-									None=>"",
-								});
-	let is_encrypted = if line == "off" { false} else {true};
+	let fs_list_output = exec.zfs(host, &["list", "-H", "-t", "filesystem,volume", "-o", "encryption", dataset]).await;
+	if !fs_list_output.success
+	{
+		return Err(zfs_error(host, dataset, "list", &fs_list_output));
+	}
+	let mut lines = fs_list_output.stdout.lines();
+	let line = String::from(lines.next().unwrap_or_default());
+	let is_encrypted = line != "off";
 	if is_encrypted
 	{
 		info!("{}Dataset \"{}:{}\" is encrypted.",padding,host,dataset);
 	}
-	return is_encrypted;
+	Ok(is_encrypted)
 }
 
-fn get_child_datasets(padding: &str, host: &str, dataset:&str) -> Vec<String>
+// whether a job's sourcedataset is matched against the datasets on the source host,
+// rather than naming the one dataset to replicate.
+fn finds_sources(j:&Job) -> bool
 {
-	let mut vector:Vec<String> = Vec::new();
-	info!("{}Get child datasets \"{}\"", padding, dataset);
-	debug!("{}zfs list -H -d 1 -t filesystem,volume -o name -s createtxg {}", padding,dataset);
-	let mut snapshot_list = if host=="" {std::process::Command::new("zfs")}else{std::process::Command::new("ssh")};
-			if host != ""
-			{
-				snapshot_list.arg(host);
-				snapshot_list.arg("zfs");
-			}
-			snapshot_list.arg("list");
-			snapshot_list.arg("-H");
-			snapshot_list.arg("-d");
-			snapshot_list.arg("1");
-			snapshot_list.arg("-t");
-			snapshot_list.arg("filesystem,volume");
-			snapshot_list.arg("-o");
-			snapshot_list.arg("name");
-			snapshot_list.arg("-s");
-			snapshot_list.arg("createtxg");
-			snapshot_list.arg(dataset);
-	let snapshot_output= match snapshot_list.stdout(Stdio::piped())
-			.output()
-			{
-				Err(e)=>{error!("{}Error getting child datasets output:{}", padding,e);return vector },
-				Ok(snapshot_output)=>snapshot_output,
-			};
-	let stdout = match String::from_utf8(snapshot_output.stdout)
+	j.source_property.is_some() || j.sourcedataset.contains(['*', '?'])
+}
+
+// the datasets a job replicates: its sourcedataset, or the datasets on the source host
+// which match it (a glob) and have its source_property, sorted by name. the datasets
+// under one which matches are left to a recursive job to replicate as its children.
+async fn get_sources(exec:&dyn ZfsExecutor, padding:&str, j:&Job, host:&str, sourcedataset:&str) -> Result<Vec<String>, PiperError>
+{
+	if !finds_sources(j)
+	{
+		// a sourcedataset which can't be looked at fails when it is replicated instead.
+		debug!("{}zfs get -H -o value {} {}", padding, SKIP_PROPERTY, sourcedataset);
+		let skip_out = exec.zfs(host, &["get", "-H", "-o", "value", SKIP_PROPERTY, sourcedataset]).await;
+		if skip_out.success && skip_out.stdout.trim() == "on"
+		{
+			info!("{}Skipping \"{}\": {} is on.", padding, sourcedataset, SKIP_PROPERTY);
+			return Ok(Vec::new());
+		}
+		return Ok(vec![String::from(sourcedataset)]);
+	}
+	// only the datasets under the part of the glob before its first wildcard can match.
+	let root = sourcedataset.split('/').take_while(|part| !part.contains(['*', '?'])).collect::<Vec<&str>>().join("/");
+	if root.is_empty()
+	{
+		return Err(PiperError::Config(format!("the pool of sourcedataset \"{}\" can't be a wildcard", sourcedataset)));
+	}
+	let glob = if root == sourcedataset
+		{
+			None
+		}
+		else
+		{
+			Some(Pattern::new(sourcedataset).map_err(PiperError::Config)?)
+		};
+	let selector = match &j.source_property
+		{
+			None=>None,
+			Some(selector)=>match selector.split_once('=')
+				{
+					None=>return Err(PiperError::Config(format!("source_property \"{}\" must be \"<property>=<value>\"", selector))),
+					Some(selector)=>Some(selector),
+				},
+		};
+	let columns = match selector
+		{
+			None=>format!("name,{}", SKIP_PROPERTY),
+			Some((property, _))=>format!("name,{},{}", SKIP_PROPERTY, property),
+		};
+	info!("{}Finding the datasets matching \"{}\"{} on \"{}\"", padding, sourcedataset, match &j.source_property {None=>String::from(""), Some(s)=>format!(" with {}", s)}, if host.is_empty() {"localhost"} else {host});
+	debug!("{}zfs list -H -r -t filesystem,volume -o {} -s name {}", padding, columns, root);
+	let list_out = exec.zfs(host, &["list", "-H", "-r", "-t", "filesystem,volume", "-o", columns.as_str(), "-s", "name", root.as_str()]).await;
+	if !list_out.success
+	{
+		return Err(zfs_error(host, root.as_str(), "list", &list_out));
+	}
+	let mut sources:Vec<String> = Vec::new();
+	for line in list_out.stdout.lines()
+	{
+		let mut fields = line.split('\t');
+		let (name, skip, value) = (fields.next().unwrap_or(""), fields.next().unwrap_or("-"), fields.next().unwrap_or(""));
+		if !glob.as_ref().is_none_or(|glob| glob.matches(name))
+		{
+			continue;
+		}
+		if let Some((_, wanted)) = selector
+		{
+			if value != wanted
 			{
-				Err(e)=>{error!("{}Error converting child datasets output to uft8:{}", padding,e);return vector },
-				Ok(stdout)=>stdout,
-			};
+				continue;
+			}
+		}
+		if skip == "on"
+		{
+			info!("{}\tSkipping \"{}\": {} is on.", padding, name, SKIP_PROPERTY);
+			continue;
+		}
+		if j.recursive == Some(true) && sources.iter().any(|source| name.starts_with(format!("{}/", source).as_str()))
+		{
+			debug!("{}\t\"{}\" matches, but is replicated with its parent.", padding, name);
+			continue;
+		}
+		sources.push(String::from(name));
+	}
+	let ess = if sources.len() == 1 {""} else {"s"};
+	info!("{}\t{} dataset{} matched: {}", padding, sources.len(), ess, sources.join(", "));
+	Ok(sources)
+}
+
+// the user property which, set to "on", stops piper replicating a dataset (and so, as
+// it is inherited, everything under it), whether it is a child, the sourcedataset or
+// one the sourcedataset matches.
+const SKIP_PROPERTY:&str = "piper:skip";
+
+// returns each child dataset, and whether SKIP_PROPERTY is on for it.
+async fn get_child_datasets(exec:&dyn ZfsExecutor, padding: &str, host: &str, dataset:&str) -> Result<Vec<(String, bool)>, PiperError>
+{
+	let mut vector:Vec<(String, bool)> = Vec::new();
+	let properties = format!("name,{}", SKIP_PROPERTY);
+	info!("{}Get child datasets \"{}\"", padding, dataset);
+	debug!("{}zfs list -H -d 1 -t filesystem,volume -o {} -s createtxg {}", padding, properties, dataset);
+	let snapshot_output = exec.zfs(host, &["list", "-H", "-d", "1", "-t", "filesystem,volume", "-o", properties.as_str(), "-s", "createtxg", dataset]).await;
+	if !snapshot_output.success
+	{
+		return Err(zfs_error(host, dataset, "list", &snapshot_output));
+	}
 	// the output of the zfs list command will include not only the child
 	// datasets, but the parent dataset itself. this will be the first result
 	// so we need to skip the first result, and *not* add it to the vector.
 	let mut count = 0;
-	for line in stdout.lines()
+	for line in snapshot_output.stdout.lines()
 	{
 		if count > 0
 		{
-			debug!("{}\tChild Dataset:\"{}\"", padding, line);
-			vector.push(String::from(line));
+			let (name, skip) = line.split_once('\t').unwrap_or((line, "-"));
+			debug!("{}\tChild Dataset:\"{}\"", padding, name);
+			vector.push((String::from(name), skip == "on"));
 		}
-		count = count +1;
+		count += 1;
 	}
 	let ess=if count == 2 {""} else {"s"};
 	info!("{}\t{} child dataset{}.",padding, (count-1), ess);
-	return vector
+	Ok(vector)
 }
 
 fn rsplit_once(source:&str, split_on:char) -> String
 {
-	let (_,value) = match source.rsplit_once(split_on)
-					{
-						None=> ("",""),
-						Some(value)=> value,
-					};
+	let (_,value) = source.rsplit_once(split_on).unwrap_or_default();
 	String::from(value)
 }
 
-// probably need Result<T> here as well: true,false,error
-fn does_dataset_exist_on_target(padding:&str, sourcedataset:&str, targetdataset:&str, host:&str) -> bool
+async fn does_dataset_exist_on_target(exec:&dyn ZfsExecutor, padding:&str, targetdatasetname:&str, host:&str) -> Result<bool, PiperError>
 {
-	let mut dataset_exists=false;
-	info!("{}Does source dataset ({}) exist in \"{}\" on \"{}\"", padding, sourcedataset, targetdataset, host);
-	let datasetname = rsplit_once(sourcedataset, '/');
-	let targetdatasetname = format!("{}/{}", targetdataset,datasetname);
-	let ssh = if host=="" {String::from("")}else{format!("ssh {} ", host)};
+	info!("{}Does dataset \"{}\" exist on \"{}\"", padding, targetdatasetname, host);
+	let ssh = if host.is_empty() {String::from("")}else{format!("ssh {} ", host)};
 	debug!("{}{}zfs list -H -t filesystem,volume -o name -S createtxg {}", padding,ssh,targetdatasetname);
-	let mut dataset_list = if host=="" {std::process::Command::new("zfs")}else{std::process::Command::new("ssh")};
-			if host != ""
-			{
-				dataset_list.arg(host);
-				dataset_list.arg("zfs");
-			}
-			dataset_list.arg("list");
-			dataset_list.arg("-H");
-			dataset_list.arg("-t");
-			dataset_list.arg("filesystem,volume");
-			dataset_list.arg("-o");
-			dataset_list.arg("name");
-			dataset_list.arg("-S");
-			dataset_list.arg("createtxg");
-			dataset_list.arg(targetdatasetname);
-	let dataset_list_out= match dataset_list.stdout(Stdio::piped())
-			.output()
-			{
-				Err(e)=> {error!("{}Error getting dataset_list_out:{}", padding,e);return false},
-				Ok(dataset_list_out)=>dataset_list_out,
-			};
-	info!("{}Dataset_list_out status: \"{}\"", padding, match dataset_list.status() { Err(e)=>{format!("{}",e)},Ok(o)=>format!("{}",o)});
-	if dataset_list_out.status.success()
+	let dataset_list_out = exec.zfs(host, &["list", "-H", "-t", "filesystem,volume", "-o", "name", "-S", "createtxg", targetdatasetname]).await;
+	info!("{}Dataset_list_out status: \"{}\"", padding, dataset_list_out.success);
+	if dataset_list_out.success
 	{
 		// maybe should be info! rather than debug!
 		debug!("{}Dataset exists on target.", padding);
-		dataset_exists = true;
+		return Ok(true);
 	}
-	else
+	match zfs_error(host, targetdatasetname, "list", &dataset_list_out)
 	{
-		// maybe should be info! rather than debug!
-		debug!("{}Dataset does not exist on target.", padding);
+		PiperError::DatasetMissing{..}=>
+			{
+				// maybe should be info! rather than debug!
+				debug!("{}Dataset does not exist on target.", padding);
+				Ok(false)
+			},
+		e=>Err(e),
 	}
-	return dataset_exists;
 }
 
 
-fn get_last_replicated_snapshot(padding:&str, sourcedataset:&str, targetdataset:&str, host:&str) -> String
+async fn get_last_replicated_snapshot(exec:&dyn ZfsExecutor, padding:&str, targetdatasetname:&str, host:&str) -> Result<String, PiperError>
 {
-	let error_value = String::from("");
-	debug!("{}get last replicated snapshot named \"{}\" in \"{}\" on \"{}\"", padding, sourcedataset, targetdataset, host);
-	let datasetname = rsplit_once(sourcedataset, '/');
-	let targetdatasetname = format!("{}/{}", targetdataset,datasetname);
-	let ssh = if host=="" {String::from("")}else{format!("ssh {} ", host)};
+	debug!("{}get last replicated snapshot in \"{}\" on \"{}\"", padding, targetdatasetname, host);
+	let ssh = if host.is_empty() {String::from("")}else{format!("ssh {} ", host)};
 	debug!("{}{}zfs list -H -t snapshot -o name -S createtxg {}", padding,ssh,targetdatasetname);
-	let mut snapshot_list = if host=="" {std::process::Command::new("zfs")}else{std::process::Command::new("ssh")};
-			if host != ""
-			{
-				snapshot_list.arg(host);
-				snapshot_list.arg("zfs");
-			}
-			snapshot_list.arg("list");
-			snapshot_list.arg("-H");
-			snapshot_list.arg("-t");
-			snapshot_list.arg("snapshot");
-			snapshot_list.arg("-o");
-			snapshot_list.arg("name");
-			snapshot_list.arg("-S");
-			snapshot_list.arg("createtxg");
-			snapshot_list.arg(targetdatasetname);
-	let snapshot_out= match snapshot_list.stdout(Stdio::piped())
-			.output()
-			{
-				Err(e)=> {error!("{}Error getting snapshot_list stdout {}", padding,e);return error_value},
-				Ok(snapshot_out)=>snapshot_out,
-			};
-	let stdout = match String::from_utf8(snapshot_out.stdout)
-						{
-							Err(e)=>{error!("{}Error converting output to UTF8:{}", padding,e);error_value},
-							Ok(stdout)=>stdout
-						};
-	debug!("{}snapshot_out status: \"{}\"", padding, match snapshot_list.status() { Err(e)=>{format!("{}",e)},Ok(o)=>format!("{}",o)});
-	if snapshot_out.status.success()
+	let snapshot_out = exec.zfs(host, &["list", "-H", "-t", "snapshot", "-o", "name", "-S", "createtxg", targetdatasetname]).await;
+	debug!("{}snapshot_out status: \"{}\"", padding, snapshot_out.success);
+	if snapshot_out.success
 	{
-		let line = stdout.lines().next();
-		if let None = line
+		let line = snapshot_out.stdout.lines().next();
+		if line.is_none()
 		{
 			debug!("{}No last replicated snapshot", padding);
-			return String::from("")
+			return Ok(String::from(""))
 		}
-		let uline = match line
-						{
-							None=>"",
-							Some(ul)=>ul,
-						};
+		let uline = line.unwrap_or_default();
 		let name = rsplit_once(uline, '@');
 		debug!("{}Last replicated snapshot:\"{}\"", padding, name);
-		return String::from(name);
+		return Ok(name);
 	}
-	else
+	match zfs_error(host, targetdatasetname, "list", &snapshot_out)
 	{
-		error!("{}Dataset does not exist on target. Has not been replicated yet.", padding);
-		return String::from("");
+		PiperError::DatasetMissing{..}=>
+			{
+				debug!("{}Dataset does not exist on target. Has not been replicated yet.", padding);
+				Ok(String::from(""))
+			},
+		e=>Err(e),
 	}
 }
 
-fn get_most_recent_snapshot(padding:&str, dataset:&str, host:&str, prefix: &str) -> String
+// the nearest dataset above targetdatasetname on host which exists, which is what a
+// dataset received into targetdatasetname (and any parents piper creates for it) inherits from.
+async fn get_existing_parent(exec:&dyn ZfsExecutor, padding:&str, targetdatasetname:&str, host:&str) -> Result<String, PiperError>
 {
-	let error=String::from("//!!--XX--ERROR--XX--!!\\\\"); 
-	debug!("{}get most recent snapshot named \"{}\" on \"{}\"", padding, dataset, host);
-	debug!("{}zfs list -H -t snapshot -o name -S createtxg {}", padding,dataset);
-
-	let mut snapshot_list = if host=="" {std::process::Command::new("zfs")}else{std::process::Command::new("ssh")};
-			if host != ""
-			{
-				snapshot_list.arg(host);
-				snapshot_list.arg("zfs");
-			}
-			snapshot_list.arg("list");
-			snapshot_list.arg("-H");
-			snapshot_list.arg("-t");
-			snapshot_list.arg("snapshot");
-			snapshot_list.arg("-o");
-			snapshot_list.arg("name");
-			snapshot_list.arg("-S");
-			snapshot_list.arg("createtxg");
-			snapshot_list.arg(dataset);
-	let snapshot_out = match snapshot_list.stdout(Stdio::piped())
-			.output()
-			{
-				Err(e)=>{error!("{}Error getting snapshot_lit output:{}", padding,e);return error},
-				Ok(snapshot_out)=>snapshot_out,
-			};
-
-	let stdout = match String::from_utf8(snapshot_out.stdout)
-				{
-					Err(e)=>{error!("{}Error converting snapshot_out to utf8:{}", padding,e);error},
-					Ok(stdout)=>stdout,
-				};
-
-	for line in stdout.lines()
+	let mut parent = targetdatasetname;
+	while let Some((above, _)) = parent.rsplit_once('/')
 	{
-		trace!("{}Examining snapshot \"{}\"", padding, line);
-		let name = rsplit_once(line, '@');
-		trace!("{}\t tag \"{}\"", padding, name);
-		if prefix == ""
+		parent = above;
+		if does_dataset_exist_on_target(exec, padding, parent, host).await?
 		{
-			trace!("{}\t Prefix is empty, so take this, the first result.", padding);
-			return String::from(name);
-		}
-		else
-		{
-			trace!("{}\t Prefix is \"{}\"", padding, prefix);
-			if name.starts_with(prefix)
-			{
-				debug!("{}\t\tName starts with prefix, so return this result: \"{}\"", padding, name);
-				return String::from(name);
-			}
-			else
-			{
-				trace!("{}\t\tName does NOT start with prefix, on to the next result...", padding);
-			}
+			break;
 		}
 	}
-	debug!("No recent snapshot.");
-	return String::from("")
+	Ok(String::from(parent))
 }
 
-fn split_host_and_dataset(string:&str) -> (&str, &str)
+// creates the datasets above targetdatasetname on host which don't exist yet, so that a
+// full replication can be received into it. they only hold the datasets under them, so
+// they are created with canmount=off.
+async fn create_target_parents(exec:&dyn ZfsExecutor, opts:&JobOptions, padding:&str, host:&str, targetdatasetname:&str) -> Result<(), PiperError>
 {
-	let mut host="";
-	let dataset;
-	let parts = string.split(":");
-	let count = parts.collect::<Vec<&str>>();
-	if count.len() == 2
+	let existing = get_existing_parent(exec, padding, targetdatasetname, host).await?;
+	let mut missing = Vec::new();
+	let mut parent = targetdatasetname;
+	while let Some((above, _)) = parent.rsplit_once('/')
 	{
-		host=count[0];
-		dataset=count[1];
+		if above.len() <= existing.len()
+		{
+			break;
+		}
+		missing.push(above);
+		parent = above;
 	}
-	else
+	for dataset in missing.iter().rev()
 	{
-		dataset=count[0];
+		if opts.send_no_op || opts.recv_no_op
+		{
+			info!("{}Would create \"{}\" on \"{}\"", padding, dataset, host);
+			continue;
+		}
+		info!("{}Creating \"{}\" on \"{}\"", padding, dataset, host);
+		debug!("{}zfs create -o canmount=off {}", padding, dataset);
+		let create_out = exec.zfs(host, &["create", "-o", "canmount=off", dataset]).await;
+		if !create_out.success
+		{
+			return Err(zfs_error(host, dataset, "create", &create_out));
+		}
 	}
-	(host, dataset)
+	Ok(())
 }
 
-async fn process_job(j:&Job, send_no_op:bool, recv_no_op:bool) 
+// returns the receive_resume_token of an interrupted "zfs recv -s" on the target,
+// or "" if there isn't one (zfs reports "-" when no partial state exists).
+async fn get_receive_resume_token(exec:&dyn ZfsExecutor, padding:&str, host:&str, targetdatasetname:&str) -> Result<String, PiperError>
 {
-	let (sourcehost,sourcedataset)=split_host_and_dataset(&j.sourcedataset);
-	let (targethost,targetdataset)=split_host_and_dataset(&j.targetdataset);
-	let recursive:bool= match &j.recursive
-		{
-			None=>false,
-			Some(s)=>*s,
-		};
-	let canmount:bool= match &j.canmount
-		{
-			None=>false,
-			Some(s)=>*s,
+	debug!("{}get receive_resume_token of \"{}\" on \"{}\"", padding, targetdatasetname, host);
+	debug!("{}zfs get -H -o value receive_resume_token {}", padding, targetdatasetname);
+	let token_out = exec.zfs(host, &["get", "-H", "-o", "value", "receive_resume_token", targetdatasetname]).await;
+	if !token_out.success
+	{
+		return match zfs_error(host, targetdatasetname, "get receive_resume_token", &token_out)
+			{
+				PiperError::DatasetMissing{..}=>
+					{
+						// the dataset doesn't exist on the target, so there can't be any partial state.
+						debug!("{}No receive_resume_token (target dataset does not exist).", padding);
+						Ok(String::from(""))
+					},
+				e=>Err(e),
+			};
+	}
+	let token = token_out.stdout.trim();
+	if token.is_empty() || token == "-"
+	{
+		debug!("{}No receive_resume_token.", padding);
+		return Ok(String::from(""));
+	}
+	info!("{}Found receive_resume_token on \"{}\".", padding, targetdatasetname);
+	debug!("{}\t{}", padding, token);
+	Ok(String::from(token))
+}
+
+// validates a resume token against the source with "zfs send -nv -t <token>".
+// returns the full name of the snapshot the interrupted replication was sending
+// ("toname" in the token contents), or "" if the token is stale and can not be
+// resumed (typically because that snapshot no longer exists on the source).
+async fn check_resume_token(exec:&dyn ZfsExecutor, padding:&str, host:&str, token:&str) -> Result<String, PiperError>
+{
+	debug!("{}check resume token on \"{}\"", padding, host);
+	debug!("{}zfs send -nv -t {}", padding, token);
+	let check_out = exec.zfs(host, &["send", "-n", "-v", "-t", token]).await;
+	if check_out.unreachable(host)
+	{
+		// not being able to ask isn't the same as the token being stale.
+		return Err(zfs_error(host, "", "send -n -v -t", &check_out));
+	}
+	if !check_out.success
+	{
+		info!("{}Resume token is stale and can not be resumed:", padding);
+		for line in check_out.stderr.lines()
+		{
+			info!("{}\t{}", padding, line);
+		}
+		return Ok(String::from(""));
+	}
+	// the token contents are printed as an nvlist, with the snapshot being sent as
+	// "toname = pool/dataset@snapshot". Depending on the zfs version this is on
+	// stdout or stderr, so look at both.
+	for line in check_out.stdout.lines().chain(check_out.stderr.lines())
+	{
+		let line = line.trim();
+		if line.starts_with("toname")
+		{
+			let (_,toname) = line.split_once('=').unwrap_or_default();
+			debug!("{}Resume token toname:\"{}\"", padding, toname.trim());
+			return Ok(String::from(toname.trim()));
+		}
+	}
+	error!("{}Could not find the snapshot name in the resume token contents.", padding);
+	Ok(String::from(""))
+}
+
+// discards the partial state of an interrupted "zfs recv -s" with "zfs recv -A".
+async fn abort_partial_receive(exec:&dyn ZfsExecutor, padding:&str, host:&str, targetdatasetname:&str) -> Result<(), PiperError>
+{
+	info!("{}Aborting partial receive state of \"{}\" on \"{}\"", padding, targetdatasetname, host);
+	debug!("{}zfs recv -A {}", padding, targetdatasetname);
+	let abort_out = exec.zfs(host, &["recv", "-A", targetdatasetname]).await;
+	if !abort_out.success
+	{
+		return Err(zfs_error(host, targetdatasetname, "recv -A", &abort_out));
+	}
+	Ok(())
+}
+
+async fn get_most_recent_snapshot(exec:&dyn ZfsExecutor, padding:&str, dataset:&str, host:&str, prefix: &str) -> Result<String, PiperError>
+{
+	debug!("{}get most recent snapshot named \"{}\" on \"{}\"", padding, dataset, host);
+	debug!("{}zfs list -H -t snapshot -o name -S createtxg {}", padding,dataset);
+
+	let snapshot_out = exec.zfs(host, &["list", "-H", "-t", "snapshot", "-o", "name", "-S", "createtxg", dataset]).await;
+	if !snapshot_out.success
+	{
+		return Err(zfs_error(host, dataset, "list", &snapshot_out));
+	}
+
+	for line in snapshot_out.stdout.lines()
+	{
+		trace!("{}Examining snapshot \"{}\"", padding, line);
+		let name = rsplit_once(line, '@');
+		trace!("{}\t tag \"{}\"", padding, name);
+		if prefix.is_empty()
+		{
+			trace!("{}\t Prefix is empty, so take this, the first result.", padding);
+			return Ok(name);
+		}
+		else
+		{
+			trace!("{}\t Prefix is \"{}\"", padding, prefix);
+			if name.starts_with(prefix)
+			{
+				debug!("{}\t\tName starts with prefix, so return this result: \"{}\"", padding, name);
+				return Ok(name);
+			}
+			else
+			{
+				trace!("{}\t\tName does NOT start with prefix, on to the next result...", padding);
+			}
+		}
+	}
+	debug!("No recent snapshot.");
+	Err(PiperError::NoSnapshots{host:String::from(host), dataset:String::from(dataset), prefix:String::from(prefix)})
+}
+
+// the creation time of the newest snapshot of dataset whose name starts with prefix,
+// if it has one.
+async fn get_newest_snapshot_creation(exec:&dyn ZfsExecutor, padding:&str, host:&str, dataset:&str, prefix:&str) -> Option<i64>
+{
+	debug!("{}zfs list -H -p -t snapshot -o name,creation -S createtxg {}", padding, dataset);
+	let list_out = exec.zfs(host, &["list", "-H", "-p", "-t", "snapshot", "-o", "name,creation", "-S", "createtxg", dataset]).await;
+	if !list_out.success
+	{
+		debug!("{}Can't list the snapshots of \"{}\": {}", padding, dataset, list_out.stderr.trim());
+		return None;
+	}
+	list_out.stdout.lines().filter_map(|line| line.split_once('\t')).find(|(name, _)| rsplit_once(name, '@').starts_with(prefix)).and_then(|(_, creation)| creation.parse().ok())
+}
+
+// snapshots dataset (and its children, if recursive) as "<prefix><timestamp>", so
+// that the snapshot is the newest one matching the prefix.
+async fn create_snapshot(exec:&dyn ZfsExecutor, opts:&JobOptions, padding:&str, prefix:&str, host:&str, dataset:&str) -> Result<(), PiperError>
+{
+	let snapshot = format!("{}@{}{}", dataset, prefix, Local::now().format("%Y-%m-%d_%H.%M.%S"));
+	let mut args = vec!["snapshot"];
+	if opts.recursive
+	{
+		args.push("-r");
+	}
+	args.push(snapshot.as_str());
+	if opts.send_no_op
+	{
+		info!("{}No-op: not creating snapshot \"{}\"", padding, snapshot);
+		return Ok(());
+	}
+	info!("{}Creating snapshot \"{}\" on host \"{}\"", padding, snapshot, host);
+	debug!("{}zfs {}", padding, args.join(" "));
+	let snapshot_out = exec.zfs(host, &args).await;
+	if !snapshot_out.success
+	{
+		return Err(zfs_error(host, snapshot.as_str(), "snapshot", &snapshot_out));
+	}
+	opts.events.emit("snapshot_created", opts.name.as_str(), &[("host", Value::from(host)), ("snapshot", Value::from(snapshot.as_str())), ("recursive", Value::from(opts.recursive))]);
+	Ok(())
+}
+
+// splits "<host>:<dataset>" into the host (resolved against the hosts section of the
+// config file) and the dataset. the host is "" if there isn't one.
+fn split_host_and_dataset<'a>(hosts:&'a Hosts, string:&'a str) -> (&'a str, &'a str)
+{
+	let mut host="";
+	let dataset;
+	let parts = string.split(":");
+	let count = parts.collect::<Vec<&str>>();
+	if count.len() == 2
+	{
+		host=count[0];
+		dataset=count[1];
+	}
+	else
+	{
+		dataset=count[0];
+	}
+	(hosts::resolve(hosts, host), dataset)
+}
+
+// the environment of a job's hooks: what the job replicates, and once it has run
+// (if result is given), how it went. a job which matches several datasets has them
+// all in PIPER_SOURCE_DATASET, separated by spaces, and their targets in PIPER_TARGET_DATASET.
+// for several targets PIPER_TARGET_HOST is empty and each target is "<host>:<dataset>".
+fn hook_env(name:&str, hook:&str, sourcehost:&str, targets:&[JobTarget], result:Option<&JobResult>) -> Vec<(&'static str, String)>
+{
+	let (targethost, targetdatasets) = match targets
+		{
+			[target]=>(target.host.clone(), target.map.roots().join(" ")),
+			_=>(String::from(""), targets.iter().flat_map(|target| target.map.roots().into_iter().map(move |root| plan::host_and_dataset(target.host.as_str(), root))).collect::<Vec<String>>().join(" ")),
+		};
+	let mut env = vec![
+			("PIPER_JOB", String::from(name)),
+			("PIPER_HOOK", String::from(hook)),
+			("PIPER_SOURCE_HOST", String::from(sourcehost)),
+			("PIPER_SOURCE_DATASET", targets.first().map(|target| target.map.sources().join(" ")).unwrap_or_default()),
+			("PIPER_TARGET_HOST", targethost),
+			("PIPER_TARGET_DATASET", targetdatasets),
+		];
+	if let Some(result) = result
+	{
+		// the sends of the job's own dataset, rather than of its children.
+		let sends = result.datasets.first().map(|dataset| dataset.sends.as_slice()).unwrap_or(&[]);
+		let error = result.error.as_ref().or(result.datasets.iter().find_map(|dataset| dataset.result.as_ref().err()));
+		env.push(("PIPER_RESULT", String::from(if result.failed() {"failed"} else {"ok"})));
+		env.push(("PIPER_ERROR_CLASS", String::from(error.map(|e| e.class()).unwrap_or(""))));
+		env.push(("PIPER_ERROR", error.map(|e| e.to_string()).unwrap_or_default()));
+		env.push(("PIPER_FROM_SNAPSHOT", sends.first().map(|send| send.from.clone()).unwrap_or_default()));
+		env.push(("PIPER_SNAPSHOT", sends.iter().rev().find(|send| send.error.is_none()).map(|send| send.to.clone()).unwrap_or_default()));
+		env.push(("PIPER_BYTES", format!("{}", result.datasets.iter().map(|dataset| dataset.bytes()).sum::<u64>())));
+		env.push(("PIPER_DATASETS", format!("{}", result.datasets.len())));
+		env.push(("PIPER_FAILED_DATASETS", format!("{}", result.datasets.iter().filter(|dataset| dataset.result.is_err()).count())));
+	}
+	env
+}
+
+// runs one of the job's hooks. a hook on the target host of a job with several
+// targets is run on each of them in turn, with the variables of that target.
+async fn run_hook(exec:&dyn ZfsExecutor, name:&str, hook_name:&str, hook:&Hook, sourcehost:&str, targets:&[JobTarget], result:Option<&JobResult>) -> Result<(), PiperError>
+{
+	let padding = format!("[{}] ", name);
+	let padding = padding.as_str();
+	if hook.host.as_deref() == Some("target")
+	{
+		for target in targets.iter()
+		{
+			let env = hook_env(name, hook_name, sourcehost, std::slice::from_ref(target), result);
+			hooks::run(exec, padding, hook_name, hook, sourcehost, target.host.as_str(), &env).await?;
+		}
+		return Ok(());
+	}
+	let env = hook_env(name, hook_name, sourcehost, targets, result);
+	hooks::run(exec, padding, hook_name, hook, sourcehost, "", &env).await
+}
+
+// one of a job's targets, with the job's settings filled in.
+struct JobTarget
+{
+	target: Target,
+	host: String,
+	// the name on the target of each dataset replicated.
+	map: TargetMap,
+}
+
+// where each of the job's sources is replicated to on each of its targets. two
+// targets can't replicate anything into the same place.
+async fn map_targets(exec:&dyn ZfsExecutor, hosts:&Hosts, j:&Job, sourcehost:&str, sources:&[String]) -> Result<Vec<JobTarget>, PiperError>
+{
+	let mut targets:Vec<JobTarget> = Vec::new();
+	for target in job_targets(j).map_err(PiperError::Config)?
+	{
+		let (targethost,targetdataset)=split_host_and_dataset(hosts, &target.targetdataset);
+		let map = TargetMap::new(exec, j.target_mapping.as_ref(), sourcehost, sources, targetdataset).await?;
+		for other in targets.iter()
+		{
+			if other.host == targethost && other.map.roots().iter().any(|root| map.roots().contains(root))
+			{
+				return Err(PiperError::Config(format!("targets \"{}\" and \"{}\" would replicate into the same place", other.target.targetdataset, target.targetdataset)));
+			}
+		}
+		targets.push(JobTarget{host:String::from(targethost), map, target});
+	}
+	Ok(targets)
+}
+
+// replicates a job, and runs its post_hook if it succeeds or its on_failure hook if
+// it fails (the pre_hook is run by replicate_job, once the job is ready to go).
+async fn process_job(exec:&dyn ZfsExecutor, limits:&Limits, hosts:&Hosts, j:&Job, job_locks:Option<&JobLocks>, run:&Run<'_>) -> JobResult
+{
+	let name = job_name(j);
+	let padding = format!("[{}] ", name);
+	let (sourcehost,sourcedataset)=split_host_and_dataset(hosts, &j.sourcedataset);
+	let failed = |e:PiperError|
+		{
+			error!("{}Can't replicate: {}.", padding, e);
+			JobResult{name:name.clone(), error:Some(e), datasets:Vec::new(), matched:None}
 		};
-	let inherit_encryption:bool= match &j.inherit_encryption
+	let sources = match get_sources(exec, padding.as_str(), j, sourcehost, sourcedataset).await
 		{
-			None=>true,
-			Some(s)=>*s,
+			Err(e)=>return failed(e),
+			Ok(sources)=>sources,
 		};
-	let prefix = match &j.prefix
+	let targets = match map_targets(exec, hosts, j, sourcehost, &sources).await
 		{
-			None=>"",
-			Some(s)=>s,
+			Err(e)=>return failed(e),
+			Ok(targets)=>targets,
+		};
+	let mut job_result = replicate_job(exec, limits, j, sourcehost, &targets, job_locks, run).await;
+	if finds_sources(j)
+	{
+		job_result.matched = Some(sources);
+	}
+	// planning doesn't change anything, so it doesn't run hooks either.
+	if let Mode::Plan{..} = run.mode
+	{
+		return job_result;
+	}
+	if let Some(hook) = &j.post_hook
+	{
+		if !job_result.failed()
+		{
+			if let Err(e) = run_hook(exec, name.as_str(), "post_hook", hook, sourcehost, &targets, Some(&job_result)).await
+			{
+				error!("{}Job failed: {}.", padding, e);
+				job_result.error = Some(e);
+			}
+		}
+	}
+	if let Some(hook) = &j.on_failure
+	{
+		if job_result.failed()
+		{
+			if let Err(e) = run_hook(exec, name.as_str(), "on_failure", hook, sourcehost, &targets, Some(&job_result)).await
+			{
+				error!("{}{}.", padding, e);
+			}
+		}
+	}
+	job_result
+}
+
+// the options the job replicates to one of its targets with.
+fn job_options(j:&Job, target:&JobTarget, run:&Run<'_>) -> Result<JobOptions, PiperError>
+{
+	let opts = JobOptions{
+			name: job_name(j),
+			recursive: match &j.recursive
+				{
+					None=>false,
+					Some(s)=>*s,
+				},
+			children: ChildFilter::new(&j.include, &j.exclude).map_err(PiperError::Config)?,
+			parallel_children: match &j.parallel_children
+				{
+					None=>false,
+					Some(s)=>*s,
+				},
+			canmount: match &j.canmount
+				{
+					None=>false,
+					Some(s)=>*s,
+				},
+			inherit_encryption: match &j.inherit_encryption
+				{
+					None=>true,
+					Some(s)=>*s,
+				},
+			prefix: match &target.target.prefix
+				{
+					None=>String::from(""),
+					Some(s)=>s.clone(),
+				},
+			resume_policy: match &j.resume_policy
+				{
+					None=>String::from("resume"),
+					Some(s)=>s.clone(),
+				},
+			intermediates: match &target.target.intermediates
+				{
+					None=>false,
+					Some(s)=>*s,
+				},
+			target_retention: j.target_retention.clone(),
+			targets: target.map.clone(),
+			// each job, and each of its targets, has its own holds, so that they don't release each other's.
+			hold_tag: hold_tag(j, target.target.targetdataset.as_str()),
+			pipeline: PipelineOptions::new(&target.target.compression, &target.target.mbuffer, &target.target.rate_limit)?,
+			mode: run.mode.clone(),
+			events: run.events.clone(),
+			send_no_op: run.send_no_op,
+			recv_no_op: run.recv_no_op,
 		};
+	if opts.resume_policy != "resume" && opts.resume_policy != "abort"
+	{
+		return Err(PiperError::Config(format!("unknown resume_policy \"{}\" (must be \"resume\" or \"abort\")", opts.resume_policy)));
+	}
+	if let Some(rules) = &opts.target_retention
+	{
+		retention::validate(rules).map_err(PiperError::Config)?;
+	}
+	Ok(opts)
+}
+
+async fn replicate_job(exec:&dyn ZfsExecutor, limits:&Limits, j:&Job, sourcehost:&str, targets:&[JobTarget], job_locks:Option<&JobLocks>, run:&Run<'_>) -> JobResult
+{
+	let name = job_name(j);
+	let mut job_result = JobResult{name:name.clone(), error:None, datasets:Vec::new(), matched:None};
+	// every line logged for the job starts with its name so that the output of jobs
+	// running at the same time can be told apart.
+	let padding = format!("[{}] ", name);
+	info!("{}Job \"{}\"", padding, name);
+	let mut target_opts:Vec<JobOptions> = Vec::new();
+	for target in targets.iter()
+	{
+		match job_options(j, target, run)
+		{
+			Err(e)=>
+				{
+					error!("{}Can't replicate: {}.", padding, e);
+					job_result.error = Some(e);
+					return job_result
+				},
+			Ok(opts)=>target_opts.push(opts),
+		}
+	}
+	for (hook_name, hook) in [("pre_hook", &j.pre_hook), ("post_hook", &j.post_hook), ("on_failure", &j.on_failure)]
+	{
+		if let Err(message) = hook.as_ref().map_or(Ok(()), |hook| hook.validate(hook_name))
+		{
+			let e = PiperError::Config(message);
+			error!("{}Can't replicate: {}.", padding, e);
+			job_result.error = Some(e);
+			return job_result
+		}
+	}
+
+	// held until the job is done. a job with several targets takes their locks in
+	// order, so that it can't wait for a job which is waiting for it.
+	let mut lock_targets:Vec<String> = targets.iter().flat_map(|target| target.map.roots().into_iter().map(move |root| plan::host_and_dataset(target.host.as_str(), root))).collect();
+	lock_targets.sort();
+	let mut _job_locks = Vec::new();
+	for lock_target in lock_targets.iter()
+	{
+		if let Some(job_locks) = job_locks
+		{
+			match job_locks.acquire(padding.as_str(), lock_target.as_str()).await
+			{
+				Err(e)=>
+					{
+						error!("{}Can't replicate: {}.", padding, e);
+						job_result.error = Some(e);
+						return job_result
+					},
+				Ok(l)=>_job_locks.push(l),
+			}
+		}
+	}
 
 	// check if we can login to the source or target hosts (if remote)
 	// if we can't login, then there's nothing else we can do, so quit the job early.
-	if sourcehost != ""
+	if !sourcehost.is_empty()
+	{
+		info!("{}sourcehost: \"{}\"", padding, sourcehost);
+		if let Err(e) = can_login_to_host(exec, sourcehost).await
+		{
+			error!("{}Can't replicate: can't login to source host {}.", padding, sourcehost);
+			job_result.error = Some(e);
+			return job_result
+		}
+	}
+	let mut targethosts:Vec<&str> = targets.iter().map(|target| target.host.as_str()).filter(|host| !host.is_empty()).collect();
+	targethosts.sort();
+	targethosts.dedup();
+	for targethost in targethosts
+	{
+		info!("{}targethost: \"{}\"", padding, targethost);
+		if let Err(e) = can_login_to_host(exec, targethost).await
+		{
+			error!("{}Can't replicate: can't login to target host {}.", padding, targethost);
+			job_result.error = Some(e);
+			return job_result
+		}
+	}
+
+	// a pre_hook which fails skips the job.
+	if let (Some(hook), false) = (&j.pre_hook, matches!(run.mode, Mode::Plan{..}))
 	{
-		info!("sourcehost: \"{}\"", sourcehost);
-		if !can_login_to_host(sourcehost)
+		if let Err(e) = run_hook(exec, name.as_str(), "pre_hook", hook, sourcehost, targets, None).await
 		{
-			error!("Can't replicate: can't login to source host {}.", targethost);
-			return
+			error!("{}Skipping the job: {}.", padding, e);
+			job_result.error = Some(e);
+			return job_result
 		}
 	}
-	if targethost != ""
+
+	// a saved plan says which snapshot to send, so applying one doesn't make a new one.
+	// a snapshot is made with each prefix the targets use, so that every target has one
+	// to send.
+	if let (Some(true), Mode::Run) = (j.snapshot_before_send, run.mode)
 	{
-		info!("targethost: \"{}\"", targethost);
-		if !can_login_to_host(targethost)
+		let mut prefixes:Vec<&str> = Vec::new();
+		for opts in target_opts.iter()
 		{
-			error!("Can't replicate: can't login to target host {}.", targethost);
-			return
+			if prefixes.contains(&opts.prefix.as_str())
+			{
+				continue;
+			}
+			prefixes.push(opts.prefix.as_str());
+			for sourcedataset in opts.targets.sources().iter()
+			{
+				if let Err(e) = create_snapshot(exec, opts, padding.as_str(), opts.prefix.as_str(), sourcehost, sourcedataset).await
+				{
+					error!("{}Can't replicate: {}.", padding, e);
+					job_result.error = Some(e);
+					return job_result
+				}
+			}
 		}
 	}
 
-	process_dataset_intermediate("", sourcehost, sourcedataset, targethost,targetdataset, inherit_encryption, canmount, recursive, prefix, send_no_op, recv_no_op).await;
+	// the targets are replicated to one after another, and what is looked up about the
+	// source for the first is kept for the rest.
+	let cache = SourceCache::new(exec, sourcehost, &target_opts[0].targets.sources());
+	for (target, opts) in targets.iter().zip(target_opts.iter())
+	{
+		if targets.len() > 1
+		{
+			info!("{}Replicating to \"{}\"", padding, target.target.targetdataset);
+		}
+		// the datasets a job matches are replicated like the children of a recursive job.
+		let sources = opts.targets.sources();
+		if opts.parallel_children
+		{
+			let source_futures = sources.iter().map(|sourcedataset| process_dataset_intermediate(&cache, limits, opts, padding.as_str(), sourcehost, sourcedataset, target.host.as_str()));
+			for mut source_results in join_all(source_futures).await
+			{
+				job_result.datasets.append(&mut source_results);
+			}
+		}
+		else
+		{
+			for sourcedataset in sources.iter()
+			{
+				job_result.datasets.append(&mut process_dataset_intermediate(&cache, limits, opts, padding.as_str(), sourcehost, sourcedataset, target.host.as_str()).await);
+			}
+		}
+	}
+	job_result
 }
 
 /*
@@ -509,26 +1428,73 @@ async fn process_job(j:&Job, send_no_op:bool, recv_no_op:bool)
 	it also make the recursion a little easier to keep clear in a hypothetical programmer's head.
 */
 #[async_recursion]
-async fn process_dataset_intermediate(opadding: &str, sourcehost:&str,sourcedataset:&str, targethost:&str,targetdataset:&str, inherit_encryption:bool, canmount:bool, recursive:bool, prefix:&str, send_no_op:bool, recv_no_op:bool)
+async fn process_dataset_intermediate(exec:&dyn ZfsExecutor, limits:&Limits, opts:&JobOptions, opadding: &str, sourcehost:&str,sourcedataset:&str, targethost:&str) -> Vec<DatasetResult>
 {
-	let children= process_dataset(opadding, sourcehost, sourcedataset, targethost,targetdataset, inherit_encryption, canmount, recursive, prefix, send_no_op, recv_no_op).await;
-	if recursive
+	let targetdatasetname = opts.targets.target(sourcedataset);
+	// the slots are only held while this dataset is replicated, not while its children
+	// are, otherwise children waiting for a slot their parent holds would never get one.
+	let permits = limits.acquire(opadding, sourcehost, targethost).await;
+	let started = Local::now();
+	let mut sends = Vec::new();
+	let (children, result, mut plan) = process_dataset(exec, opts, opadding, sourcehost, sourcedataset, targethost, &mut sends).await;
+	let duration = (Local::now() - started).to_std().unwrap_or_default();
+	drop(permits);
+	// how far the target is behind the source, for the metrics.
+	let lag = match opts.mode
+		{
+			Mode::Plan{..}=>None,
+			_=>match (get_newest_snapshot_creation(exec, opadding, sourcehost, sourcedataset, opts.prefix.as_str()).await, get_newest_snapshot_creation(exec, opadding, targethost, targetdatasetname.as_str(), opts.prefix.as_str()).await)
+				{
+					(Some(source), Some(target))=>Some((source - target).max(0)),
+					_=>None,
+				},
+		};
+	if let Err(e) = &result
+	{
+		error!("{}Can't replicate \"{}\": {}", opadding, sourcedataset, e);
+		// a dataset which can't be planned is still in the plan, with the reason.
+		if let Mode::Plan{..} = opts.mode
+		{
+			plan = Some(DatasetPlan::failed(sourcehost, sourcedataset, targethost, targetdatasetname.as_str(), e));
+		}
+	}
+	let mut results = vec![DatasetResult{
+			dataset: String::from(sourcedataset),
+			sourcehost: String::from(sourcehost),
+			targethost: String::from(targethost),
+			target: targetdatasetname,
+			started,
+			duration,
+			sends,
+			result,
+			plan,
+			lag,
+		}];
+	if opts.recursive
 	{
 		info!("{}Recursive = True.  Examining child datasets...",opadding);
 		// process child datasets
-		let npadding = format!("\t{}",opadding);
-		let mut count=0;
-		for child_data_set in children.iter()
+		let npadding = format!("{}\t",opadding);
+		if opts.parallel_children
+		{
+			debug!("{}Examining {} child datasets in parallel", npadding, children.len());
+			let child_futures = children.iter().map(|child_data_set| process_dataset_intermediate(exec, limits, opts, npadding.as_str(), sourcehost, child_data_set, targethost));
+			for mut child_results in join_all(child_futures).await
+			{
+				results.append(&mut child_results);
+			}
+		}
+		else
 		{
-			count = count +1;
-			debug!("{}Recursively examining child dataset #{} \"{}\"",count ,npadding,child_data_set);
-			// targetdataset is not correct
-			// targetdataset needs to be targetdataset/sourcedataset_name
-			let child_dataset_name = rsplit_once(sourcedataset, '/');
-			let child_target_dataset=format!("{}/{}",targetdataset,child_dataset_name);
-			process_dataset_intermediate(npadding.as_str(), sourcehost, child_data_set, targethost,child_target_dataset.as_str(), inherit_encryption, canmount, recursive, prefix, send_no_op, recv_no_op ).await;
+			let mut count=0;
+			for child_data_set in children.iter()
+			{
+				count += 1;
+				debug!("{}Recursively examining child dataset #{} \"{}\"",npadding ,count,child_data_set);
+				results.append(&mut process_dataset_intermediate(exec, limits, opts, npadding.as_str(), sourcehost, child_data_set, targethost).await);
+			}
 		}
-		if count == 0
+		if children.is_empty()
 		{
 			info!("{}No child datasets!",opadding);
 		}
@@ -537,92 +1503,545 @@ async fn process_dataset_intermediate(opadding: &str, sourcehost:&str,sourcedata
 	{
 		info!("{}Recursive = False. Ignoring child datasets...",opadding);
 	}
+	results
+}
+
+// the tag older versions of piper held every snapshot with, whichever job replicated it.
+const LEGACY_HOLD_TAG:&str = "piper";
 
+// the tag of the holds of the chain from the job to one of its targets, as given in
+// the config file: "piper:<job name>:<targetdataset>", with the sourcedataset standing
+// in for the name of a job which hasn't got one.
+fn hold_tag(j:&Job, targetdataset:&str) -> String
+{
+	format!("piper:{}:{}", j.name.as_deref().unwrap_or(j.sourcedataset.as_str()), targetdataset)
 }
 
-fn snapshot_hold(padding: &str, host:&str,snapshot:&str, action:&str)->bool
+async fn snapshot_hold(exec:&dyn ZfsExecutor, opts:&JobOptions, padding: &str, host:&str,snapshot:&str, action:&str, tag:&str)->bool
 {
-	info!("{}{} snapshot \"{}\" on host \"{}\"",padding, action, snapshot, host);
-	let full_command = format!("zfs hold piper {}",snapshot);
+	info!("{}{} snapshot \"{}\" on host \"{}\" ({})",padding, action, snapshot, host, tag);
+	let full_command = format!("zfs {} {} {}",action, tag, snapshot);
 	debug!("{}{}", padding, full_command);
 
-	let mut hold = if host=="" {std::process::Command::new("zfs")}else{std::process::Command::new("ssh")};
-			if host != ""
-			{
-				hold.arg(host);
-				hold.arg("zfs");
-			}
-			hold.arg(action);
-			hold.arg("piper");
-			hold.arg(snapshot);
-	let hold_out= match hold.stdout(Stdio::piped())
-			.output()
+	let hold_out = exec.zfs(host, &[action, tag, snapshot]).await;
+	let success:bool = hold_out.success;
+	debug!("{} .... {} {}",padding,action, if success {"Succeeded!"}else{"Failed!"});
+	if !success
+	{
+		error!("{}{}",padding, hold_out.stderr.trim());
+	}
+	else
+	{
+		opts.events.emit(if action == "hold" {"hold_placed"} else {"hold_released"}, opts.name.as_str(), &[("host", Value::from(host)), ("snapshot", Value::from(snapshot)), ("tag", Value::from(tag))]);
+	}
+	success
+}
+
+// releases the chain's holds on snapshot, which is no longer its base: its own tag,
+// and the legacy tag, which the chain's own hold on the new base takes over from.
+// the holds of other chains on the snapshot, such as those of a job which goes on
+// to replicate the target somewhere else, are left alone.
+async fn release_holds(exec:&dyn ZfsExecutor, opts:&JobOptions, padding:&str, host:&str, snapshot:&str) -> bool
+{
+	debug!("{}zfs holds -H {}", padding, snapshot);
+	let holds_out = exec.zfs(host, &["holds", "-H", snapshot]).await;
+	if !holds_out.success
+	{
+		error!("{}Can't list the holds on \"{}\": {}", padding, snapshot, holds_out.stderr.trim());
+		return false;
+	}
+	let mut released = true;
+	for tag in holds_out.stdout.lines().filter_map(|line| line.split('\t').nth(1))
+	{
+		if tag == opts.hold_tag || tag == LEGACY_HOLD_TAG
+		{
+			released = snapshot_hold(exec, opts, padding, host, snapshot, "release", tag).await && released;
+		}
+	}
+	released
+}
+
+#[derive(Clone)]
+struct Snapshot
+{
+	name: String,
+	guid: String,
+	createtxg: u64,
+}
+
+// lists the snapshots (and/or bookmarks, depending on types) of a single dataset
+// with their guid and createtxg, newest first. an empty list is returned if the
+// dataset doesn't exist.
+async fn list_snapshots(exec:&dyn ZfsExecutor, padding:&str, host:&str, dataset:&str, types:&str) -> Result<Vec<Snapshot>, PiperError>
+{
+	let mut vector:Vec<Snapshot> = Vec::new();
+	debug!("{}list {} of \"{}\" on \"{}\"", padding, types, dataset, host);
+	debug!("{}zfs list -H -p -t {} -o name,guid,createtxg -S createtxg {}", padding, types, dataset);
+	let snapshot_out = exec.zfs(host, &["list", "-H", "-p", "-t", types, "-o", "name,guid,createtxg", "-S", "createtxg", dataset]).await;
+	if !snapshot_out.success
+	{
+		return match zfs_error(host, dataset, "list", &snapshot_out)
 			{
-				Err(e)=> {error!("{}Error on {}:{}", padding, action, e);return false},
-				Ok(hold_out)=>hold_out,
+				PiperError::DatasetMissing{..}=>
+					{
+						debug!("{}\"{}\" does not exist.", padding, dataset);
+						Ok(vector)
+					},
+				e=>Err(e),
 			};
-	let hold_err= match hold.stderr(Stdio::piped())
-			.output()
+	}
+	for line in snapshot_out.stdout.lines()
+	{
+		let fields:Vec<&str> = line.split('\t').collect();
+		if fields.len() != 3
+		{
+			error!("{}Unexpected zfs list output:\"{}\"", padding, line);
+			continue;
+		}
+		trace!("{}\t{} {} {}", padding, fields[0], fields[1], fields[2]);
+		vector.push(Snapshot{name:String::from(fields[0]), guid:String::from(fields[1]), createtxg:fields[2].parse().unwrap_or(0)});
+	}
+	Ok(vector)
+}
+
+struct CommonSnapshot
+{
+	// the snapshot (or bookmark) on the source to use as the incremental base
+	source: Snapshot,
+	// the same snapshot on the target, possibly under another name
+	target: Snapshot,
+	// snapshots on the target newer than the common snapshot which don't exist on the source
+	target_only: Vec<String>,
+}
+
+// finds the newest snapshot which exists on both source and target. snapshots are
+// matched by guid rather than name, so a snapshot renamed on either side is still
+// found, and a bookmark on the source can stand in for a snapshot that has since
+// been destroyed there. both lists must be sorted newest first (as from list_snapshots).
+// if there is no common snapshot, the reason is returned in the error.
+fn find_common_snapshot(targetdatasetname:&str, source_snapshots:&[Snapshot], target_snapshots:&[Snapshot]) -> Result<CommonSnapshot, PiperError>
+{
+	if target_snapshots.is_empty()
+	{
+		return Err(PiperError::DivergedTarget{dataset:String::from(targetdatasetname), reason:String::from("The target has no snapshots.")});
+	}
+	if source_snapshots.is_empty()
+	{
+		return Err(PiperError::DivergedTarget{dataset:String::from(targetdatasetname), reason:String::from("The source has no snapshots or bookmarks.")});
+	}
+	let mut target_only:Vec<String> = Vec::new();
+	for target in target_snapshots.iter()
+	{
+		// prefer a snapshot over a bookmark of the same snapshot as -R can be used with it.
+		let source = match source_snapshots.iter().find(|s| s.guid == target.guid && s.name.contains('@'))
 			{
-				Err(e)=>{error!("{} .... {}",padding,e);return false},
-				Ok(hold_out)=>hold_out
+				Some(source)=>Some(source),
+				None=>source_snapshots.iter().find(|s| s.guid == target.guid),
 			};
-	let stderr = match String::from_utf8(hold_err.stderr)
+		if let Some(source) = source
+		{
+			return Ok(CommonSnapshot{
+				source: source.clone(),
+				target: target.clone(),
+				target_only,
+			});
+		}
+		target_only.push(target.name.clone());
+	}
+	Err(PiperError::DivergedTarget{dataset:String::from(targetdatasetname), reason:format!("None of the {} snapshots on the target (newest \"{}\") exist on the source, either as a snapshot or as a bookmark. The target has diverged from the source. To \"fix\" this either replicate to another parent dataset, or destroy the target dataset and re-run the replication. THIS WILL DESTROY DATA.",
+			target_snapshots.len(), target_snapshots[0].name)})
+}
+
+// returns the guid of a snapshot or bookmark, or "" if it doesn't exist.
+async fn get_guid(exec:&dyn ZfsExecutor, padding:&str, host:&str, name:&str) -> String
+{
+	debug!("{}get guid of \"{}\" on \"{}\"", padding, name, host);
+	debug!("{}zfs get -H -o value guid {}", padding, name);
+	let guid_out = exec.zfs(host, &["get", "-H", "-o", "value", "guid", name]).await;
+	if !guid_out.success
+	{
+		debug!("{}\"{}\" does not exist.", padding, name);
+		return String::from("");
+	}
+	let guid = String::from(guid_out.stdout.trim());
+	debug!("{}\tguid:\"{}\"", padding, guid);
+	guid
+}
+
+// the incremental sends which bring the target from common up to the current snapshot.
+// without intermediates this is one send from common to current. with intermediates
+// and no prefix it is one -I send, so every snapshot in between is sent too. with a
+// prefix only the snapshots in between which match it are sent, by chaining -i sends
+// from one to the next. source_snapshots are as from list_snapshots.
+fn incremental_steps(source_snapshots:&[Snapshot], common:&Snapshot, current:&str, current_createtxg:u64, prefix:&str, intermediates:bool) -> Vec<IncrementalStep>
+{
+	let step = |from:&str, to:&str, intermediates:bool| IncrementalStep{from:String::from(from), to:String::from(to), intermediates};
+	if !intermediates
+	{
+		return vec![step(common.name.as_str(), current, false)];
+	}
+	let mut between:Vec<&Snapshot> = source_snapshots.iter().filter(|snapshot| snapshot.name.contains('@')
+			&& snapshot.createtxg > common.createtxg && snapshot.createtxg < current_createtxg
+			&& rsplit_once(snapshot.name.as_str(), '@').starts_with(prefix)).collect();
+	between.sort_by_key(|snapshot| snapshot.createtxg);
+	if !prefix.is_empty()
+	{
+		let mut steps = Vec::new();
+		let mut from = common.name.as_str();
+		for snapshot in between.iter()
+		{
+			steps.push(step(from, snapshot.name.as_str(), false));
+			from = snapshot.name.as_str();
+		}
+		steps.push(step(from, current, false));
+		return steps;
+	}
+	if common.name.contains('#')
+	{
+		// -I can't be sent from a bookmark, so the first snapshot after it is sent on its own.
+		return match between.first()
 			{
-				Err(e)=>{error!("{}Error converting hold_err to utf8:{}",padding,e);return false},
-				Ok(stderr)=>stderr
+				None=>vec![step(common.name.as_str(), current, false)],
+				Some(first)=>vec![step(common.name.as_str(), first.name.as_str(), false), step(first.name.as_str(), current, true)],
 			};
+	}
+	vec![step(common.name.as_str(), current, true)]
+}
 
-	let success:bool = hold_out.status.success();
-	debug!("{} .... {} {}",padding,action, if success {"Succeeded!"}else{"Failed!"});
-	if !success
+// the bookmark piper keeps on the source for each target it replicates to, named
+// after the target's host and dataset. only letters, digits, "." and "-" are kept as
+// they are in each. every other character (such as "/", which isn't allowed in a
+// bookmark name) is written as "_", its code in hex and "_" again, which leaves ":"
+// free to separate the host from the dataset, so no two targets share a bookmark.
+fn bookmark_name(sourcedataset:&str, targethost:&str, targetdataset:&str) -> String
+{
+	let escape = |name:&str| name.chars().map(|c| if c.is_ascii_alphanumeric() || c == '.' || c == '-' {String::from(c)} else {format!("_{:x}_", u32::from(c))}).collect::<String>();
+	if targethost.is_empty()
+	{
+		format!("{}#piper_{}", sourcedataset, escape(targetdataset))
+	}
+	else
 	{
-		error!("{}{}",padding, stderr);
+		format!("{}#piper_{}:{}", sourcedataset, escape(targethost), escape(targetdataset))
 	}
-	return success;
 }
 
-async fn process_dataset(padding: &str, sourcehost:&str,sourcedataset:&str, targethost:&str,targetdataset:&str, inherit_encryption:bool, canmount:bool, recursive:bool, prefix:&str, send_no_op:bool, recv_no_op:bool)-> Vec<String>
+// moves the piper bookmark for a target to the snapshot just replicated.
+// bookmarks can't be renamed or overwritten, so the old one is destroyed first.
+async fn update_bookmark(exec:&dyn ZfsExecutor, padding:&str, host:&str, snapshot:&str, bookmark:&str) -> Result<(), PiperError>
 {
-	//let spadding = format!("    {}",opadding);
-	//let padding = spadding.as_str();
-	//let mut completed=true;
-	let encrypted=is_dataset_encrypted(padding, sourcehost,sourcedataset);
-	let targetencrypted=is_dataset_encrypted(padding, targethost,targetdataset);
+	info!("{}Bookmark snapshot \"{}\" as \"{}\" on host \"{}\"", padding, snapshot, bookmark, host);
+	if !get_guid(exec, padding, host, bookmark).await.is_empty()
+	{
+		debug!("{}zfs destroy {}", padding, bookmark);
+		let destroy_out = exec.zfs(host, &["destroy", bookmark]).await;
+		if !destroy_out.success
+		{
+			error!("{}Could not destroy old bookmark \"{}\": {}", padding, bookmark, destroy_out.stderr.trim());
+			return Err(PiperError::Command{host:String::from(host), command:format!("zfs destroy {}", bookmark), message:String::from(destroy_out.stderr.trim())});
+		}
+	}
+	debug!("{}zfs bookmark {} {}", padding, snapshot, bookmark);
+	let create_out = exec.zfs(host, &["bookmark", snapshot, bookmark]).await;
+	if !create_out.success
+	{
+		error!("{}Could not create bookmark \"{}\": {}", padding, bookmark, create_out.stderr.trim());
+		return Err(PiperError::Command{host:String::from(host), command:format!("zfs bookmark {} {}", snapshot, bookmark), message:String::from(create_out.stderr.trim())});
+	}
+	Ok(())
+}
+
+// whether child is replicated, on its own as every child is excluded from its parent's send.
+fn wants_child(opts:&JobOptions, padding:&str, child:&str, skip:bool) -> Wanted
+{
+	if skip
+	{
+		info!("{}Skipping child dataset \"{}\": {} is on.", padding, child, SKIP_PROPERTY);
+		return Wanted::No;
+	}
+	let wanted = opts.children.wants(opts.targets.source_of(child).unwrap_or(""), child);
+	match wanted
+	{
+		Wanted::Yes=>(),
+		Wanted::Through=>info!("{}Passing through child dataset \"{}\": it isn't included, but datasets under it may be.", padding, child),
+		Wanted::No=>info!("{}Skipping child dataset \"{}\": it is left out by the job's include/exclude patterns.", padding, child),
+	}
+	wanted
+}
+
+// the datasets under a dataset which are replicated as its children: those of its
+// children which are wanted, and those under each child which is passed through.
+#[async_recursion]
+async fn wanted_children(exec:&dyn ZfsExecutor, opts:&JobOptions, padding:&str, host:&str, children:&[(String, bool)]) -> Result<Vec<String>, PiperError>
+{
+	let mut wanted = Vec::new();
+	for (child, skip) in children.iter()
+	{
+		match wants_child(opts, padding, child.as_str(), *skip)
+		{
+			Wanted::Yes=>wanted.push(child.clone()),
+			Wanted::Through=>
+				{
+					let grandchildren = get_child_datasets(exec, padding, host, child.as_str()).await?;
+					wanted.append(&mut wanted_children(exec, opts, padding, host, &grandchildren).await?);
+				},
+			Wanted::No=>(),
+		}
+	}
+	Ok(wanted)
+}
+
+async fn process_dataset(exec:&dyn ZfsExecutor, opts:&JobOptions, padding: &str, sourcehost:&str,sourcedataset:&str, targethost:&str, sends:&mut Vec<SendRecord>)-> (Vec<String>, Result<Outcome, PiperError>, Option<DatasetPlan>)
+{
+	let targetdatasetname = opts.targets.target(sourcedataset);
+	let targetdatasetname = targetdatasetname.as_str();
+	// the children to be replicated are returned even if this dataset fails so that
+	// they can still be replicated, so they're looked up first. the send excludes all
+	// of them, including those which aren't replicated.
+	let (child_datasets, children) = match get_child_datasets(exec, padding, sourcehost, sourcedataset).await
+		{
+			Err(e)=>return (Vec::new(), Err(e), None),
+			Ok(children)=>
+				{
+					let wanted:Vec<String> = if opts.recursive
+						{
+							match wanted_children(exec, opts, padding, sourcehost, &children).await
+							{
+								Err(e)=>return (Vec::new(), Err(e), None),
+								Ok(wanted)=>wanted,
+							}
+						}
+						else
+						{
+							Vec::new()
+						};
+					(children.into_iter().map(|(child, _)| child).collect::<Vec<String>>(), wanted)
+				},
+		};
+	let encrypted=match is_dataset_encrypted(exec, padding, sourcehost,sourcedataset).await
+		{
+			Err(e)=>return (children, Err(e), None),
+			Ok(encrypted)=>encrypted,
+		};
+	// what a new target inherits its encryption from.
+	let targetencrypted=match get_existing_parent(exec, padding, targetdatasetname, targethost).await
+		{
+			Err(e)=>return (children, Err(e), None),
+			Ok(parent)=>match is_dataset_encrypted(exec, padding, targethost, parent.as_str()).await
+				{
+					Err(e)=>return (children, Err(e), None),
+					Ok(encrypted)=>encrypted,
+				},
+		};
 	info!("{}sourcedataset     : \"{}\"", padding, sourcedataset);
-	info!("{}recursive         : \"{}\"", padding, recursive);
-	info!("{}targetdataset     : \"{}\"", padding, targetdataset);
+	info!("{}recursive         : \"{}\"", padding, opts.recursive);
+	info!("{}targetdataset     : \"{}\"", padding, targetdatasetname);
 	info!("{}encrypted         : \"{}\"", padding, encrypted);
 	info!("{}targetencrypted   : \"{}\"", padding, targetencrypted);
-	info!("{}inherit encryption: \"{}\"", padding, inherit_encryption);
+	info!("{}inherit encryption: \"{}\"", padding, opts.inherit_encryption);
+	info!("{}resume policy     : \"{}\"", padding, opts.resume_policy);
+	info!("{}intermediates     : \"{}\"", padding, opts.intermediates);
 
-	let current_snapshot_name=get_most_recent_snapshot(padding, sourcedataset, sourcehost, prefix);
-	let previous_snapshot_name=get_last_replicated_snapshot(padding, sourcedataset, targetdataset, targethost);
-	let child_datasets = get_child_datasets(padding, sourcehost, sourcedataset);
-	if previous_snapshot_name != ""
+	let plan = match plan_dataset(exec, opts, padding, sourcehost, sourcedataset, targethost, targetdatasetname).await
+		{
+			Err(e)=>return (children, Err(e), None),
+			Ok(plan)=>plan,
+		};
+	info!("{}Plan: {}", padding, plan.describe());
+	match &opts.mode
+	{
+		Mode::Run=>{},
+		Mode::Plan{estimate}=>
+			{
+				let mut plan = plan;
+				if *estimate
+				{
+					plan.size = estimate_size(exec, padding, &plan, encrypted, &child_datasets).await;
+				}
+				return (children, Ok(Outcome::Planned), Some(plan));
+			},
+		Mode::Apply(saved)=>
+			{
+				let saved = saved.jobs.iter().find(|job| job.name == opts.name).map(|job| job.datasets.as_slice()).unwrap_or(&[]);
+				let difference = match plan::find(saved, &plan)
+					{
+						None=>Some(String::from("it is not in the plan")),
+						Some(saved)=>saved.difference(&plan),
+					};
+				if let Some(reason) = difference
+				{
+					return (children, Err(PiperError::Drifted{dataset:plan.target, reason}), None);
+				}
+			},
+	}
+	let dataset_sends = DatasetSends{encrypted, targetencrypted, child_datasets:&child_datasets};
+	let mut result = carry_out(exec, opts, padding, &plan, &dataset_sends, sends).await;
+	// a resumed replication may not have brought the target up to date, so the rest is
+	// planned and carried out straight away, unless carrying out a saved plan, which
+	// only said to resume.
+	if let (Ok(Outcome::Resumed), Mode::Run) = (&result, &opts.mode)
 	{
-		info!("{}{} exists on target. Dataset has been replicated, so we'll check most recent snapshot.", padding, sourcedataset);
-		if  previous_snapshot_name != current_snapshot_name
+		result = match plan_dataset(exec, opts, padding, sourcehost, sourcedataset, targethost, targetdatasetname).await
+			{
+				Err(e)=>Err(e),
+				Ok(plan)=>
+					{
+						info!("{}Plan: {}", padding, plan.describe());
+						match carry_out(exec, opts, padding, &plan, &dataset_sends, sends).await
+						{
+							Ok(Outcome::UpToDate)=>Ok(Outcome::Resumed),
+							other=>other,
+						}
+					},
+			};
+	}
+	if let (Ok(outcome), Some(rules)) = (&result, &opts.target_retention)
+	{
+		if *outcome != Outcome::UpToDate && !(opts.send_no_op || opts.recv_no_op)
+		{
+			if let Err(e) = apply_retention(exec, padding, rules, targethost, plan.target.as_str()).await
+			{
+				result = Err(e);
+			}
+		}
+	}
+	(children, result, None)
+}
+
+// destroys the snapshots of dataset on host which the retention rules don't keep.
+async fn apply_retention(exec:&dyn ZfsExecutor, padding:&str, rules:&[RetentionRule], host:&str, dataset:&str) -> Result<(), PiperError>
+{
+	info!("{}Applying target retention to \"{}\"", padding, dataset);
+	debug!("{}zfs list -H -p -t snapshot -o name,creation,userrefs -S createtxg {}", padding, dataset);
+	let list_out = exec.zfs(host, &["list", "-H", "-p", "-t", "snapshot", "-o", "name,creation,userrefs", "-S", "createtxg", dataset]).await;
+	if !list_out.success
+	{
+		return Err(zfs_error(host, dataset, "list", &list_out));
+	}
+	let mut snapshots:Vec<TargetSnapshot> = Vec::new();
+	for line in list_out.stdout.lines()
+	{
+		let fields:Vec<&str> = line.split('\t').collect();
+		if fields.len() != 3
+		{
+			error!("{}Unexpected zfs list output:\"{}\"", padding, line);
+			continue;
+		}
+		snapshots.push(TargetSnapshot{name:rsplit_once(fields[0], '@'), creation:fields[1].parse().unwrap_or(0), held:fields[2] != "0"});
+	}
+	let expired = retention::expired(rules, &snapshots);
+	info!("{}{} of {} snapshots have expired.", padding, expired.len(), snapshots.len());
+	for name in expired.iter()
+	{
+		let snapshot = format!("{}@{}", dataset, name);
+		info!("{}Destroying expired snapshot \"{}\"", padding, snapshot);
+		debug!("{}zfs destroy {}", padding, snapshot);
+		let destroy_out = exec.zfs(host, &["destroy", snapshot.as_str()]).await;
+		if !destroy_out.success
+		{
+			return Err(zfs_error(host, snapshot.as_str(), "destroy", &destroy_out));
+		}
+	}
+	Ok(())
+}
+
+// works out what has to be done to bring the target up to date with the source,
+// without changing anything on either.
+async fn plan_dataset(exec:&dyn ZfsExecutor, opts:&JobOptions, padding: &str, sourcehost:&str,sourcedataset:&str, targethost:&str,targetdatasetname:&str)-> Result<DatasetPlan, PiperError>
+{
+	// an earlier replication of this dataset may have been interrupted, leaving partial
+	// state on the target. this has to be dealt with before anything else is done
+	// as zfs recv will refuse to receive anything else into the dataset until the
+	// partial state is either resumed or aborted.
+	let mut plan = DatasetPlan::new(sourcehost, sourcedataset, targethost, targetdatasetname);
+	let resume_token = get_receive_resume_token(exec, padding, targethost, targetdatasetname).await?;
+	if !resume_token.is_empty()
+	{
+		let resume_snapshot_name = if opts.resume_policy == "abort"
+			{
+				info!("{}Resume policy is \"abort\", so partial receive state will be discarded.", padding);
+				String::from("")
+			}
+			else
+			{
+				// an error here means the source couldn't be asked about the token, not that
+				// it is stale, so the partial state is left alone.
+				check_resume_token(exec, padding, sourcehost, resume_token.as_str()).await?
+			};
+		if resume_snapshot_name.is_empty()
+		{
+			plan.abort_partial = true;
+			plan.resume_token = resume_token;
+		}
+		else
+		{
+			info!("{}An interrupted replication of \"{}\" can be resumed.", padding, resume_snapshot_name);
+			let resume_previous_snapshot_name=get_last_replicated_snapshot(exec, padding, targetdatasetname, targethost).await?;
+			plan.action = Action::Resume;
+			plan.guid = get_guid(exec, padding, sourcehost, resume_snapshot_name.as_str()).await;
+			plan.snapshot = resume_snapshot_name;
+			plan.resume_token = resume_token;
+			if !resume_previous_snapshot_name.is_empty()
+			{
+				plan.from = format!("{}@{}", sourcedataset, resume_previous_snapshot_name);
+				plan.from_target = format!("{}@{}", targetdatasetname, resume_previous_snapshot_name);
+			}
+			return Ok(plan);
+		}
+	}
+
+	let current_snapshot_name=get_most_recent_snapshot(exec, padding, sourcedataset, sourcehost, opts.prefix.as_str()).await?;
+	let current_snapshot_name_full = format!("{}@{}", sourcedataset, current_snapshot_name);
+	plan.snapshot = current_snapshot_name_full.clone();
+	let target_snapshots = list_snapshots(exec, padding, targethost, targetdatasetname, "snapshot").await?;
+	if !target_snapshots.is_empty()
+	{
+		info!("{}{} exists on target. Dataset has been replicated, so we'll look for the newest snapshot common to source and target.", padding, sourcedataset);
+		let source_snapshots = list_snapshots(exec, padding, sourcehost, sourcedataset, "snapshot,bookmark").await?;
+		let common = find_common_snapshot(targetdatasetname, &source_snapshots, &target_snapshots)?;
+		info!("{}Newest common snapshot: \"{}\" (guid {})", padding, common.source.name, common.source.guid);
+		if common.source.name.contains('#')
+		{
+			info!("{}The common snapshot no longer exists on the source. Using its bookmark as the incremental base.", padding);
+		}
+		if rsplit_once(common.source.name.as_str(), '@') != rsplit_once(common.target.name.as_str(), '@')
+		{
+			info!("{}The common snapshot has been renamed. It is \"{}\" on the target.", padding, common.target.name);
+		}
+		let current_createtxg = match source_snapshots.iter().find(|snapshot| snapshot.name == current_snapshot_name_full)
+			{
+				None=>0,
+				Some(snapshot)=>
+					{
+						plan.guid = snapshot.guid.clone();
+						snapshot.createtxg
+					},
+			};
+		if common.source.createtxg < current_createtxg
 		{
-			info!("{}\"{}\" != \"{}\"", padding, previous_snapshot_name, current_snapshot_name);
-			info!("{}Snapshots do not match, doing incremental replication.", padding);
-			let current_snapshot_name_full = format!("{}@{}", sourcedataset, current_snapshot_name);
-			let previous_snapshot_name_full = format!("{}@{}", sourcedataset, previous_snapshot_name);
-
-			if replicate(padding, sourcehost, sourcedataset,current_snapshot_name_full.as_str(), previous_snapshot_name_full.as_str(), encrypted, targetencrypted, inherit_encryption, canmount, recursive, &child_datasets, targethost, targetdataset, send_no_op, recv_no_op).await
+			info!("{}\"{}\" is newer than \"{}\"", padding, current_snapshot_name_full, common.source.name);
+			if opts.target_retention.is_some() && !common.target_only.is_empty()
 			{
-				info!("{}Incremental Replication succeeded.", padding);
+				// without -F the receive can't roll them back.
+				return Err(PiperError::DivergedTarget{dataset:String::from(targetdatasetname), reason:format!("{} snapshot(s) newer than the common snapshot (newest \"{}\") only exist on the target. They can't be rolled back as the receive doesn't use -F when target_retention is set.",
+						common.target_only.len(), common.target_only[0])});
 			}
-			else
+			for target_only in common.target_only.iter()
 			{
-				error!("{}Incremental Replication failed.", padding);
+				info!("{}Snapshot \"{}\" only exists on the target and will be rolled back by the receive.", padding, target_only);
 			}
+			plan.action = Action::Incremental;
+			plan.steps = incremental_steps(&source_snapshots, &common.source, current_snapshot_name_full.as_str(), current_createtxg, opts.prefix.as_str(), opts.intermediates);
+			plan.from = common.source.name;
+			plan.from_target = common.target.name;
 		}
 		else
 		{
-			// dataset has been replciated, snapshot's match so no additional replication required no
-			info!("{}Snapshot's match, so no additional replication required now.", padding);
+			// the target already has the most recent snapshot (or a newer one), so no additional replication required now
+			info!("{}Target already has \"{}\" or newer, so no additional replication required now.", padding, current_snapshot_name_full);
+			plan.action = Action::UpToDate;
 		}
+		Ok(plan)
 	}
 	else
 	{
@@ -631,50 +2050,256 @@ async fn process_dataset(padding: &str, sourcehost:&str,sourcedataset:&str, targ
 		// we've already established there are not snapshots, but we need to check for the dataset.
 		// if the dataset exists without any snapshots, we can't replicate as that would overwrite the
 		// existing dataset and zfs recv will not do that.
-		if does_dataset_exist_on_target(padding, sourcedataset, targetdataset, targethost)
+		if does_dataset_exist_on_target(exec, padding, targetdatasetname, targethost).await?
 		{
 			// dataset exists on target, but doesn't have any snapshots. can't replicate.
-			let datasetname = rsplit_once(sourcedataset, '/');
-			let targetdatasetname = format!("{}/{}", targetdataset,datasetname);
-
 			error!("{}Target dataset exists but has no snapshots. Can't replicate.", padding);
 			error!("{}To \"fix\" this either replicate to another parent dataset, or", padding);
-			error!("{}destroy the target dataset: \"{}\" on {}, and then", padding,targetdatasetname, if targethost==""{"lostalhost"}else{targethost});
+			error!("{}destroy the target dataset: \"{}\" on {}, and then", padding,targetdatasetname, if targethost.is_empty(){"lostalhost"}else{targethost});
 			error!("{}re-reun the replication.", padding);
 			error!("{}!!!! THIS WILL DESTROY DATA !!!!", padding);
 			error!("{}DO NOT DO THIS UNLESS YOU ARE VERY SURE IT IS THE CORRECT ACTION TO TAKE.", padding);
+			return Err(PiperError::DivergedTarget{dataset:String::from(targetdatasetname), reason:String::from("The target dataset exists but has no snapshots.")});
+		}
+		info!("{}{} does not exist on target. No replication has occured, full replication needed.", padding, sourcedataset);
+		info!("{}Last snapshot made: \"{}\"", padding, current_snapshot_name);
+		plan.action = Action::Full;
+		plan.guid = get_guid(exec, padding, sourcehost, current_snapshot_name_full.as_str()).await;
+		Ok(plan)
+	}
+}
+
+// what every send made for a dataset shares.
+struct DatasetSends<'a>
+{
+	encrypted: bool,
+	// whether what a new target inherits its encryption from is encrypted.
+	targetencrypted: bool,
+	// every child of the dataset, as they are all excluded from its sends.
+	child_datasets: &'a [String],
+}
+
+// one send made to carry out a plan: snapshot, from previous (previous_target on the
+// target) if it is incremental, or the rest of an interrupted send if there is a
+// resume_token.
+struct SendStep<'a>
+{
+	snapshot: &'a str,
+	previous: &'a str,
+	previous_target: &'a str,
+	resume_token: &'a str,
+	intermediates: bool,
+}
+
+// carries out a plan made by plan_dataset.
+async fn carry_out(exec:&dyn ZfsExecutor, opts:&JobOptions, padding: &str, plan:&DatasetPlan, dataset_sends:&DatasetSends<'_>, sends:&mut Vec<SendRecord>)-> Result<Outcome, PiperError>
+{
+	let targethost = plan.targethost.as_str();
+	if plan.abort_partial
+	{
+		abort_partial_receive(exec, padding, targethost, plan.target.as_str()).await?;
+	}
+	match plan.action
+	{
+		Action::Resume=>
+			{
+				info!("{}Resuming interrupted replication of \"{}\".", padding, plan.snapshot);
+				if let Err(e) = send_step(exec, opts, padding, plan, &SendStep{snapshot:plan.snapshot.as_str(), previous:plan.from.as_str(), previous_target:plan.from_target.as_str(), resume_token:plan.resume_token.as_str(), intermediates:false}, dataset_sends, sends).await
+				{
+					error!("{}Resumed Replication failed. The partial state has been kept so the next run can try to resume again.", padding);
+					return Err(e);
+				}
+				info!("{}Resumed Replication succeeded.", padding);
+				Ok(Outcome::Resumed)
+			},
+		Action::Incremental=>
+			{
+				info!("{}Doing incremental replication.", padding);
+				if plan.steps.len() > 1
+				{
+					info!("{}Sending {} snapshots one after another.", padding, plan.steps.len());
+				}
+				for (i, step) in plan.steps.iter().enumerate()
+				{
+					let previous_target = if i == 0 {plan.from_target.clone()} else {format!("{}@{}", plan.target, rsplit_once(step.from.as_str(), '@'))};
+					if let Err(e) = send_step(exec, opts, padding, plan, &SendStep{snapshot:step.to.as_str(), previous:step.from.as_str(), previous_target:previous_target.as_str(), resume_token:"", intermediates:step.intermediates}, dataset_sends, sends).await
+					{
+						error!("{}Incremental Replication failed.", padding);
+						return Err(e);
+					}
+				}
+				info!("{}Incremental Replication succeeded.", padding);
+				Ok(Outcome::Incremental)
+			},
+		Action::Full=>
+			{
+				info!("{}Full replication commencing.", padding);
+				create_target_parents(exec, opts, padding, targethost, plan.target.as_str()).await?;
+				if let Err(e) = send_step(exec, opts, padding, plan, &SendStep{snapshot:plan.snapshot.as_str(), previous:"", previous_target:"", resume_token:"", intermediates:false}, dataset_sends, sends).await
+				{
+					error!("{}Full Replication failed.", padding);
+					return Err(e);
+				}
+				info!("{}Full Replication succeeded.", padding);
+				Ok(Outcome::Full)
+			},
+		Action::UpToDate=>Ok(Outcome::UpToDate),
+		// plan_dataset returns the error rather than a plan which can't be carried out.
+		Action::Blocked|Action::Diverged=>Err(PiperError::Config(format!("the plan for \"{}\" can't be carried out: {}", plan.target, plan.reason))),
+	}
+}
+
+// makes the send as replicate does, and records it in sends.
+async fn send_step(exec:&dyn ZfsExecutor, opts:&JobOptions, padding:&str, plan:&DatasetPlan, step:&SendStep<'_>, dataset_sends:&DatasetSends<'_>, sends:&mut Vec<SendRecord>) -> Result<(), PiperError>
+{
+	let source = plan::host_and_dataset(plan.sourcehost.as_str(), plan.source.as_str());
+	let target = plan::host_and_dataset(plan.targethost.as_str(), plan.target.as_str());
+	opts.events.emit("send_started", opts.name.as_str(), &[("source", Value::from(source.as_str())), ("target", Value::from(target.as_str())),
+			("from", Value::from(step.previous)), ("to", Value::from(step.snapshot)), ("resume", Value::from(!step.resume_token.is_empty()))]);
+	let started = Local::now();
+	let result = replicate(exec, opts, padding, plan, step, dataset_sends).await;
+	let send = SendRecord{
+			from: String::from(step.previous),
+			to: String::from(step.snapshot),
+			bytes: match &result
+				{
+					Err(_)=>0,
+					Ok(bytes)=>*bytes,
+				},
+			started: started.to_rfc3339(),
+			seconds: (Local::now() - started).num_milliseconds() as f64 / 1000.0,
+			error: result.as_ref().err().map(|e| String::from(e.class())),
+		};
+	opts.events.emit("send_finished", opts.name.as_str(), &[("source", Value::from(source)), ("target", Value::from(target)), ("from", Value::from(send.from.as_str())), ("to", Value::from(send.to.as_str())),
+			("bytes", Value::from(send.bytes)), ("seconds", Value::from(send.seconds)), ("error", Value::from(send.error.clone()))]);
+	sends.push(send);
+	result.map(|_| ())
+}
+
+// the estimated size of what carrying out plan would send, from "zfs send -nP".
+async fn estimate_size(exec:&dyn ZfsExecutor, padding:&str, plan:&DatasetPlan, encrypted:bool, child_datasets:&[String]) -> Option<u64>
+{
+	// (snapshot, previous snapshot, resume token, intermediates) for each send.
+	let sends:Vec<(&str, &str, &str, bool)> = match plan.action
+		{
+			Action::Full=>vec![(plan.snapshot.as_str(), "", "", false)],
+			Action::Incremental=>plan.steps.iter().map(|step| (step.to.as_str(), step.from.as_str(), "", step.intermediates)).collect(),
+			Action::Resume=>vec![(plan.snapshot.as_str(), plan.from.as_str(), plan.resume_token.as_str(), false)],
+			Action::UpToDate=>return Some(0),
+			Action::Blocked|Action::Diverged=>return None,
+		};
+	let mut total:u64 = 0;
+	for (snapshot, previous, token, intermediates) in sends
+	{
+		let mut sendc = vec!["send", "-n", "-P"];
+		sendc.append(&mut send_args(padding, snapshot, previous, token, intermediates, encrypted, child_datasets));
+		debug!("{}zfs {}", padding, sendc.join(" "));
+		let send_out = exec.zfs(plan.sourcehost.as_str(), &sendc).await;
+		let size = send_out.stdout.lines().filter_map(|line| line.strip_prefix("size\t")).next_back().and_then(|size| size.trim().parse::<u64>().ok());
+		match size
+		{
+			Some(size) if send_out.success=>total += size,
+			_=>
+				{
+					debug!("{}Can't estimate the size of the send: {}", padding, send_out.stderr.trim());
+					return None;
+				},
+		}
+	}
+	Some(total)
+}
+
+// the arguments to "zfs send" (after "send" and any -n) which send snapshot_name,
+// incrementally from previous_snapshot_name if that isn't "".
+fn send_args<'a>(padding:&str, snapshot_name:&'a str, previous_snapshot_name:&'a str, resume_token:&'a str, send_intermediates:bool, encrypted:bool, child_datasets:&'a [String]) -> Vec<&'a str>
+{
+	let mut sendc:Vec<&str> = Vec::new();
+	if !resume_token.is_empty()
+	{
+		// a resumed send picks up all of the options of the original send
+		// from the token, so none of the other options may be given.
+		sendc.push("-t");
+		sendc.push(resume_token);
+	}
+	else
+	{
+		if encrypted
+		{
+			sendc.push("-w");
+		}
+		if previous_snapshot_name.contains('#')
+		{
+			// a replication stream (-R) can't be sent from a bookmark, so send
+			// just this dataset with its properties (-p) instead. children are
+			// excluded from the replication stream anyway.
+			sendc.push("-p");
+			sendc.push("-i");
+			sendc.push(previous_snapshot_name);
+			sendc.push(snapshot_name);
 		}
 		else
 		{
-			info!("{}{} does not exist on target. No replication has occured, full replication commencing.", padding, sourcedataset);
-			info!("{}Last snapshot made: \"{}\"", padding, current_snapshot_name);
+			sendc.push("-R");
+			sendc.push("-s");
 
-			let current_snapshot_name_full = format!("{}@{}", sourcedataset, current_snapshot_name);
-			if replicate(padding, sourcehost, sourcedataset,current_snapshot_name_full.as_str(), "", encrypted, targetencrypted, inherit_encryption, canmount, recursive, &child_datasets, targethost, targetdataset, send_no_op, recv_no_op).await
+			// We need to exclude child datasets from *this* replication. 
+			// If recursive is true, we'll replicate the children separately.
+			// If we recusively replicate here, then properties set in the receive 
+			// will **NOT** be applied to children (properties like canmount=off),
+			// and that could be not good.
+			let lines = child_datasets.iter();
+			for line in lines
 			{
-				info!("{}Full Replication succeeded.", padding);
+					info!("{}Excluding child dataset: \"{}\"",padding, line);
+					sendc.push("-X");
+					sendc.push(line);
 			}
-			else
+		
+			if !previous_snapshot_name.is_empty()
 			{
-				error!("{}Full Replication failed.", padding);
-				//completed=false;
+				// -I sends every snapshot between the two as well.
+				sendc.push(if send_intermediates {"-I"} else {"-i"});
+				sendc.push(previous_snapshot_name);
 			}
+			sendc.push(snapshot_name);
 		}
 	}
-	return child_datasets;
+	sendc
+}
+
+// the error for a failed stage of the replication pipeline.
+fn stage_error(stage:&Stage, output:&CommandOutput) -> PiperError
+{
+	let message = String::from(output.stderr.trim());
+	if output.unreachable(stage.host.as_str())
+	{
+		return PiperError::SshFailure{host:stage.host.clone(), message};
+	}
+	match stage.name.as_str()
+	{
+		"zfs send"=>PiperError::SendFailure(message),
+		"zfs recv"=>PiperError::RecvFailure(message),
+		_=>PiperError::StageFailure{stage:stage.name.clone(), message},
+	}
 }
 
-async fn replicate(padding:&str, sourcehost:&str, _sourcedataset:&str, snapshot_name:&str, previous_snapshot_name:&str, 
-					encrypted:bool, targetencrypted:bool, inherit_encryption:bool, canmount:bool, recursive:bool, child_datasets:&Vec<String>, targethost:&str, targetdataset:&str, 
-					send_no_op:bool, recv_no_op:bool) -> bool
+async fn replicate(exec:&dyn ZfsExecutor, opts:&JobOptions, padding:&str, plan:&DatasetPlan, step:&SendStep<'_>, dataset_sends:&DatasetSends<'_>) -> Result<u64, PiperError>
 {
-	let mut replication_status = false;
+	let (sourcehost, sourcedataset, targethost, targetdataset) = (plan.sourcehost.as_str(), plan.source.as_str(), plan.targethost.as_str(), plan.target.as_str());
+	let (snapshot_name, previous_snapshot_name, previous_target_snapshot_name, resume_token, send_intermediates) = (step.snapshot, step.previous, step.previous_target, step.resume_token, step.intermediates);
+	let (encrypted, targetencrypted, child_datasets) = (dataset_sends.encrypted, dataset_sends.targetencrypted, dataset_sends.child_datasets);
+	let (inherit_encryption, canmount, recursive, send_no_op, recv_no_op) = (opts.inherit_encryption, opts.canmount, opts.recursive, opts.send_no_op, opts.recv_no_op);
+	let mut replication_status = Ok(0);
+	let mut bookmarked:Result<(), PiperError> = Ok(());
 	info!("{}REPLICATE",padding);
 	info!("{}sourcehost            : \"{}\"",padding, sourcehost);
 	info!("{}snapshot_name         : \"{}\"",padding, snapshot_name);
 	info!("{}previous_snapshot_name: \"{}\"",padding, previous_snapshot_name);
+	info!("{}previous_target_name  : \"{}\"",padding, previous_target_snapshot_name);
+	info!("{}resuming              : \"{}\"",padding, !resume_token.is_empty());
+	info!("{}intermediates         : \"{}\"",padding, send_intermediates);
 	info!("{}encrypted             : \"{}\"",padding, encrypted);
-	info!("{}targetencrypted       : \"{}\"",padding, encrypted);
+	info!("{}targetencrypted       : \"{}\"",padding, targetencrypted);
 	info!("{}inherit encryption    : \"{}\"",padding, inherit_encryption);
 	info!("{}canmount              : \"{}\"",padding, canmount);
 	info!("{}recursive             : \"{}\"",padding, recursive);
@@ -693,167 +2318,469 @@ async fn replicate(padding:&str, sourcehost:&str, _sourcedataset:&str, snapshot_
 	{
 		info!("{}Sending \"{}\":\"{}\" to \"{}\":\"{}\"",padding, sourcehost, snapshot_name, targethost, targetdataset);
 
-		let mut sendc = if sourcehost != "" {Command::new("ssh")} else { Command::new("zfs")};
-			if sourcehost != ""
-			{
-				info!("Pull from {}", sourcehost);
-				sendc.arg(sourcehost);
-				sendc.arg("zfs");
-			}
-			sendc.arg("send");
+		let mut sendc:Vec<&str> = vec!["send"];
 			if send_no_op
 			{
-				sendc.arg("-n");
+				sendc.push("-n");
 			}
-			if encrypted
+			else
 			{
-				sendc.arg("-w");
+				// -P writes the size of the stream to stderr, so that it can be reported.
+				sendc.push("-P");
 			}
-			sendc.arg("-R");
-			sendc.arg("-s");
+			sendc.append(&mut send_args(padding, snapshot_name, previous_snapshot_name, resume_token, send_intermediates, encrypted, child_datasets));
 
-			// We need to exclude child datasets from *this* replication. 
-			// If recursive is true, we'll replicate the children separately.
-			// If we recusively replicate here, then properties set in the receive 
-			// will **NOT** be applied to children (properties like canmount=off),
-			// and that could be not good.
-			let lines = child_datasets.iter();
-			for line in lines
-			{
-					info!("{}Excluding child dataset: \"{}\"",padding, line);
-					sendc.arg("-X");
-					sendc.arg(line);
-			}
-			
-			if previous_snapshot_name != ""
-			{
-				sendc.arg("-i");
-				sendc.arg(previous_snapshot_name);
-			}
-			sendc.arg(snapshot_name);
-		let mut sendo = match sendc.stdout(Stdio::piped())
-			.spawn()
-			{
-				Err(e)=>{error!("{}Error getting senc stdout:{}",padding,e);return replication_status},
-				Ok(sendo)=>sendo,
-			};
-		debug!("{}Created sendc & sendo",padding);
-
-		let recv_stdin_a = match sendo.stdout.take()
-								{
-									None=>{error!("{}Failed to take sendo.stdout",padding);return replication_status;},
-									Some(o)=>o,
-								};
-		let recv_stdin: Stdio = match recv_stdin_a.try_into()
-									{
-										Err(_e)=>{error!("{}Failed to try_into sendo.stdout",padding);return replication_status;},
-										Ok(o)=>o,
-									};
-		debug!("{}Created recv_stdin",padding);
-		let mut recvc = if targethost != "" { Command::new("ssh")}else{Command::new("zfs")};
-			if targethost != ""
-			{
-				info!("{}Pushing to {} ... ",padding, targethost);
-				recvc.arg(targethost);
-				recvc.arg("zfs");
-			}
-			recvc.arg("recv");
+		let mut recvc:Vec<&str> = vec!["recv"];
 			if send_no_op || recv_no_op
 			{
-				recvc.arg("-n");
+				recvc.push("-n");
 			}
-			recvc.arg("-v");
-			recvc.arg("-e");
+			recvc.push("-v");
+			// -s saves the partial state if the receive is interrupted so that the next
+			// run can resume it with "zfs send -t" rather than starting from scratch.
+			recvc.push("-s");
 
-			recvc.arg("-o");
+			recvc.push("-o");
 			if canmount
 			{
-				recvc.arg("canmount=on");
+				recvc.push("canmount=on");
 			}
 			else
 			{
-				recvc.arg("canmount=off");
+				recvc.push("canmount=off");
 			}
 			if (!encrypted) && (targetencrypted) && (inherit_encryption)
 			{
 				//-x encryption -x keylocation -x keyformat 
 				// these options will inherit the encryption options of the receiving parent dataset
 				info!("{}defaulting to inheriting encrypting on {} ... ",padding, targethost);
-				recvc.arg("-x");
-				recvc.arg("encryption");
-				recvc.arg("-x");
-				recvc.arg("keylocation");
-				recvc.arg("-x");
-				recvc.arg("keyformat");
-			}
-
-			recvc.arg("-F");
-			recvc.arg("-u");
-			recvc.arg(targetdataset);
-			recvc.stdin(recv_stdin);
-		let mut recvo = match recvc.stdout(Stdio::piped())
-			.spawn()
-			{
-				Err(e)=>{error!("{}Error creating recvo. Replication may have occured: {}",padding,e);return replication_status},
-				Ok(recvo)=>recvo,
-			};
-		debug!("{}Created recvc & recvo", padding);
-		let stdout = match recvo.stdout.take()
-								{
-									None=>{error!("{}Failed to take recvo.stdout. Replication may have occured.",padding);return replication_status;},
-									Some(o)=>o,
-								};
-		debug!("{}got stdout from recvo",padding);
-		let (send_output, recv_output) = (sendo.wait_with_output().await, recvo.wait_with_output().await);
+				recvc.push("-x");
+				recvc.push("encryption");
+				recvc.push("-x");
+				recvc.push("keylocation");
+				recvc.push("-x");
+				recvc.push("keyformat");
+			}
+
+			// with -F the receive destroys the snapshots on the target which no longer exist
+			// on the source. a job with its own retention rules for the target keeps them.
+			if opts.target_retention.is_none()
+			{
+				recvc.push("-F");
+			}
+			recvc.push("-u");
+			// the dataset is received into its own name on the target rather than
+			// under a parent (-e), as the job's target_mapping may give it another.
+			recvc.push(targetdataset);
+		debug!("{}zfs {}", padding, sendc.join(" "));
+		debug!("{}zfs {}", padding, recvc.join(" "));
+		let stages = pipeline::stages(&opts.pipeline, sourcehost, &sendc, targethost, &recvc);
+		let stage_names:Vec<&str> = stages.iter().map(|stage| stage.name.as_str()).collect();
+		debug!("{}pipeline: {}", padding, stage_names.join(" | "));
+		let outputs = exec.pipeline(&stages).await;
 		debug!("{}waited output and have results.",padding);
-		let so = match send_output
-						{
-							Err(e) => {error!("{}Failed to get send_output. Replication may have occured:{}",padding,e);return replication_status},
-							Ok(so)=>so,
-						};
-		if so.status.success()
+		// when one stage fails the stages before it usually fail as well, as the pipe they
+		// write to has been closed. a broken pipe isn't the cause of the failure, so the
+		// first failure which isn't one is the one returned.
+		let mut failure:Option<(bool, PiperError)> = None;
+		for (stage, output) in stages.iter().zip(outputs.iter())
 		{
-			let ro = match recv_output
-						{
-							Err(e) => {error!("{}Failed to get recv_output. Send was successful, but receive is unknown. Replication may have occured:{}",padding,e);return replication_status},
-							Ok(ro)=>ro,
-						};
-			if ro.status.success()
+			if output.success
 			{
-				replication_status=true;
-
-				let mut reader = BufReader::new(stdout).lines();
-				while let Some(line) = match reader.next_line().await {Ok(l)=>l,Err(e)=>{error!("{}Replication succeeded, but there was an error reading the results:{}",padding,e);return replication_status} }
-				{
-					info!("{}ZFS RECV: {}",padding, line);
-				}
+				continue;
 			}
-			else
+			let code = match output.code
+				{
+					None=>String::from(""),
+					Some(code)=>format!(" (exit {})", code),
+				};
+			error!("{}Stage \"{}\" on {} failed{}:", padding, stage.name, if stage.host.is_empty() {"localhost"} else {stage.host.as_str()}, code);
+			for line in output.stderr.lines()
 			{
-				error!("{}ZFS Receive failed.",padding);
+				error!("{}\t{}",padding,line);
 			}
+			let broken_pipe = output.code == Some(141) || (output.code.is_none() && output.stderr.trim() == "") || output.stderr.contains("Broken pipe");
+			let e = stage_error(stage, output);
+			failure = match failure
+				{
+					None=>Some((broken_pipe, e)),
+					Some((true, _)) if !broken_pipe=>Some((broken_pipe, e)),
+					other=>other,
+				};
 		}
-		else
+		match failure
 		{
-			error!("{}ZFS Send failed:",padding);
-			for x in &so.stderr 
-			{
-				error!("{}{}",padding,x);
-			}
+			Some((_, e))=>
+				{
+					error!("{}ZFS Send/Receive failed.",padding);
+					replication_status = Err(e);
+				},
+			None=>
+				{
+					// bookmark the snapshot just replicated so that it can still be used as
+					// the incremental base for this target if the snapshot itself is destroyed.
+					if !(send_no_op || recv_no_op)
+					{
+						// named after the parent of the target, as it was before targets could be mapped.
+						let targetparent = targetdataset.rsplit_once('/').map(|(parent, _)| parent).unwrap_or(targetdataset);
+						bookmarked = update_bookmark(exec, padding, sourcehost, snapshot_name, bookmark_name(sourcedataset, targethost, targetparent).as_str()).await;
+					}
+
+					if let Some(ro) = outputs.last()
+					{
+						for line in ro.stdout.lines()
+						{
+							info!("{}ZFS RECV: {}",padding, line);
+							opts.events.emit("recv_line", opts.name.as_str(), &[("target", Value::from(plan::host_and_dataset(targethost, targetdataset))), ("line", Value::from(line))]);
+						}
+					}
+					let bytes = outputs.first().and_then(|so| so.stderr.lines().filter_map(|line| line.strip_prefix("size\t")).next_back().and_then(|size| size.trim().parse::<u64>().ok()));
+					replication_status = Ok(bytes.unwrap_or(0));
+				},
 		}
 	}
 	debug!("{}REPLICATION Done",padding);
-	snapshot_hold(padding, sourcehost,snapshot_name, "hold");
-	let remote_target_snapshot=format!("{}/{}",targetdataset,rsplit_once(snapshot_name,'/'));
-	snapshot_hold(padding, targethost, remote_target_snapshot.as_str(),"hold");
+	// the previous snapshots are only released once the new one is held on both the
+	// source and the target, so the chain always has a held base. a replication which
+	// failed, or didn't really happen, leaves the holds as they were.
+	if replication_status.is_err() || send_no_op || recv_no_op
+	{
+		return replication_status
+	}
+	let remote_target_snapshot=format!("{}@{}",targetdataset,rsplit_once(snapshot_name,'@'));
+	let source_held = snapshot_hold(exec, opts, padding, sourcehost,snapshot_name, "hold", opts.hold_tag.as_str()).await;
+	let target_held = snapshot_hold(exec, opts, padding, targethost, remote_target_snapshot.as_str(),"hold", opts.hold_tag.as_str()).await;
+	if !(source_held && target_held)
+	{
+		error!("{}Not releasing the holds on the previous snapshots as the new one couldn't be held.", padding);
+	}
+	else
+	{
+		// when sending from a bookmark the previous snapshot is already gone from the source.
+		if !previous_snapshot_name.is_empty() && !previous_snapshot_name.contains('#')
+		{
+			release_holds(exec, opts, padding, sourcehost, previous_snapshot_name).await;
+		}
+		if !previous_target_snapshot_name.is_empty()
+		{
+			release_holds(exec, opts, padding, targethost, previous_target_snapshot_name).await;
+		}
+	}
+	// the snapshot was replicated, but without its bookmark the next replication has
+	// nothing to fall back on if it is destroyed, so the dataset fails all the same.
+	bookmarked.and(replication_status)
+}
+
+// what the jobs run together share: the limits on how many datasets are replicated
+// at once, the job locks and how to reach the hosts.
+struct JobLimits
+{
+	limits: Limits,
+	job_locks: Option<JobLocks>,
+	hosts: Hosts,
+}
+
+impl JobLimits
+{
+	fn new(piper:&Piper, mode:&Mode) -> JobLimits
+	{
+		let max_parallel = piper.max_parallel.unwrap_or(1);
+		let limits = match &piper.host_limits
+			{
+				None=>Limits::new(max_parallel, &BTreeMap::new()),
+				Some(host_limits)=>Limits::new(max_parallel, host_limits),
+			};
+		// planning doesn't change anything, so it can go ahead while a job is running.
+		let job_locks = match (&piper.job_lock_dir, mode)
+			{
+				(None, _)|(_, Mode::Plan{..})=>None,
+				(Some(dir), _)=>Some(JobLocks::new(Path::new(dir.as_str()))),
+			};
+		let hosts = match &piper.hosts
+			{
+				None=>Hosts::new(),
+				Some(hosts)=>hosts.clone(),
+			};
+		JobLimits{limits, job_locks, hosts}
+	}
+}
+
+// runs one job within job_limits, telling the events when it starts and finishes.
+async fn start_job(exec:&dyn ZfsExecutor, job_limits:&JobLimits, j:&Job, run:&Run<'_>) -> JobResult
+{
+	let name = job_name(j);
+	run.events.emit("job_started", name.as_str(), &[("source", Value::from(j.sourcedataset.as_str())), ("target", Value::from(target_names(j).join(" ")))]);
+	let job_result = process_job(exec, &job_limits.limits, &job_limits.hosts, j, job_limits.job_locks.as_ref(), run).await;
+	run.events.emit("job_finished", name.as_str(), &[("failed", Value::from(job_result.failed())),
+			("error", Value::from(job_result.error.as_ref().map(|e| e.class()))),
+			("datasets", Value::from(job_result.datasets.len())),
+			("failed_datasets", Value::from(job_result.datasets.iter().filter(|d| d.result.is_err()).count()))]);
+	job_result
+}
+
+// runs jobs (all at once, within the limits in the config) and returns their results.
+async fn run_jobs(exec:&dyn ZfsExecutor, piper:&Piper, jobs:Vec<&Job>, run:&Run<'_>) -> Vec<JobResult>
+{
+	let start_time = Local::now();
+	info!("--------------------------------------------------------------------------------");
+	info!("{}", start_time);
+	info!("Piper Beginning Replication Jobs");
+	let job_limits = JobLimits::new(piper, run.mode);
+	let results:Vec<JobResult> = join_all(jobs.into_iter().map(|j| start_job(exec, &job_limits, j, run))).await;
+	let end_time = Local::now();
+	info!("Piper Ending Replication Jobs");
+	info!("{}", end_time);
+	results
+}
+
+// plans every job, prints the plan, and saves it to output_path if there is one.
+// returns the exit code.
+async fn make_plan(exec:&dyn ZfsExecutor, piper:&Piper, config_path:&Path, output_path:Option<&Path>, json:bool, events:&Arc<Events>) -> i32
+{
+	let results = run_jobs(exec, piper, piper.jobs.iter().collect(), &Run{mode:&Mode::Plan{estimate:true}, events, send_no_op:false, recv_no_op:false}).await;
+	let exit_code = summary::summarize(&results);
+	let plan = Plan{created:Local::now().to_rfc3339(), config:config_path.display().to_string(), jobs:plan::job_plans(&results)};
+	if json
+	{
+		match serde_json::to_string_pretty(&plan)
+		{
+			Err(e)=>error!("Error writing the plan: {}", e),
+			Ok(text)=>println!("{}", text),
+		}
+	}
+	else
+	{
+		plan::print(&plan);
+	}
+	if let Some(path) = output_path
+	{
+		if let Err(e) = plan::save(&plan, path)
+		{
+			error!("{}", e);
+			return 1;
+		}
+		info!("Plan saved to \"{}\"", path.display());
+	}
+	exit_code
+}
+
+// carries out the plan saved at plan_path, unless anything has changed since it was
+// made, in which case nothing is done and the exit code is returned instead.
+async fn apply_plan(exec:&dyn ZfsExecutor, piper:&Piper, plan_path:&Path, events:&Arc<Events>, send_no_op:bool, recv_no_op:bool) -> Result<Vec<JobResult>, i32>
+{
+	let saved = match plan::load(plan_path)
+		{
+			Err((code, message))=>{error!("{}", message);return Err(code)},
+			Ok(saved)=>saved,
+		};
+	let jobs:Vec<&Job> = piper.jobs.iter().filter(|j| saved.jobs.iter().any(|job| job.name == job_name(j))).collect();
+	info!("Checking that the plan made {} is still what would be done.", saved.created);
+	let current = run_jobs(exec, piper, jobs.clone(), &Run{mode:&Mode::Plan{estimate:false}, events:&Arc::new(Events::none()), send_no_op:false, recv_no_op:false}).await;
+	let differences = plan::drift(&saved, &plan::job_plans(&current));
+	if !differences.is_empty()
+	{
+		error!("Not applying \"{}\" as things have changed since it was made:", plan_path.display());
+		for difference in differences.iter()
+		{
+			error!("\t{}", difference);
+		}
+		return Err(summary::EXIT_DRIFTED);
+	}
+	Ok(run_jobs(exec, piper, jobs, &Run{mode:&Mode::Apply(saved), events, send_no_op, recv_no_op}).await)
+}
+
+// the creation time of a snapshot, in seconds since the epoch.
+async fn get_snapshot_creation(exec:&dyn ZfsExecutor, padding:&str, host:&str, snapshot:&str) -> Result<i64, PiperError>
+{
+	debug!("{}zfs get -H -p -o value creation {}", padding, snapshot);
+	let creation_out = exec.zfs(host, &["get", "-H", "-p", "-o", "value", "creation", snapshot]).await;
+	if !creation_out.success
+	{
+		return Err(zfs_error(host, snapshot, "get", &creation_out));
+	}
+	let creation = creation_out.stdout.trim();
+	debug!("{}\tcreation:\"{}\"", padding, creation);
+	creation.parse().map_err(|_| PiperError::Config(format!("unexpected creation time \"{}\" of \"{}\"", creation, snapshot)))
+}
+
+// how far behind the source the target of a job is, against its warn_lag and max_lag.
+// a job which matches several datasets, or has several targets, is checked for each of them.
+async fn check_job(exec:&dyn ZfsExecutor, hosts:&Hosts, j:&Job) -> Vec<JobCheck>
+{
+	let name = job_name(j);
+	let padding = format!("[{}] ", name);
+	let unknown = |message:String| vec![JobCheck{name:name.clone(), status:Status::Unknown, message}];
+	let mut limits = Vec::new();
+	for (label, lag) in [("warn_lag", &j.warn_lag), ("max_lag", &j.max_lag)]
+	{
+		limits.push(match lag.as_deref().map(parse_duration)
+			{
+				None=>None,
+				Some(Err(e))=>return unknown(format!("bad {}: {}", label, e)),
+				Some(Ok(limit))=>Some(limit),
+			});
+	}
+	let (warn_lag, max_lag) = (limits[0], limits[1]);
+	let (sourcehost,sourcedataset)=split_host_and_dataset(hosts, &j.sourcedataset);
+	let sources = match get_sources(exec, padding.as_str(), j, sourcehost, sourcedataset).await
+		{
+			Err(e)=>return unknown(e.to_string()),
+			Ok(sources)=>sources,
+		};
+	if sources.is_empty()
+	{
+		return unknown(format!("no dataset matches \"{}\"", j.sourcedataset));
+	}
+	let targets = match map_targets(exec, hosts, j, sourcehost, &sources).await
+		{
+			Err(e)=>return unknown(e.to_string()),
+			Ok(targets)=>targets,
+		};
+	let mut checks = Vec::new();
+	for target in targets.iter()
+	{
+		for sourcedataset in sources.iter()
+		{
+			let check_name = match (finds_sources(j), j.targets.is_some())
+				{
+					(false, false)=>name.clone(),
+					(true, false)=>format!("{} ({})", name, sourcedataset),
+					(false, true)=>format!("{} ({})", name, target.target.targetdataset),
+					(true, true)=>format!("{} ({} -> {})", name, sourcedataset, target.target.targetdataset),
+				};
+			checks.push(check_dataset(exec, padding.as_str(), check_name, sourcehost, sourcedataset, target, (warn_lag, max_lag)).await);
+		}
+	}
+	checks
+}
+
+// checks how far the target's copy of sourcedataset lags behind it, against the job's
+// (warn_lag, max_lag).
+async fn check_dataset(exec:&dyn ZfsExecutor, padding:&str, name:String, sourcehost:&str, sourcedataset:&str, target:&JobTarget, (warn_lag, max_lag):(Option<std::time::Duration>, Option<std::time::Duration>)) -> JobCheck
+{
+	let targethost = target.host.as_str();
+	let targetdatasetname = target.map.target(sourcedataset);
+	let targetdatasetname = targetdatasetname.as_str();
+	let prefix = match &target.target.prefix
+		{
+			None=>"",
+			Some(s)=>s.as_str(),
+		};
+	let unknown = |message:String| JobCheck{name:name.clone(), status:Status::Unknown, message};
+	let source_snapshot = match get_most_recent_snapshot(exec, padding, sourcedataset, sourcehost, prefix).await
+		{
+			Err(e)=>return unknown(format!("can't find the newest snapshot on the source: {}", e)),
+			Ok(snapshot)=>format!("{}@{}", sourcedataset, snapshot),
+		};
+	let target_snapshot = match get_last_replicated_snapshot(exec, padding, targetdatasetname, targethost).await
+		{
+			Err(e)=>return unknown(format!("can't find the newest snapshot on the target: {}", e)),
+			Ok(snapshot) if snapshot.is_empty()=>
+				{
+					let status = if max_lag.is_some() {Status::Critical} else {Status::Warning};
+					return JobCheck{name:name.clone(), status, message:String::from("the target has no snapshots, it has never been replicated")};
+				},
+			Ok(snapshot)=>format!("{}@{}", targetdatasetname, snapshot),
+		};
+	let source_creation = match get_snapshot_creation(exec, padding, sourcehost, source_snapshot.as_str()).await
+		{
+			Err(e)=>return unknown(e.to_string()),
+			Ok(creation)=>creation,
+		};
+	let target_creation = match get_snapshot_creation(exec, padding, targethost, target_snapshot.as_str()).await
+		{
+			Err(e)=>return unknown(e.to_string()),
+			Ok(creation)=>creation,
+		};
+	debug!("{}\"{}\" was created at {}, \"{}\" at {}", padding, source_snapshot, source_creation, target_snapshot, target_creation);
+	let lag = std::time::Duration::from_secs((source_creation - target_creation).max(0) as u64);
+	check::lag_status(name.as_str(), lag, warn_lag, max_lag)
+}
+
+// checks every job with a warn_lag or max_lag, and returns the nagios exit code.
+async fn check_jobs(exec:&dyn ZfsExecutor, piper:&Piper) -> i32
+{
+	let hosts = match &piper.hosts
+		{
+			None=>Hosts::new(),
+			Some(hosts)=>hosts.clone(),
+		};
+	let mut checks = Vec::new();
+	for j in piper.jobs.iter().filter(|j| j.warn_lag.is_some() || j.max_lag.is_some())
+	{
+		checks.append(&mut check_job(exec, &hosts, j).await);
+	}
+	let (lines, code) = check::report(&checks);
+	for line in lines.iter()
+	{
+		println!("{}", line);
+	}
+	code
+}
+
+fn save_simulation(fake:&FakeExecutor)
+{
+	if let Err(e) = fake.save()
+	{
+		error!("{}", e);
+	}
+}
+
+// the history file, if there is one. when simulating, only a history file given in the config is used.
+fn history_file(piper:&Piper, simulating:bool) -> Option<&str>
+{
+	match (&piper.history_file, simulating)
+	{
+		(Some(path), _) if path.is_empty()=>None,
+		(Some(path), _)=>Some(path.as_str()),
+		(None, false)=>Some("/var/lib/piper/history.jsonl"),
+		(None, true)=>None,
+	}
+}
+
+// adds the sends made in a run to the history file, if there is one.
+fn write_history(piper:&Piper, results:&[JobResult], simulating:bool)
+{
+	if let Some(path) = history_file(piper, simulating)
+	{
+		debug!("Writing history to \"{}\"", path);
+		if let Err(e) = history::append(Path::new(path), results)
+		{
+			error!("{}", e);
+		}
+	}
+}
 
-	if previous_snapshot_name != ""
+// tells the notifiers about the jobs which failed or recovered in a run. when
+// simulating, only a state file given in the config is used.
+async fn notify_results(piper:&Piper, results:&[JobResult], simulating:bool)
+{
+	let notifiers = match &piper.notifiers
+		{
+			None=>return,
+			Some(notifiers)=>notifiers,
+		};
+	let state_file = match (&piper.notify_state_file, simulating)
+		{
+			(Some(path), _) if path.is_empty()=>None,
+			(Some(path), _)=>Some(path.as_str()),
+			(None, false)=>Some("/var/lib/piper/notify.json"),
+			(None, true)=>None,
+		};
+	notify::notify(notifiers, state_file.map(Path::new), results, simulating).await;
+}
+
+// adds the results of a run to the metrics file, if there is one.
+fn write_metrics(piper:&Piper, results:&[JobResult])
+{
+	if let Some(path) = &piper.metrics_file
 	{
-		snapshot_hold(padding, sourcehost, previous_snapshot_name,"release");
-		let remote_previous_target_snapshot=format!("{}/{}",targetdataset,rsplit_once(previous_snapshot_name,'/'));
-		snapshot_hold(padding, targethost, remote_previous_target_snapshot.as_str(),"release");
+		debug!("Writing metrics to \"{}\"", path);
+		if let Err(e) = metrics::write(Path::new(path), results)
+		{
+			error!("{}", e);
+		}
 	}
-	return replication_status
 }
 
 #[tokio::main]
@@ -868,6 +2795,20 @@ async fn main()
 	let mut skip_argument=false;
 	let mut send_no_op=false;
 	let mut recv_no_op=false;
+	let mut simulate_file_path:Option<&Path> = None;
+	let mut daemon=false;
+	let mut planning=false;
+	let mut plan_output_path:Option<&Path> = None;
+	let mut plan_json=false;
+	let mut apply_plan_path:Option<&Path> = None;
+	let mut report=false;
+	let mut report_file_path:Option<&Path> = None;
+	let mut events_file_path:Option<&str> = None;
+	let mut show_history=false;
+	let mut checking=false;
+	let mut show_stats=false;
+	let mut history_job:Option<String> = None;
+	let mut history_since:Option<&str> = None;
 
 	for i in start..end
 	{
@@ -895,6 +2836,127 @@ async fn main()
 							error!("No config file on command line.");
 						}
 					}
+				"--simulate" =>
+					{
+						if (i+1) < end
+						{
+							simulate_file_path = Some(Path::new(&args[i+1]));
+							skip_argument = true;
+						}
+						else
+						{
+							error!("No simulation file on command line.");
+						}
+					}
+				"--daemon" =>
+					{
+						daemon = true;
+					}
+				"plan" =>
+					{
+						planning = true;
+					}
+				"-o" =>
+					{
+						if (i+1) < end
+						{
+							plan_output_path = Some(Path::new(&args[i+1]));
+							skip_argument = true;
+						}
+						else
+						{
+							error!("No plan file on command line.");
+						}
+					}
+				"--json" =>
+					{
+						plan_json = true;
+					}
+				"--report" =>
+					{
+						if (i+1) < end && args[i+1] == "json"
+						{
+							report = true;
+							skip_argument = true;
+						}
+						else
+						{
+							println!("The only report format is \"json\".");
+							usage();
+						}
+					}
+				"--report-file" =>
+					{
+						if (i+1) < end
+						{
+							report_file_path = Some(Path::new(&args[i+1]));
+							skip_argument = true;
+						}
+						else
+						{
+							error!("No report file on command line.");
+						}
+					}
+				"--events" =>
+					{
+						if (i+1) < end
+						{
+							events_file_path = Some(&args[i+1]);
+							skip_argument = true;
+						}
+						else
+						{
+							error!("No events file on command line.");
+						}
+					}
+				"apply" =>
+					{
+						if (i+1) < end
+						{
+							apply_plan_path = Some(Path::new(&args[i+1]));
+							skip_argument = true;
+						}
+						else
+						{
+							error!("No plan file on command line.");
+						}
+					}
+				"check" =>
+					{
+						checking = true;
+					}
+				"history" =>
+					{
+						show_history = true;
+					}
+				"stats" =>
+					{
+						show_stats = true;
+					}
+				"--job" =>
+					{
+						if (i+1) < end
+						{
+							history_job = Some(args[i+1].clone());
+							skip_argument = true;
+						}
+						else
+						{
+							error!("No job name on command line.");
+						}
+					}
+				"--since" =>
+					{
+						if (i+1) < end
+						{
+							history_since = Some(&args[i+1]);
+							skip_argument = true;
+						}
+						else
+						{
+							error!("No time on command line.");
+						}
+					}
 				"-n" =>
 					{
 						send_no_op = true;
@@ -917,7 +2979,7 @@ async fn main()
 					} 
 				"-c"|"--configtest" =>
 					{
-						do_walk=!do_walk;
+						do_walk = !do_walk;
 					}
 				_ =>
 					{
@@ -942,15 +3004,554 @@ async fn main()
 		process::exit(0);
 	}
 
-	let start_time = Local::now();
-	info!("--------------------------------------------------------------------------------");
-	info!("{}", start_time);
-	info!("Piper Beginning Replication Jobs");
-	for j in &piper.jobs
+	// looking at the history doesn't run anything.
+	if show_history || show_stats
 	{
-		process_job(&j, send_no_op, recv_no_op).await;
-	} 
-	let end_time = Local::now();
-	info!("Piper Ending Replication Jobs");
-	info!("{}", end_time);
+		let since = match history_since.map(history::parse_since)
+			{
+				None=>None,
+				Some(Err(e))=>{error!("{}", e);process::exit(1)},
+				Some(Ok(since))=>Some(since),
+			};
+		let path = match history_file(&piper, simulate_file_path.is_some())
+			{
+				None=>{error!("There is no history file.");process::exit(1)},
+				Some(path)=>path,
+			};
+		let entries = match history::read(Path::new(path), &history::Filter{job:history_job, since})
+			{
+				Err(e)=>{error!("{}", e);process::exit(1)},
+				Ok(entries)=>entries,
+			};
+		if show_stats
+		{
+			history::stats(&entries);
+		}
+		else
+		{
+			history::print(&entries);
+		}
+		process::exit(0);
+	}
+
+	// the lock for the whole run. when simulating, only a lock file given in the config is used.
+	// planning and checking don't change anything, so they don't need the lock.
+	let lock_file = match (&piper.lock_file, simulate_file_path)
+		{
+			_ if planning || checking=>"",
+			(Some(path), _)=>path.as_str(),
+			(None, None)=>"/var/run/piper.lock",
+			(None, Some(_))=>"",
+		};
+	let run_lock = if lock_file.is_empty()
+		{
+			None
+		}
+		else
+		{
+			match Lock::acquire(Path::new(lock_file))
+			{
+				Err(e)=>{error!("Not running: {}.", e);process::exit(summary::EXIT_LOCKED)},
+				Ok(l)=>Some(l),
+			}
+		};
+
+	let fake = match simulate_file_path
+		{
+			None=>None,
+			Some(path)=>match FakeExecutor::load(path)
+				{
+					Err(e)=>{error!("{}", e);process::exit(3)},
+					Ok(fake)=>Some(fake),
+				},
+		};
+	let process_executor = ProcessExecutor::new(match &piper.hosts
+		{
+			None=>Hosts::new(),
+			Some(hosts)=>hosts.clone(),
+		});
+	let exec:&dyn ZfsExecutor = match &fake
+		{
+			None=>&process_executor,
+			Some(fake)=>fake,
+		};
+
+	let events = Arc::new(match events_file_path
+		{
+			None=>Events::none(),
+			Some(path)=>match Events::open(path)
+				{
+					Err(e)=>{error!("{}", e);process::exit(1)},
+					Ok(events)=>events,
+				},
+		});
+
+	let exit_code = if daemon
+		{
+			daemon::run(fake.as_ref(), json_file_path, piper, &events, send_no_op, recv_no_op, if report {Some(report_file_path)} else {None}).await
+		}
+		else if checking
+		{
+			check_jobs(exec, &piper).await
+		}
+		else if planning
+		{
+			make_plan(exec, &piper, json_file_path, plan_output_path, plan_json, &events).await
+		}
+		else
+		{
+			let results = match apply_plan_path
+				{
+					None=>Ok(run_jobs(exec, &piper, piper.jobs.iter().collect(), &Run{mode:&Mode::Run, events:&events, send_no_op, recv_no_op}).await),
+					Some(plan_path)=>apply_plan(exec, &piper, plan_path, &events, send_no_op, recv_no_op).await,
+				};
+			match results
+			{
+				Err(exit_code)=>exit_code,
+				Ok(results)=>
+					{
+						if let Some(fake) = &fake
+						{
+							save_simulation(fake);
+						}
+						write_metrics(&piper, &results);
+						write_history(&piper, &results, fake.is_some());
+						notify_results(&piper, &results, fake.is_some()).await;
+						if report
+						{
+							if let Err(e) = report::write(&results, report_file_path, false)
+							{
+								error!("{}", e);
+							}
+						}
+						summary::summarize(&results)
+					},
+			}
+		};
+	// process::exit doesn't run destructors, so the lock has to be released first.
+	drop(run_lock);
+	process::exit(exit_code);
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+
+	fn snapshot(name:&str, guid:&str, createtxg:u64) -> Snapshot
+	{
+		Snapshot{name:String::from(name), guid:String::from(guid), createtxg}
+	}
+
+	fn steps(steps:&[IncrementalStep]) -> Vec<(&str, &str, bool)>
+	{
+		steps.iter().map(|step| (step.from.as_str(), step.to.as_str(), step.intermediates)).collect()
+	}
+
+	#[test]
+	fn common_snapshot_is_the_newest_on_both()
+	{
+		let source = vec![snapshot("tank/data@c", "3", 30), snapshot("tank/data@b", "2", 20), snapshot("tank/data@a", "1", 10)];
+		let target = vec![snapshot("backup/data@b", "2", 5), snapshot("backup/data@a", "1", 4)];
+		let common = find_common_snapshot("backup/data", &source, &target).unwrap();
+		assert_eq!(common.source.name, "tank/data@b");
+		assert_eq!(common.target.name, "backup/data@b");
+		assert!(common.target_only.is_empty());
+	}
+
+	#[test]
+	fn common_snapshot_is_matched_by_guid()
+	{
+		let source = vec![snapshot("tank/data@renamed", "2", 20), snapshot("tank/data@a", "1", 10)];
+		let target = vec![snapshot("backup/data@local", "9", 6), snapshot("backup/data@b", "2", 5)];
+		let common = find_common_snapshot("backup/data", &source, &target).unwrap();
+		assert_eq!(common.source.name, "tank/data@renamed");
+		assert_eq!(common.target.name, "backup/data@b");
+		assert_eq!(common.target_only, vec![String::from("backup/data@local")]);
+	}
+
+	#[test]
+	fn common_snapshot_prefers_a_snapshot_to_its_bookmark()
+	{
+		let source = vec![snapshot("tank/data#piper_backup", "2", 20), snapshot("tank/data@b", "2", 20)];
+		let target = vec![snapshot("backup/data@b", "2", 5)];
+		assert_eq!(find_common_snapshot("backup/data", &source, &target).unwrap().source.name, "tank/data@b");
+		let source = vec![snapshot("tank/data@c", "3", 30), snapshot("tank/data#piper_backup", "2", 20)];
+		assert_eq!(find_common_snapshot("backup/data", &source, &target).unwrap().source.name, "tank/data#piper_backup");
+	}
+
+	#[test]
+	fn no_common_snapshot_is_a_diverged_target()
+	{
+		let source = vec![snapshot("tank/data@a", "1", 10)];
+		let target = vec![snapshot("backup/data@x", "9", 5)];
+		assert!(matches!(find_common_snapshot("backup/data", &source, &target), Err(PiperError::DivergedTarget{..})));
+		assert!(matches!(find_common_snapshot("backup/data", &source, &[]), Err(PiperError::DivergedTarget{..})));
+		assert!(matches!(find_common_snapshot("backup/data", &[], &target), Err(PiperError::DivergedTarget{..})));
+	}
+
+	#[test]
+	fn incremental_steps_without_intermediates()
+	{
+		let source = vec![snapshot("tank/data@c", "3", 30), snapshot("tank/data@b", "2", 20), snapshot("tank/data@a", "1", 10)];
+		let plan = incremental_steps(&source, &source[2], "tank/data@c", 30, "", false);
+		assert_eq!(steps(&plan), vec![("tank/data@a", "tank/data@c", false)]);
+	}
+
+	#[test]
+	fn incremental_steps_with_intermediates()
+	{
+		let source = vec![snapshot("tank/data@c", "3", 30), snapshot("tank/data@b", "2", 20), snapshot("tank/data@a", "1", 10)];
+		let plan = incremental_steps(&source, &source[2], "tank/data@c", 30, "", true);
+		assert_eq!(steps(&plan), vec![("tank/data@a", "tank/data@c", true)]);
+	}
+
+	#[test]
+	fn incremental_steps_with_a_prefix_chain_the_matching_snapshots()
+	{
+		let source = vec![snapshot("tank/data@auto-d", "4", 40), snapshot("tank/data@manual-c", "3", 30), snapshot("tank/data@auto-b", "2", 20), snapshot("tank/data@auto-a", "1", 10)];
+		let plan = incremental_steps(&source, &source[3], "tank/data@auto-d", 40, "auto-", true);
+		assert_eq!(steps(&plan), vec![("tank/data@auto-a", "tank/data@auto-b", false), ("tank/data@auto-b", "tank/data@auto-d", false)]);
+	}
+
+	#[test]
+	fn incremental_steps_from_a_bookmark()
+	{
+		let source = vec![snapshot("tank/data@c", "3", 30), snapshot("tank/data@b", "2", 20), snapshot("tank/data#piper_backup", "1", 10)];
+		let plan = incremental_steps(&source, &source[2], "tank/data@c", 30, "", true);
+		assert_eq!(steps(&plan), vec![("tank/data#piper_backup", "tank/data@b", false), ("tank/data@b", "tank/data@c", true)]);
+		let plan = incremental_steps(&source[1..], &source[2], "tank/data@b", 20, "", true);
+		assert_eq!(steps(&plan), vec![("tank/data#piper_backup", "tank/data@b", false)]);
+	}
+
+	async fn run_job(fake:&FakeExecutor, job:&str) -> JobResult
+	{
+		let j:Job = serde_json::from_str(job).unwrap();
+		process_job(fake, &Limits::new(1, &BTreeMap::new()), &Hosts::new(), &j, None, &Run{mode:&Mode::Run, events:&Arc::new(Events::none()), send_no_op:false, recv_no_op:false}).await
+	}
+
+	// the names of the snapshots of dataset on host, oldest first.
+	async fn snapshot_names(fake:&FakeExecutor, host:&str, dataset:&str) -> Vec<String>
+	{
+		let list_out = fake.zfs(host, &["list", "-H", "-t", "snapshot", "-o", "name", "-s", "createtxg", dataset]).await;
+		list_out.stdout.lines().map(|line| rsplit_once(line, '@')).collect()
+	}
+
+	const JOB:&str = r#"{"sourcedataset":"tank/data", "targetdataset":"backup:backup"}"#;
+
+	#[tokio::test]
+	async fn full_send()
+	{
+		let fake = FakeExecutor::from_json(r#"{"hosts":{
+				"localhost":{"datasets":[{"name":"tank"}, {"name":"tank/data", "snapshots":[{"name":"one"}, {"name":"two"}]}]},
+				"backup":{"datasets":[{"name":"backup"}]}}}"#);
+		let result = run_job(&fake, JOB).await;
+		assert!(!result.failed());
+		assert!(matches!(result.datasets[0].result, Ok(Outcome::Full)));
+		assert_eq!(result.datasets[0].target, "backup/data");
+		assert!(snapshot_names(&fake, "backup", "backup/data").await.contains(&String::from("two")));
+	}
+
+	#[tokio::test]
+	async fn incremental_send()
+	{
+		let fake = FakeExecutor::from_json(r#"{"hosts":{
+				"localhost":{"datasets":[{"name":"tank"}, {"name":"tank/data", "snapshots":[{"name":"one", "guid":11, "createtxg":3}, {"name":"two", "guid":12, "createtxg":4}]}]},
+				"backup":{"datasets":[{"name":"backup"}, {"name":"backup/data", "snapshots":[{"name":"one", "guid":11, "createtxg":3}]}]}}}"#);
+		let result = run_job(&fake, JOB).await;
+		assert!(matches!(result.datasets[0].result, Ok(Outcome::Incremental)));
+		assert_eq!(result.datasets[0].sends[0].from, "tank/data@one");
+		assert_eq!(snapshot_names(&fake, "backup", "backup/data").await, vec!["one", "two"]);
+		// and now there is nothing left to send.
+		let result = run_job(&fake, JOB).await;
+		assert!(matches!(result.datasets[0].result, Ok(Outcome::UpToDate)));
+	}
+
+	#[tokio::test]
+	async fn bookmark_fallback()
+	{
+		// the common snapshot has been destroyed on the source, but not its bookmark.
+		let fake = FakeExecutor::from_json(r#"{"hosts":{
+				"localhost":{"datasets":[{"name":"tank"}, {"name":"tank/data", "snapshots":[{"name":"two", "guid":12, "createtxg":4}],
+					"bookmarks":[{"name":"piper_backup_backup", "guid":11, "createtxg":3}]}]},
+				"backup":{"datasets":[{"name":"backup"}, {"name":"backup/data", "snapshots":[{"name":"one", "guid":11, "createtxg":3}]}]}}}"#);
+		let result = run_job(&fake, JOB).await;
+		assert!(matches!(result.datasets[0].result, Ok(Outcome::Incremental)));
+		assert_eq!(result.datasets[0].sends[0].from, "tank/data#piper_backup_backup");
+		assert_eq!(snapshot_names(&fake, "backup", "backup/data").await, vec!["one", "two"]);
+	}
+
+	#[tokio::test]
+	async fn diverged_target()
+	{
+		let fake = FakeExecutor::from_json(r#"{"hosts":{
+				"localhost":{"datasets":[{"name":"tank"}, {"name":"tank/data", "snapshots":[{"name":"one", "guid":11, "createtxg":3}, {"name":"two", "guid":12, "createtxg":4}]}]},
+				"backup":{"datasets":[{"name":"backup"}, {"name":"backup/data", "snapshots":[{"name":"other", "guid":99, "createtxg":3}]}]}}}"#);
+		let result = run_job(&fake, JOB).await;
+		assert!(result.failed());
+		assert!(matches!(result.datasets[0].result, Err(PiperError::DivergedTarget{..})));
+		assert_eq!(snapshot_names(&fake, "backup", "backup/data").await, vec!["other"]);
+	}
+
+	#[tokio::test]
+	async fn interrupted_receive_is_resumed()
+	{
+		let fake = FakeExecutor::from_json(r#"{"hosts":{
+				"localhost":{"datasets":[{"name":"tank"}, {"name":"tank/data", "snapshots":[{"name":"one", "guid":11, "createtxg":3}, {"name":"two", "guid":12, "createtxg":4}]}]},
+				"backup":{"datasets":[{"name":"backup"}, {"name":"backup/data", "snapshots":[{"name":"one", "guid":11, "createtxg":3}]}], "interrupt_receives":1}}}"#);
+		let result = run_job(&fake, JOB).await;
+		assert!(result.failed());
+		let token_out = fake.zfs("backup", &["get", "-H", "-o", "value", "receive_resume_token", "backup/data"]).await;
+		assert!(!matches!(token_out.stdout.trim(), ""|"-"));
+		let result = run_job(&fake, JOB).await;
+		assert!(matches!(result.datasets[0].result, Ok(Outcome::Resumed)));
+		assert_eq!(snapshot_names(&fake, "backup", "backup/data").await, vec!["one", "two"]);
+	}
+
+	#[tokio::test]
+	async fn deep_include_passes_through_its_ancestors()
+	{
+		let fake = FakeExecutor::from_json(r#"{"hosts":{
+				"localhost":{"datasets":[{"name":"tank"}, {"name":"tank/data", "snapshots":[{"name":"one"}]},
+					{"name":"tank/data/a", "snapshots":[{"name":"one"}]}, {"name":"tank/data/a/b", "snapshots":[{"name":"one"}]},
+					{"name":"tank/data/a/b/c", "snapshots":[{"name":"one"}]}, {"name":"tank/data/d", "snapshots":[{"name":"one"}]}]},
+				"backup":{"datasets":[{"name":"backup"}]}}}"#);
+		let result = run_job(&fake, r#"{"sourcedataset":"tank/data", "targetdataset":"backup:backup", "recursive":true, "include":["tank/data/a/b/*"]}"#).await;
+		assert!(!result.failed());
+		let replicated:Vec<&str> = result.datasets.iter().map(|dataset| dataset.dataset.as_str()).collect();
+		assert_eq!(replicated, vec!["tank/data", "tank/data/a/b/c"]);
+		assert_eq!(snapshot_names(&fake, "backup", "backup/data/a/b/c").await, vec!["one"]);
+		assert!(snapshot_names(&fake, "backup", "backup/data/a").await.is_empty());
+	}
+
+	#[tokio::test]
+	async fn skip_property_on_a_source()
+	{
+		let fake = FakeExecutor::from_json(r#"{"hosts":{
+				"localhost":{"datasets":[{"name":"tank"}, {"name":"tank/data", "properties":{"piper:skip":"on"}, "snapshots":[{"name":"one"}]},
+					{"name":"tank/other", "snapshots":[{"name":"one"}]}]},
+				"backup":{"datasets":[{"name":"backup"}]}}}"#);
+		assert!(run_job(&fake, JOB).await.datasets.is_empty());
+		let result = run_job(&fake, r#"{"sourcedataset":"tank/*", "targetdataset":"backup:backup"}"#).await;
+		let replicated:Vec<&str> = result.datasets.iter().map(|dataset| dataset.dataset.as_str()).collect();
+		assert_eq!(replicated, vec!["tank/other"]);
+	}
+
+	#[tokio::test]
+	async fn snapshot_before_send_makes_one_snapshot_per_prefix()
+	{
+		let fake = FakeExecutor::from_json(r#"{"hosts":{
+				"localhost":{"datasets":[{"name":"tank"}, {"name":"tank/data"}]},
+				"backup":{"datasets":[{"name":"backup"}]},
+				"offsite":{"datasets":[{"name":"pool"}]}}}"#);
+		let result = run_job(&fake, r#"{"sourcedataset":"tank/data", "snapshot_before_send":true, "prefix":"DAILY_",
+				"targets":[{"targetdataset":"backup:backup"}, {"targetdataset":"offsite:pool", "prefix":"HOURLY_"}, {"targetdataset":"offsite:pool/copy", "prefix":"HOURLY_"}]}"#).await;
+		assert!(!result.failed());
+		let snapshots = snapshot_names(&fake, "", "tank/data").await;
+		assert_eq!(snapshots.len(), 2);
+		assert!(snapshots[0].starts_with("DAILY_"));
+		assert!(snapshots[1].starts_with("HOURLY_"));
+		assert!(snapshot_names(&fake, "offsite", "pool/data").await[0].starts_with("HOURLY_"));
+	}
+
+	// the tags of the holds on snapshot on host.
+	async fn hold_tags(fake:&FakeExecutor, host:&str, snapshot:&str) -> Vec<String>
+	{
+		let holds_out = fake.zfs(host, &["holds", "-H", snapshot]).await;
+		holds_out.stdout.lines().filter_map(|line| line.split('\t').nth(1)).map(String::from).collect()
+	}
+
+	const TAG:&str = "piper:tank/data:backup:backup";
+
+	#[tokio::test]
+	async fn holds_move_to_the_new_base_once_it_is_held()
+	{
+		let fake = FakeExecutor::from_json(r#"{"hosts":{
+				"localhost":{"datasets":[{"name":"tank"}, {"name":"tank/data", "snapshots":[{"name":"one", "guid":11, "createtxg":3, "holds":["piper:tank/data:backup:backup"]}, {"name":"two", "guid":12, "createtxg":4}]}]},
+				"backup":{"datasets":[{"name":"backup"}, {"name":"backup/data", "snapshots":[{"name":"one", "guid":11, "createtxg":3, "holds":["piper:tank/data:backup:backup"]}]}]}}}"#);
+		assert!(!run_job(&fake, JOB).await.failed());
+		assert_eq!(hold_tags(&fake, "localhost", "tank/data@two").await, vec![TAG]);
+		assert_eq!(hold_tags(&fake, "backup", "backup/data@two").await, vec![TAG]);
+		assert!(hold_tags(&fake, "localhost", "tank/data@one").await.is_empty());
+		assert!(hold_tags(&fake, "backup", "backup/data@one").await.is_empty());
+		for (host, dataset) in [("localhost", "tank/data"), ("backup", "backup/data")]
+		{
+			let commands = fake.commands(host);
+			let held = commands.iter().position(|c| *c == format!("zfs hold {} {}@two", TAG, dataset)).unwrap();
+			let released = commands.iter().position(|c| *c == format!("zfs release {} {}@one", TAG, dataset)).unwrap();
+			assert!(held < released);
+		}
+	}
+
+	#[tokio::test]
+	async fn holds_stay_when_the_new_base_cant_be_held()
+	{
+		// the new snapshot already has the tag, so holding it fails.
+		let fake = FakeExecutor::from_json(r#"{"hosts":{
+				"localhost":{"datasets":[{"name":"tank"}, {"name":"tank/data", "snapshots":[{"name":"one", "guid":11, "createtxg":3, "holds":["piper:tank/data:backup:backup"]}, {"name":"two", "guid":12, "createtxg":4, "holds":["piper:tank/data:backup:backup"]}]}]},
+				"backup":{"datasets":[{"name":"backup"}, {"name":"backup/data", "snapshots":[{"name":"one", "guid":11, "createtxg":3, "holds":["piper:tank/data:backup:backup"]}]}]}}}"#);
+		run_job(&fake, JOB).await;
+		assert_eq!(hold_tags(&fake, "localhost", "tank/data@one").await, vec![TAG]);
+		assert_eq!(hold_tags(&fake, "backup", "backup/data@one").await, vec![TAG]);
+		assert!(!fake.commands("localhost").iter().any(|c| c.starts_with("zfs release")));
+		assert!(!fake.commands("backup").iter().any(|c| c.starts_with("zfs release")));
+	}
+
+	#[tokio::test]
+	async fn chains_sharing_a_snapshot_keep_each_others_holds()
+	{
+		// the source is replicated offsite by another job too, and the target is replicated
+		// on from the backup host by a third.
+		let fake = FakeExecutor::from_json(r#"{"hosts":{
+				"localhost":{"datasets":[{"name":"tank"}, {"name":"tank/data", "snapshots":[{"name":"one", "guid":11, "createtxg":3, "holds":["piper:tank/data:backup:backup", "piper:offsite:offsite:pool"]}, {"name":"two", "guid":12, "createtxg":4}]}]},
+				"backup":{"datasets":[{"name":"backup"}, {"name":"backup/data", "snapshots":[{"name":"one", "guid":11, "createtxg":3, "holds":["piper:tank/data:backup:backup", "piper:relay:far:pool/data"]}]}]}}}"#);
+		assert!(!run_job(&fake, JOB).await.failed());
+		assert_eq!(hold_tags(&fake, "localhost", "tank/data@one").await, vec!["piper:offsite:offsite:pool"]);
+		assert_eq!(hold_tags(&fake, "backup", "backup/data@one").await, vec!["piper:relay:far:pool/data"]);
+		assert_eq!(hold_tags(&fake, "localhost", "tank/data@two").await, vec![TAG]);
+		// and the other job's run leaves this one's hold on the new base.
+		let offsite = r#"{"name":"offsite", "sourcedataset":"tank/data", "targetdataset":"offsite:pool"}"#;
+		let fake = FakeExecutor::from_json(r#"{"hosts":{
+				"localhost":{"datasets":[{"name":"tank"}, {"name":"tank/data", "snapshots":[{"name":"one", "guid":11, "createtxg":3, "holds":["piper:offsite:offsite:pool"]}, {"name":"two", "guid":12, "createtxg":4, "holds":["piper:tank/data:backup:backup"]}, {"name":"three", "guid":13, "createtxg":5}]}]},
+				"offsite":{"datasets":[{"name":"pool"}, {"name":"pool/data", "snapshots":[{"name":"one", "guid":11, "createtxg":3, "holds":["piper:offsite:offsite:pool"]}]}]}}}"#);
+		assert!(!run_job(&fake, offsite).await.failed());
+		assert_eq!(hold_tags(&fake, "localhost", "tank/data@two").await, vec![TAG]);
+		assert_eq!(hold_tags(&fake, "localhost", "tank/data@three").await, vec!["piper:offsite:offsite:pool"]);
+		assert!(hold_tags(&fake, "localhost", "tank/data@one").await.is_empty());
+	}
+
+	#[tokio::test]
+	async fn legacy_holds_are_released_once_the_chain_holds_the_new_base()
+	{
+		let fake = FakeExecutor::from_json(r#"{"hosts":{
+				"localhost":{"datasets":[{"name":"tank"}, {"name":"tank/data", "snapshots":[{"name":"one", "guid":11, "createtxg":3, "holds":["piper"]}, {"name":"two", "guid":12, "createtxg":4}]}]},
+				"backup":{"datasets":[{"name":"backup"}, {"name":"backup/data", "snapshots":[{"name":"one", "guid":11, "createtxg":3, "holds":["piper"]}]}]}}}"#);
+		assert!(!run_job(&fake, JOB).await.failed());
+		assert!(hold_tags(&fake, "localhost", "tank/data@one").await.is_empty());
+		assert!(hold_tags(&fake, "backup", "backup/data@one").await.is_empty());
+		assert_eq!(hold_tags(&fake, "localhost", "tank/data@two").await, vec![TAG]);
+		assert_eq!(hold_tags(&fake, "backup", "backup/data@two").await, vec![TAG]);
+		let commands = fake.commands("localhost");
+		let held = commands.iter().position(|c| *c == format!("zfs hold {} tank/data@two", TAG)).unwrap();
+		let released = commands.iter().position(|c| c == "zfs release piper tank/data@one").unwrap();
+		assert!(held < released);
+	}
+
+	#[tokio::test]
+	async fn a_second_target_reuses_the_source_lookups()
+	{
+		let fake = FakeExecutor::from_json(r#"{"hosts":{
+				"localhost":{"datasets":[{"name":"tank"}, {"name":"tank/data", "snapshots":[{"name":"one"}, {"name":"two"}]}]},
+				"backup":{"datasets":[{"name":"backup"}]},
+				"offsite":{"datasets":[{"name":"pool"}]}}}"#);
+		let job = r#"{"sourcedataset":"tank/data", "targets":[{"targetdataset":"backup:backup"}, {"targetdataset":"offsite:pool"}]}"#;
+		assert!(!run_job(&fake, job).await.failed());
+		let lookups:Vec<String> = fake.commands("localhost").into_iter().filter(|c| c.starts_with("zfs list") || c.starts_with("zfs get")).collect();
+		assert!(!lookups.is_empty());
+		for lookup in lookups.iter()
+		{
+			assert_eq!(lookups.iter().filter(|l| *l == lookup).count(), 1, "\"{}\" was run more than once", lookup);
+		}
+		assert_eq!(snapshot_names(&fake, "offsite", "pool/data").await, vec!["two"]);
+	}
+
+	#[tokio::test]
+	async fn events_of_a_run_come_in_order()
+	{
+		let fake = FakeExecutor::from_json(r#"{"hosts":{
+				"localhost":{"datasets":[{"name":"tank"}, {"name":"tank/data", "snapshots":[{"name":"one", "guid":11, "createtxg":3, "holds":["piper:tank/data:backup:backup"]}, {"name":"two", "guid":12, "createtxg":4}]}]},
+				"backup":{"datasets":[{"name":"backup"}, {"name":"backup/data", "snapshots":[{"name":"one", "guid":11, "createtxg":3, "holds":["piper:tank/data:backup:backup"]}]}]}}}"#);
+		let path = std::env::temp_dir().join(format!("piper-run-events-{}.json", std::process::id()));
+		let _ = std::fs::remove_file(&path);
+		let piper:Piper = serde_json::from_str(format!("{{\"jobs\":[{}]}}", JOB).as_str()).unwrap();
+		let events = Arc::new(Events::open(path.to_str().unwrap()).unwrap());
+		run_jobs(&fake, &piper, piper.jobs.iter().collect(), &Run{mode:&Mode::Run, events:&events, send_no_op:false, recv_no_op:false}).await;
+		let text = std::fs::read_to_string(&path).unwrap();
+		let events:Vec<Value> = text.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+		let names:Vec<(&str, &str)> = events.iter().map(|e| (e["event"].as_str().unwrap(), e["snapshot"].as_str().unwrap_or(""))).collect();
+		// the holds are moved as part of the send, before it is finished.
+		assert_eq!(names, vec![("job_started", ""), ("send_started", ""), ("recv_line", ""),
+				("hold_placed", "tank/data@two"), ("hold_placed", "backup/data@two"), ("hold_released", "tank/data@one"), ("hold_released", "backup/data@one"),
+				("send_finished", ""), ("job_finished", "")]);
+		assert_eq!(events[1]["from"], "tank/data@one");
+		assert_eq!(events[1]["to"], "tank/data@two");
+		assert_eq!(events[1]["target"], "backup:backup/data");
+		assert_eq!(events[7]["error"], Value::Null);
+		assert_eq!(events[8]["failed"], false);
+		assert_eq!(events[8]["datasets"], 1);
+		std::fs::remove_file(&path).unwrap();
+	}
+
+	#[test]
+	fn bookmark_names_dont_collide()
+	{
+		assert_eq!(bookmark_name("tank/data", "backup", "tank/backups"), "tank/data#piper_backup:tank_2f_backups");
+		assert_eq!(bookmark_name("tank/data", "", "backup"), "tank/data#piper_backup");
+		let targets = [("host", "a_b"), ("host", "a/b"), ("host", "a:b"), ("host:a", "b"), ("", "host:a_b"), ("", "host_3a_a_5f_b"), ("host.lan", "a-b.c")];
+		let names:Vec<String> = targets.iter().map(|(host, dataset)| bookmark_name("tank/data", host, dataset)).collect();
+		for (i, name) in names.iter().enumerate()
+		{
+			assert!(!names[i+1..].contains(name), "\"{}\" is used for more than one target", name);
+			assert!(name.split_once('#').unwrap().1.chars().all(|c| c.is_ascii_alphanumeric() || "_.-:".contains(c)));
+		}
+	}
+
+	#[tokio::test]
+	async fn a_snapshot_which_cant_be_bookmarked_fails_the_dataset()
+	{
+		let fake = FakeExecutor::from_json(r#"{"hosts":{
+				"localhost":{"datasets":[{"name":"tank"}, {"name":"tank/data", "snapshots":[{"name":"one", "guid":11, "createtxg":3}, {"name":"two", "guid":12, "createtxg":4}]}], "fail_commands":["zfs bookmark"]},
+				"backup":{"datasets":[{"name":"backup"}, {"name":"backup/data", "snapshots":[{"name":"one", "guid":11, "createtxg":3}]}]}}}"#);
+		let result = run_job(&fake, JOB).await;
+		assert!(result.failed());
+		match &result.datasets[0].result
+		{
+			Err(e @ PiperError::Command{command, ..})=>
+				{
+					assert_eq!(command, "zfs bookmark tank/data@two tank/data#piper_backup:backup");
+					assert_eq!(e.class(), "command_failure");
+				},
+			_=>panic!("the dataset didn't fail"),
+		}
+		// the snapshot was replicated, and the chain moved on to it.
+		assert_eq!(snapshot_names(&fake, "backup", "backup/data").await, vec!["one", "two"]);
+		assert_eq!(hold_tags(&fake, "localhost", "tank/data@two").await, vec![TAG]);
+	}
+
+	#[tokio::test]
+	async fn a_replicated_snapshot_is_bookmarked()
+	{
+		let fake = FakeExecutor::from_json(r#"{"hosts":{
+				"localhost":{"datasets":[{"name":"tank"}, {"name":"tank/data", "snapshots":[{"name":"one", "guid":11, "createtxg":3}, {"name":"two", "guid":12, "createtxg":4}]}]},
+				"backup":{"datasets":[{"name":"backup"}, {"name":"backup/data", "snapshots":[{"name":"one", "guid":11, "createtxg":3}]}]}}}"#);
+		assert!(!run_job(&fake, JOB).await.failed());
+		let bookmarks = fake.zfs("", &["list", "-H", "-t", "bookmark", "-o", "name,guid", "tank/data"]).await;
+		assert_eq!(bookmarks.stdout, "tank/data#piper_backup:backup\t12\n");
+	}
+
+	#[tokio::test]
+	async fn hooks_are_run_under_timeout_with_the_job_in_their_environment()
+	{
+		let fake = FakeExecutor::from_json(r#"{"hosts":{
+				"localhost":{"datasets":[{"name":"tank"}, {"name":"tank/data", "snapshots":[{"name":"one", "guid":11, "createtxg":3}, {"name":"two", "guid":12, "createtxg":4}]}]},
+				"backup":{"datasets":[{"name":"backup"}, {"name":"backup/data", "snapshots":[{"name":"one", "guid":11, "createtxg":3}]}]}}}"#);
+		let job = r#"{"name":"root", "sourcedataset":"tank/data", "targetdataset":"backup:backup",
+				"pre_hook":{"command":"echo pre", "timeout":"5m"}, "post_hook":{"command":"echo post", "host":"target"}}"#;
+		assert!(!run_job(&fake, job).await.failed());
+		let pre = fake.commands("localhost").into_iter().find(|c| c.starts_with("timeout")).unwrap();
+		assert!(pre.starts_with("timeout -k 10s 300s sh -c export "));
+		assert!(pre.ends_with("\necho pre"));
+		for variable in ["PIPER_JOB=root", "PIPER_HOOK=pre_hook", "PIPER_SOURCE_HOST=''", "PIPER_SOURCE_DATASET=tank/data", "PIPER_TARGET_HOST=backup", "PIPER_TARGET_DATASET=backup/data"]
+		{
+			assert!(pre.contains(format!(" {} ", variable).as_str()) || pre.contains(format!(" {}\n", variable).as_str()), "{} isn't in \"{}\"", variable, pre);
+		}
+		assert!(!pre.contains("PIPER_RESULT"));
+		// a hook on a remote host has its script quoted once more for the remote shell.
+		let post = fake.commands("backup").into_iter().find(|c| c.starts_with("timeout")).unwrap();
+		assert!(post.starts_with("timeout -k 10s 3600s sh -c 'export "));
+		for variable in ["PIPER_HOOK=post_hook", "PIPER_RESULT=ok", "PIPER_ERROR_CLASS=", "PIPER_FROM_SNAPSHOT=tank/data@one", "PIPER_SNAPSHOT=tank/data@two", "PIPER_DATASETS=1", "PIPER_FAILED_DATASETS=0"]
+		{
+			assert!(post.contains(variable), "{} isn't in \"{}\"", variable, post);
+		}
+	}
 }
@@ -0,0 +1,266 @@
+/*
+	mapping
+	where each dataset a job replicates ends up on the target. by default the job's
+	sourcedataset is received into its targetdataset under its own name (the last
+	part of its path), and its children under that, so "tank/home" replicated to
+	"backup" is "backup/home" and "tank/home/alice" is "backup/home/alice". the
+	"target_mapping" of a job changes the name the sourcedataset is given:
+	"full_path" keeps its whole path ("backup/tank/home"), "strip_prefix" keeps
+	its path without the prefix given (and so implies full_path), "rename" gives it
+	another name in place of its own, and "prepend_host" puts the name of the
+	source host in front ("backup/server1/tank/home" with full_path). children
+	always keep their path below the sourcedataset. a job whose sourcedataset
+	matches several datasets maps each of them the same way, and two of them can't
+	end up in the same place. every name on the target is worked out here, so the
+	lookups, the receive, the holds and the locks all agree.
+*/
+use serde::{Deserialize, Serialize};
+use crate::error::PiperError;
+use crate::executor::ZfsExecutor;
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct TargetMapping
+{
+	pub full_path: Option<bool>,
+	pub strip_prefix: Option<String>,
+	pub rename: Option<String>,
+	pub prepend_host: Option<bool>,
+}
+
+// the datasets on the target of a job.
+#[derive(Clone)]
+pub struct TargetMap
+{
+	// each of the job's sources, and its target.
+	roots: Vec<(String, String)>,
+}
+
+impl TargetMap
+{
+	// sourcehost is "" for the local host, whose name is asked for if prepend_host needs it.
+	pub async fn new(exec:&dyn ZfsExecutor, mapping:Option<&TargetMapping>, sourcehost:&str, sources:&[String], targetdataset:&str) -> Result<TargetMap, PiperError>
+	{
+		let host = match mapping.and_then(|mapping| mapping.prepend_host)
+			{
+				Some(true)=>host_name(exec, sourcehost).await?,
+				_=>String::from(""),
+			};
+		let mut roots:Vec<(String, String)> = Vec::new();
+		for source in sources.iter()
+		{
+			let root = format!("{}/{}", targetdataset, map(mapping, host.as_str(), source.as_str())?);
+			if let Some((other, _)) = roots.iter().find(|(_, other_root)| *other_root == root)
+			{
+				return Err(PiperError::Config(format!("\"{}\" and \"{}\" would both be replicated into \"{}\"", other, source, root)));
+			}
+			roots.push((source.clone(), root));
+		}
+		Ok(TargetMap{roots})
+	}
+
+	// the job's sources, in the order given.
+	pub fn sources(&self) -> Vec<&str>
+	{
+		self.roots.iter().map(|(source, _)| source.as_str()).collect()
+	}
+
+	// the targets of the job's sources.
+	pub fn roots(&self) -> Vec<&str>
+	{
+		self.roots.iter().map(|(_, root)| root.as_str()).collect()
+	}
+
+	// the job's source which dataset is, or is under.
+	pub fn source_of(&self, dataset:&str) -> Option<&str>
+	{
+		self.roots.iter().map(|(source, _)| source.as_str()).filter(|source| is_under(dataset, source)).max_by_key(|source| source.len())
+	}
+
+	// the target of dataset, which is one of the job's sources or one of their children.
+	pub fn target(&self, dataset:&str) -> String
+	{
+		let (source, root) = match self.roots.iter().filter(|(source, _)| is_under(dataset, source.as_str())).max_by_key(|(source, _)| source.len())
+			{
+				// every dataset replicated is found by listing the job's sources.
+				None=>unreachable!("\"{}\" is not one of the sources {:?}", dataset, self.sources()),
+				Some(pair)=>pair,
+			};
+		match dataset.strip_prefix(source.as_str()).and_then(|rest| rest.strip_prefix('/'))
+		{
+			None=>root.clone(),
+			Some(rest)=>format!("{}/{}", root, rest),
+		}
+	}
+}
+
+// whether dataset is source or one of its children.
+fn is_under(dataset:&str, source:&str) -> bool
+{
+	dataset == source || dataset.strip_prefix(source).is_some_and(|rest| rest.starts_with('/'))
+}
+
+// the name of sourcedataset under the targetdataset. host is the name of the source host.
+fn map(mapping:Option<&TargetMapping>, host:&str, sourcedataset:&str) -> Result<String, PiperError>
+{
+	let mapping = match mapping
+		{
+			None=>return Ok(leaf(sourcedataset)),
+			Some(mapping)=>mapping,
+		};
+	let mut path = match (&mapping.strip_prefix, mapping.full_path)
+		{
+			(Some(prefix), _)=>
+				{
+					let prefix = prefix.trim_end_matches('/');
+					match sourcedataset.strip_prefix(prefix).and_then(|rest| rest.strip_prefix('/'))
+					{
+						None=>return Err(PiperError::Config(format!("strip_prefix \"{}\" is not a parent of \"{}\"", prefix, sourcedataset))),
+						Some(rest)=>String::from(rest),
+					}
+				},
+			(None, Some(true))=>String::from(sourcedataset),
+			(None, _)=>leaf(sourcedataset),
+		};
+	if let Some(name) = &mapping.rename
+	{
+		if name.is_empty() || name.contains(['/', '@', '#', ':'])
+		{
+			return Err(PiperError::Config(format!("can't rename \"{}\" to \"{}\"", sourcedataset, name)));
+		}
+		path = match path.rsplit_once('/')
+			{
+				None=>name.clone(),
+				Some((parent, _))=>format!("{}/{}", parent, name),
+			};
+	}
+	if !host.is_empty()
+	{
+		path = format!("{}/{}", host, path);
+	}
+	Ok(path)
+}
+
+fn leaf(dataset:&str) -> String
+{
+	String::from(dataset.rsplit('/').next().unwrap_or(dataset))
+}
+
+// the name of the source host in the target: the host given in sourcedataset (or the
+// name of its entry in hosts), or the local host's short hostname.
+async fn host_name(exec:&dyn ZfsExecutor, sourcehost:&str) -> Result<String, PiperError>
+{
+	if !sourcehost.is_empty()
+	{
+		return Ok(String::from(sourcehost));
+	}
+	let hostname_out = exec.run("", "hostname", &[]).await;
+	let hostname = hostname_out.stdout.trim().split('.').next().unwrap_or("");
+	if !hostname_out.success || hostname.is_empty()
+	{
+		return Err(PiperError::Command{host:String::from(""), command:String::from("hostname"), message:String::from(hostname_out.stderr.trim())});
+	}
+	Ok(String::from(hostname))
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+	use crate::fake::FakeExecutor;
+
+	fn mapping(full_path:Option<bool>, strip_prefix:Option<&str>, rename:Option<&str>, prepend_host:Option<bool>) -> TargetMapping
+	{
+		TargetMapping{full_path, strip_prefix:strip_prefix.map(String::from), rename:rename.map(String::from), prepend_host}
+	}
+
+	async fn target_map(mapping:Option<&TargetMapping>, sourcehost:&str, sources:&[&str]) -> Result<TargetMap, PiperError>
+	{
+		let fake = FakeExecutor::from_json(r#"{"hosts":{}}"#);
+		let sources:Vec<String> = sources.iter().map(|source| String::from(*source)).collect();
+		TargetMap::new(&fake, mapping, sourcehost, &sources, "backup").await
+	}
+
+	#[test]
+	fn names_under_the_targetdataset()
+	{
+		let table = [
+				(None, "tank/home", "home"),
+				(None, "tank", "tank"),
+				(Some(mapping(Some(false), None, None, None)), "tank/home", "home"),
+				(Some(mapping(Some(true), None, None, None)), "tank/home", "tank/home"),
+				(Some(mapping(None, Some("tank"), None, None)), "tank/home/alice", "home/alice"),
+				(Some(mapping(Some(false), Some("tank/"), None, None)), "tank/home/alice", "home/alice"),
+				(Some(mapping(None, None, Some("house"), None)), "tank/home", "house"),
+				(Some(mapping(Some(true), None, Some("house"), None)), "tank/home", "tank/house"),
+			];
+		for (mapping, source, name) in table.iter()
+		{
+			assert_eq!(map(mapping.as_ref(), "", source).unwrap(), *name, "{}", source);
+		}
+		assert_eq!(map(Some(&mapping(Some(true), None, None, Some(true))), "server1", "tank/home").unwrap(), "server1/tank/home");
+	}
+
+	#[test]
+	fn names_which_cant_be_mapped()
+	{
+		assert!(map(Some(&mapping(None, Some("pool"), None, None)), "", "tank/home").is_err());
+		// a prefix has to be a whole parent, not just the start of the name.
+		assert!(map(Some(&mapping(None, Some("tank/ho"), None, None)), "", "tank/home").is_err());
+		assert!(map(Some(&mapping(None, Some("tank/home"), None, None)), "", "tank/home").is_err());
+		for name in ["", "a/b", "a@b", "a#b", "host:a"]
+		{
+			assert!(map(Some(&mapping(None, None, Some(name), None)), "", "tank/home").is_err(), "{}", name);
+		}
+	}
+
+	#[tokio::test]
+	async fn children_keep_their_path_below_the_source()
+	{
+		let default = target_map(None, "", &["tank/home"]).await.unwrap();
+		assert_eq!(default.roots(), vec!["backup/home"]);
+		assert_eq!(default.target("tank/home"), "backup/home");
+		assert_eq!(default.target("tank/home/alice"), "backup/home/alice");
+		assert_eq!(default.target("tank/home/alice/mail"), "backup/home/alice/mail");
+		let full_path = target_map(Some(&mapping(Some(true), None, None, None)), "", &["tank/home"]).await.unwrap();
+		assert_eq!(full_path.target("tank/home/alice"), "backup/tank/home/alice");
+		let strip_prefix = target_map(Some(&mapping(None, Some("tank"), None, None)), "", &["tank/home"]).await.unwrap();
+		assert_eq!(strip_prefix.target("tank/home/alice"), "backup/home/alice");
+	}
+
+	#[tokio::test]
+	async fn each_dataset_goes_under_the_closest_source()
+	{
+		let map = target_map(None, "", &["tank/home", "tank/home/alice", "tank/homes"]).await.unwrap();
+		assert_eq!(map.target("tank/home/bob"), "backup/home/bob");
+		assert_eq!(map.target("tank/home/alice/mail"), "backup/alice/mail");
+		assert_eq!(map.target("tank/homes/carol"), "backup/homes/carol");
+		assert_eq!(map.source_of("tank/home/alice/mail"), Some("tank/home/alice"));
+		assert_eq!(map.source_of("tank/other"), None);
+	}
+
+	#[tokio::test]
+	async fn the_source_host_is_prepended()
+	{
+		let prepend = mapping(None, None, None, Some(true));
+		assert_eq!(target_map(Some(&prepend), "server1", &["tank/home"]).await.unwrap().target("tank/home/alice"), "backup/server1/home/alice");
+		// the local host's name is asked for.
+		assert_eq!(target_map(Some(&prepend), "", &["tank/home"]).await.unwrap().roots(), vec!["backup/localhost/home"]);
+	}
+
+	#[tokio::test]
+	async fn two_sources_cant_share_a_target()
+	{
+		match target_map(None, "", &["tank/home", "pool/home"]).await
+		{
+			Err(PiperError::Config(message))=>assert_eq!(message, "\"tank/home\" and \"pool/home\" would both be replicated into \"backup/home\""),
+			_=>panic!("two sources were mapped to the same target"),
+		}
+	}
+
+	#[tokio::test]
+	#[should_panic(expected = "\"pool/home\" is not one of the sources")]
+	async fn a_dataset_from_outside_the_sources_has_no_target()
+	{
+		target_map(None, "", &["tank/home"]).await.unwrap().target("pool/home");
+	}
+}
@@ -0,0 +1,252 @@
+/*
+	metrics
+	with "metrics_file" set in the config file piper writes the file after each run
+	in the prometheus text format, for node_exporter's textfile collector to pick up.
+	for each source and target there is the time of the last successful replication,
+	how far the target lags behind the newest snapshot on the source, the bytes sent
+	and the time taken by the last replication and whether it succeeded, and running
+	totals of the bytes sent and of the failures, by error class. a run only covers
+	some of the datasets (or none, if a job can't be run at all), so everything read
+	from the file as it was before the run is kept, and only what the run changed is
+	replaced or added to. the file is written alongside and renamed into place so the
+	collector never reads half of it.
+*/
+use chrono::Local;
+use std::{collections::BTreeMap, fs, path::{Path, PathBuf}};
+use crate::plan::host_and_dataset;
+use crate::summary::JobResult;
+
+// name, type and help of each metric, in the order they are written.
+const METRICS:[(&str, &str, &str); 8] = [
+	("piper_last_run_timestamp_seconds", "gauge", "When piper last finished a run."),
+	("piper_last_success_timestamp_seconds", "gauge", "When the target was last brought up to date with the source."),
+	("piper_lag_seconds", "gauge", "How much older the newest snapshot on the target is than the newest snapshot on the source."),
+	("piper_last_result", "gauge", "1 if the last replication succeeded, 0 if it failed."),
+	("piper_last_bytes_sent", "gauge", "The bytes sent by the last replication."),
+	("piper_last_duration_seconds", "gauge", "How long the last replication took."),
+	("piper_sent_bytes_total", "counter", "The bytes sent in all."),
+	("piper_failures_total", "counter", "The replications which have failed, by error class."),
+];
+
+// the values of each metric, by the labels of the series.
+type Series = BTreeMap<String, BTreeMap<String, f64>>;
+
+fn escape(value:&str) -> String
+{
+	value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+fn labels(pairs:&[(&str, &str)]) -> String
+{
+	let pairs:Vec<String> = pairs.iter().map(|(name, value)| format!("{}=\"{}\"", name, escape(value))).collect();
+	format!("{{{}}}", pairs.join(","))
+}
+
+// the series in a file written before, so that they can be carried forward. a file
+// which can't be read is started again.
+fn read(path:&Path) -> Series
+{
+	let mut series = Series::new();
+	let text = match fs::read_to_string(path)
+		{
+			Err(_)=>return series,
+			Ok(text)=>text,
+		};
+	for line in text.lines()
+	{
+		if line.starts_with('#') || line.trim() == ""
+		{
+			continue;
+		}
+		// label values are escaped so can't end the line, but can hold spaces.
+		let (key, value) = match line.rsplit_once(' ')
+			{
+				None=>continue,
+				Some(pair)=>pair,
+			};
+		let value:f64 = match value.parse()
+			{
+				Err(_)=>continue,
+				Ok(value)=>value,
+			};
+		let (name, labels) = match key.find('{')
+			{
+				None=>(key, ""),
+				Some(i)=>key.split_at(i),
+			};
+		series.entry(String::from(name)).or_default().insert(String::from(labels), value);
+	}
+	series
+}
+
+fn set(series:&mut Series, name:&str, labels:&str, value:f64)
+{
+	series.entry(String::from(name)).or_default().insert(String::from(labels), value);
+}
+
+fn add(series:&mut Series, name:&str, labels:&str, value:f64)
+{
+	*series.entry(String::from(name)).or_default().entry(String::from(labels)).or_insert(0.0) += value;
+}
+
+fn update(series:&mut Series, jobs:&[JobResult])
+{
+	let now = Local::now().timestamp() as f64;
+	set(series, "piper_last_run_timestamp_seconds", "", now);
+	for job in jobs.iter()
+	{
+		if let Some(e) = &job.error
+		{
+			add(series, "piper_failures_total", labels(&[("job", job.name.as_str()), ("source", ""), ("target", ""), ("class", e.class())]).as_str(), 1.0);
+		}
+		for dataset in job.datasets.iter()
+		{
+			let source = host_and_dataset(dataset.sourcehost.as_str(), dataset.dataset.as_str());
+			let target = host_and_dataset(dataset.targethost.as_str(), dataset.target.as_str());
+			let pair = labels(&[("job", job.name.as_str()), ("source", source.as_str()), ("target", target.as_str())]);
+			let pair = pair.as_str();
+			match &dataset.result
+			{
+				Ok(_)=>
+					{
+						set(series, "piper_last_success_timestamp_seconds", pair, (dataset.started.timestamp() as f64) + dataset.duration.as_secs_f64());
+						set(series, "piper_last_result", pair, 1.0);
+					},
+				Err(e)=>
+					{
+						set(series, "piper_last_result", pair, 0.0);
+						add(series, "piper_failures_total", labels(&[("job", job.name.as_str()), ("source", source.as_str()), ("target", target.as_str()), ("class", e.class())]).as_str(), 1.0);
+					},
+			}
+			if let Some(lag) = dataset.lag
+			{
+				set(series, "piper_lag_seconds", pair, lag as f64);
+			}
+			set(series, "piper_last_bytes_sent", pair, dataset.bytes() as f64);
+			set(series, "piper_last_duration_seconds", pair, dataset.duration.as_secs_f64());
+			add(series, "piper_sent_bytes_total", pair, dataset.bytes() as f64);
+		}
+	}
+}
+
+fn format(series:&Series) -> String
+{
+	let mut text = String::new();
+	for (name, kind, help) in METRICS.iter()
+	{
+		let values = match series.get(*name)
+			{
+				None=>continue,
+				Some(values)=>values,
+			};
+		text.push_str(format!("# HELP {} {}\n# TYPE {} {}\n", name, help, name, kind).as_str());
+		for (labels, value) in values.iter()
+		{
+			text.push_str(format!("{}{} {}\n", name, labels, value).as_str());
+		}
+	}
+	text
+}
+
+// adds the results of a run to the metrics file at path.
+pub fn write(path:&Path, jobs:&[JobResult]) -> Result<(), String>
+{
+	let mut series = read(path);
+	update(&mut series, jobs);
+	let temporary = PathBuf::from(format!("{}.tmp", path.display()));
+	fs::write(&temporary, format(&series)).map_err(|e| format!("Error writing metrics file \"{}\": {}", temporary.display(), e))?;
+	fs::rename(&temporary, path).map_err(|e| format!("Error renaming \"{}\" to \"{}\": {}", temporary.display(), path.display(), e))
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+	use chrono::TimeZone;
+	use std::time::Duration;
+	use crate::error::PiperError;
+	use crate::summary::{DatasetResult, Outcome, SendRecord};
+
+	const PAIR:&str = "{job=\"root\",source=\"tank/data\",target=\"backup:backup/data\"}";
+
+	fn dataset(result:Result<Outcome, PiperError>, bytes:u64, lag:Option<i64>) -> DatasetResult
+	{
+		let send = SendRecord{from:String::from(""), to:String::from("tank/data@b"), bytes, started:String::from(""), seconds:2.0, error:None};
+		DatasetResult{
+				dataset: String::from("tank/data"),
+				sourcehost: String::from(""),
+				targethost: String::from("backup"),
+				target: String::from("backup/data"),
+				started: Local.timestamp_opt(1_700_000_000, 0).unwrap(),
+				duration: Duration::from_secs(30),
+				sends: vec![send],
+				result,
+				plan: None,
+				lag,
+			}
+	}
+
+	fn job(datasets:Vec<DatasetResult>) -> JobResult
+	{
+		JobResult{name:String::from("root"), error:None, datasets, matched:None}
+	}
+
+	#[test]
+	fn success_is_recorded()
+	{
+		let mut series = Series::new();
+		update(&mut series, &[job(vec![dataset(Ok(Outcome::Full), 2048, Some(60))])]);
+		assert_eq!(series["piper_last_success_timestamp_seconds"][PAIR], 1_700_000_030.0);
+		assert_eq!(series["piper_last_result"][PAIR], 1.0);
+		assert_eq!(series["piper_lag_seconds"][PAIR], 60.0);
+		assert_eq!(series["piper_last_bytes_sent"][PAIR], 2048.0);
+		assert_eq!(series["piper_last_duration_seconds"][PAIR], 30.0);
+		assert_eq!(series["piper_sent_bytes_total"][PAIR], 2048.0);
+		assert!(!series.contains_key("piper_failures_total"));
+	}
+
+	#[test]
+	fn failure_keeps_the_last_success()
+	{
+		let mut series = Series::new();
+		update(&mut series, &[job(vec![dataset(Ok(Outcome::Full), 2048, None)])]);
+		update(&mut series, &[job(vec![dataset(Err(PiperError::SendFailure(String::from("broken pipe"))), 0, None)])]);
+		assert_eq!(series["piper_last_success_timestamp_seconds"][PAIR], 1_700_000_030.0);
+		assert_eq!(series["piper_last_result"][PAIR], 0.0);
+		assert_eq!(series["piper_failures_total"]["{job=\"root\",source=\"tank/data\",target=\"backup:backup/data\",class=\"send_failure\"}"], 1.0);
+		// the counters carry on from one run to the next.
+		assert_eq!(series["piper_sent_bytes_total"][PAIR], 2048.0);
+	}
+
+	#[test]
+	fn a_job_which_can_not_run_is_a_failure()
+	{
+		let mut series = Series::new();
+		let mut failed = job(Vec::new());
+		failed.error = Some(PiperError::Config(String::from("bad pattern")));
+		update(&mut series, &[failed]);
+		assert_eq!(series["piper_failures_total"]["{job=\"root\",source=\"\",target=\"\",class=\"config\"}"], 1.0);
+	}
+
+	#[test]
+	fn the_file_is_read_back()
+	{
+		let mut series = Series::new();
+		update(&mut series, &[job(vec![dataset(Ok(Outcome::Incremental), 2048, Some(60))])]);
+		let text = format(&series);
+		assert!(text.contains("# HELP piper_lag_seconds How much older the newest snapshot on the target is than the newest snapshot on the source.\n# TYPE piper_lag_seconds gauge\n"));
+		assert!(text.contains("# TYPE piper_sent_bytes_total counter\n"));
+		assert!(text.contains(format!("piper_lag_seconds{} 60\n", PAIR).as_str()));
+		let path = std::env::temp_dir().join(format!("piper-metrics-{}.prom", std::process::id()));
+		fs::write(&path, text.as_str()).unwrap();
+		let read_back = read(&path);
+		fs::remove_file(&path).unwrap();
+		assert_eq!(read_back, series);
+	}
+
+	#[test]
+	fn label_values_are_escaped()
+	{
+		assert_eq!(labels(&[("job", "a \"b\"\\c\nd")]), "{job=\"a \\\"b\\\"\\\\c\\nd\"}");
+	}
+}
@@ -0,0 +1,497 @@
+/*
+	notify
+	"notifiers" in the config file are told when a job fails, and when a job which
+	failed last time succeeds again, but not when a job which was fine is still
+	fine, or one which failed last time fails again. each notifier does one of three things: runs a "command" (with sh -c)
+	with the notification as json on its stdin, POSTs the json to a "webhook" (a
+	plain http:// url; for https use a command with curl), or pipes an email to
+	"sendmail" for the address given. a notifier can be limited to some "jobs",
+	and to a "severity" of "failure" or "recovery". whether each job failed last
+	time is kept in a small state file between runs. notifications aren't part of
+	replicating anything, so unlike everything else they don't go through the
+	executor. when simulating they are only logged, as hooks are only run by the
+	simulated executor.
+*/
+use chrono::Local;
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, fs, path::Path, process::Stdio, time::Duration};
+use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::TcpStream, process::Command, time::timeout};
+use crate::plan::host_and_dataset;
+use crate::summary::JobResult;
+
+// how long a notifier gets before it is given up on.
+const TIMEOUT:Duration = Duration::from_secs(30);
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Notifier
+{
+	pub command: Option<String>,
+	pub webhook: Option<String>,
+	// the address to email.
+	pub sendmail: Option<String>,
+	// "/usr/sbin/sendmail" by default.
+	pub sendmail_path: Option<String>,
+	// "failure" and/or "recovery". both if not given.
+	pub severity: Option<Vec<String>>,
+	// the names of the jobs to notify about. all jobs if not given.
+	pub jobs: Option<Vec<String>>,
+}
+
+impl Notifier
+{
+	fn wants(&self, severity:&str, job:&str) -> bool
+	{
+		self.severity.as_ref().is_none_or(|severities| severities.iter().any(|s| s == severity)) &&
+			self.jobs.as_ref().is_none_or(|jobs| jobs.iter().any(|j| j == job))
+	}
+
+	fn describe(&self) -> String
+	{
+		match (&self.command, &self.webhook, &self.sendmail)
+		{
+			(Some(command), _, _)=>format!("command \"{}\"", command),
+			(_, Some(url), _)=>format!("webhook \"{}\"", url),
+			(_, _, Some(address))=>format!("sendmail to \"{}\"", address),
+			_=>String::from("notifier"),
+		}
+	}
+}
+
+pub fn validate(notifiers:&[Notifier]) -> Result<(), String>
+{
+	for notifier in notifiers.iter()
+	{
+		let kinds = [notifier.command.is_some(), notifier.webhook.is_some(), notifier.sendmail.is_some()].iter().filter(|kind| **kind).count();
+		if kinds != 1
+		{
+			return Err(String::from("each notifier must have exactly one of \"command\", \"webhook\" or \"sendmail\""));
+		}
+		for severity in notifier.severity.iter().flatten()
+		{
+			if severity != "failure" && severity != "recovery"
+			{
+				return Err(format!("unknown notifier severity \"{}\" (must be \"failure\" or \"recovery\")", severity));
+			}
+		}
+		if let Some(url) = &notifier.webhook
+		{
+			parse_url(url.as_str())?;
+		}
+	}
+	Ok(())
+}
+
+#[derive(Serialize)]
+struct DatasetNotification
+{
+	source: String,
+	target: String,
+	// "ok" or "failed".
+	result: &'static str,
+	error_class: Option<&'static str>,
+	error: Option<String>,
+}
+
+#[derive(Serialize)]
+struct Notification
+{
+	// "failure" or "recovery".
+	severity: &'static str,
+	job: String,
+	time: String,
+	summary: String,
+	// an error which stopped the job before any dataset was looked at.
+	error_class: Option<&'static str>,
+	error: Option<String>,
+	datasets: Vec<DatasetNotification>,
+}
+
+impl Notification
+{
+	fn new(severity:&'static str, job:&JobResult) -> Notification
+	{
+		let ess = if job.datasets.len() == 1 {""} else {"s"};
+		let summary = match (&job.error, severity)
+			{
+				(Some(e), _)=>format!("Job \"{}\" FAILED ({}): {}", job.name, e.class(), e),
+				(None, "failure")=>format!("Job \"{}\" FAILED ({} of {} dataset{})", job.name, job.datasets.iter().filter(|d| d.result.is_err()).count(), job.datasets.len(), ess),
+				(None, _)=>format!("Job \"{}\" has recovered: OK ({} dataset{})", job.name, job.datasets.len(), ess),
+			};
+		Notification{
+			severity,
+			job: job.name.clone(),
+			time: Local::now().to_rfc3339(),
+			summary,
+			error_class: job.error.as_ref().map(|e| e.class()),
+			error: job.error.as_ref().map(|e| e.to_string()),
+			datasets: job.datasets.iter().map(|dataset| DatasetNotification{
+					source: host_and_dataset(dataset.sourcehost.as_str(), dataset.dataset.as_str()),
+					target: host_and_dataset(dataset.targethost.as_str(), dataset.target.as_str()),
+					result: if dataset.result.is_ok() {"ok"} else {"failed"},
+					error_class: dataset.result.as_ref().err().map(|e| e.class()),
+					error: dataset.result.as_ref().err().map(|e| e.to_string()),
+				}).collect(),
+		}
+	}
+
+	fn email(&self, address:&str) -> String
+	{
+		let mut body = format!("To: {}\nSubject: piper: {}\nContent-Type: text/plain; charset=utf-8\n\n{}\n\n", address, self.summary, self.summary);
+		for dataset in self.datasets.iter()
+		{
+			body.push_str(format!("\"{}\" -> \"{}\": {}\n", dataset.source, dataset.target, match &dataset.error
+				{
+					None=>String::from("ok"),
+					Some(e)=>format!("FAILED ({}): {}", dataset.error_class.unwrap_or(""), e),
+				}).as_str());
+		}
+		body
+	}
+}
+
+// runs program with input on its stdin.
+async fn run(program:&str, args:&[&str], input:&str) -> Result<(), String>
+{
+	let mut child = Command::new(program).args(args).stdin(Stdio::piped()).stdout(Stdio::null()).stderr(Stdio::piped()).kill_on_drop(true).spawn()
+		.map_err(|e| format!("Error running {}: {}", program, e))?;
+	if let Some(mut stdin) = child.stdin.take()
+	{
+		// a command which doesn't read its stdin isn't an error.
+		let _ = stdin.write_all(input.as_bytes()).await;
+	}
+	let output = match timeout(TIMEOUT, child.wait_with_output()).await
+		{
+			Err(_)=>return Err(format!("{} took longer than {}s", program, TIMEOUT.as_secs())),
+			Ok(output)=>output.map_err(|e| format!("Error running {}: {}", program, e))?,
+		};
+	if output.status.success()
+	{
+		Ok(())
+	}
+	else
+	{
+		Err(format!("{} failed ({}): {}", program, output.status, String::from_utf8_lossy(&output.stderr).trim()))
+	}
+}
+
+// the host, port and path of an http:// url.
+fn parse_url(url:&str) -> Result<(String, u16, String), String>
+{
+	let rest = match url.strip_prefix("http://")
+		{
+			None=>return Err(format!("webhook \"{}\" is not an http:// url (for https use a command notifier with curl)", url)),
+			Some(rest)=>rest,
+		};
+	let (authority, path) = match rest.find('/')
+		{
+			None=>(rest, "/"),
+			Some(i)=>rest.split_at(i),
+		};
+	let (host, port) = match authority.rsplit_once(':')
+		{
+			None=>(authority, 80),
+			Some((host, port))=>(host, port.parse().map_err(|_| format!("bad port \"{}\" in webhook \"{}\"", port, url))?),
+		};
+	if host.is_empty()
+	{
+		return Err(format!("no host in webhook \"{}\"", url));
+	}
+	Ok((String::from(host), port, String::from(path)))
+}
+
+async fn post(url:&str, body:&str) -> Result<(), String>
+{
+	let (host, port, path) = parse_url(url)?;
+	let request = format!("POST {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: piper\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", path, host, body.len(), body);
+	let exchange = async
+		{
+			let mut stream = TcpStream::connect((host.as_str(), port)).await?;
+			stream.write_all(request.as_bytes()).await?;
+			let mut response = Vec::new();
+			stream.read_to_end(&mut response).await?;
+			Ok::<Vec<u8>, std::io::Error>(response)
+		};
+	let response = match timeout(TIMEOUT, exchange).await
+		{
+			Err(_)=>return Err(format!("webhook \"{}\" took longer than {}s", url, TIMEOUT.as_secs())),
+			Ok(response)=>response.map_err(|e| format!("Error posting to webhook \"{}\": {}", url, e))?,
+		};
+	let response = String::from_utf8_lossy(&response);
+	let status = response.lines().next().unwrap_or("");
+	match status.split_whitespace().nth(1)
+	{
+		Some(code) if code.starts_with('2')=>Ok(()),
+		_=>Err(format!("webhook \"{}\" answered \"{}\"", url, status)),
+	}
+}
+
+async fn send(notifier:&Notifier, notification:&Notification) -> Result<(), String>
+{
+	let json = serde_json::to_string(notification).map_err(|e| format!("Error writing notification: {}", e))?;
+	match (&notifier.command, &notifier.webhook, &notifier.sendmail)
+	{
+		(Some(command), _, _)=>run("sh", &["-c", command.as_str()], format!("{}\n", json).as_str()).await,
+		(_, Some(url), _)=>post(url.as_str(), json.as_str()).await,
+		(_, _, Some(address))=>
+			{
+				let sendmail = notifier.sendmail_path.as_deref().unwrap_or("/usr/sbin/sendmail");
+				run(sendmail, &["-t"], notification.email(address.as_str()).as_str()).await
+			},
+		_=>Err(String::from("notifier has no \"command\", \"webhook\" or \"sendmail\"")),
+	}
+}
+
+// whether each job failed the last time it was run, by name.
+fn read_state(path:Option<&Path>) -> BTreeMap<String, bool>
+{
+	path.and_then(|path| fs::read_to_string(path).ok()).and_then(|text| serde_json::from_str(text.as_str()).ok()).unwrap_or_default()
+}
+
+fn write_state(path:&Path, state:&BTreeMap<String, bool>) -> Result<(), String>
+{
+	if let Some(directory) = path.parent()
+	{
+		if !directory.as_os_str().is_empty()
+		{
+			fs::create_dir_all(directory).map_err(|e| format!("Error creating directory \"{}\": {}", directory.display(), e))?;
+		}
+	}
+	let text = serde_json::to_string_pretty(state).map_err(|e| format!("Error writing notify state: {}", e))?;
+	fs::write(path, text).map_err(|e| format!("Error writing notify state file \"{}\": {}", path.display(), e))
+}
+
+// tells the notifiers about each job which has failed, or recovered, since it was
+// last run. without a state file a recovery can't be told from a job which has never
+// failed, nor a failure from one which carries on, so every failure is notified. when simulating, the notifications
+// are logged rather than sent.
+pub async fn notify(notifiers:&[Notifier], state_path:Option<&Path>, jobs:&[JobResult], simulating:bool)
+{
+	let mut state = read_state(state_path);
+	for job in jobs.iter()
+	{
+		let failed = job.failed();
+		let severity = match (failed, state.get(&job.name))
+			{
+				(true, Some(true))=>None,
+				(true, _)=>Some("failure"),
+				(false, Some(true))=>Some("recovery"),
+				(false, _)=>None,
+			};
+		state.insert(job.name.clone(), failed);
+		let severity = match severity
+			{
+				None=>continue,
+				Some(severity)=>severity,
+			};
+		let notification = Notification::new(severity, job);
+		for notifier in notifiers.iter().filter(|notifier| notifier.wants(severity, job.name.as_str()))
+		{
+			if simulating
+			{
+				info!("Simulating: not notifying {} of the {} of job \"{}\"", notifier.describe(), severity, job.name);
+				continue;
+			}
+			info!("Notifying {} of the {} of job \"{}\"", notifier.describe(), severity, job.name);
+			if let Err(e) = send(notifier, &notification).await
+			{
+				error!("Can't notify {}: {}", notifier.describe(), e);
+			}
+		}
+	}
+	if let Some(path) = state_path
+	{
+		if let Err(e) = write_state(path, &state)
+		{
+			error!("{}", e);
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+	use tokio::net::TcpListener;
+	use crate::error::PiperError;
+
+	fn failed_job(name:&str) -> JobResult
+	{
+		JobResult{name:String::from(name), error:Some(PiperError::Config(String::from("broken"))), datasets:Vec::new(), matched:None}
+	}
+
+	fn good_job(name:&str) -> JobResult
+	{
+		JobResult{name:String::from(name), error:None, datasets:Vec::new(), matched:None}
+	}
+
+	// a file of its own for each test.
+	fn temp_path(test:&str) -> std::path::PathBuf
+	{
+		let path = std::env::temp_dir().join(format!("piper-notify-{}-{}", std::process::id(), test));
+		let _ = fs::remove_file(&path);
+		path
+	}
+
+	// a command notifier which appends the notification to path.
+	fn notifier(path:&Path) -> Notifier
+	{
+		Notifier{command:Some(format!("cat >> {}", path.display())), webhook:None, sendmail:None, sendmail_path:None, severity:None, jobs:None}
+	}
+
+	// the severity of each notification appended to path.
+	fn severities(path:&Path) -> Vec<String>
+	{
+		let text = fs::read_to_string(path).unwrap_or_default();
+		text.lines().map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap()["severity"].as_str().unwrap().to_string()).collect()
+	}
+
+	#[tokio::test]
+	async fn simulating_only_logs_notifications()
+	{
+		let path = temp_path("simulating");
+		notify(&[notifier(&path)], None, &[failed_job("root")], true).await;
+		assert!(!path.exists());
+		notify(&[notifier(&path)], None, &[failed_job("root")], false).await;
+		assert_eq!(severities(&path), vec!["failure"]);
+		fs::remove_file(&path).unwrap();
+	}
+
+	#[tokio::test]
+	async fn one_notification_for_each_change()
+	{
+		let path = temp_path("changes");
+		let state = temp_path("changes-state.json");
+		let runs = [good_job("root"), failed_job("root"), failed_job("root"), good_job("root"), good_job("root"), failed_job("root")];
+		for job in runs
+		{
+			notify(&[notifier(&path)], Some(&state), &[job], false).await;
+		}
+		assert_eq!(severities(&path), vec!["failure", "recovery", "failure"]);
+		// without a state file every failure is notified, and no recovery.
+		let _ = fs::remove_file(&path);
+		for job in [failed_job("root"), failed_job("root"), good_job("root")]
+		{
+			notify(&[notifier(&path)], None, &[job], false).await;
+		}
+		assert_eq!(severities(&path), vec!["failure", "failure"]);
+		let _ = fs::remove_file(&path);
+		let _ = fs::remove_file(&state);
+	}
+
+	#[test]
+	fn notifiers_filter_by_severity_and_job()
+	{
+		let mut filtered = notifier(Path::new("/dev/null"));
+		assert!(filtered.wants("failure", "root") && filtered.wants("recovery", "other"));
+		filtered.severity = Some(vec![String::from("failure")]);
+		assert!(filtered.wants("failure", "root"));
+		assert!(!filtered.wants("recovery", "root"));
+		filtered.jobs = Some(vec![String::from("root"), String::from("vms")]);
+		assert!(filtered.wants("failure", "vms"));
+		assert!(!filtered.wants("failure", "other"));
+		assert!(!filtered.wants("recovery", "vms"));
+	}
+
+	#[tokio::test]
+	async fn filtered_notifiers_are_left_out()
+	{
+		let failures = temp_path("failures");
+		let recoveries = temp_path("recoveries");
+		let other_job = temp_path("other-job");
+		let state = temp_path("filtered-state.json");
+		let notifiers = [
+				Notifier{severity:Some(vec![String::from("failure")]), ..notifier(&failures)},
+				Notifier{severity:Some(vec![String::from("recovery")]), ..notifier(&recoveries)},
+				Notifier{jobs:Some(vec![String::from("other")]), ..notifier(&other_job)},
+			];
+		notify(&notifiers, Some(&state), &[failed_job("root")], false).await;
+		notify(&notifiers, Some(&state), &[good_job("root")], false).await;
+		assert_eq!(severities(&failures), vec!["failure"]);
+		assert_eq!(severities(&recoveries), vec!["recovery"]);
+		assert!(!other_job.exists());
+		for path in [failures, recoveries, state]
+		{
+			let _ = fs::remove_file(&path);
+		}
+	}
+
+	#[test]
+	fn webhook_urls()
+	{
+		assert_eq!(parse_url("http://alerts.example.com:8080/piper/hook").unwrap(), (String::from("alerts.example.com"), 8080, String::from("/piper/hook")));
+		assert_eq!(parse_url("http://alerts.example.com").unwrap(), (String::from("alerts.example.com"), 80, String::from("/")));
+		assert!(parse_url("https://alerts.example.com/piper").is_err());
+		assert!(parse_url("http://alerts.example.com:http/piper").is_err());
+		assert!(parse_url("http:///piper").is_err());
+	}
+
+	// a local stand in for a webhook, which answers one request with status and
+	// returns the request it was sent.
+	async fn webhook(status:&'static str) -> (String, tokio::task::JoinHandle<String>)
+	{
+		let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let url = format!("http://127.0.0.1:{}/hooks/piper", listener.local_addr().unwrap().port());
+		let server = tokio::spawn(async move
+			{
+				let (mut stream, _) = listener.accept().await.unwrap();
+				let mut request:Vec<u8> = Vec::new();
+				let mut buffer = [0u8; 4096];
+				// read the headers, and then as much body as they say there is.
+				loop
+				{
+					let n = stream.read(&mut buffer).await.unwrap();
+					request.extend_from_slice(&buffer[..n]);
+					let text = String::from_utf8_lossy(&request).to_string();
+					if let Some((headers, body)) = text.split_once("\r\n\r\n")
+					{
+						let length:usize = headers.lines().filter_map(|line| line.strip_prefix("Content-Length: ")).next().unwrap().parse().unwrap();
+						if body.len() >= length || n == 0
+						{
+							break;
+						}
+					}
+					else if n == 0
+					{
+						break;
+					}
+				}
+				stream.write_all(format!("HTTP/1.1 {}\r\nContent-Length: 0\r\n\r\n", status).as_bytes()).await.unwrap();
+				String::from_utf8(request).unwrap()
+			});
+		(url, server)
+	}
+
+	#[tokio::test]
+	async fn webhooks_post_the_notification()
+	{
+		let (url, server) = webhook("204 No Content").await;
+		let hook = Notifier{command:None, webhook:Some(url.clone()), sendmail:None, sendmail_path:None, severity:None, jobs:None};
+		notify(&[hook], None, &[failed_job("root")], false).await;
+		let request = server.await.unwrap();
+		let (headers, body) = request.split_once("\r\n\r\n").unwrap();
+		let mut lines = headers.lines();
+		assert_eq!(lines.next(), Some("POST /hooks/piper HTTP/1.1"));
+		let headers:Vec<&str> = lines.collect();
+		assert!(headers.contains(&"Host: 127.0.0.1"));
+		assert!(headers.contains(&"Content-Type: application/json"));
+		assert!(headers.contains(&format!("Content-Length: {}", body.len()).as_str()));
+		assert!(headers.contains(&"Connection: close"));
+		let json:serde_json::Value = serde_json::from_str(body).unwrap();
+		assert_eq!(json["severity"], "failure");
+		assert_eq!(json["job"], "root");
+		assert_eq!(json["summary"], "Job \"root\" FAILED (config): configuration error: broken");
+		assert_eq!(json["error_class"], "config");
+		assert_eq!(json["error"], "configuration error: broken");
+		assert_eq!(json["datasets"], serde_json::json!([]));
+	}
+
+	#[tokio::test]
+	async fn webhooks_which_dont_answer_2xx_fail()
+	{
+		let (url, server) = webhook("500 Internal Server Error").await;
+		let result = post(url.as_str(), "{}").await;
+		server.await.unwrap();
+		assert_eq!(result, Err(format!("webhook \"{}\" answered \"HTTP/1.1 500 Internal Server Error\"", url)));
+	}
+}
@@ -0,0 +1,166 @@
+/*
+	parallel
+	jobs (and, optionally, the children of a recursive job) run concurrently. how
+	many datasets are replicated at once is limited overall by max_parallel, and for
+	any one host by its entry in host_limits, so a slow link to one host doesn't
+	take every slot and hold back replications to the others.
+*/
+use log::*;
+use std::collections::BTreeMap;
+use tokio::sync::{Semaphore, SemaphorePermit};
+
+pub struct Limits
+{
+	global: Semaphore,
+	hosts: BTreeMap<String, Semaphore>,
+}
+
+// held while a dataset is being replicated. the slots are given back when dropped.
+pub struct Permits<'a>
+{
+	_hosts: Vec<SemaphorePermit<'a>>,
+	_global: Option<SemaphorePermit<'a>>,
+}
+
+fn host_key(host:&str) -> &str
+{
+	if host.is_empty() {"localhost"} else {host}
+}
+
+impl Limits
+{
+	pub fn new(max_parallel:usize, host_limits:&BTreeMap<String, usize>) -> Limits
+	{
+		let mut hosts = BTreeMap::new();
+		for (host, limit) in host_limits.iter()
+		{
+			if *limit == 0
+			{
+				// a limit of 0 would mean nothing to or from the host ever runs.
+				warn!("host_limits for \"{}\" is 0, using 1 instead.", host);
+			}
+			hosts.insert(host.clone(), Semaphore::new(std::cmp::max(*limit, 1)));
+		}
+		if max_parallel == 0
+		{
+			warn!("max_parallel is 0, using 1 instead.");
+		}
+		Limits{global:Semaphore::new(std::cmp::max(max_parallel, 1)), hosts}
+	}
+
+	// waits for a slot on each of the hosts (if it has a limit) and then for a global slot.
+	// the host slots are taken first, and always in the same order, so that waiting for a
+	// busy host doesn't hold a global slot, and two datasets can't each hold a slot the
+	// other is waiting for.
+	pub async fn acquire(&self, padding:&str, sourcehost:&str, targethost:&str) -> Permits<'_>
+	{
+		let mut names = vec![host_key(sourcehost), host_key(targethost)];
+		names.sort();
+		names.dedup();
+		let mut hosts = Vec::new();
+		for name in names
+		{
+			if let Some(semaphore) = self.hosts.get(name)
+			{
+				if semaphore.available_permits() == 0
+				{
+					info!("{}Waiting for a free slot on host \"{}\"...", padding, name);
+				}
+				if let Ok(permit) = semaphore.acquire().await
+				{
+					hosts.push(permit);
+				}
+			}
+		}
+		if self.global.available_permits() == 0
+		{
+			debug!("{}Waiting for a free replication slot...", padding);
+		}
+		Permits{_hosts:hosts, _global:self.global.acquire().await.ok()}
+	}
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+	use futures::future::join_all;
+	use std::sync::Mutex;
+	use std::time::Duration;
+
+	// replicates each of the (sourcehost, targethost) pairs at once, within the limits, and
+	// returns the most replications there were at one time overall ("global") and to or
+	// from each host.
+	async fn most_at_once(limits:&Limits, pairs:&[(&str, &str)]) -> BTreeMap<String, usize>
+	{
+		// how many are running now, and the most there have been.
+		let running:Mutex<BTreeMap<String, (usize, usize)>> = Mutex::new(BTreeMap::new());
+		join_all(pairs.iter().enumerate().map(|(i, (sourcehost, targethost))|
+			{
+				let running = &running;
+				let mut names = vec!["global", host_key(sourcehost), host_key(targethost)];
+				names.sort();
+				names.dedup();
+				async move
+				{
+					let _permits = limits.acquire("", sourcehost, targethost).await;
+					for name in names.iter()
+					{
+						let mut running = running.lock().unwrap();
+						let (now, most) = running.entry(String::from(*name)).or_insert((0, 0));
+						*now += 1;
+						*most = std::cmp::max(*most, *now);
+					}
+					tokio::time::sleep(Duration::from_millis(2 + (i % 3) as u64)).await;
+					for name in names.iter()
+					{
+						running.lock().unwrap().get_mut(*name).unwrap().0 -= 1;
+					}
+				}
+			})).await;
+		running.into_inner().unwrap().into_iter().map(|(name, (_, most))| (name, most)).collect()
+	}
+
+	fn host_limits(limits:&[(&str, usize)]) -> BTreeMap<String, usize>
+	{
+		limits.iter().map(|(host, limit)| (String::from(*host), *limit)).collect()
+	}
+
+	#[tokio::test]
+	async fn no_more_than_max_parallel_run_at_once()
+	{
+		let pairs = [("", "backup"), ("", "offsite"), ("server1", ""), ("server2", "backup"), ("", "backup"), ("server1", "offsite")];
+		let most = most_at_once(&Limits::new(3, &BTreeMap::new()), &pairs).await;
+		assert_eq!(most["global"], 3);
+		let most = most_at_once(&Limits::new(1, &BTreeMap::new()), &pairs).await;
+		assert!(most.values().all(|most| *most == 1));
+	}
+
+	#[tokio::test]
+	async fn no_more_than_a_hosts_limit_run_to_or_from_it()
+	{
+		let pairs = [("", "backup"), ("", "backup"), ("", "backup"), ("server1", "backup"), ("backup", "offsite"), ("", "offsite"), ("", "offsite"), ("server1", "offsite")];
+		let most = most_at_once(&Limits::new(8, &host_limits(&[("backup", 2), ("localhost", 3), ("offsite", 1)])), &pairs).await;
+		assert_eq!(most["backup"], 2);
+		assert!(most["localhost"] <= 3);
+		assert_eq!(most["offsite"], 1);
+	}
+
+	#[tokio::test]
+	async fn the_tighter_of_the_two_limits_holds()
+	{
+		let pairs = [("", "backup"); 6];
+		assert_eq!(most_at_once(&Limits::new(2, &host_limits(&[("backup", 4)])), &pairs).await["backup"], 2);
+		assert_eq!(most_at_once(&Limits::new(4, &host_limits(&[("backup", 2)])), &pairs).await["global"], 2);
+	}
+
+	#[tokio::test]
+	async fn limits_of_0_are_1()
+	{
+		let pairs = [("", "backup"), ("", "offsite"), ("", "backup")];
+		let most = most_at_once(&Limits::new(0, &host_limits(&[("backup", 0)])), &pairs).await;
+		assert_eq!(most["global"], 1);
+		let most = most_at_once(&Limits::new(3, &host_limits(&[("backup", 0)])), &pairs).await;
+		assert_eq!(most["backup"], 1);
+	}
+}
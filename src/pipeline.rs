@@ -0,0 +1,85 @@
+/*
+	pipeline
+	the stages a replication stream passes through between zfs send and zfs recv.
+	by default there are none, and zfs send is piped straight into zfs recv. a job
+	can add compression (on the sending host, undone on the receiving host), a rate
+	limit (on the sending host, using pv) and an mbuffer (on the receiving host).
+	compression is only used when the stream crosses from one host to another.
+*/
+use crate::executor::Stage;
+use crate::error::PiperError;
+
+pub struct PipelineOptions
+{
+	pub compression: String,
+	// the size of the mbuffer, or "" for none.
+	pub mbuffer: String,
+	// bytes per second, or 0 for no limit.
+	pub rate_limit: u64,
+}
+
+impl PipelineOptions
+{
+	pub fn new(compression:&Option<String>, mbuffer:&Option<String>, rate_limit:&Option<u64>) -> Result<PipelineOptions, PiperError>
+	{
+		let compression = match compression
+			{
+				None=>String::from(""),
+				Some(s)=>s.clone(),
+			};
+		if !compression.is_empty() && compression != "zstd" && compression != "lz4"
+		{
+			return Err(PiperError::Config(format!("unknown compression \"{}\" (must be \"zstd\" or \"lz4\")", compression)));
+		}
+		Ok(PipelineOptions{
+			compression,
+			mbuffer: match mbuffer
+				{
+					None=>String::from(""),
+					Some(s)=>s.clone(),
+				},
+			rate_limit: match rate_limit
+				{
+					None=>0,
+					Some(n)=>*n,
+				},
+		})
+	}
+}
+
+fn stage(name:&str, host:&str, program:&str, args:&[&str]) -> Stage
+{
+	Stage{
+		name: String::from(name),
+		host: String::from(host),
+		program: String::from(program),
+		args: args.iter().map(|a| String::from(*a)).collect(),
+	}
+}
+
+// the stages from "zfs <send_args>" on sourcehost to "zfs <recv_args>" on targethost.
+pub fn stages(options:&PipelineOptions, sourcehost:&str, send_args:&[&str], targethost:&str, recv_args:&[&str]) -> Vec<Stage>
+{
+	let compress = !options.compression.is_empty() && sourcehost != targethost;
+	let rate = options.rate_limit.to_string();
+	let mut stages = vec![stage("zfs send", sourcehost, "zfs", send_args)];
+	if compress
+	{
+		stages.push(stage(options.compression.as_str(), sourcehost, options.compression.as_str(), &["-q", "-c"]));
+	}
+	if options.rate_limit > 0
+	{
+		stages.push(stage("pv", sourcehost, "pv", &["-q", "-L", rate.as_str()]));
+	}
+	if !options.mbuffer.is_empty()
+	{
+		stages.push(stage("mbuffer", targethost, "mbuffer", &["-q", "-s", "128k", "-m", options.mbuffer.as_str()]));
+	}
+	if compress
+	{
+		let name = format!("{} -d", options.compression);
+		stages.push(stage(name.as_str(), targethost, options.compression.as_str(), &["-q", "-d", "-c"]));
+	}
+	stages.push(stage("zfs recv", targethost, "zfs", recv_args));
+	stages
+}
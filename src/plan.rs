@@ -0,0 +1,428 @@
+/*
+	plan
+	"piper plan" works out what a run would do to each dataset without doing any of
+	it, and prints the plan, optionally saving it as json as well. "piper apply"
+	carries out a saved plan. it plans again first, and refuses to run at all if
+	anything has changed since the saved plan was made (a new snapshot on the
+	source, a snapshot destroyed on the target and so on), so that it does exactly
+	what the plan says.
+*/
+use serde::{Deserialize, Serialize};
+use std::{fs::File, path::Path};
+use crate::error::PiperError;
+use crate::summary::JobResult;
+
+// what is to be done to a dataset.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Action
+{
+	Full,
+	Incremental,
+	// finish an interrupted receive with "zfs send -t".
+	Resume,
+	UpToDate,
+	// something other than the state of the target stops the dataset being replicated.
+	Blocked,
+	Diverged,
+}
+
+impl Action
+{
+	pub fn name(&self) -> &'static str
+	{
+		match self
+		{
+			Action::Full=>"full",
+			Action::Incremental=>"incremental",
+			Action::Resume=>"resume",
+			Action::UpToDate=>"up to date",
+			Action::Blocked=>"blocked",
+			Action::Diverged=>"diverged",
+		}
+	}
+}
+
+// one incremental send: from a snapshot (or bookmark) to a newer snapshot.
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+pub struct IncrementalStep
+{
+	pub from: String,
+	pub to: String,
+	// send every snapshot between from and to as well (-I rather than -i).
+	pub intermediates: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct DatasetPlan
+{
+	pub sourcehost: String,
+	pub source: String,
+	pub targethost: String,
+	// the dataset which is received into, not the parent it is received under.
+	pub target: String,
+	pub action: Action,
+	// partial receive state on the target which is to be discarded first.
+	pub abort_partial: bool,
+	pub resume_token: String,
+	// the snapshot the target will have once the plan is carried out, and its guid,
+	// as a snapshot of the same name may have been destroyed and made again since.
+	pub snapshot: String,
+	pub guid: String,
+	// the incremental base on the source, and the same snapshot on the target.
+	pub from: String,
+	pub from_target: String,
+	pub steps: Vec<IncrementalStep>,
+	// the estimated size of what is to be sent, in bytes.
+	pub size: Option<u64>,
+	// why a blocked or diverged dataset can't be replicated.
+	pub reason: String,
+}
+
+impl DatasetPlan
+{
+	pub fn new(sourcehost:&str, source:&str, targethost:&str, target:&str) -> DatasetPlan
+	{
+		DatasetPlan{
+			sourcehost: String::from(sourcehost),
+			source: String::from(source),
+			targethost: String::from(targethost),
+			target: String::from(target),
+			action: Action::UpToDate,
+			abort_partial: false,
+			resume_token: String::from(""),
+			snapshot: String::from(""),
+			guid: String::from(""),
+			from: String::from(""),
+			from_target: String::from(""),
+			steps: Vec::new(),
+			size: None,
+			reason: String::from(""),
+		}
+	}
+
+	// the plan for a dataset which couldn't be planned because of e.
+	pub fn failed(sourcehost:&str, source:&str, targethost:&str, target:&str, e:&PiperError) -> DatasetPlan
+	{
+		let mut plan = DatasetPlan::new(sourcehost, source, targethost, target);
+		plan.action = match e
+			{
+				PiperError::DivergedTarget{..}=>Action::Diverged,
+				_=>Action::Blocked,
+			};
+		plan.reason = e.to_string();
+		plan
+	}
+
+	fn is_for(&self, other:&DatasetPlan) -> bool
+	{
+		self.sourcehost == other.sourcehost && self.source == other.source && self.targethost == other.targethost && self.target == other.target
+	}
+
+	// how this plan differs from other, a plan for the same dataset, if it does.
+	// the estimated size and the reason a dataset is blocked don't count.
+	pub fn difference(&self, other:&DatasetPlan) -> Option<String>
+	{
+		if self.action != other.action
+		{
+			return Some(format!("the plan was \"{}\", it is now \"{}\"", self.action.name(), other.action.name()));
+		}
+		if self.action == Action::Blocked || self.action == Action::Diverged
+		{
+			return None;
+		}
+		if self.snapshot != other.snapshot || self.guid != other.guid
+		{
+			return Some(format!("the plan was to replicate \"{}\" (guid {}), it is now \"{}\" (guid {})", self.snapshot, self.guid, other.snapshot, other.guid));
+		}
+		if self.from != other.from || self.from_target != other.from_target || self.steps != other.steps
+		{
+			return Some(format!("the plan was to send from \"{}\", it is now from \"{}\"", self.from, other.from));
+		}
+		if self.abort_partial != other.abort_partial || self.resume_token != other.resume_token
+		{
+			return Some(String::from("the partial receive state on the target has changed"));
+		}
+		None
+	}
+
+	pub fn describe(&self) -> String
+	{
+		let size = match self.size
+			{
+				None=>String::from(""),
+				Some(size)=>format!(" (about {})", format_size(size)),
+			};
+		let description = match self.action
+			{
+				Action::Full=>format!("full replication of \"{}\"{}", self.snapshot, size),
+				Action::Incremental if self.steps.len() > 1=>format!("incremental from \"{}\" to \"{}\" in {} sends{}", self.from, self.snapshot, self.steps.len(), size),
+				Action::Incremental=>format!("incremental from \"{}\" to \"{}\"{}", self.from, self.snapshot, size),
+				Action::Resume=>format!("resume the interrupted replication of \"{}\"{}", self.snapshot, size),
+				Action::UpToDate=>String::from("up to date"),
+				Action::Blocked|Action::Diverged=>format!("{}: {}", self.action.name(), self.reason),
+			};
+		if self.abort_partial
+		{
+			format!("{}, after discarding the partial receive state on the target", description)
+		}
+		else
+		{
+			description
+		}
+	}
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct JobPlan
+{
+	pub name: String,
+	// an error which stopped the job from being planned at all.
+	pub error: Option<String>,
+	pub datasets: Vec<DatasetPlan>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Plan
+{
+	pub created: String,
+	// the config file the plan was made from.
+	pub config: String,
+	pub jobs: Vec<JobPlan>,
+}
+
+// how a run treats the plan for each dataset.
+#[derive(Clone)]
+pub enum Mode
+{
+	// carry it out straight away, as a normal run does.
+	Run,
+	// only plan. with estimate the size of each send is estimated as well.
+	Plan{estimate:bool},
+	// carry out a saved plan.
+	Apply(Plan),
+}
+
+// "host:dataset", or just "dataset" on the local host.
+pub fn host_and_dataset(host:&str, dataset:&str) -> String
+{
+	if host.is_empty() {String::from(dataset)} else {format!("{}:{}", host, dataset)}
+}
+
+pub fn format_size(size:u64) -> String
+{
+	let units = ["B", "KiB", "MiB", "GiB", "TiB", "PiB"];
+	let mut value = size as f64;
+	let mut unit = 0;
+	while value >= 1024.0 && unit + 1 < units.len()
+	{
+		value /= 1024.0;
+		unit += 1;
+	}
+	if unit == 0 {format!("{} B", size)} else {format!("{:.1} {}", value, units[unit])}
+}
+
+// the plans of the jobs in the results of a run made with Mode::Plan.
+pub fn job_plans(results:&[JobResult]) -> Vec<JobPlan>
+{
+	results.iter().map(|job| JobPlan{
+			name: job.name.clone(),
+			error: job.error.as_ref().map(|e| e.to_string()),
+			datasets: job.datasets.iter().filter_map(|dataset| dataset.plan.clone()).collect(),
+		}).collect()
+}
+
+// the plan for the same dataset as plan in the plans of a job, if there is one.
+pub fn find<'a>(plans:&'a [DatasetPlan], plan:&DatasetPlan) -> Option<&'a DatasetPlan>
+{
+	plans.iter().find(|p| p.is_for(plan))
+}
+
+// everything which differs between the saved plan and the plans made now.
+pub fn drift(saved:&Plan, current:&[JobPlan]) -> Vec<String>
+{
+	let mut differences = Vec::new();
+	for job in saved.jobs.iter()
+	{
+		let now = match current.iter().find(|j| j.name == job.name)
+			{
+				None=>
+					{
+						differences.push(format!("Job \"{}\" is no longer in the config file.", job.name));
+						continue;
+					},
+				Some(now)=>now,
+			};
+		if job.error.is_some() != now.error.is_some()
+		{
+			differences.push(format!("Job \"{}\" {}.", job.name, match &now.error
+				{
+					None=>String::from("could not be planned, but can now"),
+					Some(e)=>format!("can no longer be planned: {}", e),
+				}));
+			continue;
+		}
+		for dataset in job.datasets.iter()
+		{
+			match find(&now.datasets, dataset)
+			{
+				None=>differences.push(format!("Job \"{}\": \"{}\" is no longer replicated to \"{}\".", job.name, host_and_dataset(&dataset.sourcehost, &dataset.source), host_and_dataset(&dataset.targethost, &dataset.target))),
+				Some(dataset_now)=>if let Some(difference) = dataset.difference(dataset_now)
+					{
+						differences.push(format!("Job \"{}\": \"{}\": {}.", job.name, host_and_dataset(&dataset.sourcehost, &dataset.source), difference));
+					},
+			}
+		}
+		for dataset in now.datasets.iter().filter(|d| find(&job.datasets, d).is_none())
+		{
+			differences.push(format!("Job \"{}\": \"{}\" is not in the plan.", job.name, host_and_dataset(&dataset.sourcehost, &dataset.source)));
+		}
+	}
+	differences
+}
+
+pub fn print(plan:&Plan)
+{
+	println!("Plan made {} from \"{}\"", plan.created, plan.config);
+	for job in plan.jobs.iter()
+	{
+		println!("Job \"{}\":", job.name);
+		if let Some(e) = &job.error
+		{
+			println!("\tcan't be planned: {}", e);
+		}
+		for dataset in job.datasets.iter()
+		{
+			println!("\t\"{}\" -> \"{}\": {}", host_and_dataset(&dataset.sourcehost, &dataset.source), host_and_dataset(&dataset.targethost, &dataset.target), dataset.describe());
+		}
+	}
+	let sizes:Vec<u64> = plan.jobs.iter().flat_map(|job| job.datasets.iter()).filter_map(|dataset| dataset.size).collect();
+	if !sizes.is_empty()
+	{
+		println!("About {} to send in all.", format_size(sizes.iter().sum()));
+	}
+}
+
+pub fn save(plan:&Plan, path:&Path) -> Result<(), String>
+{
+	let file = File::create(path).map_err(|e| format!("Error creating plan file \"{}\": {}", path.display(), e))?;
+	serde_json::to_writer_pretty(file, plan).map_err(|e| format!("Error writing plan file \"{}\": {}", path.display(), e))
+}
+
+// reads a saved plan. if it can't be read, the error is returned with the exit code for it.
+pub fn load(path:&Path) -> Result<Plan, (i32, String)>
+{
+	let file = File::open(path).map_err(|e| (1, format!("Error opening plan file \"{}\": {}", path.display(), e)))?;
+	serde_json::from_reader(file).map_err(|e| (3, format!("Error parsing plan file \"{}\": {}", path.display(), e)))
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+
+	fn incremental(source:&str, from:&str, snapshot:&str, guid:&str) -> DatasetPlan
+	{
+		let mut plan = DatasetPlan::new("", source, "backup", source.replacen("tank", "backup", 1).as_str());
+		plan.action = Action::Incremental;
+		plan.snapshot = format!("{}@{}", source, snapshot);
+		plan.guid = String::from(guid);
+		plan.from = format!("{}@{}", source, from);
+		plan.steps = vec![IncrementalStep{from:plan.from.clone(), to:plan.snapshot.clone(), intermediates:false}];
+		plan
+	}
+
+	fn job(name:&str, datasets:Vec<DatasetPlan>) -> JobPlan
+	{
+		JobPlan{name:String::from(name), error:None, datasets}
+	}
+
+	fn plan(jobs:Vec<JobPlan>) -> Plan
+	{
+		Plan{created:String::from("2024-06-01 00:00:00"), config:String::from("piper.json"), jobs}
+	}
+
+	#[test]
+	fn no_drift()
+	{
+		let saved = plan(vec![job("root", vec![incremental("tank/data", "a", "b", "2")])]);
+		let mut now = saved.jobs.clone();
+		// nor does the estimate count.
+		now[0].datasets[0].size = Some(1024);
+		assert!(drift(&saved, &now).is_empty());
+	}
+
+	#[test]
+	fn new_snapshot_on_the_source()
+	{
+		let saved = plan(vec![job("root", vec![incremental("tank/data", "a", "b", "2")])]);
+		let now = vec![job("root", vec![incremental("tank/data", "a", "c", "3")])];
+		assert_eq!(drift(&saved, &now), vec!["Job \"root\": \"tank/data\": the plan was to replicate \"tank/data@b\" (guid 2), it is now \"tank/data@c\" (guid 3)."]);
+	}
+
+	#[test]
+	fn snapshot_made_again()
+	{
+		let saved = plan(vec![job("root", vec![incremental("tank/data", "a", "b", "2")])]);
+		let now = vec![job("root", vec![incremental("tank/data", "a", "b", "9")])];
+		assert_eq!(drift(&saved, &now).len(), 1);
+	}
+
+	#[test]
+	fn new_incremental_base()
+	{
+		let saved = plan(vec![job("root", vec![incremental("tank/data", "a", "b", "2")])]);
+		let now = vec![job("root", vec![incremental("tank/data", "z", "b", "2")])];
+		assert_eq!(drift(&saved, &now), vec!["Job \"root\": \"tank/data\": the plan was to send from \"tank/data@a\", it is now from \"tank/data@z\"."]);
+	}
+
+	#[test]
+	fn action_changed()
+	{
+		let saved = plan(vec![job("root", vec![incremental("tank/data", "a", "b", "2")])]);
+		let mut diverged = DatasetPlan::failed("", "tank/data", "backup", "backup/data", &PiperError::Config(String::from("broken")));
+		assert_eq!(diverged.action, Action::Blocked);
+		diverged.action = Action::Diverged;
+		let now = vec![job("root", vec![diverged])];
+		assert_eq!(drift(&saved, &now), vec!["Job \"root\": \"tank/data\": the plan was \"incremental\", it is now \"diverged\"."]);
+	}
+
+	#[test]
+	fn partial_state_changed()
+	{
+		let saved = plan(vec![job("root", vec![incremental("tank/data", "a", "b", "2")])]);
+		let mut now = saved.jobs.clone();
+		now[0].datasets[0].abort_partial = true;
+		assert_eq!(drift(&saved, &now), vec!["Job \"root\": \"tank/data\": the partial receive state on the target has changed."]);
+	}
+
+	#[test]
+	fn jobs_and_datasets_come_and_go()
+	{
+		let saved = plan(vec![job("root", vec![incremental("tank/data", "a", "b", "2")]), job("gone", Vec::new())]);
+		let now = vec![job("root", vec![incremental("tank/other", "a", "b", "2")])];
+		assert_eq!(drift(&saved, &now), vec![
+				"Job \"root\": \"tank/data\" is no longer replicated to \"backup:backup/data\".",
+				"Job \"root\": \"tank/other\" is not in the plan.",
+				"Job \"gone\" is no longer in the config file.",
+			]);
+	}
+
+	#[test]
+	fn job_can_no_longer_be_planned()
+	{
+		let saved = plan(vec![job("root", Vec::new())]);
+		let mut now = saved.jobs.clone();
+		now[0].error = Some(String::from("can't login to source host"));
+		assert_eq!(drift(&saved, &now), vec!["Job \"root\" can no longer be planned: can't login to source host."]);
+		assert_eq!(drift(&plan(now), &saved.jobs), vec!["Job \"root\" could not be planned, but can now."]);
+	}
+
+	#[test]
+	fn sizes()
+	{
+		assert_eq!(format_size(1023), "1023 B");
+		assert_eq!(format_size(1536), "1.5 KiB");
+		assert_eq!(format_size(5 * 1024 * 1024 * 1024), "5.0 GiB");
+	}
+}
@@ -0,0 +1,131 @@
+/*
+	report
+	with "--report json" piper writes a json object for each dataset to stdout (or
+	to the file given with --report-file) at the end of a run, one per line: the
+	source and target, the snapshots sent from and to, what was done, how many
+	bytes were sent and how long it took, and the result with its error class. a
+	job which failed before any dataset was looked at gets one object of its own.
+*/
+use serde::Serialize;
+use std::{fs::OpenOptions, io::Write, path::Path};
+use crate::plan::host_and_dataset;
+use crate::summary::JobResult;
+
+#[derive(Serialize)]
+struct DatasetReport
+{
+	job: String,
+	source: String,
+	target: String,
+	from: String,
+	to: String,
+	// full, incremental, resumed or up to date. none if the dataset failed.
+	mode: Option<&'static str>,
+	bytes: u64,
+	started: String,
+	seconds: f64,
+	// "ok" or "failed".
+	result: &'static str,
+	error_class: Option<&'static str>,
+	error: Option<String>,
+}
+
+// the report of a run, one line per dataset.
+fn lines(jobs:&[JobResult]) -> Vec<String>
+{
+	let mut reports = Vec::new();
+	for job in jobs.iter()
+	{
+		if let Some(e) = &job.error
+		{
+			reports.push(DatasetReport{job:job.name.clone(), source:String::from(""), target:String::from(""), from:String::from(""), to:String::from(""),
+					mode:None, bytes:0, started:String::from(""), seconds:0.0, result:"failed", error_class:Some(e.class()), error:Some(e.to_string())});
+		}
+		for dataset in job.datasets.iter()
+		{
+			reports.push(DatasetReport{
+				job: job.name.clone(),
+				source: host_and_dataset(dataset.sourcehost.as_str(), dataset.dataset.as_str()),
+				target: host_and_dataset(dataset.targethost.as_str(), dataset.target.as_str()),
+				from: dataset.sends.first().map(|send| send.from.clone()).unwrap_or_default(),
+				to: dataset.sends.last().map(|send| send.to.clone()).unwrap_or_default(),
+				mode: dataset.result.as_ref().ok().map(|outcome| outcome.name()),
+				bytes: dataset.bytes(),
+				started: dataset.started.to_rfc3339(),
+				seconds: dataset.duration.as_secs_f64(),
+				result: if dataset.result.is_ok() {"ok"} else {"failed"},
+				error_class: dataset.result.as_ref().err().map(|e| e.class()),
+				error: dataset.result.as_ref().err().map(|e| e.to_string()),
+			});
+		}
+	}
+	reports.iter().filter_map(|report| serde_json::to_string(report).ok()).collect()
+}
+
+// writes the report to path, or to stdout if there is no path. the daemon appends
+// the report of each job to the file as the job finishes, rather than rewriting it.
+pub fn write(jobs:&[JobResult], path:Option<&Path>, append:bool) -> Result<(), String>
+{
+	let mut out:Box<dyn Write> = match path
+		{
+			None=>Box::new(std::io::stdout()),
+			Some(path)=>Box::new(OpenOptions::new().write(true).create(true).append(append).truncate(!append).open(path).map_err(|e| format!("Error creating report file \"{}\": {}", path.display(), e))?),
+		};
+	for line in lines(jobs).iter()
+	{
+		writeln!(out, "{}", line).map_err(|e| format!("Error writing report: {}", e))?;
+	}
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+	use chrono::{Local, TimeZone};
+	use std::time::Duration;
+	use crate::error::PiperError;
+	use crate::summary::{DatasetResult, Outcome, SendRecord};
+
+	fn dataset(sends:Vec<SendRecord>, result:Result<Outcome, PiperError>) -> DatasetResult
+	{
+		DatasetResult{
+				dataset: String::from("tank/data"),
+				sourcehost: String::from(""),
+				targethost: String::from("backup"),
+				target: String::from("backup/data"),
+				started: Local.timestamp_opt(1_700_000_000, 0).unwrap(),
+				duration: Duration::from_millis(2500),
+				sends,
+				result,
+				plan: None,
+				lag: None,
+			}
+	}
+
+	fn send(from:&str, to:&str, bytes:u64) -> SendRecord
+	{
+		SendRecord{from:String::from(from), to:String::from(to), bytes, started:String::from(""), seconds:1.0, error:None}
+	}
+
+	#[test]
+	fn a_dataset_is_reported_with_every_field_in_order()
+	{
+		let started = Local.timestamp_opt(1_700_000_000, 0).unwrap().to_rfc3339();
+		let sends = vec![send("tank/data@one", "tank/data@two", 100), send("tank/data@two", "tank/data@three", 50)];
+		let job = JobResult{name:String::from("root"), error:None, datasets:vec![dataset(sends, Ok(Outcome::Incremental))], matched:None};
+		assert_eq!(lines(&[job]), vec![format!("{{\"job\":\"root\",\"source\":\"tank/data\",\"target\":\"backup:backup/data\",\"from\":\"tank/data@one\",\"to\":\"tank/data@three\",\"mode\":\"incremental\",\"bytes\":150,\"started\":\"{}\",\"seconds\":2.5,\"result\":\"ok\",\"error_class\":null,\"error\":null}}", started)]);
+	}
+
+	#[test]
+	fn failures_are_reported_with_their_class()
+	{
+		let started = Local.timestamp_opt(1_700_000_000, 0).unwrap().to_rfc3339();
+		let failed = dataset(Vec::new(), Err(PiperError::Config(String::from("broken"))));
+		let job = JobResult{name:String::from("root"), error:Some(PiperError::Config(String::from("no hook"))), datasets:vec![failed], matched:None};
+		assert_eq!(lines(&[job]), vec![
+				String::from("{\"job\":\"root\",\"source\":\"\",\"target\":\"\",\"from\":\"\",\"to\":\"\",\"mode\":null,\"bytes\":0,\"started\":\"\",\"seconds\":0.0,\"result\":\"failed\",\"error_class\":\"config\",\"error\":\"configuration error: no hook\"}"),
+				format!("{{\"job\":\"root\",\"source\":\"tank/data\",\"target\":\"backup:backup/data\",\"from\":\"\",\"to\":\"\",\"mode\":null,\"bytes\":0,\"started\":\"{}\",\"seconds\":2.5,\"result\":\"failed\",\"error_class\":\"config\",\"error\":\"configuration error: broken\"}}", started),
+			]);
+	}
+}
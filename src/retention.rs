@@ -0,0 +1,172 @@
+/*
+	retention
+	normally the target is pruned by "zfs recv -F", which destroys any snapshot on
+	the target which has been destroyed on the source. a job with "target_retention"
+	receives without -F instead, so the target keeps its snapshots, and piper prunes
+	it by its own rules after each replication. each rule keeps the newest "keep"
+	snapshots whose names start with its "prefix" (every snapshot if it has none), or
+	if it has a "period", the newest snapshot in each of the newest "keep" hours,
+	days, weeks, months or years which have one. a snapshot is kept if any rule keeps
+	it, and snapshots which no rule matches are left alone.
+*/
+use chrono::{Local, TimeZone};
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct RetentionRule
+{
+	pub prefix: Option<String>,
+	// "hourly", "daily", "weekly", "monthly" or "yearly".
+	pub period: Option<String>,
+	pub keep: usize,
+}
+
+pub struct TargetSnapshot
+{
+	// the name of the snapshot, without the dataset.
+	pub name: String,
+	// seconds since the epoch.
+	pub creation: i64,
+	// held snapshots can't be destroyed.
+	pub held: bool,
+}
+
+// the format of the key which is the same for every snapshot in the same period.
+fn period_format(period:&str) -> Option<&'static str>
+{
+	match period
+	{
+		"hourly"=>Some("%Y-%m-%d %H"),
+		"daily"=>Some("%Y-%m-%d"),
+		// the iso week, whose year isn't always the calendar year.
+		"weekly"=>Some("%G-%V"),
+		"monthly"=>Some("%Y-%m"),
+		"yearly"=>Some("%Y"),
+		_=>None,
+	}
+}
+
+pub fn validate(rules:&[RetentionRule]) -> Result<(), String>
+{
+	for rule in rules.iter()
+	{
+		if let Some(period) = &rule.period
+		{
+			if period_format(period.as_str()).is_none()
+			{
+				return Err(format!("unknown target_retention period \"{}\" (must be \"hourly\", \"daily\", \"weekly\", \"monthly\" or \"yearly\")", period));
+			}
+		}
+	}
+	Ok(())
+}
+
+// the names of the snapshots which none of the rules keep. snapshots must be sorted
+// newest first. the newest snapshot is always kept, as it is the incremental base for
+// the next replication, as are held snapshots.
+pub fn expired(rules:&[RetentionRule], snapshots:&[TargetSnapshot]) -> Vec<String>
+{
+	let mut matched = vec![false; snapshots.len()];
+	let mut kept = vec![false; snapshots.len()];
+	for rule in rules.iter()
+	{
+		let prefix = match &rule.prefix
+			{
+				None=>"",
+				Some(s)=>s.as_str(),
+			};
+		let format = rule.period.as_deref().and_then(period_format);
+		let mut periods:Vec<String> = Vec::new();
+		for (i, snapshot) in snapshots.iter().enumerate()
+		{
+			if !snapshot.name.starts_with(prefix)
+			{
+				continue;
+			}
+			matched[i] = true;
+			// without a period every snapshot is a period of its own.
+			let period = match format
+				{
+					None=>snapshot.name.clone(),
+					Some(format)=>match Local.timestamp_opt(snapshot.creation, 0).single()
+						{
+							None=>snapshot.name.clone(),
+							Some(creation)=>creation.format(format).to_string(),
+						},
+				};
+			if !periods.contains(&period) && periods.len() < rule.keep
+			{
+				periods.push(period);
+				kept[i] = true;
+			}
+		}
+	}
+	snapshots.iter().enumerate().filter(|(i, snapshot)| *i > 0 && matched[*i] && !kept[*i] && !snapshot.held).map(|(_, snapshot)| snapshot.name.clone()).collect()
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+
+	fn rule(prefix:Option<&str>, period:Option<&str>, keep:usize) -> RetentionRule
+	{
+		RetentionRule{prefix:prefix.map(String::from), period:period.map(String::from), keep}
+	}
+
+	// a snapshot taken at noon local time, days days ago from a fixed day.
+	fn snapshot(name:&str, days:i64, held:bool) -> TargetSnapshot
+	{
+		let noon = Local.with_ymd_and_hms(2024, 6, 30, 12, 0, 0).unwrap().timestamp();
+		TargetSnapshot{name:String::from(name), creation:noon - days*24*60*60, held}
+	}
+
+	#[test]
+	fn keeps_exactly_keep_snapshots()
+	{
+		let snapshots = vec![snapshot("c", 0, false), snapshot("b", 1, false), snapshot("a", 2, false)];
+		assert!(expired(&[rule(None, None, 3)], &snapshots).is_empty());
+		assert_eq!(expired(&[rule(None, None, 2)], &snapshots), vec!["a"]);
+	}
+
+	#[test]
+	fn the_newest_snapshot_is_always_kept()
+	{
+		// it is the base of the next incremental replication, whatever the rules say.
+		let snapshots = vec![snapshot("manual", 0, false), snapshot("auto-b", 1, false), snapshot("auto-a", 2, false)];
+		assert_eq!(expired(&[rule(None, None, 0)], &snapshots), vec!["auto-b", "auto-a"]);
+		assert!(expired(&[rule(Some("manual"), None, 0)], &snapshots).is_empty());
+	}
+
+	#[test]
+	fn held_snapshots_are_kept()
+	{
+		// the previous base is still held until the new one is.
+		let snapshots = vec![snapshot("c", 0, false), snapshot("b", 1, true), snapshot("a", 2, false)];
+		assert_eq!(expired(&[rule(None, None, 1)], &snapshots), vec!["a"]);
+	}
+
+	#[test]
+	fn snapshots_no_rule_matches_are_left_alone()
+	{
+		let snapshots = vec![snapshot("daily-c", 0, false), snapshot("manual", 1, false), snapshot("daily-b", 2, false), snapshot("daily-a", 3, false)];
+		assert_eq!(expired(&[rule(Some("daily-"), None, 2)], &snapshots), vec!["daily-a"]);
+	}
+
+	#[test]
+	fn periods_keep_the_newest_snapshot_in_each()
+	{
+		let snapshots = vec![snapshot("e", 0, false), snapshot("d", 1, false), snapshot("c", 1, false), snapshot("b", 2, false), snapshot("a", 3, false)];
+		// two days: the newest of today and the newest of yesterday.
+		assert_eq!(expired(&[rule(None, Some("daily"), 2)], &snapshots), vec!["c", "b", "a"]);
+		// exactly as many days as there are.
+		assert_eq!(expired(&[rule(None, Some("daily"), 4)], &snapshots), vec!["c"]);
+	}
+
+	#[test]
+	fn a_snapshot_any_rule_keeps_is_kept()
+	{
+		let snapshots = vec![snapshot("d", 0, false), snapshot("c", 1, false), snapshot("b", 40, false), snapshot("a", 80, false)];
+		assert_eq!(expired(&[rule(None, None, 2), rule(None, Some("monthly"), 2)], &snapshots), vec!["a"]);
+	}
+}
@@ -0,0 +1,258 @@
+/*
+	schedule
+	when a job runs in daemon mode. a schedule is either an interval ("every 1h",
+	or just "1h") measured from the start of the job's last run, or a cron
+	expression with the usual five fields (minute hour day-of-month month
+	day-of-week) or one of @hourly, @daily, @weekly or @monthly.
+*/
+use chrono::{DateTime, Datelike, Duration as ChronoDuration, Local, Timelike};
+use std::time::Duration;
+use crate::duration::parse_duration;
+
+pub enum Schedule
+{
+	Interval(Duration),
+	Cron(Cron),
+}
+
+pub struct Cron
+{
+	minutes: Vec<bool>,
+	hours: Vec<bool>,
+	days: Vec<bool>,
+	months: Vec<bool>,
+	weekdays: Vec<bool>,
+	// cron runs a job if either the day of the month or the day of the week matches,
+	// unless one of them is "*", in which case only the other has to match.
+	any_day: bool,
+	any_weekday: bool,
+}
+
+// parses one field of a cron expression into the set of values it allows.
+// supports "*", single values, ranges ("1-5"), steps ("*/15", "0-30/10") and lists ("1,15").
+fn parse_field(field:&str, min:u32, max:u32) -> Result<Vec<bool>, String>
+{
+	let mut allowed = vec![false; (max + 1) as usize];
+	for part in field.split(',')
+	{
+		let (range, step) = match part.split_once('/')
+			{
+				None=>(part, 1),
+				Some((range, step))=>match step.parse::<u32>()
+					{
+						Ok(step) if step > 0=>(range, step),
+						_=>return Err(format!("bad step \"{}\" in \"{}\"", step, field)),
+					},
+			};
+		let (start, end) = if range == "*"
+			{
+				(min, max)
+			}
+			else
+			{
+				let (start, end) = match range.split_once('-')
+					{
+						None=>(range, range),
+						Some(bounds)=>bounds,
+					};
+				match (start.parse::<u32>(), end.parse::<u32>())
+				{
+					(Ok(start), Ok(end))=>(start, end),
+					_=>return Err(format!("bad value \"{}\" in \"{}\"", range, field)),
+				}
+			};
+		if start < min || end > max || start > end
+		{
+			return Err(format!("\"{}\" is out of range ({}-{}) in \"{}\"", range, min, max, field));
+		}
+		let mut value = Some(start);
+		while let Some(v) = value.filter(|v| *v <= end)
+		{
+			allowed[v as usize] = true;
+			value = v.checked_add(step);
+		}
+	}
+	Ok(allowed)
+}
+
+impl Cron
+{
+	pub fn parse(expression:&str) -> Result<Cron, String>
+	{
+		let expression = match expression.trim()
+			{
+				"@hourly"=>"0 * * * *",
+				"@daily"|"@midnight"=>"0 0 * * *",
+				"@weekly"=>"0 0 * * 0",
+				"@monthly"=>"0 0 1 * *",
+				other=>other,
+			};
+		let fields:Vec<&str> = expression.split_whitespace().collect();
+		if fields.len() != 5
+		{
+			return Err(format!("a cron expression needs 5 fields, \"{}\" has {}", expression, fields.len()));
+		}
+		let mut weekdays = parse_field(fields[4], 0, 7)?;
+		// both 0 and 7 are sunday.
+		if weekdays[7]
+		{
+			weekdays[0] = true;
+		}
+		Ok(Cron{
+			minutes: parse_field(fields[0], 0, 59)?,
+			hours: parse_field(fields[1], 0, 23)?,
+			days: parse_field(fields[2], 1, 31)?,
+			months: parse_field(fields[3], 1, 12)?,
+			weekdays,
+			any_day: fields[2] == "*",
+			any_weekday: fields[4] == "*",
+		})
+	}
+
+	fn matches(&self, time:&DateTime<Local>) -> bool
+	{
+		let day = self.days[time.day() as usize];
+		let weekday = self.weekdays[time.weekday().num_days_from_sunday() as usize];
+		let day_matches = match (self.any_day, self.any_weekday)
+			{
+				(true, true)=>true,
+				(true, false)=>weekday,
+				(false, true)=>day,
+				(false, false)=>day || weekday,
+			};
+		self.minutes[time.minute() as usize] && self.hours[time.hour() as usize] && self.months[time.month() as usize] && day_matches
+	}
+
+	// the first minute after time which matches, or None if there isn't one within
+	// a few years (say "0 0 31 2 *").
+	pub fn next_after(&self, time:DateTime<Local>) -> Option<DateTime<Local>>
+	{
+		let mut next = time.with_second(0)?.with_nanosecond(0)? + ChronoDuration::minutes(1);
+		let limit = time + ChronoDuration::days(4 * 366);
+		while next < limit
+		{
+			if self.matches(&next)
+			{
+				return Some(next);
+			}
+			next += ChronoDuration::minutes(1);
+		}
+		None
+	}
+}
+
+impl Schedule
+{
+	pub fn parse(text:&str) -> Result<Schedule, String>
+	{
+		let text = text.trim();
+		if text.starts_with('@') || text.split_whitespace().count() == 5
+		{
+			return Ok(Schedule::Cron(Cron::parse(text)?));
+		}
+		let interval = match text.strip_prefix("every ")
+			{
+				None=>text,
+				Some(interval)=>interval,
+			};
+		let interval = parse_duration(interval)?;
+		if interval.as_secs() == 0
+		{
+			return Err(format!("the interval in \"{}\" must be more than 0", text));
+		}
+		Ok(Schedule::Interval(interval))
+	}
+
+	// when the job should next run, given when it last started (if it has run yet).
+	// an interval job which hasn't run yet is due straight away.
+	pub fn next_run(&self, last_run:Option<DateTime<Local>>, now:DateTime<Local>) -> Option<DateTime<Local>>
+	{
+		match self
+		{
+			Schedule::Interval(interval)=>match last_run
+				{
+					None=>Some(now),
+					Some(last_run)=>last_run.checked_add_signed(ChronoDuration::from_std(*interval).ok()?),
+				},
+			Schedule::Cron(cron)=>cron.next_after(match last_run
+				{
+					None=>now,
+					Some(last_run)=>std::cmp::max(last_run, now),
+				}),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+	use chrono::TimeZone;
+
+	fn at(day:u32, hour:u32, minute:u32) -> DateTime<Local>
+	{
+		// june 2024 starts on a saturday.
+		Local.with_ymd_and_hms(2024, 6, day, hour, minute, 0).unwrap()
+	}
+
+	fn values(field:&str, min:u32, max:u32) -> Vec<u32>
+	{
+		parse_field(field, min, max).unwrap().iter().enumerate().filter(|(_, allowed)| **allowed).map(|(value, _)| value as u32).collect()
+	}
+
+	#[test]
+	fn fields()
+	{
+		assert_eq!(values("*", 1, 12), (1..=12).collect::<Vec<u32>>());
+		assert_eq!(values("5", 0, 59), vec![5]);
+		assert_eq!(values("1-5", 0, 7), vec![1, 2, 3, 4, 5]);
+		assert_eq!(values("*/15", 0, 59), vec![0, 15, 30, 45]);
+		assert_eq!(values("0-30/10", 0, 59), vec![0, 10, 20, 30]);
+		assert_eq!(values("1,15,20-21", 1, 31), vec![1, 15, 20, 21]);
+		assert_eq!(values("59-59/4294967295", 0, 59), vec![59]);
+	}
+
+	#[test]
+	fn bad_fields_are_errors()
+	{
+		assert!(parse_field("60", 0, 59).is_err());
+		assert!(parse_field("0", 1, 31).is_err());
+		assert!(parse_field("5-1", 0, 59).is_err());
+		assert!(parse_field("*/0", 0, 59).is_err());
+		assert!(parse_field("a", 0, 59).is_err());
+		assert!(Cron::parse("* * * *").is_err());
+	}
+
+	#[test]
+	fn cron_next_run()
+	{
+		let cron = Cron::parse("5 0 * * *").unwrap();
+		assert_eq!(cron.next_after(at(1, 0, 4)), Some(at(1, 0, 5)));
+		assert_eq!(cron.next_after(at(1, 0, 5)), Some(at(2, 0, 5)));
+		assert_eq!(Cron::parse("@hourly").unwrap().next_after(at(1, 10, 30)), Some(at(1, 11, 0)));
+		// sunday is 0 and 7.
+		assert_eq!(Cron::parse("0 0 * * 7").unwrap().next_after(at(1, 12, 0)), Some(at(2, 0, 0)));
+		assert_eq!(Cron::parse("0 0 31 2 *").unwrap().next_after(at(1, 0, 0)), None);
+	}
+
+	#[test]
+	fn day_of_month_or_day_of_week()
+	{
+		// either the 10th or a monday (the 3rd).
+		assert_eq!(Cron::parse("0 0 10 * 1").unwrap().next_after(at(1, 0, 0)), Some(at(3, 0, 0)));
+		// only the 10th.
+		assert_eq!(Cron::parse("0 0 10 * *").unwrap().next_after(at(1, 0, 0)), Some(at(10, 0, 0)));
+	}
+
+	#[test]
+	fn intervals()
+	{
+		let schedule = Schedule::parse("every 1h30m").unwrap();
+		assert_eq!(schedule.next_run(None, at(1, 0, 0)), Some(at(1, 0, 0)));
+		assert_eq!(schedule.next_run(Some(at(1, 0, 0)), at(1, 0, 10)), Some(at(1, 1, 30)));
+		assert!(Schedule::parse("every 0s").is_err());
+		assert!(Schedule::parse("every 18446744073709551615w").is_err());
+		// too far in the future to ever come due.
+		assert_eq!(Schedule::parse("18446744073709551615").unwrap().next_run(Some(at(1, 0, 0)), at(1, 0, 0)), None);
+	}
+}
@@ -0,0 +1,247 @@
+/*
+	summary
+	the result of each job, and of each dataset within a job, is collected as it
+	runs so that a summary can be printed at the end of the run and the exit code
+	can tell a healthy run from one where something went wrong.
+*/
+use chrono::{DateTime, Local};
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use crate::error::PiperError;
+use crate::plan::DatasetPlan;
+
+// exit codes. 1 (usage/can't open the config file), 3 (can't parse the config
+// file or simulation file) and 10 (can't start logging) are set before any job runs.
+pub const EXIT_OK:i32 = 0;
+pub const EXIT_SOME_FAILED:i32 = 4;
+pub const EXIT_ALL_FAILED:i32 = 5;
+// another piper holds the lock for the run, so no job was run.
+pub const EXIT_LOCKED:i32 = 6;
+// something has changed since the plan given to apply was made, so no job was run.
+pub const EXIT_DRIFTED:i32 = 7;
+
+// what was done to a dataset which didn't fail.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Outcome
+{
+	Full,
+	Incremental,
+	Resumed,
+	UpToDate,
+	// only planned, by "piper plan".
+	Planned,
+}
+
+impl Outcome
+{
+	pub fn name(&self) -> &'static str
+	{
+		match self
+		{
+			Outcome::Full=>"full",
+			Outcome::Incremental=>"incremental",
+			Outcome::Resumed=>"resumed",
+			Outcome::UpToDate=>"up to date",
+			Outcome::Planned=>"planned",
+		}
+	}
+}
+
+// one zfs send and receive.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct SendRecord
+{
+	// "" for a full send.
+	pub from: String,
+	pub to: String,
+	// as reported by "zfs send -P", 0 if it wasn't.
+	pub bytes: u64,
+	pub started: String,
+	pub seconds: f64,
+	// the class of error, if the send failed.
+	pub error: Option<String>,
+}
+
+pub struct DatasetResult
+{
+	pub dataset: String,
+	pub sourcehost: String,
+	pub targethost: String,
+	// the dataset received into.
+	pub target: String,
+	pub started: DateTime<Local>,
+	pub duration: Duration,
+	// every send made for the dataset, in order.
+	pub sends: Vec<SendRecord>,
+	pub result: Result<Outcome, PiperError>,
+	// what was planned for the dataset, when only planning.
+	pub plan: Option<DatasetPlan>,
+	// how many seconds older the newest snapshot on the target is than the newest on
+	// the source, if both have one.
+	pub lag: Option<i64>,
+}
+
+pub struct JobResult
+{
+	pub name: String,
+	// an error which stopped the job before any dataset was looked at, or a post_hook
+	// which failed after they all were.
+	pub error: Option<PiperError>,
+	pub datasets: Vec<DatasetResult>,
+	// the datasets a job whose sourcedataset is a glob, or which has a source_property, matched.
+	pub matched: Option<Vec<String>>,
+}
+
+impl DatasetResult
+{
+	pub fn bytes(&self) -> u64
+	{
+		self.sends.iter().map(|send| send.bytes).sum()
+	}
+}
+
+impl JobResult
+{
+	pub fn failed(&self) -> bool
+	{
+		self.error.is_some() || self.datasets.iter().any(|d| d.result.is_err())
+	}
+}
+
+// logs one line per job, and one line per failed dataset, and returns the exit code for the run.
+pub fn summarize(jobs:&[JobResult]) -> i32
+{
+	info!("Summary:");
+	for job in jobs.iter()
+	{
+		if let Some(e) = &job.error
+		{
+			error!("\tJob \"{}\": FAILED ({}): {}", job.name, e.class(), e);
+			continue;
+		}
+		let ess = if job.datasets.len() == 1 {""} else {"s"};
+		if let Some(matched) = &job.matched
+		{
+			match matched.len()
+			{
+				0=>warn!("\tJob \"{}\" matched no datasets", job.name),
+				n=>info!("\tJob \"{}\" matched {} dataset{}: {}", job.name, n, if n == 1 {""} else {"s"}, matched.join(", ")),
+			}
+		}
+		if job.failed()
+		{
+			let errors = job.datasets.iter().filter(|d| d.result.is_err()).count();
+			error!("\tJob \"{}\": FAILED ({} of {} dataset{})", job.name, errors, job.datasets.len(), ess);
+		}
+		else
+		{
+			info!("\tJob \"{}\": OK ({} dataset{})", job.name, job.datasets.len(), ess);
+		}
+		for dataset in job.datasets.iter()
+		{
+			match &dataset.result
+			{
+				Ok(outcome)=>debug!("\t\t\"{}\": {}", dataset.dataset, outcome.name()),
+				Err(e)=>error!("\t\t\"{}\": {}: {}", dataset.dataset, e.class(), e),
+			}
+		}
+	}
+	let failed = jobs.iter().filter(|job| job.failed()).count();
+	if failed == 0
+	{
+		EXIT_OK
+	}
+	else if failed == jobs.len()
+	{
+		EXIT_ALL_FAILED
+	}
+	else
+	{
+		EXIT_SOME_FAILED
+	}
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+
+	fn dataset(result:Result<Outcome, PiperError>) -> DatasetResult
+	{
+		DatasetResult{
+				dataset: String::from("tank/data"),
+				sourcehost: String::from(""),
+				targethost: String::from("backup"),
+				target: String::from("backup/data"),
+				started: Local::now(),
+				duration: Duration::from_secs(1),
+				sends: Vec::new(),
+				result,
+				plan: None,
+				lag: None,
+			}
+	}
+
+	fn job(error:Option<PiperError>, results:Vec<Result<Outcome, PiperError>>) -> JobResult
+	{
+		JobResult{name:String::from("root"), error, datasets:results.into_iter().map(dataset).collect(), matched:None}
+	}
+
+	fn ok() -> JobResult
+	{
+		job(None, vec![Ok(Outcome::Incremental), Ok(Outcome::UpToDate)])
+	}
+
+	fn every_error() -> Vec<(PiperError, &'static str)>
+	{
+		vec![
+				(PiperError::Config(String::from("broken")), "config"),
+				(PiperError::SshFailure{host:String::from("backup"), message:String::from("refused")}, "ssh_failure"),
+				(PiperError::DatasetMissing{host:String::from("backup"), dataset:String::from("backup")}, "dataset_missing"),
+				(PiperError::NoSnapshots{host:String::from(""), dataset:String::from("tank/data"), prefix:String::from("")}, "no_snapshots"),
+				(PiperError::DivergedTarget{dataset:String::from("backup/data"), reason:String::from("gone")}, "diverged_target"),
+				(PiperError::Command{host:String::from(""), command:String::from("zfs hold"), message:String::from("busy")}, "command_failure"),
+				(PiperError::SendFailure(String::from("broken pipe")), "send_failure"),
+				(PiperError::RecvFailure(String::from("out of space")), "recv_failure"),
+				(PiperError::StageFailure{stage:String::from("mbuffer"), message:String::from("killed")}, "pipeline_failure"),
+				(PiperError::Locked{path:String::from("/var/run/piper.lock"), message:String::from("held")}, "locked"),
+				(PiperError::Drifted{dataset:String::from("tank/data"), reason:String::from("new snapshot")}, "drifted"),
+				(PiperError::HookFailure{hook:String::from("pre_hook"), message:String::from("exited with 1")}, "hook_failure"),
+			]
+	}
+
+	#[test]
+	fn exit_codes_of_mixed_results()
+	{
+		let failed_dataset = || job(None, vec![Ok(Outcome::Full), Err(PiperError::SendFailure(String::from("broken pipe")))]);
+		let failed_job = || job(Some(PiperError::Locked{path:String::from("/tmp/x.lock"), message:String::from("held")}), Vec::new());
+		let failed_post_hook = || job(Some(PiperError::HookFailure{hook:String::from("post_hook"), message:String::from("exited with 1")}), vec![Ok(Outcome::Full)]);
+		let table:Vec<(Vec<JobResult>, i32)> = vec![
+				(vec![], EXIT_OK),
+				(vec![ok()], EXIT_OK),
+				(vec![ok(), job(None, Vec::new())], EXIT_OK),
+				(vec![ok(), failed_dataset()], EXIT_SOME_FAILED),
+				(vec![failed_job(), ok(), ok()], EXIT_SOME_FAILED),
+				(vec![ok(), failed_post_hook()], EXIT_SOME_FAILED),
+				(vec![failed_dataset()], EXIT_ALL_FAILED),
+				(vec![failed_dataset(), failed_job(), failed_post_hook()], EXIT_ALL_FAILED),
+			];
+		for (i, (jobs, code)) in table.iter().enumerate()
+		{
+			assert_eq!(summarize(jobs), *code, "row {}", i);
+		}
+	}
+
+	#[test]
+	fn every_error_class_fails_its_dataset_and_its_job()
+	{
+		for (e, class) in every_error()
+		{
+			assert_eq!(e.class(), class);
+			assert_eq!(summarize(&[ok(), job(None, vec![Ok(Outcome::Full), Err(e.clone())])]), EXIT_SOME_FAILED, "{}", class);
+			assert_eq!(summarize(&[ok(), job(Some(e.clone()), Vec::new())]), EXIT_SOME_FAILED, "{}", class);
+			assert_eq!(summarize(&[job(None, vec![Err(e)])]), EXIT_ALL_FAILED, "{}", class);
+		}
+	}
+}